@@ -0,0 +1,125 @@
+//! The per frame work on the relay path: a client frame is decoded (is it
+//! a control message? does it carry a `message_id`?), routed as a
+//! relayed `TextMessage`, and encoded for the peer, either as is or
+//! wrapped in a `RelayEnvelope`.
+//!
+//! Run with `cargo bench`; criterion keeps the previous run in
+//! `target/criterion` and reports the change against it.
+
+#[macro_use]
+extern crate criterion;
+extern crate channelserver;
+
+use std::time::Instant;
+
+use criterion::{black_box, Bencher, Criterion, ParameterizedBenchmark, Throughput};
+
+use channelserver::protocol::{self, ClientControl};
+use channelserver::server::{Framing, TextMessage};
+
+/// Payload sizes, from a small pairing message up to the default
+/// `max_frame_size`.
+const SIZES: &[usize] = &[64, 1024, 16 * 1024, 64 * 1024];
+
+/// How a channel relays its frames.
+#[derive(Clone, Copy)]
+enum Encoding {
+    /// verbatim
+    Text,
+    /// `require_json`, with duplicate suppression
+    Json,
+    /// `sequence_frames`
+    Sequenced,
+    /// `sequence_frames` and `stamp_frames`
+    Stamped,
+    /// `hop_frames`
+    Hop,
+}
+
+/// A client frame carrying `size` bytes of payload.
+fn frame(size: usize, encoding: Encoding) -> String {
+    let data = "x".repeat(size);
+    match encoding {
+        Encoding::Text => data,
+        _ => format!(r#"{{"message_id": "m-1", "data": "{}"}}"#, data),
+    }
+}
+
+/// The server's framing for `encoding`.
+fn framing(encoding: Encoding) -> Framing<'static> {
+    match encoding {
+        Encoding::Text | Encoding::Json => Framing::default(),
+        Encoding::Sequenced => Framing {
+            sequence: true,
+            ..Framing::default()
+        },
+        Encoding::Stamped => Framing {
+            sequence: true,
+            stamp: true,
+            node: None,
+        },
+        Encoding::Hop => Framing {
+            sequence: true,
+            stamp: true,
+            node: Some("http://relay-0.example.com:8000"),
+        },
+    }
+}
+
+/// What the server does with each relayed frame, short of sending it.
+fn relay(frame: &str, encoding: Encoding) -> TextMessage {
+    let received = Instant::now();
+    let frame = frame.trim();
+    if let Some(control) = protocol::client_control(frame) {
+        panic!("not a relayed frame: {:?}", control);
+    }
+    if let Encoding::Json = encoding {
+        assert!(protocol::is_json(frame));
+        black_box(protocol::message_id(frame));
+    }
+    let text = framing(encoding).encode(frame, 1, received);
+    TextMessage::relayed(text, received)
+}
+
+fn encoding(encoding: Encoding) -> impl FnMut(&mut Bencher, &usize) + 'static {
+    move |b, size| {
+        let frame = frame(*size, encoding);
+        b.iter(|| relay(&frame, encoding))
+    }
+}
+
+fn bench_relay(c: &mut Criterion) {
+    c.bench(
+        "relay",
+        ParameterizedBenchmark::new("text", encoding(Encoding::Text), SIZES.to_vec())
+            .with_function("json", encoding(Encoding::Json))
+            .with_function("sequenced", encoding(Encoding::Sequenced))
+            .with_function("stamped", encoding(Encoding::Stamped))
+            .with_function("hop", encoding(Encoding::Hop))
+            .throughput(|size: &usize| Throughput::Bytes(*size as u32)),
+    );
+}
+
+/// Chunks are the only control messages that carry a payload.
+fn bench_chunk(c: &mut Criterion) {
+    c.bench(
+        "chunk",
+        ParameterizedBenchmark::new(
+            "decode",
+            |b: &mut Bencher, size: &usize| {
+                let frame = format!(
+                    r#"{{"control": "chunk", "transfer": "t-1", "index": 0, "count": 4, "data": "{}"}}"#,
+                    "x".repeat(*size)
+                );
+                b.iter(|| match protocol::client_control(&frame) {
+                    Some(ClientControl::Chunk { data, .. }) => data.len(),
+                    other => panic!("not a chunk: {:?}", other),
+                })
+            },
+            SIZES.to_vec(),
+        ).throughput(|size: &usize| Throughput::Bytes(*size as u32)),
+    );
+}
+
+criterion_group!(benches, bench_relay, bench_chunk);
+criterion_main!(benches);
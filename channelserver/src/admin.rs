@@ -0,0 +1,552 @@
+//! Administrative HTTP API.
+//!
+//! Every handler here requires an `Authorization: Bearer <admin_token>`
+//! header. If no `admin_token` is configured the admin API is disabled and
+//! all requests are refused. Clients that fail to authenticate too often
+//! are refused without their token being checked, for a while.
+//!
+//! Every authorized call is logged, and recorded in the audit history if
+//! there is one, with who made it and what it acted on.
+
+use std::io;
+use std::time::{Duration, Instant};
+
+use actix::{Actor, ActorContext, AsyncContext, Handler, StreamHandler};
+use actix_web::{http, ws, AsyncResponder, Error, FutureResponse, HttpRequest, HttpResponse, Json};
+use cadence::Counted;
+use futures::{future, Future};
+use rand;
+use serde_json::{self, Value};
+use sha2::{Digest, Sha256};
+use uuid::Uuid;
+
+use apikey;
+use logging::{ErrorLevel, SetLevel};
+use migrate;
+use perror::HandlerErrorKind;
+use profile;
+use ratelimit;
+use ratelimiter::Key;
+use replica;
+use server;
+use logging;
+use session::{self, WsChannelSessionState};
+use settings::Source;
+
+/// Body of a key issuance request.
+#[derive(Debug, Deserialize)]
+pub struct IssueKey {
+    pub tenant: String,
+}
+
+/// Body of a log level change.
+#[derive(Debug, Deserialize)]
+pub struct LogLevel {
+    pub level: String,
+    /// module to change, rather than the overall level
+    pub module: Option<String>,
+    /// seconds until the change is reverted
+    pub duration: Option<u64>,
+}
+
+/// How long a log level change lasts, unless the request says otherwise.
+const LOG_LEVEL_DURATION: u64 = 600;
+
+/// Compare two byte strings without exiting early on the first mismatch.
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    if a.len() != b.len() {
+        return false;
+    }
+    a.iter().zip(b).fold(0, |acc, (x, y)| acc | (x ^ y)) == 0
+}
+
+/// Check the request's bearer token against the configured admin token.
+pub fn authorized(req: &HttpRequest<WsChannelSessionState>) -> bool {
+    // may have been rotated since startup
+    let token = req.state().secrets.get("admin_token");
+    if token.is_empty() {
+        return false;
+    }
+    let ip = session::client_ip(req);
+    let limiter = &req.state().limiters.admin_auth;
+    let key = ip.map(Key::Ip);
+    if let Some(ref key) = key {
+        if ratelimit::with(limiter, &req.state().metrics, |l| l.exhausted(key)) {
+            req.state().metrics.incr("admin.auth.limited").ok();
+            return false;
+        }
+    }
+    let ok = req.headers()
+        .get(http::header::AUTHORIZATION)
+        .and_then(|v| v.to_str().ok())
+        .and_then(|v| {
+            if v.starts_with("Bearer ") {
+                Some(v[7..].trim())
+            } else {
+                None
+            }
+        })
+        .map_or(false, |v| constant_time_eq(v.as_bytes(), token.as_bytes()));
+    if !ok {
+        req.state().metrics.incr("admin.auth.failed").ok();
+        let from = match ip {
+            Some(ip) if !req.state().settings.relay_only => format!(" from {}", ip),
+            _ => "".to_owned(),
+        };
+        req.state().log.do_send(logging::LogMessage {
+            level: logging::ErrorLevel::Warn,
+            module: module_path!(),
+            msg: format!("Failed admin authentication{}", from),
+            trace: None,
+        });
+        if let Some(ref key) = key {
+            ratelimit::with(limiter, &req.state().metrics, |l| l.check(key));
+        }
+    }
+    ok
+}
+
+/// Who made an authorized request. There is only the admin token, so
+/// this is a fingerprint of it, which tells rotated tokens apart.
+fn principal(req: &HttpRequest<WsChannelSessionState>) -> String {
+    let digest = Sha256::digest(req.state().secrets.get("admin_token").as_bytes());
+    let fingerprint: String = digest.iter().take(4).map(|b| format!("{:02x}", b)).collect();
+    format!("admin:{}", fingerprint)
+}
+
+/// Log and audit an administrative action.
+fn record(req: &HttpRequest<WsChannelSessionState>, action: &str, resource: Value) {
+    let from = if req.state().settings.relay_only {
+        None
+    } else {
+        session::client_ip(req).map(|ip| ip.to_string())
+    };
+    req.state().shards.any().do_send(server::AdminAction {
+        action: action.to_owned(),
+        principal: principal(req),
+        from,
+        resource,
+        ts: apikey::now(),
+    });
+}
+
+fn key_response(info: apikey::KeyInfo, key: String) -> HttpResponse {
+    HttpResponse::Ok().json(json!({
+        "id": info.id,
+        "tenant": info.tenant,
+        "created": info.created,
+        "key": key,
+    }))
+}
+
+/// The key file couldn't be read or written, so the change didn't take.
+fn key_store_error(e: io::Error) -> HttpResponse {
+    HandlerErrorKind::UnavailableErr.response_with(Some(json!({
+        "reason": format!("api_key_file: {}", e)
+    })))
+}
+
+/// `POST /admin/keys` - mint a new key for a tenant.
+pub fn issue_key(
+    (req, body): (HttpRequest<WsChannelSessionState>, Json<IssueKey>),
+) -> HttpResponse {
+    if !authorized(&req) {
+        return HandlerErrorKind::UnauthorizedErr.response();
+    }
+    let issued = req.state().keys.write().unwrap().issue(&body.tenant);
+    match issued {
+        Ok((info, key)) => {
+            record(&req, "key.issue", json!({ "key": info.id, "tenant": info.tenant }));
+            key_response(info, key)
+        }
+        Err(e) => key_store_error(e),
+    }
+}
+
+/// `GET /admin/keys` - list issued keys (never the keys themselves).
+pub fn list_keys(req: &HttpRequest<WsChannelSessionState>) -> HttpResponse {
+    if !authorized(req) {
+        return HandlerErrorKind::UnauthorizedErr.response();
+    }
+    record(req, "key.list", json!({}));
+    let keys = req.state().keys.write().unwrap().list();
+    match keys {
+        Ok(keys) => HttpResponse::Ok().json(keys),
+        Err(e) => key_store_error(e),
+    }
+}
+
+/// `POST /admin/keys/{id}/rotate` - issue a replacement key. The old key
+/// remains valid for `api_key_overlap` seconds.
+pub fn rotate_key(req: &HttpRequest<WsChannelSessionState>) -> HttpResponse {
+    if !authorized(req) {
+        return HandlerErrorKind::UnauthorizedErr.response();
+    }
+    let id = req.match_info().get("id").unwrap_or("").to_owned();
+    record(req, "key.rotate", json!({ "key": id }));
+    let overlap = req.state().settings.api_key_overlap;
+    let rotated = req.state().keys.write().unwrap().rotate(&id, overlap);
+    match rotated {
+        Ok(Some((info, key))) => key_response(info, key),
+        Ok(None) => HandlerErrorKind::NotFoundErr.response(),
+        Err(e) => key_store_error(e),
+    }
+}
+
+/// `DELETE /admin/keys/{id}` - revoke a key immediately.
+pub fn revoke_key(req: &HttpRequest<WsChannelSessionState>) -> HttpResponse {
+    if !authorized(req) {
+        return HandlerErrorKind::UnauthorizedErr.response();
+    }
+    let id = req.match_info().get("id").unwrap_or("").to_owned();
+    record(req, "key.revoke", json!({ "key": id }));
+    let revoked = req.state().keys.write().unwrap().revoke(&id);
+    match revoked {
+        Ok(true) => HttpResponse::Ok().finish(),
+        Ok(false) => HandlerErrorKind::NotFoundErr.response(),
+        Err(e) => key_store_error(e),
+    }
+}
+
+/// `GET /admin/config` - the settings this node is running with (secrets
+/// redacted), and whether each came from its default, the config file or
+/// the environment.
+pub fn show_config(req: &HttpRequest<WsChannelSessionState>) -> HttpResponse {
+    if !authorized(req) {
+        return HandlerErrorKind::UnauthorizedErr.response();
+    }
+    record(req, "config.show", json!({}));
+    let settings = req.state().settings.redacted();
+    let mut config = serde_json::Map::new();
+    if let Ok(Value::Object(values)) = serde_json::to_value(&settings) {
+        for (key, value) in values {
+            let source = settings.sources.get(&key).cloned().unwrap_or(Source::Default);
+            config.insert(key, json!({ "value": value, "source": source }));
+        }
+    }
+    HttpResponse::Ok().json(Value::Object(config))
+}
+
+/// `POST /admin/log_level` - change the overall or a module's log level,
+/// reverting after `duration` seconds.
+pub fn set_log_level(
+    (req, body): (HttpRequest<WsChannelSessionState>, Json<LogLevel>),
+) -> HttpResponse {
+    if !authorized(&req) {
+        return HandlerErrorKind::UnauthorizedErr.response();
+    }
+    let level = match ErrorLevel::parse(&body.level) {
+        Some(level) => level,
+        None => {
+            return HandlerErrorKind::InvalidRequestErr
+                .response_with(Some(json!({ "reason": format!("Unknown level {:?}", body.level) })))
+        }
+    };
+    let duration = body.duration.unwrap_or(LOG_LEVEL_DURATION);
+    record(
+        &req,
+        "log_level.set",
+        json!({ "module": body.module, "level": body.level, "duration": duration }),
+    );
+    req.state().log.do_send(SetLevel {
+        module: body.module.clone(),
+        level,
+        revert_after: Duration::from_secs(duration),
+    });
+    HttpResponse::Ok().json(json!({
+        "module": body.module,
+        "level": body.level.to_lowercase(),
+        "duration": duration,
+    }))
+}
+
+/// `POST /admin/replica` - apply registry mutations (and resyncs) sent by
+/// the primary node, when this node is acting as its warm standby.
+pub fn apply_replica(
+    (req, body): (HttpRequest<WsChannelSessionState>, Json<replica::ReplicaBatch>),
+) -> HttpResponse {
+    if !authorized(&req) {
+        return HandlerErrorKind::UnauthorizedErr.response();
+    }
+    record(
+        &req,
+        "replica.apply",
+        json!({
+            "events": body.events.len(),
+            "channels": body.channels.as_ref().map(|channels| channels.len()),
+        }),
+    );
+    req.state().shards.replicate(body.into_inner());
+    HttpResponse::Ok().finish()
+}
+
+/// `POST /admin/drain` - stop taking new channels, for a deploy, letting
+/// the existing ones finish. `DELETE` takes new channels again. Both, and
+/// `GET`, return the node's status, including how many channels are live.
+pub fn drain(req: &HttpRequest<WsChannelSessionState>) -> FutureResponse<HttpResponse> {
+    if !authorized(req) {
+        return Box::new(future::ok(HandlerErrorKind::UnauthorizedErr.response()));
+    }
+    let shards = &req.state().shards;
+    if *req.method() == http::Method::POST {
+        record(req, "drain.start", json!({}));
+        shards.drain(true);
+    } else if *req.method() == http::Method::DELETE {
+        record(req, "drain.stop", json!({}));
+        shards.drain(false);
+    }
+    // Sent after the change, so reflects it.
+    shards
+        .status()
+        .then(|res| {
+            Ok(match res {
+                Ok(status) => HttpResponse::Ok().json(status),
+                Err(_) => HandlerErrorKind::UnavailableErr.response(),
+            })
+        })
+        .responder()
+}
+
+/// `POST /admin/migrate` - move `channels` (or every channel) to the node
+/// at `to`, and send their participants there.
+pub fn migrate(
+    (req, body): (HttpRequest<WsChannelSessionState>, Json<migrate::Migrate>),
+) -> FutureResponse<HttpResponse> {
+    if !authorized(&req) {
+        return Box::new(future::ok(HandlerErrorKind::UnauthorizedErr.response()));
+    }
+    let state = req.state();
+    let to = body.to.trim().trim_right_matches('/').to_owned();
+    if to.is_empty() || to == state.cluster.read().unwrap().me {
+        return Box::new(future::ok(HandlerErrorKind::InvalidRequestErr.response_with(Some(
+            json!({ "reason": "to must be the public URL of another node" }),
+        ))));
+    }
+    let mut token = state.secrets.get("migrate_token");
+    if token.is_empty() {
+        token = state.secrets.get("admin_token");
+    }
+    record(
+        &req,
+        "migrate.out",
+        json!({ "to": to, "channels": body.channels }),
+    );
+    // Remembered as long as a channel could live, and its participants
+    // be away from it.
+    let ttl = Duration::from_secs(state.settings.timeout + state.settings.reconnect_grace);
+    let shards = state.shards.clone();
+    let cluster = state.cluster.clone();
+    let requested = body.into_inner().channels;
+    state
+        .shards
+        .export(requested.clone())
+        .map_err(|err| err.to_string())
+        .and_then(move |channels| {
+            let ids: Vec<Uuid> = channels.iter().map(|&(id, _)| id).collect();
+            migrate::send(&to, &token, channels.into_iter().collect()).map(move |_| (to, ids))
+        })
+        .then(move |res| {
+            Ok(match res {
+                Ok((to, ids)) => {
+                    let until = Instant::now() + ttl;
+                    {
+                        let mut cluster = cluster.write().unwrap();
+                        for id in &ids {
+                            cluster.place(*id, &to, until);
+                        }
+                    }
+                    let count = ids.len();
+                    shards.moved(ids, &to);
+                    HttpResponse::Ok().json(json!({ "to": to, "channels": count }))
+                }
+                Err(reason) => {
+                    // The channels carry on here.
+                    shards.thaw(requested);
+                    HandlerErrorKind::UnavailableErr.response_with(Some(json!({ "reason": reason })))
+                }
+            })
+        })
+        .responder()
+}
+
+/// `POST /admin/migrated` - take channels migrated from another node,
+/// holding their participants' slots until they reconnect.
+pub fn migrated(
+    (req, body): (HttpRequest<WsChannelSessionState>, Json<migrate::Migration>),
+) -> HttpResponse {
+    if !authorized(&req) {
+        return HandlerErrorKind::UnauthorizedErr.response();
+    }
+    let state = req.state();
+    if state.settings.reconnect_grace == 0 {
+        // Nobody could reconnect to them.
+        return HandlerErrorKind::UnavailableErr
+            .response_with(Some(json!({ "reason": "reconnect_grace is not set" })));
+    }
+    record(&req, "migrate.in", json!({ "channels": body.len() }));
+    let now = Instant::now();
+    let until = now + Duration::from_secs(state.settings.timeout + state.settings.reconnect_grace);
+    let channel_rate = state.settings.channel_rate;
+    let channels: Vec<_> = body
+        .into_inner()
+        .into_iter()
+        .map(|(id, saved)| (id, saved.restore(now, channel_rate)))
+        .collect();
+    {
+        let mut cluster = state.cluster.write().unwrap();
+        let me = cluster.me.clone();
+        for &(id, _) in &channels {
+            cluster.place(id, &me, until);
+        }
+    }
+    let count = channels.len();
+    state.shards.restore(channels);
+    HttpResponse::Ok().json(json!({ "channels": count }))
+}
+
+/// `GET /admin/pprof/cpu?seconds=N` - profile the server's CPU use for
+/// `N` seconds, and return the profile.
+pub fn cpu_profile(req: &HttpRequest<WsChannelSessionState>) -> FutureResponse<HttpResponse> {
+    if !authorized(req) {
+        return Box::new(future::ok(HandlerErrorKind::UnauthorizedErr.response()));
+    }
+    let seconds = match req.query().get("seconds").map(|s| s.parse::<u64>()) {
+        None => profile::DEFAULT_SECONDS,
+        Some(Ok(seconds)) if seconds > 0 && seconds <= profile::MAX_SECONDS => seconds,
+        Some(_) => {
+            let reason = format!("seconds must be between 1 and {}", profile::MAX_SECONDS);
+            let kind = HandlerErrorKind::InvalidRequestErr;
+            return Box::new(future::ok(kind.response_with(Some(json!({ "reason": reason })))));
+        }
+    };
+    record(req, "profile.cpu", json!({ "seconds": seconds }));
+    profile::cpu(Duration::from_secs(seconds))
+        .then(|res| {
+            Ok(match res {
+                Ok(body) => HttpResponse::Ok()
+                    .content_type("application/octet-stream")
+                    .header(
+                        http::header::CONTENT_DISPOSITION,
+                        "attachment; filename=\"cpu.prof\"",
+                    )
+                    .body(body),
+                Err(reason) => HandlerErrorKind::UnavailableErr
+                    .response_with(Some(json!({ "reason": reason }))),
+            })
+        })
+        .responder()
+}
+
+/// What an `AdminStream` reports.
+pub enum StreamSource {
+    /// Frames relayed on a single channel. `payload` includes frame contents.
+    Tap {
+        id: server::SessionId,
+        channel: Uuid,
+        payload: bool,
+    },
+    /// Server-wide channel lifecycle events.
+    Events,
+}
+
+/// A read-only websocket stream of server data for administrators.
+pub struct AdminStream {
+    source: StreamSource,
+}
+
+impl Actor for AdminStream {
+    type Context = ws::WebsocketContext<Self, WsChannelSessionState>;
+
+    fn started(&mut self, ctx: &mut Self::Context) {
+        let addr = ctx.address().recipient();
+        match self.source {
+            StreamSource::Tap { id, channel, payload } => {
+                ctx.state().shards.get(&channel).do_send(server::AddTap {
+                    id,
+                    channel,
+                    addr,
+                    payload,
+                });
+            }
+            StreamSource::Events => {
+                ctx.state().shards.subscribe(addr);
+            }
+        }
+    }
+
+    fn stopped(&mut self, ctx: &mut Self::Context) {
+        if let StreamSource::Tap { id, channel, .. } = self.source {
+            ctx.state().shards.get(&channel).do_send(server::RemoveTap { id, channel });
+        }
+    }
+}
+
+impl Handler<server::TextMessage> for AdminStream {
+    type Result = ();
+
+    fn handle(&mut self, msg: server::TextMessage, ctx: &mut Self::Context) {
+        if msg.text == server::EOL {
+            ctx.close(None);
+        } else {
+            ctx.text(msg.text);
+        }
+    }
+}
+
+/// Anything sent by the administrator is ignored; the stream is read-only.
+impl StreamHandler<ws::Message, ws::ProtocolError> for AdminStream {
+    fn handle(&mut self, msg: ws::Message, ctx: &mut Self::Context) {
+        match msg {
+            ws::Message::Ping(msg) => ctx.pong(&msg),
+            ws::Message::Close(_) => ctx.stop(),
+            _ => (),
+        }
+    }
+}
+
+/// `GET /admin/tap/{channel}` - websocket streaming metadata (sender,
+/// recipient, size, timestamp) for every frame relayed on a channel.
+///
+/// Frame contents are only included if `tap_allow_payload` is set and the
+/// request asks for them with `?payload=1`. There are no taps in relay
+/// only mode.
+pub fn tap_route(req: &HttpRequest<WsChannelSessionState>) -> Result<HttpResponse, Error> {
+    if !authorized(req) {
+        return Ok(HandlerErrorKind::UnauthorizedErr.response());
+    }
+    if req.state().settings.relay_only {
+        return Ok(HandlerErrorKind::NotFoundErr.response());
+    }
+    let channel = match Uuid::parse_str(req.match_info().get("channel").unwrap_or("")) {
+        Ok(channel) => channel,
+        Err(_) => return Ok(HandlerErrorKind::NotFoundErr.response()),
+    };
+    let payload = req.state().settings.tap_allow_payload
+        && req.query().get("payload").map_or(false, |v| v == "1");
+    record(req, "tap.attach", json!({ "channel": channel, "payload": payload }));
+    ws::start(
+        req,
+        AdminStream {
+            source: StreamSource::Tap {
+                id: rand::random(),
+                channel,
+                payload,
+            },
+        },
+    )
+}
+
+/// `GET /admin/events` - websocket streaming every channel lifecycle
+/// event (created, joined, closed, rejected) as it happens.
+pub fn events_route(req: &HttpRequest<WsChannelSessionState>) -> Result<HttpResponse, Error> {
+    if !authorized(req) {
+        return Ok(HandlerErrorKind::UnauthorizedErr.response());
+    }
+    record(req, "events.subscribe", json!({}));
+    ws::start(
+        req,
+        AdminStream {
+            source: StreamSource::Events,
+        },
+    )
+}
@@ -0,0 +1,74 @@
+//! The global allocator, chosen at build time.
+//!
+//! The system allocator is used unless the server is built with the
+//! `jemalloc` or `mimalloc` feature. Both hold up better than glibc's
+//! against the fragmentation left by many short lived sessions. jemalloc
+//! also reports its own statistics, which are sent as `alloc.*` gauges.
+
+use std::sync::Arc;
+use std::thread;
+use std::time::Duration;
+
+use cadence::StatsdClient;
+
+#[cfg(all(feature = "jemalloc", feature = "mimalloc"))]
+compile_error!("The jemalloc and mimalloc features can't both be enabled");
+
+#[cfg(feature = "jemalloc")]
+#[global_allocator]
+static ALLOCATOR: ::jemallocator::Jemalloc = ::jemallocator::Jemalloc;
+
+#[cfg(feature = "mimalloc")]
+#[global_allocator]
+static ALLOCATOR: ::mimalloc::MiMalloc = ::mimalloc::MiMalloc;
+
+/// The allocator this server was built with.
+pub fn name() -> &'static str {
+    if cfg!(feature = "jemalloc") {
+        "jemalloc"
+    } else if cfg!(feature = "mimalloc") {
+        "mimalloc"
+    } else {
+        "system"
+    }
+}
+
+/// Report the allocator's statistics, if it keeps any.
+#[cfg(feature = "jemalloc")]
+pub fn report(metrics: &StatsdClient) {
+    use cadence::Gauged;
+    use jemalloc_ctl::{epoch, stats};
+
+    // The statistics are a snapshot, refreshed by advancing the epoch.
+    if epoch::advance().is_err() {
+        return;
+    }
+    for &(name, value) in &[
+        ("alloc.allocated", stats::allocated::read()),
+        ("alloc.active", stats::active::read()),
+        ("alloc.resident", stats::resident::read()),
+        ("alloc.mapped", stats::mapped::read()),
+        ("alloc.retained", stats::retained::read()),
+    ] {
+        if let Ok(bytes) = value {
+            metrics.gauge(name, bytes as u64).ok();
+        }
+    }
+}
+
+#[cfg(not(feature = "jemalloc"))]
+pub fn report(_metrics: &StatsdClient) {}
+
+/// Report the allocator's statistics `every` so often.
+pub fn watch(every: Duration, metrics: Arc<StatsdClient>) {
+    if !cfg!(feature = "jemalloc") {
+        return;
+    }
+    thread::Builder::new()
+        .name("alloc-stats".to_owned())
+        .spawn(move || loop {
+            thread::sleep(every);
+            report(&metrics);
+        })
+        .expect("Could not start the allocator statistics reporter");
+}
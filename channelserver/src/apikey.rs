@@ -0,0 +1,256 @@
+//! Application keys issued to tenants through the admin API.
+//!
+//! Only a SHA-256 hash of each key is retained. The plaintext key is
+//! returned once, when it is minted, and cannot be recovered afterwards.
+//!
+//! With `api_key_file` set, the hashes are kept in that file, which every
+//! node of a deployment can share: each change is written straight out,
+//! and a node re-reads the file whenever it has changed, so a key minted
+//! or revoked on one node holds on all of them.
+
+use std::collections::HashMap;
+use std::fs::{self, OpenOptions};
+use std::io::{self, Write};
+#[cfg(unix)]
+use std::os::unix::fs::OpenOptionsExt;
+use std::path::PathBuf;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use rand::{self, Rng};
+use serde_json;
+use sha2::{Digest, Sha256};
+use uuid::Uuid;
+
+/// Public description of an issued key. Never contains the key itself.
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct KeyInfo {
+    pub id: String,
+    pub tenant: String,
+    /// Issue time, in seconds since the epoch.
+    pub created: u64,
+    /// When the key stops being accepted (set once the key is rotated).
+    pub expires: Option<u64>,
+}
+
+#[derive(Deserialize, Serialize)]
+struct StoredKey {
+    info: KeyInfo,
+    /// hex encoded
+    hash: String,
+}
+
+#[derive(Default)]
+pub struct KeyStore {
+    keys: HashMap<String, StoredKey>,
+    /// where the keys are kept, if anywhere
+    path: Option<PathBuf>,
+    /// modification time and length of the file when last read
+    version: Option<(SystemTime, u64)>,
+}
+
+pub fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or(0)
+}
+
+fn hash_key(key: &str) -> String {
+    Sha256::digest(key.as_bytes())
+        .iter()
+        .map(|b| format!("{:02x}", b))
+        .collect()
+}
+
+impl KeyStore {
+    /// The keys kept at `path`, or only in memory if it is empty. A file
+    /// that doesn't exist yet holds no keys.
+    pub fn open(path: &str) -> io::Result<Self> {
+        let mut store = Self::default();
+        if !path.is_empty() {
+            store.path = Some(PathBuf::from(path));
+            store.refresh()?;
+        }
+        Ok(store)
+    }
+
+    /// Re-read the key file, if it has changed since it was last read.
+    fn refresh(&mut self) -> io::Result<()> {
+        let path = match self.path {
+            Some(ref path) => path.clone(),
+            None => return Ok(()),
+        };
+        let version = match fs::metadata(&path) {
+            Ok(meta) => Some((meta.modified()?, meta.len())),
+            Err(ref e) if e.kind() == io::ErrorKind::NotFound => None,
+            Err(e) => return Err(e),
+        };
+        if version.is_none() || version == self.version {
+            return Ok(());
+        }
+        let keys: Vec<StoredKey> = serde_json::from_slice(&fs::read(&path)?)
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+        self.keys = keys.into_iter().map(|k| (k.info.id.clone(), k)).collect();
+        self.version = version;
+        Ok(())
+    }
+
+    /// Write the keys out, if they are kept in a file.
+    fn save(&mut self) -> io::Result<()> {
+        let path = match self.path {
+            Some(ref path) => path.clone(),
+            None => return Ok(()),
+        };
+        let keys: Vec<&StoredKey> = self.keys.values().collect();
+        let body = serde_json::to_vec(&keys)
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+        // Written aside (under a name no other node will pick) and renamed
+        // into place, so a half written file is never read.
+        let partial = PathBuf::from(format!(
+            "{}.{}.partial",
+            path.display(),
+            Uuid::new_v4().simple()
+        ));
+        let mut options = OpenOptions::new();
+        options.write(true).create_new(true);
+        #[cfg(unix)]
+        options.mode(0o600);
+        options.open(&partial)?.write_all(&body)?;
+        fs::rename(&partial, &path)?;
+        let meta = fs::metadata(&path)?;
+        self.version = Some((meta.modified()?, meta.len()));
+        Ok(())
+    }
+
+    /// Mint a new key for `tenant`, returning its description and the
+    /// plaintext key.
+    pub fn issue(&mut self, tenant: &str) -> io::Result<(KeyInfo, String)> {
+        self.refresh()?;
+        let raw: [u8; 32] = rand::thread_rng().gen();
+        let key: String = raw.iter().map(|b| format!("{:02x}", b)).collect();
+        let info = KeyInfo {
+            id: Uuid::new_v4().simple().to_string(),
+            tenant: tenant.to_owned(),
+            created: now(),
+            expires: None,
+        };
+        self.keys.insert(
+            info.id.clone(),
+            StoredKey {
+                info: info.clone(),
+                hash: hash_key(&key),
+            },
+        );
+        self.save()?;
+        Ok((info, key))
+    }
+
+    /// Issue a replacement for key `id`.
+    ///
+    /// The old key stays valid for another `overlap` seconds, so a tenant
+    /// can roll the new key out to its clients before the old one lapses.
+    pub fn rotate(&mut self, id: &str, overlap: u64) -> io::Result<Option<(KeyInfo, String)>> {
+        self.refresh()?;
+        let tenant = match self.keys.get_mut(id) {
+            Some(old) => {
+                let expires = now() + overlap;
+                old.info.expires = Some(old.info.expires.map_or(expires, |e| e.min(expires)));
+                old.info.tenant.clone()
+            }
+            None => return Ok(None),
+        };
+        // Saves the old key's expiry along with the new key.
+        self.issue(&tenant).map(Some)
+    }
+
+    /// Revoke key `id` immediately.
+    pub fn revoke(&mut self, id: &str) -> io::Result<bool> {
+        self.refresh()?;
+        if self.keys.remove(id).is_none() {
+            return Ok(false);
+        }
+        self.save()?;
+        Ok(true)
+    }
+
+    /// List all known keys, dropping any that have already lapsed.
+    pub fn list(&mut self) -> io::Result<Vec<KeyInfo>> {
+        self.refresh()?;
+        let now = now();
+        let before = self.keys.len();
+        self.keys
+            .retain(|_, k| k.info.expires.map_or(true, |e| e > now));
+        if self.keys.len() != before {
+            self.save()?;
+        }
+        Ok(self.keys.values().map(|k| k.info.clone()).collect())
+    }
+
+    /// Return the tenant owning `key`, if the key is currently valid. If
+    /// the key file can't be read, the keys last read from it are used.
+    pub fn validate(&mut self, key: &str) -> Option<String> {
+        self.refresh().ok();
+        let hash = hash_key(key);
+        let now = now();
+        self.keys
+            .values()
+            .find(|k| k.hash == hash && k.info.expires.map_or(true, |e| e > now))
+            .map(|k| k.info.tenant.clone())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::env;
+
+    use super::*;
+
+    #[test]
+    fn test_rotation_overlap() {
+        let mut store = KeyStore::default();
+        let (info, old_key) = store.issue("tenant").unwrap();
+        assert_eq!(store.validate(&old_key), Some("tenant".to_owned()));
+
+        // Both keys are valid during the overlap window.
+        let (_, new_key) = store.rotate(&info.id, 60).unwrap().unwrap();
+        assert_eq!(store.validate(&old_key), Some("tenant".to_owned()));
+        assert_eq!(store.validate(&new_key), Some("tenant".to_owned()));
+
+        // With no overlap, the old key lapses immediately.
+        let (new_info, newer_key) = {
+            let id = store
+                .list()
+                .unwrap()
+                .into_iter()
+                .find(|k| k.expires.is_none())
+                .unwrap()
+                .id;
+            store.rotate(&id, 0).unwrap().unwrap()
+        };
+        assert!(store.validate(&new_key).is_none());
+        assert!(store.validate(&newer_key).is_some());
+
+        assert!(store.revoke(&new_info.id).unwrap());
+        assert!(store.validate(&newer_key).is_none());
+        assert!(store.validate("bogus").is_none());
+    }
+
+    #[test]
+    fn test_shared_file() {
+        let path = env::temp_dir().join(format!("pairsona-{}.keys", Uuid::new_v4().simple()));
+        let path = path.to_str().unwrap();
+        let mut one = KeyStore::open(path).unwrap();
+        let (info, key) = one.issue("tenant").unwrap();
+        assert!(!fs::read_to_string(path).unwrap().contains(&key));
+
+        // Another node (or a restart) sees the key...
+        let mut two = KeyStore::open(path).unwrap();
+        assert_eq!(two.validate(&key), Some("tenant".to_owned()));
+        // ...and a revocation on either holds on both.
+        assert!(two.revoke(&info.id).unwrap());
+        assert!(one.validate(&key).is_none());
+        assert!(one.list().unwrap().is_empty());
+
+        fs::remove_file(path).unwrap();
+    }
+}
@@ -0,0 +1,575 @@
+//! The HTTP application: the public, admin and health check routes.
+
+use std::path::Path;
+use std::time::Instant;
+
+use actix_web::{
+    fs, http, ws, App, AsyncResponder, Error, FutureResponse, HttpRequest, HttpResponse,
+};
+use cadence::Counted;
+use futures::Future;
+use ratelimiter;
+use serde_json;
+use uuid::Uuid;
+
+use admin;
+use apikey;
+use cors;
+use geo;
+use headers;
+use listener;
+use logging;
+use migrate;
+use pattern;
+use perror;
+use protocol;
+use ratelimit;
+use server;
+use session;
+use trace;
+use upgrade;
+
+/*
+ * based on the Actix websocket example ChatServer
+ */
+
+/// Cookie carrying a participant's reconnect token.
+const RECONNECT_COOKIE: &str = "pair_reconnect";
+
+/// Entry point for our route
+fn channel_route(req: &HttpRequest<session::WsChannelSessionState>) -> Result<HttpResponse, Error> {
+    let settings = &req.state().settings;
+    // In relay only mode, nothing the client says about itself is kept.
+    let lang = req.state().languages.negotiate(if settings.relay_only {
+        ""
+    } else {
+        req.headers()
+            .get(http::header::ACCEPT_LANGUAGE)
+            .and_then(|v| v.to_str().ok())
+            .unwrap_or("")
+    });
+    let trace = if settings.relay_only {
+        None
+    } else {
+        trace::trace_id(req.headers())
+    };
+    if let Err(rejection) =
+        upgrade::validate(req.headers(), settings.max_headers, settings.max_header_size)
+    {
+        req.state()
+            .metrics
+            .incr(&format!("upgrade.rejected.{}", rejection.name()))
+            .ok();
+        return Ok(perror::HandlerErrorKind::UpgradeErr
+            .response_in(lang, Some(json!({ "rejection": rejection.name() }))));
+    }
+    // not sure if it's possible to have actix_web parse the path and have a properly
+    // scoped request, since the calling structure is different for the two, so
+    // manually extracting the id from the path.
+    let mut path: Vec<_> = req.path().split("/").collect();
+    let requested = Uuid::parse_str(path.pop().unwrap_or_else(|| "")).ok();
+    let ip = session::client_ip(req);
+    if let Some(ip) = ip {
+        // Creating and joining channels have separate budgets.
+        let limiters = &req.state().limiters;
+        let (limiter, action) = match requested {
+            Some(_) => (&limiters.join, "join"),
+            None => (&limiters.create, "create"),
+        };
+        let key = ratelimiter::Key::Ip(ip);
+        let quota = ratelimit::with(limiter, &req.state().metrics, |l| l.check(&key));
+        if !quota.allowed {
+            req.state().log.do_send(logging::LogMessage {
+                level: logging::ErrorLevel::Info,
+                module: module_path!(),
+                msg: if settings.relay_only {
+                    format!("Rate limited channel {}", action)
+                } else {
+                    format!("Rate limited channel {} from {}", action, ip)
+                },
+                trace: trace.clone(),
+            });
+            let mut resp = perror::HandlerErrorKind::RateLimitErr.response_in(lang, None);
+            ratelimiter::set_headers(&mut resp, &quota);
+            return Ok(resp);
+        }
+        match req.state().geo {
+            Some(ref resolver) if !settings.relay_only => {
+                if let Some(region) = resolver.region(&ip).and_then(|r| geo::metric_region(&r)) {
+                    req.state()
+                        .metrics
+                        .incr(&format!("channel.connect.region.{}", region))
+                        .ok();
+                }
+            }
+            _ => {}
+        }
+    }
+    let created = requested.is_none();
+    let channel = match requested {
+        Some(channel) => {
+            let owner = {
+                let cluster = req.state().cluster.read().unwrap();
+                if cluster.is_local(&channel) {
+                    None
+                } else {
+                    Some(cluster.owner(&channel).to_owned())
+                }
+            };
+            if let Some(owner) = owner {
+                return Ok(redirect_to_owner(req, &owner));
+            }
+            channel
+        }
+        // Pinned here once it exists (see `WsChannelSession::created`).
+        None => req.state().cluster.read().unwrap().new_local_channel(),
+    };
+    if req.state().settings.require_api_key {
+        // Browsers can't set headers on a websocket upgrade, so also accept
+        // the key as a query argument.
+        let key = req
+            .headers()
+            .get("X-Api-Key")
+            .and_then(|v| v.to_str().ok())
+            .map(|v| v.to_owned())
+            .or_else(|| req.query().get("key").cloned());
+        let tenant = key.and_then(|k| req.state().keys.write().unwrap().validate(&k));
+        match tenant {
+            Some(tenant) => {
+                req.state().log.do_send(logging::LogMessage {
+                    level: logging::ErrorLevel::Debug,
+                    module: module_path!(),
+                    msg: format!("Key accepted for tenant: \"{}\"", tenant),
+                    trace: trace.clone(),
+                });
+            }
+            None => {
+                let err = perror::HandlerErrorKind::InvalidKeyErr;
+                let event = server::ChannelEvent::Rejected {
+                    channel,
+                    reason: err.to_string(),
+                    ts: apikey::now(),
+                };
+                req.state().shards.get(&channel).do_send(server::Publish(event));
+                return Ok(err.response_in(lang, None));
+            }
+        }
+    }
+    &req.state().log.do_send(logging::LogMessage {
+        level: logging::ErrorLevel::Info,
+        module: module_path!(),
+        msg: format!("Creating session for channel: \"{}\"", channel.simple()),
+        trace: trace.clone(),
+    });
+    // A client reclaiming its slot after a dropped connection presents its
+    // reconnect token as a cookie (browsers resend it automatically) or
+    // query argument.
+    let resume = req
+        .cookie(RECONNECT_COOKIE)
+        .map(|c| c.value().to_owned())
+        .or_else(|| req.query().get("reconnect").cloned());
+    let token = Uuid::new_v4().simple().to_string();
+    // Strict mode; only honoured if this connection creates the channel.
+    let pattern = match pattern::Pattern::from_query(&req.query()) {
+        Ok(pattern) => pattern,
+        Err(reason) => {
+            return Ok(perror::HandlerErrorKind::PatternErr
+                .response_in(lang, Some(json!({ "pattern": reason }))))
+        }
+    };
+    // Like `ws::start`, but with our own message size limit. actix-web
+    // refuses continuation frames outright, so a message is always a
+    // single frame and can't be built up from pathological fragments.
+    let mut builder = ws::handshake(req)?;
+    let stream = ws::WsStream::new(req.payload()).max_size(req.state().settings.max_message_size);
+    let mut resp = builder.body(ws::WebsocketContext::create(
+        req.clone(),
+        session::WsChannelSession {
+            id: 0,
+            hb: Instant::now(),
+            channel: channel.clone(),
+            name: None,
+            lang,
+            ip,
+            trace: trace.clone(),
+            resume,
+            token: token.clone(),
+            handoff: req.query().get("handoff").cloned(),
+            pattern,
+            closed: false,
+            data: session::Outbox::default(),
+            flushing: false,
+            created,
+        },
+        stream,
+    ));
+    if req.state().settings.reconnect_grace > 0 {
+        let cookie = format!(
+            "{}={}; Path=/v1/ws/{}; HttpOnly",
+            RECONNECT_COOKIE,
+            token,
+            channel.simple()
+        );
+        if let Ok(value) = http::header::HeaderValue::from_str(&cookie) {
+            resp.headers_mut().insert(http::header::SET_COOKIE, value);
+        }
+    }
+    Ok(resp)
+}
+
+/// Point the client at the cluster node that owns the requested channel.
+fn redirect_to_owner(req: &HttpRequest<session::WsChannelSessionState>, owner: &str) -> HttpResponse {
+    let mut location = format!("{}{}", owner, req.path());
+    if !req.query_string().is_empty() {
+        location = format!("{}?{}", location, req.query_string());
+    }
+    req.state().log.do_send(logging::LogMessage {
+        level: logging::ErrorLevel::Debug,
+        module: module_path!(),
+        msg: format!("Redirecting to channel owner: {}", location),
+        trace: None,
+    });
+    if req.state().settings.cluster_redirect == "hint" {
+        perror::HandlerErrorKind::WrongNodeErr.response_with(Some(json!({ "location": location })))
+    } else {
+        HttpResponse::TemporaryRedirect()
+            .header(http::header::LOCATION, location)
+            .finish()
+    }
+}
+
+fn heartbeat(_req: &HttpRequest<session::WsChannelSessionState>) -> Result<HttpResponse, Error> {
+    // if there's more to check, add it here.
+    let body = json!({"status": "ok", "version": env!("CARGO_PKG_VERSION")});
+    Ok(HttpResponse::Ok()
+        .content_type("application/json")
+        .body(body.to_string()))
+}
+
+fn lbheartbeat(_req: &HttpRequest<session::WsChannelSessionState>) -> Result<HttpResponse, Error> {
+    // load balance heartbeat, used as the liveness check. Doesn't matter
+    // what's returned, aside from a 200
+    Ok(HttpResponse::Ok().into())
+}
+
+fn slo_report(req: &HttpRequest<session::WsChannelSessionState>) -> Result<HttpResponse, Error> {
+    // rolling SLIs, for burn rate alerting without a metrics pipeline.
+    let report = req.state().slo.report(Instant::now());
+    Ok(HttpResponse::Ok().json(report))
+}
+
+fn ready(req: &HttpRequest<session::WsChannelSessionState>) -> FutureResponse<HttpResponse> {
+    // readiness check: is this node willing to take new channels? Unlike
+    // the liveness check, failing this should only stop new traffic
+    // being routed here, not restart the process.
+    req.state()
+        .shards
+        .status()
+        .then(|res| {
+            Ok(match res {
+                Ok(ref status) if !status.draining => HttpResponse::Ok().json(status),
+                Ok(status) => perror::HandlerErrorKind::UnavailableErr
+                    .response_with(serde_json::to_value(status).ok()),
+                // a channel server isn't responding.
+                Err(_) => perror::HandlerErrorKind::UnavailableErr.response(),
+            })
+        })
+        .responder()
+}
+
+fn show_version(_req: &HttpRequest<session::WsChannelSessionState>) -> Result<HttpResponse, Error> {
+    // Return the contents of the version.json file.
+    Ok(HttpResponse::Ok()
+        .content_type("application/json")
+        .body(include_str!("../version.json")))
+}
+
+fn capabilities(req: &HttpRequest<session::WsChannelSessionState>) -> Result<HttpResponse, Error> {
+    // Describe what this server supports, so clients can adapt to it.
+    let settings = &req.state().settings;
+    let body = json!({
+        "protocol_versions": protocol::PROTOCOL_VERSIONS,
+        "encodings": protocol::ENCODINGS,
+        "max_message_size": settings.max_message_size,
+        "max_transfer_size": settings.max_transfer_size,
+        "chunk_window": settings.chunk_window,
+        "max_transfers": settings.max_transfers,
+        "max_clients": settings.max_clients,
+        "channel_ttl": settings.timeout,
+        "max_exchanges": settings.max_exchanges,
+        "max_data": settings.max_data,
+        "features": {
+            "api_key_required": settings.require_api_key,
+            "initiator_first": settings.initiator_first,
+            "sequence_frames": settings.sequence_frames,
+            "stamp_frames": settings.stamp_frames,
+            "hop_frames": settings.hop_frames,
+            "dedup_window": settings.dedup_window,
+            "app_ping": true,
+            "hello": true,
+        },
+    });
+    Ok(HttpResponse::Ok()
+        .content_type("application/json")
+        .body(body.to_string()))
+}
+
+/// The routes `routes` asks for, on `app`.
+pub fn build_app(
+    app: App<session::WsChannelSessionState>,
+    routes: listener::Routes,
+) -> App<session::WsChannelSessionState> {
+    let mut mapp = app
+            .middleware(cors::Cors)
+            .middleware(headers::SecurityHeaders)
+            // health checks are served everywhere, for load balancers.
+            .resource("/__version__", |r| r.method(http::Method::GET).f(show_version))
+            .resource("/__heartbeat__", |r| r.method(http::Method::GET).f(heartbeat))
+            .resource("/__lbheartbeat__", |r| r.method(http::Method::GET).f(lbheartbeat))
+            .resource("/__ready__", |r| r.method(http::Method::GET).f(ready))
+            .resource("/__slo__", |r| r.method(http::Method::GET).f(slo_report));
+    if routes.public {
+        mapp = mapp
+            // websocket to an existing channel
+            .resource("/v1/ws/{channel}", |r| r.route().f(channel_route))
+            // connecting to an empty channel creates a new one.
+            .resource("/v1/ws/", |r| r.route().f(channel_route))
+            .resource("/v1/capabilities", |r| r.method(http::Method::GET).f(capabilities));
+    }
+    if routes.admin {
+        mapp = mapp
+            .resource("/admin/keys", |r| {
+                r.method(http::Method::GET).f(admin::list_keys);
+                r.method(http::Method::POST).with(admin::issue_key);
+            })
+            .resource("/admin/keys/{id}", |r| r.method(http::Method::DELETE).f(admin::revoke_key))
+            .resource("/admin/keys/{id}/rotate", |r| {
+                r.method(http::Method::POST).f(admin::rotate_key)
+            })
+            .resource("/admin/config", |r| r.method(http::Method::GET).f(admin::show_config))
+            .resource("/admin/log_level", |r| {
+                r.method(http::Method::POST).with(admin::set_log_level)
+            })
+            .resource("/admin/replica", |r| r.method(http::Method::POST).with(admin::apply_replica))
+            .resource("/admin/tap/{channel}", |r| r.route().f(admin::tap_route))
+            .resource("/admin/events", |r| r.route().f(admin::events_route))
+            .resource("/admin/drain", |r| {
+                r.method(http::Method::GET).f(admin::drain);
+                r.method(http::Method::POST).f(admin::drain);
+                r.method(http::Method::DELETE).f(admin::drain);
+            })
+            .resource("/admin/migrate", |r| r.method(http::Method::POST).with(admin::migrate))
+            .resource("/admin/migrated", |r| {
+                r.method(http::Method::POST)
+                    .with_config(admin::migrated, |cfg| {
+                        cfg.1.limit(migrate::MAX_BODY);
+                    });
+            })
+            .resource("/admin/pprof/cpu", |r| r.method(http::Method::GET).f(admin::cpu_profile));
+    }
+    // Only add a static handler if the static directory exists.
+    if routes.public && Path::new("static/").exists() {
+        mapp = mapp.handler("/static/", fs::StaticFiles::new("static/").unwrap());
+    }
+    mapp
+}
+
+#[cfg(test)]
+mod test {
+    use std::str;
+    use std::sync::{Arc, RwLock};
+
+    use actix::Arbiter;
+    use actix_web::test;
+    use actix_web::ws;
+    use actix_web::HttpMessage;
+    use cadence;
+    use futures::Stream;
+
+    use super::*;
+    use cluster;
+    use i18n;
+    use ratelimit;
+    use settings::Settings;
+    use shard;
+    use slo;
+    fn get_server() -> test::TestServer {
+        get_server_with(Settings::new().unwrap())
+    }
+
+    fn get_server_with(settings: Settings) -> test::TestServer {
+        let srv = test::TestServer::build_with_state(move || {
+            let logger = logging::MozLogger::default();
+            let settings = settings.clone();
+            let shards = shard::Shards::start(1, &settings, None, &logger);
+            let log = Arbiter::start(move |_| logger);
+
+            session::WsChannelSessionState {
+                shards,
+                log: log.clone(),
+                limiters: Arc::new(ratelimit::Limiters::new(&settings).unwrap()),
+                slo: Arc::new(slo::SloTracker::new(slo::parse_windows(&settings.slo_windows))),
+                languages: Arc::new(i18n::Negotiator::default()),
+                secrets: settings.secrets(),
+                geo: None,
+                settings: Arc::new(settings),
+                keys: Arc::new(RwLock::new(apikey::KeyStore::default())),
+                cluster: Arc::new(RwLock::new(cluster::Cluster::default())),
+                metrics: Arc::new(cadence::StatsdClient::from_sink(
+                    "test",
+                    cadence::NopMetricSink,
+                )),
+            }
+        });
+        srv.start(|app| {
+            // Make this a trait eventually, for now, just copy build_app
+            app
+                .resource("/", |r| r.method(http::Method::GET).f(|_| {
+                    HttpResponse::NotFound()
+                        .finish()
+                }))
+                // websocket to an existing channel
+                .resource("/v1/ws/{channel}", |r| r.route().f(channel_route))
+                // connecting to an empty channel creates a new one.
+                .resource("/v1/ws/", |r| r.route().f(channel_route))
+                .resource("/v1/capabilities", |r| r.method(http::Method::GET).f(capabilities))
+                .resource("/__version__", |r| r.method(http::Method::GET).f(show_version))
+                .resource("/__heartbeat__", |r| r.method(http::Method::GET).f(heartbeat))
+                .resource("/__lbheartbeat__", |r| r.method(http::Method::GET).f(lbheartbeat))
+                .resource("/__ready__", |r| r.method(http::Method::GET).f(ready))
+                .resource("/__slo__", |r| r.method(http::Method::GET).f(slo_report))
+                .resource("/admin/drain", |r| {
+                    r.method(http::Method::POST).f(admin::drain);
+                    r.method(http::Method::DELETE).f(admin::drain);
+                });
+        })
+    }
+
+    #[test]
+    fn test_heartbeats() {
+        let mut srv = get_server();
+        // Test the DockerFlow URLs
+        {
+            let request = srv.get().uri(srv.url("/__heartbeat__")).finish().unwrap();
+            let response = srv.execute(request.send()).unwrap();
+            assert!(response.status().is_success());
+            let bytes = srv.execute(response.body()).unwrap();
+            let body = str::from_utf8(&bytes).unwrap();
+            assert_eq!(
+                json!({"status": "ok", "version": env!("CARGO_PKG_VERSION")}).to_string(),
+                body
+            );
+        }
+        {
+            let request = srv.get().uri(srv.url("/__lbheartbeat__")).finish().unwrap();
+            let response = srv.execute(request.send()).unwrap();
+            assert!(response.status().is_success());
+        }
+        {
+            let request = srv.get().uri(srv.url("/__ready__")).finish().unwrap();
+            let response = srv.execute(request.send()).unwrap();
+            assert!(response.status().is_success());
+            let bytes = srv.execute(response.body()).unwrap();
+            let body: serde_json::Value = serde_json::from_slice(&bytes).unwrap();
+            assert_eq!(body["draining"], json!(false));
+        }
+        {
+            let request = srv.get().uri(srv.url("/__version__")).finish().unwrap();
+            let response = srv.execute(request.send()).unwrap();
+            assert!(response.status().is_success());
+            let bytes = srv.execute(response.body()).unwrap();
+            let body = str::from_utf8(&bytes).unwrap();
+            assert_eq!(include_str!("../version.json"), body);
+        }
+    }
+
+    #[test]
+    fn test_capabilities() {
+        let mut srv = get_server();
+        let request = srv.get().uri(srv.url("/v1/capabilities")).finish().unwrap();
+        let response = srv.execute(request.send()).unwrap();
+        assert!(response.status().is_success());
+        let bytes = srv.execute(response.body()).unwrap();
+        let body: serde_json::Value = serde_json::from_slice(&bytes).unwrap();
+        assert_eq!(body["protocol_versions"], json!(["v1"]));
+        assert_eq!(body["encodings"], json!(["text"]));
+        assert!(body["max_message_size"].is_u64());
+        assert!(body["features"].is_object());
+    }
+
+    fn read(msg: ws::Message) -> String {
+        match msg {
+            ws::Message::Text(text) => text.as_str().to_owned(),
+            _ => format!("Unexpected data type {:?}", msg),
+        }
+    }
+
+    #[test]
+    fn test_drain() {
+        let mut settings = Settings::new().unwrap();
+        settings.admin_token = "token".to_owned();
+        let mut srv = get_server_with(settings);
+        let (reader, _writer) = srv.ws_at("/v1/ws/").unwrap();
+        let (item, _reader) = srv.execute(reader.into_future()).unwrap();
+        let link_addr = read(item.unwrap());
+        assert!(link_addr.starts_with("/v1/ws/"));
+
+        fn drain(srv: &mut test::TestServer, method: http::Method) {
+            let request = srv
+                .client(method, "/admin/drain")
+                .header(http::header::AUTHORIZATION, "Bearer token")
+                .finish()
+                .unwrap();
+            let response = srv.execute(request.send()).unwrap();
+            assert!(response.status().is_success());
+        }
+        drain(&mut srv, http::Method::POST);
+        // No new channels...
+        let (reader, _writer) = srv.ws_at("/v1/ws/").unwrap();
+        let (item, _) = srv.execute(reader.into_future()).unwrap();
+        let refused: serde_json::Value = serde_json::from_str(&read(item.unwrap())).unwrap();
+        assert_eq!(refused["control"], json!("error"));
+        assert_eq!(refused["code"], json!(4012));
+        // ...but live ones may still be joined.
+        let (reader, _joiner) = srv.ws_at(&link_addr).unwrap();
+        let (item, _reader) = srv.execute(reader.into_future()).unwrap();
+        assert_eq!(read(item.unwrap()), link_addr);
+
+        drain(&mut srv, http::Method::DELETE);
+        let (reader, _writer) = srv.ws_at("/v1/ws/").unwrap();
+        let (item, _) = srv.execute(reader.into_future()).unwrap();
+        let created = read(item.unwrap());
+        assert!(created.starts_with("/v1/ws/") && created != link_addr);
+    }
+
+    #[ignore]
+    #[test]
+    fn test_websockets() {
+        /// Test broken.
+        // Something in actix REALLY doesn't like having two sockets talk to
+        // each other. This test will create the sockets, but messages sent
+        // between them get lost somewhere interally.
+        // Sometimes the messages make it through and get processed by
+        // the server, however, most times they simply don't get beyond the
+        // write. In any case, the recipient (reader1) never gets the
+        // message and the test hangs forever.
+        //
+        // for now, use the ../test_chan
+        let mut srv = get_server();
+        let (mut reader1, mut writer1) = srv.ws_at("/v1/ws/").unwrap();
+        let (item, r) = srv.execute(reader1.into_future()).unwrap();
+        reader1 = r;
+        let link_addr = read(item.unwrap());
+        println!("Connecting to {:?}", link_addr);
+        let (mut reader2, mut writer2) = srv.ws_at(&link_addr).unwrap();
+        let (item, r) = srv.execute(reader2.into_future()).unwrap();
+        reader2 = r;
+        let r2_addr = read(item.unwrap());
+        println!("Connected to {:?}", r2_addr);
+        assert_eq!(link_addr, r2_addr);
+        let test_phrase = "This is a test";
+        writer2.text("writer2");
+        let (item, r) = srv.execute(reader1.into_future()).unwrap();
+        assert_eq!(test_phrase, &read(item.unwrap()));
+    }
+}
@@ -0,0 +1,314 @@
+//! Durable, queryable pairing history: channel lifecycle events, closed
+//! channel summaries and administrative actions, written to PostgreSQL.
+//!
+//! Records are queued for a dedicated writer thread, which inserts them in
+//! batches of up to `audit_batch`, one transaction per batch. If the
+//! database falls behind (or goes away) the queue fills, and further
+//! records are dropped and counted rather than slowing the relay. The
+//! writer keeps retrying the batch in hand until the database is back.
+//!
+//! Records land in the `channel_audit` table, created if need be:
+//! `ts` (seconds since the epoch), `channel` (if any), `kind` (the event,
+//! `summary` or `admin`) and `detail` (the whole record, as JSONB). The
+//! table is append only: a trigger refuses updates and deletes.
+//!
+//! The URL's `sslmode` is honoured as libpq would: `disable` never uses
+//! TLS, `allow` and `prefer` (the default) use it if the server offers it,
+//! and `require`, `verify-ca` and `verify-full` insist on it. Certificates
+//! are always verified against the system's roots and the host name.
+
+use std::sync::mpsc::{sync_channel, Receiver, SyncSender};
+
+use serde_json::Value;
+use uuid::Uuid;
+
+use logging::MozLogger;
+use server::{AdminAction, ChannelEvent};
+use settings::Settings;
+
+pub struct AuditRecord {
+    pub ts: u64,
+    pub channel: Option<Uuid>,
+    pub kind: String,
+    pub detail: Value,
+}
+
+impl AuditRecord {
+    pub fn event(event: &ChannelEvent) -> Self {
+        let (channel, ts) = match event {
+            ChannelEvent::Created { channel, ts, .. }
+            | ChannelEvent::Joined { channel, ts, .. }
+            | ChannelEvent::Closed { channel, ts }
+            | ChannelEvent::Rejected { channel, ts, .. } => (*channel, *ts),
+        };
+        let detail = json!(event);
+        Self {
+            ts,
+            channel: Some(channel),
+            kind: detail["event"].as_str().unwrap_or("").to_owned(),
+            detail,
+        }
+    }
+
+    pub fn admin(action: &AdminAction) -> Self {
+        Self {
+            ts: action.ts,
+            channel: None,
+            kind: "admin".to_owned(),
+            detail: json!(action),
+        }
+    }
+}
+
+/// Whether to connect with TLS.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum SslMode {
+    Disable,
+    Prefer,
+    Require,
+}
+
+/// `url` without its `sslmode` parameter (which the driver would pass on
+/// to the server as a setting), and the mode it asks for.
+pub fn sslmode(url: &str) -> Result<(String, SslMode), String> {
+    let (base, query) = match url.find('?') {
+        Some(i) => (&url[..i], &url[i + 1..]),
+        None => (url, ""),
+    };
+    let mut mode = SslMode::Prefer;
+    let mut rest = Vec::new();
+    for pair in query.split('&').filter(|pair| !pair.is_empty()) {
+        if !pair.starts_with("sslmode=") {
+            rest.push(pair);
+            continue;
+        }
+        mode = match &pair[8..] {
+            "disable" => SslMode::Disable,
+            "allow" | "prefer" => SslMode::Prefer,
+            "require" | "verify-ca" | "verify-full" => SslMode::Require,
+            other => return Err(format!("audit_postgres: unknown sslmode {:?}", other)),
+        };
+    }
+    if rest.is_empty() {
+        Ok((base.to_owned(), mode))
+    } else {
+        Ok((format!("{}?{}", base, rest.join("&")), mode))
+    }
+}
+
+/// Queues records for the writer thread.
+#[derive(Clone)]
+pub struct AuditLog {
+    queue: SyncSender<AuditRecord>,
+}
+
+impl AuditLog {
+    /// Start the writer, if `audit_postgres` is set.
+    pub fn start(settings: &Settings, log: &MozLogger) -> Result<Option<Self>, String> {
+        if settings.audit_postgres.is_empty() {
+            return Ok(None);
+        }
+        if settings.relay_only {
+            return Err("audit_postgres: nothing is recorded in relay_only mode".to_owned());
+        }
+        let (queue, records) = sync_channel(settings.audit_queue.max(1));
+        spawn_writer(
+            &settings.audit_postgres,
+            settings.audit_batch.max(1),
+            log,
+            records,
+        )?;
+        Ok(Some(Self { queue }))
+    }
+
+    /// Queue a record. Returns `false` if it was dropped because the
+    /// queue is full.
+    pub fn record(&self, record: AuditRecord) -> bool {
+        self.queue.try_send(record).is_ok()
+    }
+}
+
+#[cfg(feature = "postgres")]
+fn spawn_writer(
+    url: &str,
+    batch: usize,
+    log: &MozLogger,
+    records: Receiver<AuditRecord>,
+) -> Result<(), String> {
+    let writer = pg::Writer::new(url, batch, log.log.clone())?;
+    ::std::thread::Builder::new()
+        .name("audit".to_owned())
+        .spawn(move || writer.run(records))
+        .map(|_| ())
+        .map_err(|e| format!("Could not start the audit writer: {}", e))
+}
+
+#[cfg(not(feature = "postgres"))]
+fn spawn_writer(
+    url: &str,
+    batch: usize,
+    log: &MozLogger,
+    records: Receiver<AuditRecord>,
+) -> Result<(), String> {
+    Err("audit_postgres: needs the postgres feature".to_owned())
+}
+
+#[cfg(feature = "postgres")]
+mod pg {
+    use std::sync::mpsc::{Receiver, RecvTimeoutError};
+    use std::thread;
+    use std::time::Duration;
+
+    use postgres::tls::native_tls::NativeTls;
+    use postgres::{self, Connection, TlsMode};
+    use slog::Logger;
+
+    use super::{sslmode, AuditRecord, SslMode};
+
+    /// How long to wait for a batch to fill before writing what there is.
+    const FLUSH_INTERVAL: Duration = Duration::from_secs(1);
+    /// How long to wait before retrying after a database error.
+    const RETRY_INTERVAL: Duration = Duration::from_secs(5);
+
+    const SCHEMA: &str = "CREATE TABLE IF NOT EXISTS channel_audit (
+            ts BIGINT NOT NULL,
+            channel UUID,
+            kind TEXT NOT NULL,
+            detail JSONB NOT NULL
+        );
+        ALTER TABLE channel_audit ALTER COLUMN channel DROP NOT NULL;
+        CREATE INDEX IF NOT EXISTS channel_audit_channel ON channel_audit (channel);
+        CREATE OR REPLACE FUNCTION channel_audit_append_only() RETURNS trigger AS $$
+        BEGIN
+            RAISE EXCEPTION 'channel_audit is append only';
+        END $$ LANGUAGE plpgsql;
+        DROP TRIGGER IF EXISTS channel_audit_append_only ON channel_audit;
+        CREATE TRIGGER channel_audit_append_only BEFORE UPDATE OR DELETE ON channel_audit
+            FOR EACH ROW EXECUTE PROCEDURE channel_audit_append_only();";
+
+    pub struct Writer {
+        url: String,
+        mode: SslMode,
+        tls: Option<NativeTls>,
+        batch: usize,
+        log: Logger,
+        db: Option<Connection>,
+    }
+
+    impl Writer {
+        pub fn new(url: &str, batch: usize, log: Logger) -> Result<Self, String> {
+            let (url, mode) = sslmode(url)?;
+            let tls = match mode {
+                SslMode::Disable => None,
+                _ => Some(
+                    NativeTls::new()
+                        .map_err(|e| format!("audit_postgres: could not set up TLS: {}", e))?,
+                ),
+            };
+            Ok(Self {
+                url,
+                mode,
+                tls,
+                batch,
+                log,
+                db: None,
+            })
+        }
+
+        fn connect(&self) -> Result<Connection, postgres::Error> {
+            let url = self.url.as_str();
+            match (self.mode, self.tls.as_ref()) {
+                (SslMode::Require, Some(tls)) => Connection::connect(url, TlsMode::Require(tls)),
+                (SslMode::Prefer, Some(tls)) => Connection::connect(url, TlsMode::Prefer(tls)),
+                _ => Connection::connect(url, TlsMode::None),
+            }
+        }
+
+        pub fn run(mut self, records: Receiver<AuditRecord>) {
+            let mut pending: Vec<AuditRecord> = Vec::with_capacity(self.batch);
+            loop {
+                if pending.len() < self.batch {
+                    match records.recv_timeout(FLUSH_INTERVAL) {
+                        Ok(record) => pending.push(record),
+                        Err(RecvTimeoutError::Timeout) => {}
+                        Err(RecvTimeoutError::Disconnected) => {
+                            // The server is shutting down; one last try.
+                            if !pending.is_empty() {
+                                self.write(&pending).ok();
+                            }
+                            return;
+                        }
+                    }
+                    let room = self.batch - pending.len();
+                    pending.extend(records.try_iter().take(room));
+                }
+                if pending.is_empty() {
+                    continue;
+                }
+                match self.write(&pending) {
+                    Ok(()) => pending.clear(),
+                    Err(err) => {
+                        error!(
+                            self.log,
+                            "Could not write {} audit records: {:?}",
+                            pending.len(),
+                            err
+                        );
+                        self.db = None;
+                        thread::sleep(RETRY_INTERVAL);
+                    }
+                }
+            }
+        }
+
+        fn write(&mut self, records: &[AuditRecord]) -> Result<(), postgres::Error> {
+            if self.db.is_none() {
+                let db = self.connect()?;
+                db.batch_execute(SCHEMA)?;
+                self.db = Some(db);
+            }
+            let db = match self.db {
+                Some(ref db) => db,
+                None => return Ok(()),
+            };
+            let tx = db.transaction()?;
+            {
+                let insert = tx.prepare(
+                    "INSERT INTO channel_audit (ts, channel, kind, detail)
+                     VALUES ($1, $2::text::uuid, $3, $4::text::jsonb)",
+                )?;
+                for record in records {
+                    insert.execute(&[
+                        &(record.ts as i64),
+                        &record.channel.map(|c| c.hyphenated().to_string()),
+                        &record.kind,
+                        &record.detail.to_string(),
+                    ])?;
+                }
+            }
+            tx.commit()
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_sslmode() {
+        assert_eq!(
+            sslmode("postgres://db/audit").unwrap(),
+            ("postgres://db/audit".to_owned(), SslMode::Prefer)
+        );
+        assert_eq!(
+            sslmode("postgres://db/audit?sslmode=require").unwrap(),
+            ("postgres://db/audit".to_owned(), SslMode::Require)
+        );
+        assert_eq!(
+            sslmode("postgres://db/audit?application_name=x&sslmode=disable&a=b").unwrap(),
+            ("postgres://db/audit?application_name=x&a=b".to_owned(), SslMode::Disable)
+        );
+        assert!(sslmode("postgres://db/audit?sslmode=maybe").is_err());
+    }
+}
@@ -0,0 +1,385 @@
+//! `channelserver check-config [--config path]`: load and validate the
+//! settings without starting the server, so a bad deploy fails before it
+//! takes traffic.
+//!
+//! Each problem is printed on its own line, naming the setting and what
+//! is wrong with it. The exit code is 0 if the settings are usable, 1 if
+//! not, and 2 for a bad command line.
+
+use std::net::{SocketAddr, ToSocketAddrs};
+use std::path::Path;
+
+use apikey;
+use audit;
+use cors;
+use i18n;
+use listener;
+use logformat::Format;
+use logging::{self, ErrorLevel};
+use settings::Settings;
+use syslog;
+
+/// Check that every item of a `name:value,...` list parses.
+fn check_pairs<F>(problems: &mut Vec<String>, setting: &str, spec: &str, valid: F)
+where
+    F: Fn(&str, Option<&str>) -> bool,
+{
+    for item in spec.split(',').map(|item| item.trim()).filter(|item| !item.is_empty()) {
+        let mut parts = item.splitn(2, ':');
+        let name = parts.next().unwrap_or("").trim();
+        if !valid(name, parts.next().map(|value| value.trim())) {
+            problems.push(format!("{}: invalid entry {:?}", setting, item));
+        }
+    }
+}
+
+/// Everything wrong with `settings`.
+pub fn problems(settings: &Settings) -> Vec<String> {
+    let mut problems = Vec::new();
+
+    match listener::endpoints(settings) {
+        Ok(endpoints) => for endpoint in endpoints {
+            if endpoint.port == 0 {
+                problems.push(format!("listen: {} has port 0", endpoint.host));
+            } else if (endpoint.host.as_str(), endpoint.port)
+                .to_socket_addrs()
+                .map(|mut addrs| addrs.next().is_none())
+                .unwrap_or(true)
+            {
+                problems.push(format!("listen: {:?} does not resolve", endpoint.host));
+            }
+        },
+        Err(e) => problems.push(format!("listen: {}", e)),
+    }
+    match settings.ip_stack.as_str() {
+        "" | "v4" | "v6" | "dual" => {}
+        other => problems.push(format!(
+            "ip_stack: {:?} is not one of v4, v6 or dual",
+            other
+        )),
+    }
+    if settings.acceptors == 0 {
+        problems.push("acceptors: must be at least 1".to_owned());
+    } else if settings.acceptors > 1 && !settings.reuse_port {
+        problems.push("acceptors: more than 1 requires reuse_port".to_owned());
+    }
+    if settings.ipv6_prefix > 128 {
+        problems.push("ipv6_prefix: must be at most 128".to_owned());
+    }
+    if settings.max_clients == 0 {
+        problems.push("max_clients: must be at least 1".to_owned());
+    }
+    if let Err(e) = i18n::Negotiator::new(&settings.default_language, &settings.language_fallback)
+    {
+        problems.push(e);
+    }
+
+    if Format::parse(&settings.log_format).is_none() {
+        problems.push(format!(
+            "log_format: {:?} is not one of text, json, gcp or cloudwatch",
+            settings.log_format
+        ));
+    }
+    for item in settings.log_level.split(',').filter(|item| !item.trim().is_empty()) {
+        if logging::directive(item).is_none() {
+            problems.push(format!("log_level: invalid directive {:?}", item.trim()));
+        }
+    }
+    check_pairs(&mut problems, "log_sample", &settings.log_sample, |level, percent| {
+        ErrorLevel::parse(level).is_some()
+            && percent.map_or(false, |p| p.parse::<f64>().is_ok())
+    });
+    if settings.log_queue_size == 0 {
+        problems.push("log_queue_size: must be at least 1".to_owned());
+    }
+    if !settings.log_file.is_empty() && !settings.syslog.is_empty() {
+        problems.push("log_file and syslog: only one log output may be set".to_owned());
+    }
+    if !settings.log_file.is_empty() {
+        let dir = Path::new(&settings.log_file)
+            .parent()
+            .filter(|dir| !dir.as_os_str().is_empty())
+            .unwrap_or_else(|| Path::new("."));
+        if !dir.is_dir() {
+            problems.push(format!("log_file: directory {:?} does not exist", dir));
+        }
+    } else if settings.log_max_size > 0 || settings.log_max_age > 0 {
+        problems.push("log_max_size, log_max_age: rotation requires log_file".to_owned());
+    }
+    if !settings.syslog.is_empty() {
+        if settings.syslog.starts_with("unix://") {
+            if !Path::new(&settings.syslog[7..]).exists() {
+                problems.push(format!("syslog: {:?} does not exist", &settings.syslog[7..]));
+            }
+        } else if !settings.syslog.starts_with("udp://") && !settings.syslog.starts_with("tcp://")
+        {
+            problems.push(format!(
+                "syslog: {:?} is not a udp://, tcp:// or unix:// URL",
+                settings.syslog
+            ));
+        }
+        if syslog::facility(&settings.syslog_facility).is_none() {
+            problems.push(format!(
+                "syslog_facility: unknown facility {:?}",
+                settings.syslog_facility
+            ));
+        }
+    }
+
+    check_pairs(&mut problems, "slo_windows", &settings.slo_windows, |secs, rest| {
+        rest.is_none() && secs.parse::<u64>().map(|secs| secs > 0).unwrap_or(false)
+    });
+    check_pairs(&mut problems, "feature_flags", &settings.feature_flags, |name, percent| {
+        let percent_ok = |p: &str| p.parse::<u8>().map(|p| p <= 100).unwrap_or(false);
+        !name.is_empty() && percent.map_or(true, percent_ok)
+    });
+
+    if settings.latency_budget > 0 && settings.latency_budget_window == 0 {
+        problems.push("latency_budget_window: must be at least 1 second".to_owned());
+    }
+    if settings.standby_url.is_empty() != settings.standby_token.is_empty() {
+        problems.push("standby_url, standby_token: both or neither must be set".to_owned());
+    }
+    match settings.cluster_redirect.as_str() {
+        "redirect" | "hint" => {}
+        other => problems.push(format!(
+            "cluster_redirect: {:?} is not one of redirect or hint",
+            other
+        )),
+    }
+    if !settings.cluster_nodes.trim().is_empty() {
+        let me = settings.public_url.trim().trim_right_matches('/');
+        if me.is_empty() {
+            problems.push("public_url: required when cluster_nodes is set".to_owned());
+        } else if !settings
+            .cluster_nodes
+            .split(',')
+            .any(|node| node.trim().trim_right_matches('/') == me)
+        {
+            problems.push(format!("public_url: {:?} is not in cluster_nodes", me));
+        }
+    }
+    if !settings.cluster_srv.is_empty() {
+        if !cfg!(feature = "srv") {
+            problems.push("cluster_srv: needs the srv feature".to_owned());
+        }
+        if !settings.cluster_nodes.trim().is_empty() {
+            problems.push("cluster_srv, cluster_nodes: set one or the other".to_owned());
+        }
+        if settings.public_url.trim().is_empty() {
+            problems.push("public_url: required when cluster_srv is set".to_owned());
+        }
+        if settings.cluster_srv_refresh == 0 {
+            problems.push("cluster_srv_refresh: must be at least 1 second".to_owned());
+        }
+    }
+    if !settings.gossip_bind.is_empty() {
+        let advertise = if settings.gossip_advertise.is_empty() {
+            &settings.gossip_bind
+        } else {
+            &settings.gossip_advertise
+        };
+        match settings.gossip_bind.parse::<SocketAddr>() {
+            Err(_) => problems.push(format!(
+                "gossip_bind: {:?} is not an address and port",
+                settings.gossip_bind
+            )),
+            Ok(addr) if addr.ip().is_unspecified() && settings.gossip_advertise.is_empty() => {
+                problems.push(
+                    "gossip_advertise: required when gossip_bind is a wildcard address"
+                        .to_owned(),
+                )
+            }
+            Ok(_) => {}
+        }
+        if !advertise.contains(':') {
+            problems.push(format!("gossip_advertise: {:?} has no port", advertise));
+        }
+        if settings.public_url.trim().is_empty() {
+            problems.push("public_url: required when gossip_bind is set".to_owned());
+        }
+        if !settings.cluster_srv.is_empty() {
+            problems.push(
+                "cluster_srv, gossip_bind: set one or the other; gossip_seeds may be a DNS name"
+                    .to_owned(),
+            );
+        }
+        if settings.gossip_interval < 10 {
+            problems.push("gossip_interval: must be at least 10 milliseconds".to_owned());
+        }
+        if settings.gossip_suspect_timeout == 0 {
+            problems.push("gossip_suspect_timeout: must be at least 1 second".to_owned());
+        }
+        if settings.gossip_key.is_empty() {
+            problems.push("gossip_key: required when gossip_bind is set".to_owned());
+        }
+        if settings.gossip_allow.is_empty() && settings.gossip_seeds.is_empty() {
+            problems.push(
+                "gossip_allow: required when gossip_seeds is empty, or no node may join".to_owned(),
+            );
+        }
+    }
+    let store = settings.channel_store.trim();
+    if !store.is_empty() && store != "memory" {
+        if !store.starts_with("sqlite:") {
+            problems.push(format!("channel_store: unknown store {:?}", store));
+        } else if !cfg!(feature = "sqlite") {
+            problems.push("channel_store: sqlite needs the sqlite feature".to_owned());
+        } else if settings.reconnect_grace == 0 {
+            problems.push(
+                "reconnect_grace: must be set for restored channels to be rejoined".to_owned(),
+            );
+        }
+        if settings.channel_shards > 1 {
+            problems.push("channel_shards: a channel_store can't be sharded".to_owned());
+        }
+    }
+    if !settings.snapshot_path.is_empty() {
+        if settings.reconnect_grace == 0 {
+            problems.push(
+                "reconnect_grace: must be set for snapshot channels to be rejoined".to_owned(),
+            );
+        }
+        let dir = Path::new(&settings.snapshot_path)
+            .parent()
+            .filter(|dir| !dir.as_os_str().is_empty())
+            .unwrap_or_else(|| Path::new("."));
+        if !dir.is_dir() {
+            problems.push(format!("snapshot_path: directory {:?} does not exist", dir));
+        }
+    }
+    if settings.channel_shards == 0 {
+        problems.push("channel_shards: must be at least 1".to_owned());
+    }
+    if settings.max_queued_bytes < settings.max_message_size {
+        // Or a single frame would close the connection it is relayed to.
+        problems.push(format!(
+            "max_queued_bytes: must be at least max_message_size ({})",
+            settings.max_message_size
+        ));
+    }
+    if !settings.rate_limit_redis.is_empty() && !cfg!(feature = "redis") {
+        problems.push("rate_limit_redis: needs the redis feature".to_owned());
+    }
+    if !settings.audit_postgres.is_empty() && !cfg!(feature = "postgres") {
+        problems.push("audit_postgres: needs the postgres feature".to_owned());
+    }
+    if let Err(problem) = audit::sslmode(&settings.audit_postgres) {
+        problems.push(problem);
+    }
+    if settings.relay_only {
+        if !settings.audit_postgres.is_empty() {
+            problems.push("audit_postgres: nothing is recorded in relay_only mode".to_owned());
+        }
+        if settings.tap_allow_payload {
+            problems.push("tap_allow_payload: there are no taps in relay_only mode".to_owned());
+        }
+    }
+    for group in settings.security_headers.split(',').map(|g| g.trim()) {
+        match group {
+            "" | "health" | "admin" | "public" => {}
+            other => problems.push(format!(
+                "security_headers: {:?} is not one of health, admin or public",
+                other
+            )),
+        }
+    }
+    if settings.cors_credentials && cors::wildcard(&settings.cors_origins) {
+        problems.push("cors_credentials: can't be allowed for any origin (\"*\")".to_owned());
+    }
+    if settings.require_api_key && settings.admin_token.is_empty() {
+        problems.push(
+            "require_api_key: keys can't be issued without an admin_token".to_owned(),
+        );
+    }
+    let clustered = !settings.cluster_nodes.trim().is_empty() || !settings.cluster_srv.is_empty();
+    if settings.require_api_key && clustered && settings.api_key_file.is_empty() {
+        problems.push(
+            "api_key_file: needed for keys issued on one node to be accepted by the others"
+                .to_owned(),
+        );
+    }
+    if let Err(e) = apikey::KeyStore::open(&settings.api_key_file) {
+        problems.push(format!("api_key_file: {}", e));
+    }
+    if !settings.statsd_host.is_empty() && settings.statsd_port == 0 {
+        problems.push("statsd_port: must be set when statsd_host is".to_owned());
+    }
+    problems
+}
+
+/// Run the check, returning the process exit code.
+pub fn run(args: &[String]) -> i32 {
+    let mut config = None;
+    let mut args = args.iter();
+    while let Some(arg) = args.next() {
+        if arg == "--config" {
+            config = args.next().map(|path| path.as_str());
+            if config.is_none() {
+                eprintln!("check-config: --config needs a path");
+                return 2;
+            }
+        } else if arg.starts_with("--config=") {
+            config = Some(&arg[9..]);
+        } else {
+            eprintln!("check-config: unexpected argument {:?}", arg);
+            return 2;
+        }
+    }
+    let settings = match Settings::load(config) {
+        Ok(settings) => settings,
+        Err(e) => {
+            eprintln!("error: {}", e);
+            return 1;
+        }
+    };
+    let problems = problems(&settings);
+    for problem in &problems {
+        eprintln!("error: {}", problem);
+    }
+    if problems.is_empty() {
+        println!("Configuration OK");
+        0
+    } else {
+        1
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_problems() {
+        let mut settings = Settings::new().unwrap();
+        settings.listen = "127.0.0.1:8000=public".to_owned();
+        assert_eq!(problems(&settings), Vec::<String>::new());
+
+        settings.listen = "127.0.0.1=public".to_owned();
+        settings.log_format = "xml".to_owned();
+        settings.log_file = "/nonexistent/dir/pairsona.log".to_owned();
+        settings.syslog = "udp://127.0.0.1:514".to_owned();
+        settings.slo_windows = "300,5m".to_owned();
+        settings.log_level = "info,session=loud".to_owned();
+        settings.standby_url = "http://standby:8000".to_owned();
+        settings.max_queued_bytes = 1024;
+        let found = problems(&settings);
+        for setting in &[
+            "listen:",
+            "log_format:",
+            "log_file:",
+            "log_file and syslog:",
+            "slo_windows:",
+            "log_level:",
+            "standby_url, standby_token:",
+            "max_queued_bytes:",
+        ] {
+            assert!(
+                found.iter().any(|p| p.starts_with(setting)),
+                "no {} problem in {:?}",
+                setting,
+                found
+            );
+        }
+    }
+}
@@ -0,0 +1,215 @@
+//! Server-assisted transfer of payloads larger than `max_message_size`.
+//!
+//! A sender splits the payload into `chunk` control messages:
+//!
+//! ```json
+//! {"control": "chunk", "transfer": "<id>", "index": 0, "count": 3, "data": "..."}
+//! ```
+//!
+//! Chunks are relayed to the other participants as they are, for the
+//! receiver to reassemble. The server checks that chunks arrive in order
+//! and that the transfer stays within `max_transfer_size`. Once a chunk
+//! has been relayed the sender receives a `chunk_ack`; a sender may have
+//! at most `chunk_window` chunks of a transfer unacknowledged, and at most
+//! `max_transfers` transfers in progress. A chunk that reaches no peer
+//! (refused, throttled or held) isn't acknowledged, and may be sent again
+//! with the same index.
+
+use std::collections::HashMap;
+
+use server::SessionId;
+
+#[derive(Clone, Debug)]
+struct Transfer {
+    count: u32,
+    /// index of the next expected chunk
+    next: u32,
+    /// octets of data received so far
+    size: usize,
+    /// chunks accepted but not yet relayed
+    in_flight: u32,
+}
+
+/// Bounds on a single transfer, from the settings.
+#[derive(Clone, Copy, Debug)]
+pub struct Limits {
+    /// largest total payload, in octets
+    pub max_size: usize,
+    /// most chunks unacknowledged at once
+    pub window: u32,
+    /// most transfers in progress at once, per sender
+    pub concurrent: usize,
+}
+
+/// The chunked transfers in progress on a channel.
+#[derive(Clone, Debug, Default)]
+pub struct Transfers {
+    active: HashMap<(SessionId, String), Transfer>,
+}
+
+impl Transfers {
+    /// Account for chunk `index` of `count` arriving from `from`. Returns
+    /// why the chunk is refused, if it is. A refused transfer is
+    /// abandoned.
+    pub fn accept(
+        &mut self,
+        from: SessionId,
+        transfer: &str,
+        index: u32,
+        count: u32,
+        size: usize,
+        limits: Limits,
+    ) -> Result<(), String> {
+        let key = (from, transfer.to_owned());
+        if !self.active.contains_key(&key) {
+            let started = self.active.keys().filter(|&&(id, _)| id == from).count();
+            if started >= limits.concurrent {
+                return Err(format!("more than {} transfers in progress", limits.concurrent));
+            }
+        }
+        let result = {
+            let entry = self.active.entry(key.clone()).or_insert(Transfer {
+                count,
+                next: 0,
+                size: 0,
+                in_flight: 0,
+            });
+            if count == 0 || count != entry.count {
+                Err(format!("invalid chunk count {}", count))
+            } else if index != entry.next {
+                Err(format!("expected chunk {}, got {}", entry.next, index))
+            } else if entry.in_flight >= limits.window {
+                Err(format!("more than {} chunks in flight", limits.window))
+            } else if entry.size + size > limits.max_size {
+                Err(format!("transfer larger than {} octets", limits.max_size))
+            } else {
+                entry.next += 1;
+                entry.size += size;
+                entry.in_flight += 1;
+                Ok(())
+            }
+        };
+        if result.is_err() {
+            self.active.remove(&key);
+        }
+        result
+    }
+
+    /// Forget every transfer.
+    pub fn clear(&mut self) {
+        self.active.clear();
+    }
+
+    /// Chunk `index` from `from`, of `size` octets, was accepted but not
+    /// relayed: take it back, so that it may be sent again.
+    pub fn unaccept(&mut self, from: SessionId, transfer: &str, index: u32, size: usize) {
+        let key = (from, transfer.to_owned());
+        let empty = match self.active.get_mut(&key) {
+            Some(entry) => {
+                if entry.next == index + 1 {
+                    entry.next = index;
+                    entry.size = entry.size.saturating_sub(size);
+                    entry.in_flight = entry.in_flight.saturating_sub(1);
+                }
+                entry.next == 0
+            }
+            None => false,
+        };
+        if empty {
+            self.active.remove(&key);
+        }
+    }
+
+    /// A chunk from `from` has been relayed.
+    pub fn relayed(&mut self, from: SessionId, transfer: &str) {
+        let key = (from, transfer.to_owned());
+        let done = match self.active.get_mut(&key) {
+            Some(entry) => {
+                entry.in_flight = entry.in_flight.saturating_sub(1);
+                entry.next == entry.count && entry.in_flight == 0
+            }
+            None => false,
+        };
+        if done {
+            self.active.remove(&key);
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_transfer() {
+        let mut transfers = Transfers::default();
+        let limits = Limits {
+            max_size: 25,
+            window: 2,
+            concurrent: 2,
+        };
+        assert!(transfers.accept(1, "t", 0, 3, 10, limits).is_ok());
+        assert!(transfers.accept(1, "t", 1, 3, 10, limits).is_ok());
+        // window full
+        assert!(transfers.accept(1, "t", 2, 3, 5, limits).is_err());
+        // ...which abandoned the transfer.
+        assert!(transfers.accept(1, "t", 2, 3, 5, limits).is_err());
+
+        assert!(transfers.accept(1, "u", 0, 2, 10, limits).is_ok());
+        transfers.relayed(1, "u");
+        // out of order
+        assert!(transfers.accept(1, "u", 0, 2, 10, limits).is_err());
+
+        assert!(transfers.accept(2, "v", 0, 2, 20, limits).is_ok());
+        // too big
+        assert!(transfers.accept(2, "v", 1, 2, 10, limits).is_err());
+
+        assert!(transfers.accept(2, "w", 0, 1, 20, limits).is_ok());
+        transfers.relayed(2, "w");
+        assert!(transfers.active.is_empty());
+    }
+
+    #[test]
+    fn test_concurrent_transfers() {
+        let mut transfers = Transfers::default();
+        let limits = Limits {
+            max_size: 100,
+            window: 4,
+            concurrent: 2,
+        };
+        assert!(transfers.accept(1, "a", 0, 2, 10, limits).is_ok());
+        assert!(transfers.accept(1, "b", 0, 2, 10, limits).is_ok());
+        assert!(transfers.accept(1, "c", 0, 2, 10, limits).is_err());
+        // the limit is per sender, and doesn't hold up transfers under way.
+        assert!(transfers.accept(2, "c", 0, 2, 10, limits).is_ok());
+        assert!(transfers.accept(1, "a", 1, 2, 10, limits).is_ok());
+        transfers.relayed(1, "a");
+        transfers.relayed(1, "a");
+        assert!(transfers.accept(1, "c", 0, 2, 10, limits).is_ok());
+    }
+
+    #[test]
+    fn test_unaccept() {
+        let mut transfers = Transfers::default();
+        let limits = Limits {
+            max_size: 20,
+            window: 1,
+            concurrent: 1,
+        };
+        assert!(transfers.accept(1, "t", 0, 2, 10, limits).is_ok());
+        transfers.relayed(1, "t");
+        assert!(transfers.accept(1, "t", 1, 2, 10, limits).is_ok());
+        // dropped rather than relayed: the same chunk may be sent again,
+        // within the window and the size limit.
+        transfers.unaccept(1, "t", 1, 10);
+        assert!(transfers.accept(1, "t", 1, 2, 10, limits).is_ok());
+        transfers.relayed(1, "t");
+        assert!(transfers.active.is_empty());
+
+        // taking back the first chunk frees the transfer's slot.
+        assert!(transfers.accept(1, "u", 0, 2, 10, limits).is_ok());
+        transfers.unaccept(1, "u", 0, 10);
+        assert!(transfers.active.is_empty());
+        assert!(transfers.accept(1, "v", 0, 2, 10, limits).is_ok());
+    }
+}
@@ -0,0 +1,258 @@
+//! Channel ownership for clustered deployments without a shared backend.
+//!
+//! Every node is configured with the same list of public node URLs. Channel
+//! ownership is decided by rendezvous hashing over that list, so all nodes
+//! agree on the owner of a channel without talking to each other. A node
+//! that doesn't own a channel points the client at the node that does.
+//!
+//! The list may instead be discovered from DNS (see `discovery`), in which
+//! case it is replaced as the records change.
+//!
+//! A channel migrated to another node (see `migrate`) is placed there, in
+//! place of its owner, on both the node it left and the node it went to,
+//! for as long as the channel could live; other nodes still send clients
+//! to its owner, which redirects them. A channel is likewise placed on
+//! the node that created it, so that it stays there if the node list
+//! changes while it is live.
+
+use std::collections::HashMap;
+use std::time::Instant;
+
+use uuid::Uuid;
+
+/// 64 bit FNV-1a, used because it is stable across builds and platforms.
+pub fn fnv1a(parts: &[&[u8]]) -> u64 {
+    let mut hash: u64 = 0xcbf29ce484222325;
+    for part in parts {
+        for byte in part.iter() {
+            hash ^= u64::from(*byte);
+            hash = hash.wrapping_mul(0x100000001b3);
+        }
+    }
+    hash
+}
+
+fn normalize(url: &str) -> String {
+    url.trim().trim_right_matches('/').to_owned()
+}
+
+/// A comma separated list of URLs.
+fn urls(list: &str) -> Vec<String> {
+    list.split(',')
+        .map(normalize)
+        .filter(|n| !n.is_empty())
+        .collect()
+}
+
+#[derive(Clone, Debug, Default)]
+pub struct Cluster {
+    /// Public base URLs of every node in the cluster, including this one.
+    pub nodes: Vec<String>,
+    /// Public base URL of this node.
+    pub me: String,
+    /// Where clients should go instead, if this node can't take them, in
+    /// place of the other cluster nodes.
+    pub hints: Vec<String>,
+    /// Nodes gossip suspects are down, so not worth trying.
+    pub suspects: Vec<String>,
+    /// Channels migrated away from (or to) their owner: where each now
+    /// lives, and until when that is worth remembering.
+    pub placed: HashMap<Uuid, (String, Instant)>,
+    /// How many placements to let build up before forgetting expired ones.
+    prune_at: usize,
+}
+
+impl Cluster {
+    /// Build from the comma separated `cluster_nodes` setting.
+    pub fn new(nodes: &str, me: &str) -> Self {
+        Self {
+            nodes: urls(nodes),
+            me: normalize(me),
+            hints: Vec::new(),
+            suspects: Vec::new(),
+            placed: HashMap::new(),
+            prune_at: 0,
+        }
+    }
+
+    /// Use the comma separated `reconnect_hints` setting, if set, as the
+    /// alternates to this node.
+    pub fn with_hints(mut self, hints: &str) -> Self {
+        self.hints = urls(hints);
+        self
+    }
+
+    /// Replace the node list, as discovered. Returns whether it changed.
+    pub fn set_nodes(&mut self, mut nodes: Vec<String>) -> bool {
+        nodes.sort();
+        nodes.dedup();
+        if nodes == self.nodes {
+            return false;
+        }
+        self.nodes = nodes;
+        true
+    }
+
+    /// Replace the suspected nodes, as gossiped.
+    pub fn set_suspects(&mut self, suspects: Vec<String>) {
+        self.suspects = suspects;
+    }
+
+    /// Nodes a client this node turns away could try instead: never this
+    /// one, nor one that is suspected to be down.
+    pub fn alternates(&self) -> Vec<&str> {
+        let nodes = if self.hints.is_empty() {
+            &self.nodes
+        } else {
+            &self.hints
+        };
+        nodes
+            .iter()
+            .map(|n| n.as_str())
+            .filter(|n| *n != self.me && !self.suspects.iter().any(|s| s == n))
+            .collect()
+    }
+
+    /// Is clustering configured at all?
+    pub fn enabled(&self) -> bool {
+        self.nodes.len() > 1 && !self.me.is_empty()
+    }
+
+    /// Record that `channel` lives on `node` until `until`, regardless of
+    /// who owns it.
+    pub fn place(&mut self, channel: Uuid, node: &str, until: Instant) {
+        if self.placed.len() >= self.prune_at {
+            let now = Instant::now();
+            self.placed.retain(|_, placed| placed.1 > now);
+            self.prune_at = (self.placed.len() * 2).max(64);
+        }
+        self.placed.insert(channel, (normalize(node), until));
+    }
+
+    /// Where `channel` was placed, if it was and still is.
+    fn placement(&self, channel: &Uuid) -> Option<&str> {
+        match self.placed.get(channel) {
+            Some(&(ref node, until)) if until > Instant::now() => Some(node.as_str()),
+            _ => None,
+        }
+    }
+
+    /// The base URL of the node that owns `channel`.
+    pub fn owner(&self, channel: &Uuid) -> &str {
+        if let Some(node) = self.placement(channel) {
+            return node;
+        }
+        self.nodes
+            .iter()
+            .max_by_key(|node| fnv1a(&[node.as_bytes(), channel.as_bytes()]))
+            .map(|node| node.as_str())
+            .unwrap_or(&self.me)
+    }
+
+    pub fn is_local(&self, channel: &Uuid) -> bool {
+        match self.placement(channel) {
+            Some(node) => node == self.me,
+            None => !self.enabled() || self.owner(channel) == self.me,
+        }
+    }
+
+    /// Generate a new channel ID owned by this node.
+    pub fn new_local_channel(&self) -> Uuid {
+        // Each attempt lands here with probability 1/nodes, so this almost
+        // always succeeds quickly. Fall back to any ID rather than spin.
+        for _ in 0..(self.nodes.len() * 16) {
+            let channel = Uuid::new_v4();
+            if self.is_local(&channel) {
+                return channel;
+            }
+        }
+        Uuid::new_v4()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::time::Duration;
+
+    use super::*;
+
+    #[test]
+    fn test_owner_is_stable() {
+        let nodes = "http://a:8000, http://b:8000/,http://c:8000";
+        let views: Vec<Cluster> = ["http://a:8000", "http://b:8000/", "http://c:8000"]
+            .iter()
+            .map(|me| Cluster::new(nodes, me))
+            .collect();
+        for _ in 0..32 {
+            let channel = Uuid::new_v4();
+            // every node agrees on the owner, and exactly one node owns it.
+            assert!(views.iter().all(|v| v.owner(&channel) == views[0].owner(&channel)));
+            assert_eq!(views.iter().filter(|v| v.is_local(&channel)).count(), 1);
+        }
+        let channel = views[1].new_local_channel();
+        assert!(views[1].is_local(&channel));
+        // Without clustering, everything is local.
+        assert!(Cluster::new("", "").is_local(&channel));
+    }
+
+    #[test]
+    fn test_alternates() {
+        let nodes = "http://a:8000, http://b:8000/,http://c:8000";
+        let cluster = Cluster::new(nodes, "http://b:8000");
+        assert_eq!(cluster.alternates(), vec!["http://a:8000", "http://c:8000"]);
+        let cluster = cluster.with_hints("https://standby.example.com/");
+        assert_eq!(cluster.alternates(), vec!["https://standby.example.com"]);
+        assert!(Cluster::new("", "").alternates().is_empty());
+
+        let mut cluster = Cluster::new(nodes, "http://b:8000")
+            .with_hints("http://a:8000, http://b:8000, http://c:8000");
+        assert_eq!(cluster.alternates(), vec!["http://a:8000", "http://c:8000"]);
+        cluster.set_suspects(vec!["http://c:8000".to_owned()]);
+        assert_eq!(cluster.alternates(), vec!["http://a:8000"]);
+    }
+
+    #[test]
+    fn test_set_nodes() {
+        let mut cluster = Cluster::new("http://a:8000,http://b:8000", "http://b:8000");
+        let nodes = vec!["http://b:8000".to_owned(), "http://a:8000".to_owned()];
+        // the same nodes, in another order
+        assert!(!cluster.set_nodes(nodes));
+        assert!(cluster.set_nodes(vec!["http://c:8000".to_owned()]));
+        assert_eq!(cluster.nodes, vec!["http://c:8000"]);
+    }
+
+    #[test]
+    fn test_placed() {
+        let nodes = "http://a:8000,http://b:8000";
+        let mut a = Cluster::new(nodes, "http://a:8000");
+        let mut b = Cluster::new(nodes, "http://b:8000");
+        let channel = a.new_local_channel();
+        let until = Instant::now() + Duration::from_secs(60);
+        // migrated from a to b
+        a.place(channel, "http://b:8000/", until);
+        b.place(channel, "http://b:8000", until);
+        assert!(!a.is_local(&channel));
+        assert_eq!(a.owner(&channel), "http://b:8000");
+        assert!(b.is_local(&channel));
+        // and forgotten once the channel is gone
+        a.place(channel, "http://b:8000", Instant::now());
+        assert!(a.is_local(&channel));
+    }
+
+    #[test]
+    fn test_pinned() {
+        let (a, c) = ("http://a:8000", "http://c:8000");
+        let mut cluster = Cluster::new("http://a:8000,http://b:8000", a);
+        let with_c = Cluster::new("http://a:8000,http://b:8000,http://c:8000", a);
+        // a channel of a's that would be c's, once c joins
+        let channel = (0..1000)
+            .map(|_| cluster.new_local_channel())
+            .find(|channel| with_c.owner(channel) == c)
+            .unwrap();
+        cluster.place(channel, a, Instant::now() + Duration::from_secs(60));
+        assert!(cluster.set_nodes(with_c.nodes.clone()));
+        // Still live here, so still served here.
+        assert!(cluster.is_local(&channel));
+        assert!(!with_c.is_local(&channel));
+    }
+}
@@ -0,0 +1,142 @@
+//! Cross-origin access to the public HTTP API (e.g. `/v1/capabilities`),
+//! for browser clients served from another origin.
+//!
+//! Requests from an origin listed in `cors_origins` get the matching
+//! `Access-Control-Allow-*` headers, and preflight requests are answered
+//! here. Admin and health routes are never opened up, and websockets don't
+//! use CORS.
+
+use actix_web::http::header::{self, HeaderValue};
+use actix_web::http::{Method, StatusCode};
+use actix_web::middleware::{Middleware, Response, Started};
+use actix_web::{HttpRequest, HttpResponse, Result};
+
+use headers;
+use perror::HandlerErrorKind;
+use session::WsChannelSessionState;
+
+/// May `origin` call the API, per a `cors_origins` value?
+pub fn allowed(spec: &str, origin: &str) -> bool {
+    spec.split(',')
+        .map(|item| item.trim().trim_right_matches('/'))
+        .any(|item| item == "*" || item.eq_ignore_ascii_case(origin))
+}
+
+/// Does a `cors_origins` value let in any origin?
+pub fn wildcard(spec: &str) -> bool {
+    spec.split(',').any(|item| item.trim() == "*")
+}
+
+/// May requests with credentials be allowed, per `cors_credentials`?
+/// Never for any origin at all, whatever the setting says, as that would
+/// let any site act as the user.
+fn credentials(spec: &str, enabled: bool) -> bool {
+    enabled && !wildcard(spec)
+}
+
+/// The request's `Origin`, if it may call the API.
+fn origin(req: &HttpRequest<WsChannelSessionState>) -> Option<HeaderValue> {
+    if headers::group(req.path()) != "public" {
+        return None;
+    }
+    let origin = req.headers().get(header::ORIGIN)?;
+    if allowed(&req.state().settings.cors_origins, origin.to_str().ok()?) {
+        Some(origin.clone())
+    } else {
+        None
+    }
+}
+
+fn allow(req: &HttpRequest<WsChannelSessionState>, resp: &mut HttpResponse, origin: HeaderValue) {
+    let headers = resp.headers_mut();
+    // The allowed origin is echoed, so responses vary by it.
+    headers.insert(header::ACCESS_CONTROL_ALLOW_ORIGIN, origin);
+    headers.insert(header::VARY, HeaderValue::from_static("Origin"));
+    let settings = &req.state().settings;
+    if credentials(&settings.cors_origins, settings.cors_credentials) {
+        headers.insert(
+            header::ACCESS_CONTROL_ALLOW_CREDENTIALS,
+            HeaderValue::from_static("true"),
+        );
+    }
+}
+
+pub struct Cors;
+
+impl Middleware<WsChannelSessionState> for Cors {
+    fn start(&self, req: &HttpRequest<WsChannelSessionState>) -> Result<Started> {
+        let preflight = *req.method() == Method::OPTIONS
+            && req
+                .headers()
+                .contains_key(header::ACCESS_CONTROL_REQUEST_METHOD);
+        if !preflight {
+            return Ok(Started::Done);
+        }
+        let origin = match origin(req) {
+            Some(origin) => origin,
+            None => {
+                return Ok(Started::Response(
+                    HandlerErrorKind::UnauthorizedErr
+                        .response_with(Some(json!({"reason": "Origin not allowed"}))),
+                ))
+            }
+        };
+        let settings = &req.state().settings;
+        let mut resp = HttpResponse::NoContent()
+            .header(
+                header::ACCESS_CONTROL_ALLOW_METHODS,
+                settings.cors_methods.as_str(),
+            )
+            .header(
+                header::ACCESS_CONTROL_MAX_AGE,
+                settings.cors_max_age.to_string(),
+            )
+            .finish();
+        if let Some(headers) = req
+            .headers()
+            .get(header::ACCESS_CONTROL_REQUEST_HEADERS)
+            .cloned()
+        {
+            resp.headers_mut()
+                .insert(header::ACCESS_CONTROL_ALLOW_HEADERS, headers);
+        }
+        allow(req, &mut resp, origin);
+        Ok(Started::Response(resp))
+    }
+
+    fn response(
+        &self,
+        req: &HttpRequest<WsChannelSessionState>,
+        mut resp: HttpResponse,
+    ) -> Result<Response> {
+        if resp.status() != StatusCode::SWITCHING_PROTOCOLS {
+            if let Some(origin) = origin(req) {
+                allow(req, &mut resp, origin);
+            }
+        }
+        Ok(Response::Done(resp))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_allowed() {
+        let spec = "https://app.example.com, https://Other.example.com/";
+        assert!(allowed(spec, "https://app.example.com"));
+        assert!(allowed(spec, "https://other.example.com"));
+        assert!(!allowed(spec, "https://evil.example.com"));
+        assert!(!allowed("", "https://app.example.com"));
+        assert!(allowed("*", "https://anywhere.example.com"));
+    }
+
+    #[test]
+    fn test_credentials() {
+        assert!(credentials("https://app.example.com", true));
+        assert!(!credentials("https://app.example.com", false));
+        assert!(!credentials("https://app.example.com, *", true));
+        assert!(!credentials("*", true));
+    }
+}
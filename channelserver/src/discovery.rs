@@ -0,0 +1,105 @@
+//! Cluster node discovery from DNS SRV records.
+//!
+//! Kubernetes headless services and Consul both publish a record per
+//! instance, so the cluster's nodes needn't be listed in each node's
+//! config. Each record's target and port become a node URL,
+//! `<scheme>://<target>:<port>`, which this node's `public_url` must match.
+//!
+//! The records are looked up again every `cluster_srv_refresh` seconds. A
+//! failed or empty lookup keeps the nodes last found, rather than leaving
+//! this node to believe it is alone.
+
+use std::sync::{Arc, RwLock};
+use std::thread;
+use std::time::Duration;
+
+use cadence::{Counted, StatsdClient};
+use slog::Logger;
+
+use cluster::Cluster;
+
+/// The URL of the node a record points at. Targets are absolute names,
+/// with a trailing `.` that isn't part of the host name.
+pub fn node_url(scheme: &str, target: &str, port: u16) -> String {
+    format!("{}://{}:{}", scheme, target.trim_right_matches('.'), port)
+}
+
+/// The nodes `name` currently lists.
+#[cfg(feature = "srv")]
+pub fn lookup(name: &str, scheme: &str) -> Result<Vec<String>, String> {
+    use trust_dns_resolver::Resolver;
+
+    let resolver = Resolver::from_system_conf().map_err(|e| e.to_string())?;
+    let records = resolver.lookup_srv(name).map_err(|e| e.to_string())?;
+    Ok(records
+        .iter()
+        .map(|srv| node_url(scheme, &srv.target().to_utf8(), srv.port()))
+        .collect())
+}
+
+#[cfg(not(feature = "srv"))]
+pub fn lookup(_name: &str, _scheme: &str) -> Result<Vec<String>, String> {
+    Err("cluster_srv needs the srv feature".to_owned())
+}
+
+/// Look up `name` and replace the cluster's nodes with what was found.
+pub fn refresh(
+    name: &str,
+    scheme: &str,
+    cluster: &RwLock<Cluster>,
+    metrics: &StatsdClient,
+    log: &Logger,
+) {
+    let nodes = match lookup(name, scheme) {
+        Ok(ref nodes) if nodes.is_empty() => {
+            warn!(log, "No cluster nodes found at {}; keeping the last known", name);
+            metrics.incr("cluster.discovery.empty").ok();
+            return;
+        }
+        Ok(nodes) => nodes,
+        Err(err) => {
+            error!(log, "Could not look up cluster nodes at {}: {}", name, err);
+            metrics.incr("cluster.discovery.error").ok();
+            return;
+        }
+    };
+    let mut cluster = cluster.write().unwrap();
+    if cluster.set_nodes(nodes) {
+        info!(log, "Cluster nodes are now {}", cluster.nodes.join(", "));
+        metrics.incr("cluster.discovery.changed").ok();
+    }
+}
+
+/// Look up `name` now, then again every `every` in the background.
+pub fn watch(
+    name: &str,
+    scheme: &str,
+    every: Duration,
+    cluster: Arc<RwLock<Cluster>>,
+    metrics: Arc<StatsdClient>,
+    log: Logger,
+) {
+    refresh(name, scheme, &cluster, &metrics, &log);
+    let (name, scheme) = (name.to_owned(), scheme.to_owned());
+    thread::Builder::new()
+        .name("cluster-discovery".to_owned())
+        .spawn(move || loop {
+            thread::sleep(every);
+            refresh(&name, &scheme, &cluster, &metrics, &log);
+        })
+        .expect("Could not start cluster discovery");
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_node_url() {
+        assert_eq!(
+            node_url("http", "relay-0.relay.default.svc.cluster.local.", 8000),
+            "http://relay-0.relay.default.svc.cluster.local:8000"
+        );
+        assert_eq!(node_url("https", "a.example.com", 443), "https://a.example.com:443");
+    }
+}
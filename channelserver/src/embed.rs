@@ -0,0 +1,359 @@
+//! Running a whole node as a library, for embedding the relay in another
+//! process. This is also how the `channelserver` binary runs.
+//!
+//! ```ignore
+//! let server = PairsonaServer::builder()
+//!     .settings(settings)
+//!     .geo_resolver(resolver)
+//!     .build()
+//!     .run()?;
+//! server.metrics().incr("embedder.relay_started").ok();
+//! server.shutdown();
+//! ```
+//!
+//! An embedded node leaves the process' signals to its host, unless built
+//! with `signals(true)` as the binary is.
+
+use std::sync::{mpsc, Arc, RwLock};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use actix::prelude::{Actor, Context, Handler};
+use actix::{Arbiter, System};
+use actix_web::server::HttpServer;
+use actix_web::App;
+use cadence::StatsdClient;
+use futures::Future;
+
+use alloc;
+use apikey;
+use app;
+use cluster;
+use discovery;
+use geo::GeoResolver;
+use gossip;
+use i18n;
+use listener;
+use logging;
+use metrics;
+use ratelimit;
+use server;
+use session;
+use settings::Settings;
+use shard;
+use slo;
+use snapshot;
+
+/// Passes channel lifecycle events, as JSON, to an embedder.
+struct EventSink(Box<Fn(&str) + Send>);
+
+impl Actor for EventSink {
+    type Context = Context<Self>;
+}
+
+impl Handler<server::TextMessage> for EventSink {
+    type Result = ();
+
+    fn handle(&mut self, msg: server::TextMessage, _: &mut Context<Self>) {
+        if msg.text != server::EOL {
+            (self.0)(&msg.text);
+        }
+    }
+}
+
+pub struct Builder {
+    settings: Option<Settings>,
+    geo: Option<Arc<GeoResolver>>,
+    metrics: Option<StatsdClient>,
+    on_event: Option<Box<Fn(&str) + Send>>,
+    signals: bool,
+}
+
+impl Builder {
+    /// The settings to run with, rather than those from `config/<RUN_MODE>`
+    /// and the environment.
+    pub fn settings(mut self, settings: Settings) -> Self {
+        self.settings = Some(settings);
+        self
+    }
+
+    /// Count connections by region (see `geo`).
+    pub fn geo_resolver<R: GeoResolver + 'static>(mut self, resolver: R) -> Self {
+        self.geo = Some(Arc::new(resolver));
+        self
+    }
+
+    /// Send metrics to `metrics` rather than the `statsd_host` setting.
+    pub fn metrics(mut self, metrics: StatsdClient) -> Self {
+        self.metrics = Some(metrics);
+        self
+    }
+
+    /// Handle the process' signals, as a node that owns its process does:
+    /// `SIGHUP` reloads the feature flags from wherever the settings came
+    /// from, and `SIGTERM`, `SIGINT` and `SIGQUIT` stop the node.
+    pub fn signals(mut self, signals: bool) -> Self {
+        self.signals = signals;
+        self
+    }
+
+    /// Call `on_event` with each channel lifecycle event, as JSON, as sent
+    /// to `/admin/events`. It is called on the node's thread.
+    pub fn on_event<F: Fn(&str) + Send + 'static>(mut self, on_event: F) -> Self {
+        self.on_event = Some(Box::new(on_event));
+        self
+    }
+
+    pub fn build(self) -> PairsonaServer {
+        PairsonaServer {
+            settings: self.settings,
+            geo: self.geo,
+            metrics: self.metrics,
+            on_event: self.on_event,
+            signals: self.signals,
+        }
+    }
+}
+
+/// A node, ready to run.
+pub struct PairsonaServer {
+    settings: Option<Settings>,
+    geo: Option<Arc<GeoResolver>>,
+    metrics: Option<StatsdClient>,
+    on_event: Option<Box<Fn(&str) + Send>>,
+    signals: bool,
+}
+
+impl PairsonaServer {
+    pub fn builder() -> Builder {
+        Builder {
+            settings: None,
+            geo: None,
+            metrics: None,
+            on_event: None,
+            signals: false,
+        }
+    }
+
+    /// Start the node on a thread of its own, returning once it is
+    /// listening.
+    pub fn run(self) -> Result<ServerHandle, String> {
+        let (settings, reload): (Settings, server::Reload) = match self.settings {
+            Some(settings) => {
+                // Nowhere to read them again from.
+                let given = settings.clone();
+                (settings, Arc::new(move || Ok(given.clone())))
+            }
+            None => (
+                Settings::new().map_err(|e| format!("Invalid settings: {}", e))?,
+                Arc::new(|| Settings::new().map_err(|e| e.to_string())),
+            ),
+        };
+        let reload = if self.signals { Some(reload) } else { None };
+        let (geo, metrics, on_event) = (self.geo, self.metrics, self.on_event);
+        let (tx, rx) = mpsc::channel();
+        let thread = thread::Builder::new()
+            .name("pairsona-server".to_owned())
+            .spawn(move || {
+                let sys = System::new("pairsona-server");
+                let started = start(settings, reload, geo, metrics).map(|(shards, metrics)| {
+                    if let Some(on_event) = on_event {
+                        shards.subscribe(EventSink(on_event).start().recipient());
+                    }
+                    (System::current(), shards, metrics)
+                });
+                let ok = started.is_ok();
+                tx.send(started).ok();
+                if ok {
+                    let _ = sys.run();
+                }
+            })
+            .map_err(|e| format!("Could not start server thread: {}", e))?;
+        let (system, shards, metrics) = rx
+            .recv()
+            .map_err(|_| "Server thread exited while starting".to_owned())??;
+        Ok(ServerHandle {
+            system,
+            thread,
+            shards,
+            metrics,
+        })
+    }
+}
+
+/// A running node.
+pub struct ServerHandle {
+    system: System,
+    thread: thread::JoinHandle<()>,
+    shards: shard::Shards,
+    metrics: Arc<StatsdClient>,
+}
+
+impl ServerHandle {
+    /// Where the node sends its metrics, for the embedder's own.
+    pub fn metrics(&self) -> Arc<StatsdClient> {
+        self.metrics.clone()
+    }
+
+    /// Stop the node as `SIGTERM` would, saving or closing its channels
+    /// first, and wait for its thread to finish.
+    pub fn shutdown(self) {
+        self.shards.stop().wait().ok();
+        self.system.stop();
+        self.wait();
+    }
+
+    /// Wait for the node to stop, e.g. on `SIGTERM` if it handles signals.
+    pub fn wait(self) {
+        self.thread.join().ok();
+    }
+}
+
+/// Start the channel servers, the background tasks the settings ask for,
+/// and an HTTP server for each endpoint, on the current actix `System`.
+fn start(
+    settings: Settings,
+    reload: Option<server::Reload>,
+    geo: Option<Arc<GeoResolver>>,
+    metrics: Option<StatsdClient>,
+) -> Result<(shard::Shards, Arc<StatsdClient>), String> {
+    let settings = Arc::new(settings);
+    let logger = logging::MozLogger::from_settings(&settings)
+        .map_err(|e| format!("Could not open log output: {}", e))?;
+    let endpoints = listener::endpoints(&settings)?;
+    let persistent = settings.channel_store.trim().starts_with("sqlite:");
+    let shards = if settings.channel_shards > 1 && persistent {
+        warn!(
+            logger.log,
+            "channel_store can't be sharded, running a single channel server"
+        );
+        1
+    } else {
+        settings.channel_shards
+    };
+    let signals = reload.is_some();
+    let shards = shard::Shards::start(shards, &settings, reload, &logger);
+    let mut restored = Vec::new();
+    if !settings.snapshot_path.is_empty() {
+        let channels = snapshot::load(
+            &settings.snapshot_path,
+            settings.reconnect_grace,
+            settings.channel_rate,
+            &logger.log,
+        );
+        restored = channels.iter().map(|&(id, _)| id).collect();
+        shards.restore(channels);
+    }
+    // Shares the log writer (and log file) with `logger`.
+    let log_actor = logger.clone();
+    let log = Arbiter::start(move |_| log_actor);
+    let keys = apikey::KeyStore::open(&settings.api_key_file)
+        .map_err(|e| format!("Could not read api_key_file: {}", e))?;
+    let keys = Arc::new(RwLock::new(keys));
+    let mut cluster = cluster::Cluster::new(&settings.cluster_nodes, &settings.public_url)
+        .with_hints(&settings.reconnect_hints);
+    if cluster.enabled() {
+        // Restored channels stay here, as if just created.
+        let until = Instant::now() + Duration::from_secs(settings.timeout);
+        let me = cluster.me.clone();
+        for channel in restored {
+            cluster.place(channel, &me, until);
+        }
+    }
+    let cluster = Arc::new(RwLock::new(cluster));
+    let metrics = Arc::new(match metrics {
+        Some(metrics) => metrics,
+        None => metrics::metrics_from_settings(&settings, &logger),
+    });
+    if !settings.cluster_srv.is_empty() {
+        discovery::watch(
+            &settings.cluster_srv,
+            &settings.cluster_srv_scheme,
+            Duration::from_secs(settings.cluster_srv_refresh),
+            cluster.clone(),
+            metrics.clone(),
+            logger.log.clone(),
+        );
+    }
+    if settings.alloc_stats_interval > 0 {
+        alloc::watch(
+            Duration::from_secs(settings.alloc_stats_interval),
+            metrics.clone(),
+        );
+    }
+    if !settings.gossip_bind.is_empty() {
+        gossip::Gossip::bind(&settings, cluster.clone(), metrics.clone(), logger.log.clone())?
+            .start();
+    }
+    let limiters = Arc::new(ratelimit::Limiters::new(&settings)?);
+    let slo = Arc::new(
+        slo::SloTracker::new(slo::parse_windows(&settings.slo_windows)).with_budget(
+            settings.latency_budget,
+            Duration::from_secs(settings.latency_budget_window),
+        ),
+    );
+    let languages = Arc::new(i18n::Negotiator::new(
+        &settings.default_language,
+        &settings.language_fallback,
+    )?);
+    let secrets = settings.secrets();
+    {
+        // Each channel server keeps its own copy of the settings.
+        let shards = shards.clone();
+        secrets.on_rotate(move |name, value| {
+            for server in shards.all() {
+                server.do_send(server::SecretRotated {
+                    name: name.to_owned(),
+                    value: value.to_owned(),
+                })
+            }
+        });
+    }
+    if settings.secrets_refresh > 0 {
+        secrets.watch(
+            Duration::from_secs(settings.secrets_refresh),
+            logger.log.clone(),
+        );
+    }
+    // Websocket sessions state, shared by all the listeners
+    let state = session::WsChannelSessionState {
+        shards: shards.clone(),
+        log,
+        settings: settings.clone(),
+        keys,
+        cluster,
+        metrics: metrics.clone(),
+        limiters,
+        slo,
+        languages,
+        secrets,
+        geo,
+    };
+
+    // Create an Http server with websocket support for each endpoint
+    for endpoint in endpoints {
+        let sockets = listener::bind(&settings, &endpoint).map_err(|e| {
+            format!("Could not listen on {}:{}: {}", endpoint.host, endpoint.port, e)
+        })?;
+        let routes = endpoint.routes;
+        let state = state.clone();
+        let mut http =
+            HttpServer::new(move || app::build_app(App::with_state(state.clone()), routes));
+        for socket in sockets {
+            http = http.listen(socket);
+        }
+        if !signals {
+            // The host's to handle.
+            http = http.disable_signals();
+        }
+        http.start();
+        info!(
+            logger.log,
+            "Started http server: {}:{} {:?}", endpoint.host, endpoint.port, routes
+        );
+    }
+
+    info!(logger.log, "Settings: {:?}", settings.redacted());
+    info!(logger.log, "Allocator: {}", alloc::name());
+    Ok((shards, metrics))
+}
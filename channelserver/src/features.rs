@@ -0,0 +1,78 @@
+//! Runtime feature flags with percentage rollout.
+//!
+//! Flags are configured as a comma separated list of `name:percent`
+//! pairs, e.g. `sequence_frames:10,stamp_frames:100`. Whether a flag is on
+//! for a channel is decided by hashing the flag name with the channel ID,
+//! so the choice is deterministic and the same on every node. Flags are
+//! evaluated once, when a channel is created, and reloaded on SIGHUP.
+
+use std::collections::{HashMap, HashSet};
+
+use uuid::Uuid;
+
+use cluster::fnv1a;
+
+#[derive(Clone, Debug, Default)]
+pub struct FeatureFlags {
+    rollout: HashMap<String, u8>,
+}
+
+impl FeatureFlags {
+    pub fn parse(spec: &str) -> Self {
+        let rollout = spec
+            .split(',')
+            .filter_map(|item| {
+                let mut parts = item.trim().splitn(2, ':');
+                let name = parts.next()?.trim();
+                let percent = parts.next().map_or(Some(100), |p| p.trim().parse::<u8>().ok())?;
+                if name.is_empty() {
+                    return None;
+                }
+                Some((name.to_owned(), percent.min(100)))
+            })
+            .collect();
+        Self { rollout }
+    }
+
+    /// Is `flag` on for `channel`?
+    pub fn enabled(&self, flag: &str, channel: &Uuid) -> bool {
+        match self.rollout.get(flag) {
+            Some(percent) => {
+                (fnv1a(&[flag.as_bytes(), channel.as_bytes()]) % 100) < u64::from(*percent)
+            }
+            None => false,
+        }
+    }
+
+    /// All flags that are on for `channel`.
+    pub fn for_channel(&self, channel: &Uuid) -> HashSet<String> {
+        self.rollout
+            .keys()
+            .filter(|flag| self.enabled(flag, channel))
+            .cloned()
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_rollout() {
+        let flags = FeatureFlags::parse("all:100, none:0,half:50,bogus:x,implicit");
+        let channels: Vec<Uuid> = (0..200).map(|_| Uuid::new_v4()).collect();
+        assert!(channels.iter().all(|c| flags.enabled("all", c)));
+        assert!(channels.iter().all(|c| flags.enabled("implicit", c)));
+        assert!(!channels.iter().any(|c| flags.enabled("none", c)));
+        assert!(!channels.iter().any(|c| flags.enabled("bogus", c)));
+        assert!(!channels.iter().any(|c| flags.enabled("unknown", c)));
+        let half = channels.iter().filter(|c| flags.enabled("half", c)).count();
+        assert!(half > 50 && half < 150);
+        // deterministic
+        assert_eq!(
+            flags.for_channel(&channels[0]),
+            FeatureFlags::parse("all:100,none:0,half:50,implicit").for_channel(&channels[0])
+        );
+    }
+}
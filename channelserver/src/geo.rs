@@ -0,0 +1,46 @@
+//! Where clients connect from, for embedders that can look it up.
+//!
+//! The server has no geolocation database of its own. An embedder that
+//! has one passes a `GeoResolver` to `PairsonaServer::builder()`, and
+//! connections are then counted by region in the
+//! `channel.connect.region.<region>` metric. Addresses are not resolved in
+//! relay only mode.
+
+use std::net::IpAddr;
+
+/// Longest region name used in a metric.
+const MAX_REGION: usize = 16;
+
+pub trait GeoResolver: Send + Sync {
+    /// A short name for the region `ip` is in (e.g. a country code), if
+    /// known.
+    fn region(&self, ip: &IpAddr) -> Option<String>;
+}
+
+/// `region` as it may appear in a metric name: lower case letters, digits,
+/// `-` and `_` only, and at most `MAX_REGION` of them.
+pub fn metric_region(region: &str) -> Option<String> {
+    let region: String = region
+        .chars()
+        .filter(|c| c.is_ascii_alphanumeric() || *c == '-' || *c == '_')
+        .take(MAX_REGION)
+        .collect();
+    if region.is_empty() {
+        None
+    } else {
+        Some(region.to_ascii_lowercase())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_metric_region() {
+        assert_eq!(metric_region("US"), Some("us".to_owned()));
+        assert_eq!(metric_region("eu-west.1"), Some("eu-west1".to_owned()));
+        assert_eq!(metric_region("a very long region name"), Some("averylongregionn".to_owned()));
+        assert_eq!(metric_region("..."), None);
+    }
+}
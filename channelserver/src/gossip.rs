@@ -0,0 +1,763 @@
+//! Cluster membership and health by gossip, after SWIM.
+//!
+//! Every `gossip_interval` each node pings one other node, in a shuffled
+//! round robin, over UDP. If no ack comes back within a third of the
+//! interval, it asks a few other nodes to ping it on its behalf. If none of
+//! them get an ack either, the node is suspected, and unless it refutes the
+//! suspicion within `gossip_suspect_timeout` it is declared dead.
+//!
+//! Every message carries the sender's view of every member, so changes
+//! spread through the cluster in a few intervals without any further
+//! messages. A member's view is ordered by its incarnation, then by how
+//! bad its health is, so a node can only refute a suspicion by raising its
+//! own incarnation. Incarnations start at the time a node starts, so a node
+//! that restarts is believed over news of its death.
+//!
+//! Alive and suspected nodes own channels; the cluster's node list (and so
+//! the reconnect hints) is replaced as that set changes.
+//!
+//! Every datagram starts with an HMAC-SHA256 of the rest, keyed with the
+//! shared `gossip_key`; anything else is dropped. Only members listed in
+//! `gossip_allow`, or if that is empty, gossiping from a seed's address,
+//! are taken in.
+
+use std::collections::{HashMap, HashSet};
+use std::net::{SocketAddr, ToSocketAddrs, UdpSocket};
+use std::sync::{Arc, RwLock};
+use std::thread;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use cadence::{Counted, Gauged, StatsdClient};
+use rand::{self, Rng};
+use serde_json;
+use sha2::{Digest, Sha256};
+use slog::Logger;
+
+use cluster::Cluster;
+use settings::Settings;
+
+/// Nodes asked to ping a node that didn't answer.
+const INDIRECT_PROBES: usize = 3;
+
+/// Dead members are remembered, so that news of their death keeps
+/// spreading, for this many suspicion timeouts.
+const FORGET_AFTER: u32 = 10;
+
+/// Largest message accepted.
+const MAX_MESSAGE: usize = 65_507;
+
+/// Length of the MAC heading every datagram.
+const MAC_LEN: usize = 32;
+
+/// Rounds between looking up the seeds' addresses again.
+const SEED_REFRESH: u32 = 30;
+
+/// HMAC-SHA256 of `body`, keyed with `key`.
+fn mac(key: &[u8], body: &[u8]) -> Vec<u8> {
+    const BLOCK: usize = 64;
+    let mut padded = if key.len() > BLOCK {
+        Sha256::digest(key).to_vec()
+    } else {
+        key.to_vec()
+    };
+    padded.resize(BLOCK, 0);
+    let ipad: Vec<u8> = padded.iter().map(|b| b ^ 0x36).collect();
+    let opad: Vec<u8> = padded.iter().map(|b| b ^ 0x5c).collect();
+    let mut inner = Sha256::new();
+    inner.input(&ipad);
+    inner.input(body);
+    let mut outer = Sha256::new();
+    outer.input(&opad);
+    outer.input(&inner.result());
+    outer.result().to_vec()
+}
+
+/// The body of a datagram, if its MAC checks out.
+fn verify<'a>(key: &[u8], datagram: &'a [u8]) -> Option<&'a [u8]> {
+    if datagram.len() < MAC_LEN {
+        return None;
+    }
+    let (tag, body) = datagram.split_at(MAC_LEN);
+    let expected = mac(key, body);
+    // in constant time, so as not to reveal how much of a guess was right
+    let diff = tag
+        .iter()
+        .zip(expected.iter())
+        .fold(0u8, |diff, (a, b)| diff | (a ^ b));
+    if diff == 0 {
+        Some(body)
+    } else {
+        None
+    }
+}
+
+/// May `member` join? If `allow` lists any public URLs, only those may;
+/// otherwise only members gossiping from a seed's address.
+fn admitted(
+    member: &Member,
+    allow: &[String],
+    seeds: &[String],
+    seed_addrs: &HashSet<SocketAddr>,
+) -> bool {
+    if !allow.is_empty() {
+        let url = member.url.trim_right_matches('/');
+        return allow.iter().any(|allowed| allowed == url);
+    }
+    match member.addr.parse::<SocketAddr>() {
+        Ok(addr) => seed_addrs.contains(&addr),
+        Err(_) => seeds.iter().any(|seed| *seed == member.addr),
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Health {
+    Alive,
+    Suspect,
+    Dead,
+}
+
+impl Health {
+    /// Worse health wins between views of the same incarnation.
+    fn rank(self) -> u8 {
+        match self {
+            Health::Alive => 0,
+            Health::Suspect => 1,
+            Health::Dead => 2,
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct Member {
+    /// Public base URL, as used in the cluster's node list.
+    pub url: String,
+    /// Where the member's gossip is sent.
+    pub addr: String,
+    pub incarnation: u64,
+    pub health: Health,
+}
+
+impl Member {
+    fn supersedes(&self, other: &Member) -> bool {
+        (self.incarnation, self.health.rank()) > (other.incarnation, other.health.rank())
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum Message {
+    Ping { seq: u64, members: Vec<Member> },
+    /// Ping `target` and pass its ack back.
+    PingReq {
+        seq: u64,
+        target: String,
+        members: Vec<Member>,
+    },
+    Ack { seq: u64, members: Vec<Member> },
+}
+
+impl Message {
+    fn members(&self) -> &[Member] {
+        match self {
+            Message::Ping { members, .. }
+            | Message::PingReq { members, .. }
+            | Message::Ack { members, .. } => members,
+        }
+    }
+}
+
+/// This node's view of the cluster.
+pub struct Membership {
+    me: Member,
+    /// every other member, by URL
+    members: HashMap<String, Member>,
+    /// when each member's health last changed
+    since: HashMap<String, Instant>,
+    /// members left to probe this round
+    round: Vec<String>,
+}
+
+impl Membership {
+    pub fn new(url: &str, addr: &str) -> Self {
+        let started = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.as_secs())
+            .unwrap_or(0);
+        Self {
+            me: Member {
+                url: url.trim_right_matches('/').to_owned(),
+                addr: addr.to_owned(),
+                incarnation: started,
+                health: Health::Alive,
+            },
+            members: HashMap::new(),
+            since: HashMap::new(),
+            round: Vec::new(),
+        }
+    }
+
+    /// Every member, this one included, as sent with each message.
+    pub fn members(&self) -> Vec<Member> {
+        let mut members: Vec<Member> = self.members.values().cloned().collect();
+        members.push(self.me.clone());
+        members
+    }
+
+    /// Is `url` this node?
+    pub fn is_me(&self, url: &str) -> bool {
+        url.trim_right_matches('/') == self.me.url
+    }
+
+    pub fn get(&self, url: &str) -> Option<&Member> {
+        self.members.get(url)
+    }
+
+    /// Take in another node's view of a member. Returns whether this view
+    /// changed.
+    pub fn merge(&mut self, update: &Member, now: Instant) -> bool {
+        if update.url == self.me.url {
+            // Refute any suspicion of this node.
+            if update.health != Health::Alive && update.incarnation >= self.me.incarnation {
+                // An incarnation that can't be outdone can't be refuted.
+                return match update.incarnation.checked_add(1) {
+                    Some(incarnation) => {
+                        self.me.incarnation = incarnation;
+                        true
+                    }
+                    None => false,
+                };
+            }
+            return false;
+        }
+        let newer = match self.members.get(&update.url) {
+            Some(current) => update.supersedes(current),
+            // Don't learn of members only to hear they're dead.
+            None => update.health != Health::Dead,
+        };
+        if !newer {
+            return false;
+        }
+        let changed = self
+            .members
+            .get(&update.url)
+            .map_or(true, |current| current.health != update.health);
+        if changed {
+            self.since.insert(update.url.clone(), now);
+        }
+        self.members.insert(update.url.clone(), update.clone());
+        true
+    }
+
+    /// Suspect a member that didn't answer.
+    pub fn suspect(&mut self, url: &str, now: Instant) -> bool {
+        let update = match self.members.get(url) {
+            Some(member) if member.health == Health::Alive => Member {
+                health: Health::Suspect,
+                ..member.clone()
+            },
+            _ => return false,
+        };
+        self.merge(&update, now)
+    }
+
+    /// Declare dead the members suspected for longer than `timeout`, and
+    /// forget long dead ones. Returns whether any were declared dead.
+    pub fn expire(&mut self, now: Instant, timeout: Duration) -> bool {
+        let (dead, forgotten) = {
+            let aged = |url: &str, after: Duration| {
+                self.since
+                    .get(url)
+                    .map_or(true, |at| now.duration_since(*at) >= after)
+            };
+            let dead: Vec<Member> = self
+                .members
+                .values()
+                .filter(|m| m.health == Health::Suspect && aged(&m.url, timeout))
+                .map(|m| Member {
+                    health: Health::Dead,
+                    ..m.clone()
+                })
+                .collect();
+            let forgotten: Vec<String> = self
+                .members
+                .values()
+                .filter(|m| m.health == Health::Dead && aged(&m.url, timeout * FORGET_AFTER))
+                .map(|m| m.url.clone())
+                .collect();
+            (dead, forgotten)
+        };
+        for url in forgotten {
+            self.members.remove(&url);
+            self.since.remove(&url);
+        }
+        for member in &dead {
+            self.merge(member, now);
+        }
+        !dead.is_empty()
+    }
+
+    /// The nodes that own channels: this one and every member not dead.
+    pub fn nodes(&self) -> Vec<String> {
+        let mut nodes: Vec<String> = self
+            .members
+            .values()
+            .filter(|m| m.health != Health::Dead)
+            .map(|m| m.url.clone())
+            .collect();
+        nodes.push(self.me.url.clone());
+        nodes.sort();
+        nodes
+    }
+
+    /// The members suspected to be down.
+    pub fn suspects(&self) -> Vec<String> {
+        self.members
+            .values()
+            .filter(|m| m.health == Health::Suspect)
+            .map(|m| m.url.clone())
+            .collect()
+    }
+
+    pub fn count(&self, health: Health) -> usize {
+        self.members.values().filter(|m| m.health == health).count()
+    }
+
+    /// The next member to probe.
+    pub fn next_target(&mut self) -> Option<Member> {
+        loop {
+            if self.round.is_empty() {
+                self.round = self
+                    .members
+                    .values()
+                    .filter(|m| m.health != Health::Dead)
+                    .map(|m| m.url.clone())
+                    .collect();
+                if self.round.is_empty() {
+                    return None;
+                }
+                rand::thread_rng().shuffle(&mut self.round);
+            }
+            let url = self.round.pop()?;
+            match self.members.get(&url) {
+                Some(member) if member.health != Health::Dead => return Some(member.clone()),
+                _ => continue,
+            }
+        }
+    }
+
+    /// Up to `count` alive members, other than `except`, to probe through.
+    pub fn helpers(&self, except: &str, count: usize) -> Vec<Member> {
+        let mut helpers: Vec<Member> = self
+            .members
+            .values()
+            .filter(|m| m.health == Health::Alive && m.url != except)
+            .cloned()
+            .collect();
+        rand::thread_rng().shuffle(&mut helpers);
+        helpers.truncate(count);
+        helpers
+    }
+}
+
+/// A probe awaiting its ack.
+struct Probe {
+    seq: u64,
+    target: Member,
+    sent: Instant,
+    indirect: bool,
+}
+
+/// Runs the protocol over a socket.
+pub struct Gossip {
+    socket: UdpSocket,
+    membership: Membership,
+    /// addresses to join through
+    seeds: Vec<String>,
+    /// what the seeds resolved to, when last looked up
+    seed_addrs: HashSet<SocketAddr>,
+    /// public URLs of the nodes that may join; if empty, the seeds
+    allow: Vec<String>,
+    /// shared secret every datagram is authenticated with
+    key: Vec<u8>,
+    rounds: u32,
+    interval: Duration,
+    suspect_timeout: Duration,
+    cluster: Arc<RwLock<Cluster>>,
+    metrics: Arc<StatsdClient>,
+    log: Logger,
+    seq: u64,
+    probe: Option<Probe>,
+    /// pings sent for another node: our seq to theirs, and where to ack
+    relayed: HashMap<u64, (u64, SocketAddr, Instant)>,
+}
+
+impl Gossip {
+    pub fn bind(
+        settings: &Settings,
+        cluster: Arc<RwLock<Cluster>>,
+        metrics: Arc<StatsdClient>,
+        log: Logger,
+    ) -> Result<Self, String> {
+        if settings.gossip_key.is_empty() {
+            return Err("gossip_key is required to gossip".to_owned());
+        }
+        let bind = &settings.gossip_bind;
+        let socket =
+            UdpSocket::bind(bind).map_err(|e| format!("Could not bind {}: {}", bind, e))?;
+        let advertise = if settings.gossip_advertise.is_empty() {
+            bind
+        } else {
+            &settings.gossip_advertise
+        };
+        Ok(Self {
+            socket,
+            membership: Membership::new(&settings.public_url, advertise),
+            seeds: settings
+                .gossip_seeds
+                .split(',')
+                .map(|s| s.trim().to_owned())
+                .filter(|s| !s.is_empty())
+                .collect(),
+            seed_addrs: HashSet::new(),
+            allow: settings
+                .gossip_allow
+                .split(',')
+                .map(|s| s.trim().trim_right_matches('/').to_owned())
+                .filter(|s| !s.is_empty())
+                .collect(),
+            key: settings.gossip_key.as_bytes().to_vec(),
+            rounds: 0,
+            interval: Duration::from_millis(settings.gossip_interval),
+            suspect_timeout: Duration::from_secs(settings.gossip_suspect_timeout),
+            cluster,
+            metrics,
+            log,
+            seq: 0,
+            probe: None,
+            relayed: HashMap::new(),
+        })
+    }
+
+    /// Run in the background.
+    pub fn start(self) {
+        thread::Builder::new()
+            .name("gossip".to_owned())
+            .spawn(move || self.run())
+            .expect("Could not start gossip");
+    }
+
+    fn run(mut self) {
+        let mut buf = vec![0u8; MAX_MESSAGE];
+        let mut next_round = Instant::now();
+        loop {
+            let now = Instant::now();
+            if now >= next_round {
+                self.round(now);
+                next_round = now + self.interval;
+            }
+            self.check_probe(now);
+            let wait = if next_round > now {
+                next_round - now
+            } else {
+                Duration::from_millis(0)
+            };
+            let wait = wait.min(self.interval / 3).max(Duration::from_millis(1));
+            self.socket.set_read_timeout(Some(wait)).ok();
+            if let Ok((len, from)) = self.socket.recv_from(&mut buf) {
+                let message = match verify(&self.key, &buf[..len]) {
+                    Some(body) => serde_json::from_slice(body),
+                    None => {
+                        debug!(self.log, "Unauthenticated gossip from {}", from);
+                        self.metrics.incr("gossip.unauthenticated").ok();
+                        continue;
+                    }
+                };
+                match message {
+                    Ok(message) => self.handle(message, from),
+                    Err(err) => debug!(self.log, "Bad gossip from {}: {}", from, err),
+                }
+            }
+        }
+    }
+
+    /// Look up the seeds' addresses, which members not in `allow` must
+    /// gossip from.
+    fn resolve_seeds(&mut self) {
+        let mut addrs = HashSet::new();
+        for seed in &self.seeds {
+            match seed.to_socket_addrs() {
+                Ok(resolved) => addrs.extend(resolved),
+                Err(err) => debug!(self.log, "Could not resolve {}: {}", seed, err),
+            }
+        }
+        self.seed_addrs = addrs;
+    }
+
+    /// Start a protocol period: settle the last probe and send the next.
+    fn round(&mut self, now: Instant) {
+        if self.allow.is_empty() && self.rounds % SEED_REFRESH == 0 {
+            self.resolve_seeds();
+        }
+        self.rounds = self.rounds.wrapping_add(1);
+        if let Some(probe) = self.probe.take() {
+            if self.membership.suspect(&probe.target.url, now) {
+                warn!(self.log, "Suspect cluster node {}", probe.target.url);
+                self.metrics.incr("gossip.suspect").ok();
+            }
+        }
+        if self.membership.expire(now, self.suspect_timeout) {
+            warn!(self.log, "Cluster nodes declared dead");
+            self.metrics.incr("gossip.dead").ok();
+        }
+        let interval = self.interval;
+        self.relayed
+            .retain(|_, &mut (_, _, sent)| now.duration_since(sent) < interval);
+        self.update_cluster();
+        match self.membership.next_target() {
+            Some(target) => {
+                let seq = self.next_seq();
+                let ping = Message::Ping {
+                    seq,
+                    members: self.membership.members(),
+                };
+                self.send(&ping, &target.addr);
+                self.probe = Some(Probe {
+                    seq,
+                    target,
+                    sent: now,
+                    indirect: false,
+                });
+            }
+            None => {
+                // Alone, so keep knocking until someone answers.
+                let ping = Message::Ping {
+                    seq: self.next_seq(),
+                    members: self.membership.members(),
+                };
+                for seed in &self.seeds {
+                    self.send(&ping, seed);
+                }
+            }
+        }
+    }
+
+    /// Ask for help with a probe that's gone unanswered too long.
+    fn check_probe(&mut self, now: Instant) {
+        let (seq, target) = match self.probe {
+            Some(ref mut probe) => {
+                if probe.indirect || now < probe.sent + self.interval / 3 {
+                    return;
+                }
+                probe.indirect = true;
+                (probe.seq, probe.target.clone())
+            }
+            None => return,
+        };
+        let req = Message::PingReq {
+            seq,
+            target: target.addr.clone(),
+            members: self.membership.members(),
+        };
+        for helper in self.membership.helpers(&target.url, INDIRECT_PROBES) {
+            self.send(&req, &helper.addr);
+        }
+    }
+
+    fn handle(&mut self, message: Message, from: SocketAddr) {
+        let now = Instant::now();
+        let mut changed = false;
+        for member in message.members() {
+            if !self.membership.is_me(&member.url)
+                && !admitted(member, &self.allow, &self.seeds, &self.seed_addrs)
+            {
+                debug!(self.log, "Refused gossip member {} from {}", member.url, from);
+                self.metrics.incr("gossip.refused").ok();
+                continue;
+            }
+            changed |= self.membership.merge(member, now);
+        }
+        match message {
+            Message::Ping { seq, .. } => {
+                let ack = Message::Ack {
+                    seq,
+                    members: self.membership.members(),
+                };
+                self.send(&ack, &from.to_string());
+            }
+            Message::PingReq { seq, target, .. } => {
+                let ours = self.next_seq();
+                self.relayed.insert(ours, (seq, from, now));
+                let ping = Message::Ping {
+                    seq: ours,
+                    members: self.membership.members(),
+                };
+                self.send(&ping, &target);
+            }
+            Message::Ack { seq, .. } => {
+                if self.probe.as_ref().map_or(false, |p| p.seq == seq) {
+                    self.probe = None;
+                } else if let Some((theirs, requester, _)) = self.relayed.remove(&seq) {
+                    let ack = Message::Ack {
+                        seq: theirs,
+                        members: self.membership.members(),
+                    };
+                    self.send(&ack, &requester.to_string());
+                }
+            }
+        }
+        if changed {
+            self.update_cluster();
+        }
+    }
+
+    fn next_seq(&mut self) -> u64 {
+        self.seq = self.seq.wrapping_add(1);
+        self.seq
+    }
+
+    fn send(&self, message: &Message, addr: &str) {
+        let addrs = match addr.to_socket_addrs() {
+            Ok(addrs) => addrs,
+            Err(err) => {
+                debug!(self.log, "Could not resolve {}: {}", addr, err);
+                return;
+            }
+        };
+        let body = match serde_json::to_vec(message) {
+            Ok(body) => body,
+            Err(_) => return,
+        };
+        let mut datagram = mac(&self.key, &body);
+        datagram.extend_from_slice(&body);
+        // A seed may name several nodes.
+        for addr in addrs {
+            if let Err(err) = self.socket.send_to(&datagram, addr) {
+                debug!(self.log, "Could not gossip to {}: {}", addr, err);
+            }
+        }
+    }
+
+    /// Route channels to, and hint at, the nodes believed up.
+    fn update_cluster(&self) {
+        let nodes = self.membership.nodes();
+        let mut cluster = self.cluster.write().unwrap();
+        if cluster.set_nodes(nodes) {
+            info!(self.log, "Cluster nodes are now {}", cluster.nodes.join(", "));
+        }
+        cluster.set_suspects(self.membership.suspects());
+        for &(name, health) in &[
+            ("gossip.alive", Health::Alive),
+            ("gossip.suspected", Health::Suspect),
+            ("gossip.departed", Health::Dead),
+        ] {
+            self.metrics
+                .gauge(name, self.membership.count(health) as u64)
+                .ok();
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn member(url: &str, incarnation: u64, health: Health) -> Member {
+        Member {
+            url: url.to_owned(),
+            addr: format!("{}:7946", url),
+            incarnation,
+            health,
+        }
+    }
+
+    #[test]
+    fn test_merge() {
+        let now = Instant::now();
+        let mut view = Membership::new("http://a:8000/", "a:7946");
+        // news of a stranger's death isn't news
+        assert!(!view.merge(&member("http://c:8000", 1, Health::Dead), now));
+        assert!(view.merge(&member("http://b:8000", 1, Health::Alive), now));
+        assert!(view.merge(&member("http://b:8000", 1, Health::Suspect), now));
+        // stale
+        assert!(!view.merge(&member("http://b:8000", 1, Health::Alive), now));
+        // refuted
+        assert!(view.merge(&member("http://b:8000", 2, Health::Alive), now));
+        assert_eq!(view.nodes(), vec!["http://a:8000", "http://b:8000"]);
+
+        // suspicion of this node raises its incarnation
+        let mine = view.me.incarnation;
+        assert!(view.merge(&member("http://a:8000", mine, Health::Suspect), now));
+        assert_eq!(view.me.incarnation, mine + 1);
+        assert_eq!(view.me.health, Health::Alive);
+    }
+
+    #[test]
+    fn test_incarnation_overflow() {
+        let now = Instant::now();
+        let mut view = Membership::new("http://a:8000", "a:7946");
+        assert!(!view.merge(&member("http://a:8000", u64::max_value(), Health::Suspect), now));
+    }
+
+    #[test]
+    fn test_mac() {
+        // RFC 4231, test case 2
+        let tag: String = mac(b"Jefe", b"what do ya want for nothing?")
+            .iter()
+            .map(|b| format!("{:02x}", b))
+            .collect();
+        assert_eq!(
+            tag,
+            "5bdcc146bf60754e6a042426089575c75a003f089d2739839dec58b964ec3843"
+        );
+
+        let mut datagram = mac(b"key", b"{}");
+        datagram.extend_from_slice(b"{}");
+        assert_eq!(verify(b"key", &datagram), Some(&b"{}"[..]));
+        assert_eq!(verify(b"other", &datagram), None);
+        assert_eq!(verify(b"key", b"{}"), None);
+        datagram[0] ^= 1;
+        assert_eq!(verify(b"key", &datagram), None);
+    }
+
+    #[test]
+    fn test_admitted() {
+        let b = member("http://b:8000", 1, Health::Alive);
+        let seeds = vec!["b:7946".to_owned()];
+        let seed_addrs: HashSet<SocketAddr> = vec!["10.0.0.2:7946".parse().unwrap()]
+            .into_iter()
+            .collect();
+        assert!(admitted(&b, &[], &seeds, &seed_addrs));
+        assert!(!admitted(&member("http://c:8000", 1, Health::Alive), &[], &seeds, &seed_addrs));
+        let mut addressed = member("http://c:8000", 1, Health::Alive);
+        addressed.addr = "10.0.0.2:7946".to_owned();
+        assert!(admitted(&addressed, &[], &seeds, &seed_addrs));
+
+        let allow = vec!["http://c:8000".to_owned()];
+        assert!(!admitted(&b, &allow, &seeds, &seed_addrs));
+        assert!(admitted(&member("http://c:8000/", 1, Health::Alive), &allow, &seeds, &seed_addrs));
+    }
+
+    #[test]
+    fn test_expire() {
+        let start = Instant::now();
+        let timeout = Duration::from_secs(5);
+        let mut view = Membership::new("http://a:8000", "a:7946");
+        view.merge(&member("http://b:8000", 1, Health::Alive), start);
+        view.merge(&member("http://c:8000", 1, Health::Alive), start);
+        assert!(view.suspect("http://b:8000", start));
+        assert!(!view.expire(start + Duration::from_secs(1), timeout));
+        assert_eq!(view.nodes().len(), 3);
+        assert!(view.expire(start + timeout, timeout));
+        assert_eq!(view.get("http://b:8000").unwrap().health, Health::Dead);
+        assert_eq!(view.nodes(), vec!["http://a:8000", "http://c:8000"]);
+        // not probed once dead
+        for _ in 0..4 {
+            assert_eq!(view.next_target().unwrap().url, "http://c:8000");
+        }
+        view.expire(start + timeout * (FORGET_AFTER + 1), timeout);
+        assert!(view.get("http://b:8000").is_none());
+        // a restarted node is believed
+        assert!(view.merge(&member("http://b:8000", 2, Health::Alive), start));
+    }
+}
@@ -0,0 +1,91 @@
+//! Security headers for plain HTTP responses.
+//!
+//! Responses from the route groups named in `security_headers` (`health`
+//! for the `/__*__` checks, `admin` for `/admin/`, `public` for `/v1/` and
+//! static files) get `X-Content-Type-Options`, `Referrer-Policy`, a
+//! `Content-Security-Policy` and, with `hsts_max_age` set,
+//! `Strict-Transport-Security`. Headers a handler set itself are kept, and
+//! websocket upgrades are left alone.
+
+use actix_web::http::header::{HeaderName, HeaderValue};
+use actix_web::http::StatusCode;
+use actix_web::middleware::{Middleware, Response};
+use actix_web::{HttpRequest, HttpResponse, Result};
+
+use session::WsChannelSessionState;
+
+/// The route group a path belongs to.
+pub fn group(path: &str) -> &'static str {
+    if path.starts_with("/admin/") {
+        "admin"
+    } else if path.starts_with("/__") {
+        "health"
+    } else {
+        "public"
+    }
+}
+
+/// Is `group` listed in a `security_headers` value?
+pub fn enabled(spec: &str, group: &str) -> bool {
+    spec.split(',').any(|item| item.trim() == group)
+}
+
+pub struct SecurityHeaders;
+
+impl Middleware<WsChannelSessionState> for SecurityHeaders {
+    fn response(
+        &self,
+        req: &HttpRequest<WsChannelSessionState>,
+        mut resp: HttpResponse,
+    ) -> Result<Response> {
+        let settings = &req.state().settings;
+        if resp.status() == StatusCode::SWITCHING_PROTOCOLS
+            || !enabled(&settings.security_headers, group(req.path()))
+        {
+            return Ok(Response::Done(resp));
+        }
+        let mut add = vec![
+            ("x-content-type-options", "nosniff".to_owned()),
+            ("referrer-policy", "no-referrer".to_owned()),
+        ];
+        if !settings.content_security_policy.is_empty() {
+            add.push((
+                "content-security-policy",
+                settings.content_security_policy.clone(),
+            ));
+        }
+        if settings.hsts_max_age > 0 {
+            add.push((
+                "strict-transport-security",
+                format!("max-age={}", settings.hsts_max_age),
+            ));
+        }
+        let headers = resp.headers_mut();
+        for (name, value) in add {
+            let name = HeaderName::from_static(name);
+            if headers.contains_key(&name) {
+                continue;
+            }
+            if let Ok(value) = HeaderValue::from_str(&value) {
+                headers.insert(name, value);
+            }
+        }
+        Ok(Response::Done(resp))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_group() {
+        assert_eq!(group("/__heartbeat__"), "health");
+        assert_eq!(group("/admin/keys"), "admin");
+        assert_eq!(group("/v1/capabilities"), "public");
+        assert_eq!(group("/static/index.html"), "public");
+        assert!(enabled("health, admin", "admin"));
+        assert!(!enabled("health,admin", "public"));
+        assert!(!enabled("", "health"));
+    }
+}
@@ -0,0 +1,242 @@
+//! Translations of the few human readable strings the server sends.
+//!
+//! Clients are expected to key off error codes, but the `reason` text is
+//! often shown to users as is, so it is sent in the language the client
+//! asked for in its `Accept-Language` header, where we have one.
+//!
+//! Languages we have no translations for can fall back to ones we do, per
+//! the `language_fallback` setting, before the `default_language`.
+
+use std::collections::HashMap;
+
+use perror::HandlerErrorKind;
+
+/// Languages we have translations for. The first is the default.
+pub const LANGUAGES: &[&str] = &["en", "de", "es", "fr"];
+
+/// The supported language for a (lower case) tag. Only the primary
+/// subtag matters; "fr-ca" is close enough to "fr".
+fn supported(tag: &str) -> Option<&'static str> {
+    let primary = tag.split('-').next().unwrap_or("");
+    LANGUAGES.iter().find(|lang| **lang == primary).cloned()
+}
+
+/// Picks a language for a client from its `Accept-Language`.
+#[derive(Clone, Debug)]
+pub struct Negotiator {
+    default: &'static str,
+    /// language tag -> languages to try for it, in order
+    fallbacks: HashMap<String, Vec<String>>,
+}
+
+impl Default for Negotiator {
+    fn default() -> Self {
+        Self {
+            default: LANGUAGES[0],
+            fallbacks: HashMap::new(),
+        }
+    }
+}
+
+impl Negotiator {
+    /// `fallback` is a comma separated list of chains, each a language tag
+    /// followed by the languages to try in its place, e.g.
+    /// `pt-BR>pt>es>en,ca>es`.
+    pub fn new(default: &str, fallback: &str) -> Result<Self, String> {
+        let default = match LANGUAGES.iter().find(|lang| **lang == default.trim()) {
+            Some(lang) => *lang,
+            None => {
+                return Err(format!(
+                    "default_language: {:?} is not one of {}",
+                    default,
+                    LANGUAGES.join(", ")
+                ))
+            }
+        };
+        let mut fallbacks = HashMap::new();
+        for chain in fallback.split(',').filter(|chain| !chain.trim().is_empty()) {
+            let mut tags = chain.split('>').map(|tag| tag.trim().to_lowercase());
+            let tag = tags.next().unwrap_or_default();
+            let then: Vec<String> = tags.collect();
+            if tag.is_empty() || then.is_empty() || then.iter().any(|tag| tag.is_empty()) {
+                return Err(format!("language_fallback: invalid chain {:?}", chain.trim()));
+            }
+            if !then.iter().any(|tag| supported(tag).is_some()) {
+                return Err(format!(
+                    "language_fallback: no supported language in {:?}",
+                    chain.trim()
+                ));
+            }
+            fallbacks.insert(tag, then);
+        }
+        Ok(Self { default, fallbacks })
+    }
+
+    /// The supported language for a tag: the tag itself, its fallbacks,
+    /// then the same again for its primary subtag.
+    fn resolve(&self, tag: &str) -> Option<&'static str> {
+        let primary = tag.split('-').next().unwrap_or("");
+        for key in &[tag, primary] {
+            if let Some(lang) = supported(key) {
+                return Some(lang);
+            }
+            let found = self
+                .fallbacks
+                .get(*key)
+                .and_then(|then| then.iter().filter_map(|tag| supported(tag)).next());
+            if found.is_some() {
+                return found;
+            }
+        }
+        None
+    }
+
+    /// Pick the best supported language from an `Accept-Language` header
+    /// value.
+    pub fn negotiate(&self, accept_language: &str) -> &'static str {
+        let mut best = (self.default, 0.0);
+        for item in accept_language.split(',') {
+            let mut parts = item.split(';');
+            let tag = parts.next().unwrap_or("").trim().to_lowercase();
+            let q = parts
+                .filter_map(|p| {
+                    let p = p.trim();
+                    if p.starts_with("q=") {
+                        p[2..].parse::<f32>().ok()
+                    } else {
+                        None
+                    }
+                })
+                .next()
+                .unwrap_or(1.0);
+            if q <= best.1 {
+                continue;
+            }
+            if let Some(lang) = self.resolve(&tag) {
+                best = (lang, q);
+            }
+        }
+        best.0
+    }
+}
+
+/// Pick the best supported language from an `Accept-Language` header value,
+/// with no fallbacks and English as the default.
+pub fn negotiate(accept_language: &str) -> &'static str {
+    Negotiator::default().negotiate(accept_language)
+}
+
+/// The user facing description of an error, in `lang`.
+pub fn reason(kind: &HandlerErrorKind, lang: &str) -> String {
+    let translated = match lang {
+        "de" => match kind {
+            HandlerErrorKind::XSDataErr => "Zu viele Daten übertragen",
+            HandlerErrorKind::XSMessageErr => "Zu viele Nachrichten",
+            HandlerErrorKind::XSConnectionErr => "Zu viele Verbindungen angefordert",
+            HandlerErrorKind::ExpiredErr => "Verbindung abgelaufen",
+            HandlerErrorKind::ShutdownErr => "Schließen des Kanals angefordert",
+            HandlerErrorKind::RoleErr => "Nur der Initiator darf die erste Nachricht senden",
+            HandlerErrorKind::InvalidKeyErr => "Ungültiger Anwendungsschlüssel",
+            HandlerErrorKind::UnauthorizedErr => "Nicht autorisiert",
+            HandlerErrorKind::RateLimitErr => "Zu viele Anfragen",
+            HandlerErrorKind::NotFoundErr => "Nicht gefunden",
+            HandlerErrorKind::WrongNodeErr => "Der Kanal gehört zu einem anderen Knoten",
+            HandlerErrorKind::UnavailableErr => "Dienst nicht verfügbar",
+            HandlerErrorKind::QuotaErr => "Kontingent des Kanals überschritten",
+            HandlerErrorKind::HandedOffErr => "Verbindung an ein anderes Gerät übergeben",
+            HandlerErrorKind::PatternErr => "Nachrichtenmuster verletzt",
+            HandlerErrorKind::NotJsonErr => "Nachricht ist kein gültiges JSON",
+            HandlerErrorKind::ChunkErr => "Ungültige gestückelte Übertragung",
+            HandlerErrorKind::UpgradeErr => "Ungültige Websocket-Upgrade-Anfrage",
+            HandlerErrorKind::MigratedErr => "Der Kanal wurde auf einen anderen Knoten verschoben",
+            HandlerErrorKind::InvalidRequestErr => "Ungültige Anfrage",
+            HandlerErrorKind::SlowReaderErr => "Die Verbindung ist zu langsam",
+        },
+        "es" => match kind {
+            HandlerErrorKind::XSDataErr => "Se intercambiaron demasiados datos",
+            HandlerErrorKind::XSMessageErr => "Demasiados mensajes",
+            HandlerErrorKind::XSConnectionErr => "Se solicitaron demasiadas conexiones",
+            HandlerErrorKind::ExpiredErr => "La conexión ha caducado",
+            HandlerErrorKind::ShutdownErr => "Se solicitó el cierre del canal",
+            HandlerErrorKind::RoleErr => "Solo el iniciador puede enviar el primer mensaje",
+            HandlerErrorKind::InvalidKeyErr => "Clave de aplicación no válida",
+            HandlerErrorKind::UnauthorizedErr => "No autorizado",
+            HandlerErrorKind::RateLimitErr => "Demasiadas solicitudes",
+            HandlerErrorKind::NotFoundErr => "No encontrado",
+            HandlerErrorKind::WrongNodeErr => "El canal pertenece a otro nodo",
+            HandlerErrorKind::UnavailableErr => "Servicio no disponible",
+            HandlerErrorKind::QuotaErr => "Se superó la cuota del canal",
+            HandlerErrorKind::HandedOffErr => "Conexión transferida a otro dispositivo",
+            HandlerErrorKind::PatternErr => "Se infringió el patrón de intercambio de mensajes",
+            HandlerErrorKind::NotJsonErr => "El mensaje no es JSON válido",
+            HandlerErrorKind::ChunkErr => "Transferencia fragmentada no válida",
+            HandlerErrorKind::UpgradeErr => "Solicitud de actualización a websocket no válida",
+            HandlerErrorKind::MigratedErr => "El canal se trasladó a otro nodo",
+            HandlerErrorKind::InvalidRequestErr => "Solicitud no válida",
+            HandlerErrorKind::SlowReaderErr => "La conexión es demasiado lenta",
+        },
+        "fr" => match kind {
+            HandlerErrorKind::XSDataErr => "Trop de données échangées",
+            HandlerErrorKind::XSMessageErr => "Trop de messages",
+            HandlerErrorKind::XSConnectionErr => "Trop de connexions demandées",
+            HandlerErrorKind::ExpiredErr => "Connexion expirée",
+            HandlerErrorKind::ShutdownErr => "Fermeture du canal demandée",
+            HandlerErrorKind::RoleErr => "Seul l'initiateur peut envoyer le premier message",
+            HandlerErrorKind::InvalidKeyErr => "Clé d'application invalide",
+            HandlerErrorKind::UnauthorizedErr => "Non autorisé",
+            HandlerErrorKind::RateLimitErr => "Trop de requêtes",
+            HandlerErrorKind::NotFoundErr => "Introuvable",
+            HandlerErrorKind::WrongNodeErr => "Le canal appartient à un autre nœud",
+            HandlerErrorKind::UnavailableErr => "Service indisponible",
+            HandlerErrorKind::QuotaErr => "Quota du canal dépassé",
+            HandlerErrorKind::HandedOffErr => "Connexion transférée à un autre appareil",
+            HandlerErrorKind::PatternErr => "Schéma d'échange de messages non respecté",
+            HandlerErrorKind::NotJsonErr => "Le message n'est pas du JSON valide",
+            HandlerErrorKind::ChunkErr => "Transfert fragmenté invalide",
+            HandlerErrorKind::UpgradeErr => "Requête de passage en websocket invalide",
+            HandlerErrorKind::MigratedErr => "Le canal a été déplacé vers un autre nœud",
+            HandlerErrorKind::InvalidRequestErr => "Requête invalide",
+            HandlerErrorKind::SlowReaderErr => "La connexion est trop lente",
+        },
+        _ => return kind.to_string(),
+    };
+    translated.to_owned()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_negotiate() {
+        assert_eq!(negotiate(""), "en");
+        assert_eq!(negotiate("fr-CA"), "fr");
+        assert_eq!(negotiate("ja, de;q=0.5, en;q=0.4"), "de");
+        assert_eq!(negotiate("es;q=0.2, FR;q=0.9"), "fr");
+        assert_eq!(negotiate("de;q=0"), "en");
+        assert_eq!(negotiate("*"), "en");
+    }
+
+    #[test]
+    fn test_fallback() {
+        let languages = Negotiator::new("de", "pt-BR>pt>es>en, ca>fr").unwrap();
+        assert_eq!(languages.negotiate(""), "de");
+        assert_eq!(languages.negotiate("pt-BR"), "es");
+        assert_eq!(languages.negotiate("ca-ES, fr;q=0.5"), "fr");
+        assert_eq!(languages.negotiate("ja"), "de");
+        assert_eq!(languages.negotiate("en-GB"), "en");
+        assert!(Negotiator::new("pt", "").is_err());
+        assert!(Negotiator::new("en", "pt").is_err());
+        assert!(Negotiator::new("en", "pt>ja").is_err());
+    }
+
+    #[test]
+    fn test_reason() {
+        for kind in HandlerErrorKind::all() {
+            assert_eq!(reason(&kind, "en"), kind.to_string());
+            for lang in &LANGUAGES[1..] {
+                assert!(reason(&kind, lang) != kind.to_string());
+            }
+        }
+    }
+}
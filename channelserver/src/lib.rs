@@ -0,0 +1,93 @@
+//! The channel server, as a library, so that benchmarks (and anything else
+//! outside the server binary) can reach its internals.
+
+#![allow(unused_variables)]
+extern crate byteorder;
+extern crate bytes;
+extern crate cadence;
+extern crate config;
+#[cfg(feature = "profiling")]
+extern crate cpuprofiler;
+extern crate env_logger;
+#[macro_use]
+extern crate failure;
+extern crate futures;
+#[cfg(feature = "jemalloc")]
+extern crate jemalloc_ctl;
+#[cfg(feature = "jemalloc")]
+extern crate jemallocator;
+#[cfg(feature = "mimalloc")]
+extern crate mimalloc;
+extern crate pairsona_protocol;
+#[cfg(feature = "postgres")]
+extern crate postgres;
+extern crate rand;
+extern crate ratelimiter;
+#[cfg(feature = "sqlite")]
+extern crate rusqlite;
+extern crate serde;
+#[macro_use]
+extern crate serde_derive;
+#[macro_use]
+extern crate serde_json;
+extern crate sha2;
+extern crate socket2;
+extern crate tokio_core;
+extern crate tokio_io;
+#[cfg(feature = "srv")]
+extern crate trust_dns_resolver;
+
+#[macro_use]
+extern crate actix;
+extern crate actix_web;
+extern crate slog;
+extern crate slog_async;
+extern crate uuid;
+#[macro_use]
+extern crate slog_term;
+
+pub mod admin;
+pub mod alloc;
+pub mod apikey;
+pub mod app;
+pub mod audit;
+pub mod checkconfig;
+pub mod chunking;
+pub mod cluster;
+pub mod cors;
+pub mod discovery;
+pub mod embed;
+pub mod features;
+pub mod geo;
+pub mod gossip;
+pub mod headers;
+pub mod i18n;
+pub mod listener;
+pub mod logfile;
+pub mod logformat;
+pub mod logging;
+pub mod metrics;
+pub mod migrate;
+pub mod pattern;
+pub mod perror;
+pub mod pool;
+pub mod profile;
+pub mod protocol;
+pub mod ratelimit;
+pub mod replica;
+pub mod sealed;
+pub mod secrets;
+pub mod selftest;
+pub mod server;
+pub mod session;
+pub mod settings;
+pub mod shard;
+pub mod slo;
+pub mod snapshot;
+#[cfg(feature = "sqlite")]
+pub mod sqlitestore;
+pub mod store;
+pub mod syslog;
+pub mod throttle;
+pub mod trace;
+pub mod upgrade;
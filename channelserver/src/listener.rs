@@ -0,0 +1,239 @@
+//! Build the listening sockets, with the socket options from the settings.
+//!
+//! Options are set on the listening socket; Linux and the BSDs copy them
+//! to each accepted connection.
+//!
+//! With `reuse_port` set, the sockets are bound with `SO_REUSEPORT`. The
+//! kernel then spreads new connections over all sockets bound to the
+//! port, which also lets a new server binary start listening before the
+//! old one stops, for zero downtime upgrades.
+//!
+//! `listen` may list several endpoints, each serving its own set of
+//! routes, e.g. `0.0.0.0:8000=public,127.0.0.1:8081=admin` to keep the
+//! admin API off the public port. Route sets are `public` (channels,
+//! capabilities, static files), `admin` or `all`; health checks are
+//! served on every endpoint.
+//!
+//! `ip_stack` picks the address family: `v4`, `v6` (IPv6 only), or `dual`
+//! (IPv6 socket that also accepts IPv4 clients, as v4-mapped addresses).
+//! Left empty, the first address `hostname` resolves to is used, with the
+//! platform's default `IPV6_V6ONLY`.
+
+use std::io;
+use std::net::{IpAddr, Ipv6Addr, SocketAddr, TcpListener, ToSocketAddrs};
+use std::time::Duration;
+
+use socket2::{Domain, Protocol, Socket, Type};
+
+use settings::Settings;
+
+/// Pending connections the kernel will queue (the actix-web default).
+const BACKLOG: i32 = 2048;
+
+/// Which routes an endpoint serves.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Routes {
+    pub public: bool,
+    pub admin: bool,
+}
+
+impl Routes {
+    pub fn all() -> Self {
+        Routes {
+            public: true,
+            admin: true,
+        }
+    }
+}
+
+/// An address to listen on, and what to serve there.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Endpoint {
+    pub host: String,
+    pub port: u16,
+    pub routes: Routes,
+}
+
+/// The endpoints to listen on: those in `listen`, or `hostname:port`
+/// serving everything.
+pub fn endpoints(settings: &Settings) -> Result<Vec<Endpoint>, String> {
+    if settings.listen.trim().is_empty() {
+        return Ok(vec![Endpoint {
+            host: settings.hostname.clone(),
+            port: settings.port,
+            routes: Routes::all(),
+        }]);
+    }
+    settings
+        .listen
+        .split(',')
+        .map(|item| {
+            let item = item.trim();
+            let (addr, routes) = match item.find('=') {
+                Some(pos) => (&item[..pos], &item[pos + 1..]),
+                None => (item, "all"),
+            };
+            let routes = match routes {
+                "all" => Routes::all(),
+                "public" => Routes {
+                    public: true,
+                    admin: false,
+                },
+                "admin" => Routes {
+                    public: false,
+                    admin: true,
+                },
+                _ => return Err(format!("Unknown routes for {:?}", item)),
+            };
+            let pos = addr
+                .rfind(':')
+                .ok_or_else(|| format!("No port for {:?}", item))?;
+            let port = addr[pos + 1..]
+                .parse::<u16>()
+                .map_err(|_| format!("Invalid port for {:?}", item))?;
+            // IPv6 addresses are bracketed, as in URLs.
+            let host = addr[..pos].trim_left_matches('[').trim_right_matches(']');
+            Ok(Endpoint {
+                host: host.to_owned(),
+                port,
+                routes,
+            })
+        })
+        .collect()
+}
+
+/// Bind the listening sockets for an endpoint: `acceptors` of them with
+/// `reuse_port`, or just the one.
+pub fn bind(settings: &Settings, endpoint: &Endpoint) -> io::Result<Vec<TcpListener>> {
+    let count = if settings.reuse_port {
+        settings.acceptors.max(1)
+    } else {
+        1
+    };
+    (0..count).map(|_| bind_one(settings, endpoint)).collect()
+}
+
+#[cfg(unix)]
+fn set_reuse_port(socket: &Socket, reuse: bool) -> io::Result<()> {
+    socket.set_reuse_port(reuse)
+}
+
+#[cfg(not(unix))]
+fn set_reuse_port(_socket: &Socket, reuse: bool) -> io::Result<()> {
+    if reuse {
+        return Err(io::Error::new(
+            io::ErrorKind::Other,
+            "reuse_port is not supported on this platform",
+        ));
+    }
+    Ok(())
+}
+
+/// The real address of a client. IPv4 clients of a dual-stack socket
+/// appear as v4-mapped IPv6 addresses (`::ffff:a.b.c.d`).
+pub fn canonical_ip(addr: IpAddr) -> IpAddr {
+    if let IpAddr::V6(v6) = addr {
+        let seg = v6.segments();
+        if seg[..5].iter().all(|s| *s == 0) && seg[5] == 0xffff {
+            if let Some(v4) = v6.to_ipv4() {
+                return IpAddr::V4(v4);
+            }
+        }
+    }
+    addr
+}
+
+/// The address to bind to, and the `IPV6_V6ONLY` setting for it.
+fn address(settings: &Settings, endpoint: &Endpoint) -> io::Result<(SocketAddr, Option<bool>)> {
+    let invalid = |msg: &str| io::Error::new(io::ErrorKind::InvalidInput, msg.to_owned());
+    let addrs: Vec<SocketAddr> = (endpoint.host.as_str(), endpoint.port)
+        .to_socket_addrs()?
+        .collect();
+    let (want_v6, only_v6) = match settings.ip_stack.as_str() {
+        "" => {
+            let addr = addrs.first().ok_or_else(|| invalid("No address to bind to"))?;
+            return Ok((*addr, None));
+        }
+        "v4" => (false, None),
+        "v6" => (true, Some(true)),
+        "dual" => (true, Some(false)),
+        _ => return Err(invalid("ip_stack must be one of v4, v6 or dual")),
+    };
+    let addr = addrs
+        .iter()
+        .find(|addr| addr.is_ipv6() == want_v6)
+        .cloned()
+        .or_else(|| {
+            // The default hostname of 0.0.0.0 means "everywhere" in IPv6 too.
+            addrs
+                .iter()
+                .find(|addr| want_v6 && addr.ip().is_unspecified())
+                .map(|addr| SocketAddr::new(IpAddr::V6(Ipv6Addr::UNSPECIFIED), addr.port()))
+        })
+        .ok_or_else(|| invalid("hostname has no address for ip_stack"))?;
+    Ok((addr, only_v6))
+}
+
+fn bind_one(settings: &Settings, endpoint: &Endpoint) -> io::Result<TcpListener> {
+    let (addr, only_v6) = address(settings, endpoint)?;
+    let domain = if addr.is_ipv4() {
+        Domain::ipv4()
+    } else {
+        Domain::ipv6()
+    };
+    let socket = Socket::new(domain, Type::stream(), Some(Protocol::tcp()))?;
+    if let Some(only_v6) = only_v6 {
+        socket.set_only_v6(only_v6)?;
+    }
+    socket.set_reuse_address(true)?;
+    set_reuse_port(&socket, settings.reuse_port)?;
+    // Pairing messages are small and latency sensitive.
+    socket.set_nodelay(settings.tcp_nodelay)?;
+    if settings.tcp_keepalive > 0 {
+        socket.set_keepalive(Some(Duration::from_secs(settings.tcp_keepalive)))?;
+    }
+    if settings.recv_buffer > 0 {
+        socket.set_recv_buffer_size(settings.recv_buffer)?;
+    }
+    if settings.send_buffer > 0 {
+        socket.set_send_buffer_size(settings.send_buffer)?;
+    }
+    socket.bind(&addr.into())?;
+    socket.listen(BACKLOG)?;
+    Ok(socket.into_tcp_listener())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_canonical_ip() {
+        let v4: IpAddr = "192.0.2.1".parse().unwrap();
+        assert_eq!(canonical_ip("::ffff:192.0.2.1".parse().unwrap()), v4);
+        assert_eq!(canonical_ip(v4), v4);
+        let v6: IpAddr = "2001:db8::1".parse().unwrap();
+        assert_eq!(canonical_ip(v6), v6);
+    }
+
+    #[test]
+    fn test_endpoints() {
+        let mut settings = Settings::new().unwrap();
+        settings.listen = "".to_owned();
+        let default = endpoints(&settings).unwrap();
+        assert_eq!(default.len(), 1);
+        assert_eq!(default[0].routes, Routes::all());
+
+        settings.listen = "0.0.0.0:8000=public, [::1]:8081=admin".to_owned();
+        let listed = endpoints(&settings).unwrap();
+        assert_eq!(listed[0].port, 8000);
+        assert!(listed[0].routes.public && !listed[0].routes.admin);
+        assert_eq!(listed[1].host, "::1");
+        assert!(!listed[1].routes.public && listed[1].routes.admin);
+
+        settings.listen = "0.0.0.0:8000=secret".to_owned();
+        assert!(endpoints(&settings).is_err());
+        settings.listen = "0.0.0.0".to_owned();
+        assert!(endpoints(&settings).is_err());
+    }
+}
@@ -0,0 +1,142 @@
+//! A log file that rotates itself, for deployments without a log shipper.
+//!
+//! The file is rotated once it grows past `max_size` octets, or once it
+//! has been open for `max_age`. Rotated files are renamed `<path>.1`,
+//! `<path>.2`, ..., newest first, and only the newest `keep` are kept.
+//! Files are only rotated between lines, so a record written in several
+//! pieces is never split across two files.
+
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant};
+
+pub struct RotatingFile {
+    path: PathBuf,
+    file: File,
+    /// octets in the current file
+    size: u64,
+    opened: Instant,
+    /// 0 for no size limit
+    max_size: u64,
+    max_age: Option<Duration>,
+    /// rotated files to keep
+    keep: usize,
+    /// Did the last write end a line?
+    line_end: bool,
+}
+
+impl RotatingFile {
+    pub fn open<P: AsRef<Path>>(
+        path: P,
+        max_size: u64,
+        max_age: Option<Duration>,
+        keep: usize,
+    ) -> io::Result<Self> {
+        let path = path.as_ref().to_path_buf();
+        let file = OpenOptions::new().create(true).append(true).open(&path)?;
+        let size = file.metadata()?.len();
+        Ok(Self {
+            path,
+            file,
+            size,
+            opened: Instant::now(),
+            max_size,
+            max_age,
+            keep,
+            line_end: true,
+        })
+    }
+
+    fn rotated(&self, n: usize) -> PathBuf {
+        let mut name = self.path.clone().into_os_string();
+        name.push(format!(".{}", n));
+        PathBuf::from(name)
+    }
+
+    fn due(&self, incoming: usize) -> bool {
+        if self.size == 0 {
+            return false;
+        }
+        (self.max_size > 0 && self.size + incoming as u64 > self.max_size)
+            || self.max_age.map_or(false, |age| self.opened.elapsed() >= age)
+    }
+
+    fn rotate(&mut self) -> io::Result<()> {
+        self.file.flush()?;
+        if self.keep == 0 {
+            fs::remove_file(&self.path)?;
+        } else {
+            let oldest = self.rotated(self.keep);
+            if oldest.exists() {
+                fs::remove_file(&oldest)?;
+            }
+            for n in (1..self.keep).rev() {
+                let from = self.rotated(n);
+                if from.exists() {
+                    fs::rename(&from, self.rotated(n + 1))?;
+                }
+            }
+            fs::rename(&self.path, self.rotated(1))?;
+        }
+        self.file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&self.path)?;
+        self.size = 0;
+        self.opened = Instant::now();
+        Ok(())
+    }
+}
+
+impl Write for RotatingFile {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        if self.line_end && self.due(buf.len()) {
+            self.rotate()?;
+        }
+        let written = self.file.write(buf)?;
+        self.size += written as u64;
+        if written > 0 {
+            self.line_end = buf[written - 1] == b'\n';
+        }
+        Ok(written)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.file.flush()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::env;
+    use std::process;
+
+    use super::*;
+
+    #[test]
+    fn test_rotation() {
+        let dir = env::temp_dir().join(format!("pairsona-logfile-{}", process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("server.log");
+        let mut file = RotatingFile::open(&path, 10, None, 2).unwrap();
+        for line in &["first\n", "second\n", "third\n", "fourth\n"] {
+            file.write_all(line.as_bytes()).unwrap();
+        }
+        file.flush().unwrap();
+        assert_eq!(fs::read_to_string(&path).unwrap(), "fourth\n");
+        assert_eq!(fs::read_to_string(dir.join("server.log.1")).unwrap(), "third\n");
+        assert_eq!(fs::read_to_string(dir.join("server.log.2")).unwrap(), "second\n");
+        // only two rotated files are kept
+        assert!(!dir.join("server.log.3").exists());
+
+        // a record written in pieces stays whole
+        for piece in &["fif", "th\n", "si", "xth\n"] {
+            file.write_all(piece.as_bytes()).unwrap();
+        }
+        file.flush().unwrap();
+        assert_eq!(fs::read_to_string(&path).unwrap(), "sixth\n");
+        assert_eq!(fs::read_to_string(dir.join("server.log.1")).unwrap(), "fourth\nfifth\n");
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
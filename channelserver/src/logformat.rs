@@ -0,0 +1,231 @@
+//! Structured (JSON lines) log formats, so log pipelines can parse records
+//! without rewriting them.
+//!
+//! * `json` - `ts`, `level` and `msg`, plus the record's key/values.
+//! * `gcp` - Google Cloud Logging's `timestamp`, `severity` and `message`.
+//!   A `trace` value becomes `logging.googleapis.com/trace`, qualified
+//!   with `GOOGLE_CLOUD_PROJECT` when that is set.
+//! * `cloudwatch` - `timestamp` (epoch milliseconds), `level` and
+//!   `message`. Numeric values are declared as metrics in an AWS
+//!   CloudWatch Embedded Metric Format (EMF) `_aws` block.
+
+use std::env;
+use std::fmt;
+use std::io::{self, Write};
+use std::sync::Mutex;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use serde_json::{self, Map, Value};
+use slog::{self, Drain, Level, OwnedKVList, Record, KV};
+
+use syslog::timestamp;
+
+/// EMF namespace metrics are reported under.
+const NAMESPACE: &str = "pairsona";
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Format {
+    /// Human readable, the default.
+    Text,
+    Json,
+    Gcp,
+    CloudWatch,
+}
+
+impl Format {
+    pub fn parse(name: &str) -> Option<Self> {
+        match name {
+            "" | "text" => Some(Format::Text),
+            "json" => Some(Format::Json),
+            "gcp" => Some(Format::Gcp),
+            "cloudwatch" => Some(Format::CloudWatch),
+            _ => None,
+        }
+    }
+}
+
+/// Writes each record as a line of JSON.
+pub struct JsonDrain<W: Write> {
+    out: Mutex<W>,
+    format: Format,
+}
+
+/// Collects a record's key/values into a JSON object.
+struct Fields<'a>(&'a mut Map<String, Value>);
+
+impl<'a> Fields<'a> {
+    fn insert(&mut self, key: slog::Key, value: Value) -> slog::Result {
+        self.0.insert(key.to_string(), value);
+        Ok(())
+    }
+}
+
+impl<'a> slog::Serializer for Fields<'a> {
+    fn emit_arguments(&mut self, key: slog::Key, val: &fmt::Arguments) -> slog::Result {
+        self.insert(key, Value::String(val.to_string()))
+    }
+
+    fn emit_bool(&mut self, key: slog::Key, val: bool) -> slog::Result {
+        self.insert(key, Value::Bool(val))
+    }
+
+    fn emit_usize(&mut self, key: slog::Key, val: usize) -> slog::Result {
+        self.insert(key, json!(val))
+    }
+
+    fn emit_u64(&mut self, key: slog::Key, val: u64) -> slog::Result {
+        self.insert(key, json!(val))
+    }
+
+    fn emit_i64(&mut self, key: slog::Key, val: i64) -> slog::Result {
+        self.insert(key, json!(val))
+    }
+
+    fn emit_f64(&mut self, key: slog::Key, val: f64) -> slog::Result {
+        self.insert(key, json!(val))
+    }
+}
+
+impl<W: Write> JsonDrain<W> {
+    pub fn new(out: W, format: Format) -> Self {
+        Self {
+            out: Mutex::new(out),
+            format,
+        }
+    }
+
+    fn format(
+        &self,
+        level: Level,
+        msg: String,
+        mut fields: Map<String, Value>,
+        now: SystemTime,
+    ) -> Value {
+        match self.format {
+            Format::Gcp => {
+                let severity = match level {
+                    Level::Critical => "CRITICAL",
+                    Level::Error => "ERROR",
+                    Level::Warning => "WARNING",
+                    Level::Info => "INFO",
+                    Level::Debug | Level::Trace => "DEBUG",
+                };
+                if let Some(trace) = fields.remove("trace") {
+                    let id = trace.as_str().map(|id| id.to_owned());
+                    let trace = match (env::var("GOOGLE_CLOUD_PROJECT"), id) {
+                        (Ok(project), Some(id)) => {
+                            json!(format!("projects/{}/traces/{}", project, id))
+                        }
+                        _ => trace,
+                    };
+                    fields.insert("logging.googleapis.com/trace".to_owned(), trace);
+                }
+                fields.insert("timestamp".to_owned(), json!(timestamp(now)));
+                fields.insert("severity".to_owned(), json!(severity));
+                fields.insert("message".to_owned(), json!(msg));
+            }
+            Format::CloudWatch => {
+                let metrics: Vec<Value> = fields
+                    .iter()
+                    .filter(|(_, value)| value.is_number())
+                    .map(|(name, _)| json!({ "Name": name }))
+                    .collect();
+                let millis = now
+                    .duration_since(UNIX_EPOCH)
+                    .map(|d| d.as_secs() * 1000 + u64::from(d.subsec_millis()))
+                    .unwrap_or(0);
+                if !metrics.is_empty() {
+                    fields.insert(
+                        "_aws".to_owned(),
+                        json!({
+                            "Timestamp": millis,
+                            "CloudWatchMetrics": [{
+                                "Namespace": NAMESPACE,
+                                "Dimensions": [[]],
+                                "Metrics": metrics,
+                            }],
+                        }),
+                    );
+                }
+                fields.insert("timestamp".to_owned(), json!(millis));
+                fields.insert("level".to_owned(), json!(level.as_str().to_lowercase()));
+                fields.insert("message".to_owned(), json!(msg));
+            }
+            Format::Json | Format::Text => {
+                fields.insert("ts".to_owned(), json!(timestamp(now)));
+                fields.insert("level".to_owned(), json!(level.as_str()));
+                fields.insert("msg".to_owned(), json!(msg));
+            }
+        }
+        Value::Object(fields)
+    }
+}
+
+impl<W: Write> Drain for JsonDrain<W> {
+    type Ok = ();
+    type Err = io::Error;
+
+    fn log(&self, record: &Record, values: &OwnedKVList) -> io::Result<()> {
+        let mut fields = Map::new();
+        {
+            let mut serializer = Fields(&mut fields);
+            values.serialize(record, &mut serializer)?;
+            record.kv().serialize(record, &mut serializer)?;
+        }
+        let msg = record.msg().to_string();
+        let line = self.format(record.level(), msg, fields, SystemTime::now());
+        let mut out = self.out.lock().unwrap();
+        serde_json::to_writer(&mut *out, &line)?;
+        out.write_all(b"\n")?;
+        out.flush()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::time::Duration;
+
+    use super::*;
+
+    #[test]
+    fn test_formats() {
+        let then = UNIX_EPOCH + Duration::from_secs(1_000_000_000);
+        let mut fields = Map::new();
+        fields.insert("suppressed".to_owned(), json!(3));
+        fields.insert("trace".to_owned(), json!("abc"));
+
+        let line = JsonDrain::new(Vec::new(), Format::Gcp).format(
+            Level::Warning,
+            "hi".to_owned(),
+            fields.clone(),
+            then,
+        );
+        assert_eq!(line["severity"], json!("WARNING"));
+        assert_eq!(line["message"], json!("hi"));
+        assert_eq!(line["timestamp"], json!("2001-09-09T01:46:40.000000Z"));
+        assert!(line.get("trace").is_none());
+        assert!(line["logging.googleapis.com/trace"].as_str().unwrap().ends_with("abc"));
+
+        let line = JsonDrain::new(Vec::new(), Format::CloudWatch).format(
+            Level::Info,
+            "hi".to_owned(),
+            fields,
+            then,
+        );
+        assert_eq!(line["level"], json!("info"));
+        assert_eq!(line["timestamp"], json!(1_000_000_000_000u64));
+        assert_eq!(line["suppressed"], json!(3));
+        assert_eq!(
+            line["_aws"]["CloudWatchMetrics"][0]["Metrics"],
+            json!([{"Name": "suppressed"}])
+        );
+
+        let line = JsonDrain::new(Vec::new(), Format::CloudWatch).format(
+            Level::Info,
+            "hi".to_owned(),
+            Map::new(),
+            then,
+        );
+        assert!(line.get("_aws").is_none());
+    }
+}
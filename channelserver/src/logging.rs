@@ -1,18 +1,52 @@
+use std::collections::HashMap;
 use std::fmt::{Debug, Display, Formatter, Result};
+use std::io;
+use std::result;
+use std::sync::{Arc, Mutex, RwLock};
+use std::time::Duration;
 
-use actix::prelude::{Actor, Context, Handler};
+use actix::prelude::{Actor, AsyncContext, Context, Handler};
+use rand::{self, Rng};
 
 use slog;
-use slog::Drain;
+use slog::{Drain, Level, OwnedKVList, Record};
 use slog_async;
 use slog_term;
 
+use logfile::RotatingFile;
+use logformat::{Format, JsonDrain};
+use settings::Settings;
+use syslog::SyslogDrain;
+
+/// Records queued for the writer thread, by default.
+const QUEUE_SIZE: usize = 1024;
+
+/// Human readable records, for a terminal or file.
+fn terminal<D>(decorator: D) -> slog::Fuse<slog_term::CompactFormat<D>>
+where
+    D: slog_term::Decorator,
+{
+    slog_term::CompactFormat::new(decorator).build().fuse()
+}
+
+/// How often to report records suppressed by sampling.
+const SUPPRESSED_REPORT_INTERVAL: Duration = Duration::from_secs(60);
+
 #[derive(Clone, Debug)]
 pub struct MozLogger {
     pub log: slog::Logger,
+    pub levels: Levels,
+    sampling: Sampling,
+    /// records dropped by sampling since the last report, per level
+    suppressed: Suppressed,
+    /// temporary level overrides, by module (`None` for the overall
+    /// level): the latest override's generation, and the level to revert to
+    overrides: HashMap<Option<String>, (u64, Option<Level>)>,
+    generation: u64,
 }
 
 #[allow(dead_code)]
+#[derive(Clone, Copy, PartialEq, Eq, Hash)]
 pub enum ErrorLevel {
     Debug,
     Info,
@@ -37,14 +71,320 @@ impl Debug for ErrorLevel {
     }
 }
 
+impl ErrorLevel {
+    pub fn level(self) -> Level {
+        match self {
+            ErrorLevel::Debug => Level::Debug,
+            ErrorLevel::Info => Level::Info,
+            ErrorLevel::Warn => Level::Warning,
+            ErrorLevel::Error => Level::Error,
+            ErrorLevel::Critical => Level::Critical,
+        }
+    }
+
+    /// The level of a record; trace records count as debug ones.
+    pub fn from_level(level: Level) -> Self {
+        match level {
+            Level::Trace | Level::Debug => ErrorLevel::Debug,
+            Level::Info => ErrorLevel::Info,
+            Level::Warning => ErrorLevel::Warn,
+            Level::Error => ErrorLevel::Error,
+            Level::Critical => ErrorLevel::Critical,
+        }
+    }
+
+    pub fn parse(name: &str) -> Option<Self> {
+        match name.to_lowercase().as_str() {
+            "debug" => Some(ErrorLevel::Debug),
+            "info" => Some(ErrorLevel::Info),
+            "warn" => Some(ErrorLevel::Warn),
+            "error" => Some(ErrorLevel::Error),
+            "critical" => Some(ErrorLevel::Critical),
+            _ => None,
+        }
+    }
+}
+
+/// Log sampling, so debug level insight is affordable in production.
+///
+/// Configured as a comma separated list of `level:percent` pairs, e.g.
+/// `debug:1,info:10` to log 1% of debug records and 10% of info records.
+/// Unlisted levels are always logged.
+#[derive(Clone, Debug, Default)]
+pub struct Sampling {
+    percent: HashMap<ErrorLevel, f64>,
+}
+
+impl Sampling {
+    pub fn parse(spec: &str) -> Self {
+        let percent = spec
+            .split(',')
+            .filter_map(|item| {
+                let mut parts = item.trim().splitn(2, ':');
+                let level = ErrorLevel::parse(parts.next()?.trim())?;
+                let percent = parts.next()?.trim().parse::<f64>().ok()?;
+                Some((level, percent.max(0.0).min(100.0)))
+            })
+            .collect();
+        Self { percent }
+    }
+
+    /// Should a record at `level` be logged?
+    pub fn keep(&self, level: ErrorLevel) -> bool {
+        match self.percent.get(&level) {
+            Some(percent) => rand::thread_rng().gen::<f64>() * 100.0 < *percent,
+            None => true,
+        }
+    }
+}
+
+/// Records dropped by sampling, per level. Shared between a logger's drain
+/// and the actor reporting the counts.
+type Suppressed = Arc<Mutex<HashMap<ErrorLevel, u64>>>;
+
+/// The least severe level logged, overall and per module. Shared by every
+/// clone of a logger, so it can be changed while the server runs.
+///
+/// Modules are named without the crate, e.g. `session` or `server`; a
+/// module's level also applies to its submodules.
+#[derive(Clone, Debug)]
+pub struct Levels(Arc<RwLock<LevelMap>>);
+
+#[derive(Debug)]
+struct LevelMap {
+    global: Level,
+    modules: HashMap<String, Level>,
+}
+
+impl Default for Levels {
+    fn default() -> Self {
+        // slog's compile time maximum level, before levels were adjustable
+        let global = if cfg!(debug_assertions) {
+            Level::Debug
+        } else {
+            Level::Info
+        };
+        Levels(Arc::new(RwLock::new(LevelMap {
+            global,
+            modules: HashMap::new(),
+        })))
+    }
+}
+
+/// Parse a level directive: `level` for the overall level, or
+/// `module=level`.
+pub fn directive(item: &str) -> Option<(Option<&str>, Level)> {
+    let mut parts = item.trim().splitn(2, '=');
+    let first = parts.next()?.trim();
+    match parts.next() {
+        Some(level) if !first.is_empty() => {
+            Some((Some(first), ErrorLevel::parse(level.trim())?.level()))
+        }
+        Some(_) => None,
+        None => Some((None, ErrorLevel::parse(first)?.level())),
+    }
+}
+
+impl Levels {
+    /// Apply comma separated env-filter style directives, e.g.
+    /// `warn,session=debug`. Invalid directives are ignored (`check-config`
+    /// reports them).
+    pub fn apply(&self, spec: &str) {
+        for item in spec.split(',').filter(|item| !item.trim().is_empty()) {
+            if let Some((module, level)) = directive(item) {
+                self.set(module, Some(level));
+            }
+        }
+    }
+
+    /// Is a record at `level` from the module at `path` (as given by
+    /// `module_path!()`) logged?
+    pub fn enabled(&self, path: &str, level: Level) -> bool {
+        let name = path.splitn(2, "::").nth(1).unwrap_or("main");
+        let levels = self.0.read().unwrap();
+        let least = levels
+            .modules
+            .iter()
+            .filter(|(module, _)| {
+                name == module.as_str()
+                    || (name.starts_with(module.as_str()) && name[module.len()..].starts_with("::"))
+            })
+            .max_by_key(|(module, _)| module.len())
+            .map_or(levels.global, |(_, level)| *level);
+        level.is_at_least(least)
+    }
+
+    /// The level set for `module`, or the overall level.
+    pub fn get(&self, module: Option<&str>) -> Option<Level> {
+        let levels = self.0.read().unwrap();
+        match module {
+            Some(module) => levels.modules.get(module).cloned(),
+            None => Some(levels.global),
+        }
+    }
+
+    /// Set the level for `module` (`None` to follow the overall level), or
+    /// the overall level.
+    pub fn set(&self, module: Option<&str>, level: Option<Level>) {
+        let mut levels = self.0.write().unwrap();
+        match (module, level) {
+            (Some(module), Some(level)) => {
+                levels.modules.insert(module.to_owned(), level);
+            }
+            (Some(module), None) => {
+                levels.modules.remove(module);
+            }
+            (None, Some(level)) => levels.global = level,
+            (None, None) => {}
+        }
+    }
+
+    /// All the levels, by module name (`*` for the overall level).
+    pub fn report(&self) -> HashMap<String, String> {
+        let levels = self.0.read().unwrap();
+        let mut report: HashMap<String, String> = levels
+            .modules
+            .iter()
+            .map(|(module, level)| (module.clone(), level.as_str().to_lowercase()))
+            .collect();
+        report.insert("*".to_owned(), levels.global.as_str().to_lowercase());
+        report
+    }
+}
+
+/// Drops records below their module's level, and samples the rest.
+struct LevelFilter<D> {
+    drain: D,
+    levels: Levels,
+    sampling: Sampling,
+    suppressed: Suppressed,
+}
+
+impl<D> Drain for LevelFilter<D>
+where
+    D: Drain<Ok = (), Err = slog::Never>,
+{
+    type Ok = ();
+    type Err = slog::Never;
+
+    fn log(&self, record: &Record, values: &OwnedKVList) -> result::Result<(), slog::Never> {
+        // Records written for a `LogMessage` were already checked against
+        // the sender's module, and sampled.
+        if record.module() == module_path!() {
+            return self.drain.log(record, values);
+        }
+        if !self.levels.enabled(record.module(), record.level()) {
+            return Ok(());
+        }
+        let level = ErrorLevel::from_level(record.level());
+        if !self.sampling.keep(level) {
+            *self.suppressed.lock().unwrap().entry(level).or_insert(0) += 1;
+            return Ok(());
+        }
+        self.drain.log(record, values)
+    }
+}
+
 impl MozLogger {
     pub fn new() -> Self {
-        let decorator = slog_term::TermDecorator::new().build();
-        let drain = slog_term::CompactFormat::new(decorator).build().fuse();
-        let drain = slog_async::Async::new(drain).build().fuse();
+        Self::build(
+            terminal(slog_term::TermDecorator::new().build()),
+            QUEUE_SIZE,
+            Sampling::default(),
+        )
+    }
+
+    /// Records are written by a dedicated thread, from a queue of up to
+    /// `queue_size` records. When the queue is full (stdout is stalled)
+    /// records are dropped rather than blocking the caller, and the number
+    /// dropped is logged once the writer catches up.
+    ///
+    /// Sampling applies to every record, whether logged directly (as the
+    /// channel servers do) or sent as a `LogMessage`.
+    fn build<D>(drain: D, queue_size: usize, sampling: Sampling) -> Self
+    where
+        D: Drain<Ok = (), Err = slog::Never> + Send + 'static,
+    {
+        let levels = Levels::default();
+        let suppressed = Suppressed::default();
+        let drain = LevelFilter {
+            drain: slog_async::Async::new(drain)
+                .chan_size(queue_size.max(1))
+                .overflow_strategy(slog_async::OverflowStrategy::DropAndReport)
+                .build()
+                .fuse(),
+            levels: levels.clone(),
+            sampling: sampling.clone(),
+            suppressed: suppressed.clone(),
+        };
 
         Self {
             log: slog::Logger::root(drain, o!()).new(o!()),
+            levels,
+            sampling,
+            suppressed,
+            overrides: HashMap::new(),
+            generation: 0,
+        }
+    }
+
+    /// A logger writing to stdout, `log_file` or `syslog`, with the format,
+    /// queue size and sampling from the settings.
+    pub fn from_settings(settings: &Settings) -> io::Result<Self> {
+        let queue_size = settings.log_queue_size;
+        let sampling = Sampling::parse(&settings.log_sample);
+        let format = Format::parse(&settings.log_format).ok_or_else(|| {
+            io::Error::new(io::ErrorKind::InvalidInput, "Unknown log_format")
+        })?;
+        let logger = if !settings.syslog.is_empty() {
+            // A syslog server that goes away shouldn't take the server
+            // with it.
+            let drain = SyslogDrain::connect(&settings.syslog, &settings.syslog_facility)?;
+            Self::build(drain.ignore_res(), queue_size, sampling)
+        } else if settings.log_file.is_empty() {
+            match format {
+                Format::Text => Self::build(
+                    terminal(slog_term::TermDecorator::new().build()),
+                    queue_size,
+                    sampling,
+                ),
+                _ => Self::build(JsonDrain::new(io::stdout(), format).fuse(), queue_size, sampling),
+            }
+        } else {
+            let max_age = if settings.log_max_age > 0 {
+                Some(Duration::from_secs(settings.log_max_age))
+            } else {
+                None
+            };
+            let file = RotatingFile::open(
+                &settings.log_file,
+                settings.log_max_size,
+                max_age,
+                settings.log_keep,
+            )?;
+            match format {
+                Format::Text => Self::build(
+                    terminal(slog_term::PlainDecorator::new(file)),
+                    queue_size,
+                    sampling,
+                ),
+                _ => Self::build(JsonDrain::new(file, format).fuse(), queue_size, sampling),
+            }
+        };
+        logger.levels.apply(&settings.log_level);
+        Ok(logger)
+    }
+
+    fn report_suppressed(&mut self) {
+        let suppressed: Vec<(ErrorLevel, u64)> = self.suppressed.lock().unwrap().drain().collect();
+        for (level, count) in suppressed {
+            if count > 0 {
+                slog_info!(
+                    self.log,
+                    "Sampling suppressed {} {:?} records", count, level;
+                    "suppressed" => count
+                );
+            }
         }
     }
 }
@@ -57,12 +397,22 @@ impl Default for MozLogger {
 
 impl Actor for MozLogger {
     type Context = Context<Self>;
+
+    fn started(&mut self, ctx: &mut Self::Context) {
+        if !self.sampling.percent.is_empty() {
+            ctx.run_interval(SUPPRESSED_REPORT_INTERVAL, |act, _| act.report_suppressed());
+        }
+    }
 }
 
 #[derive(Message, Debug)]
 pub struct LogMessage {
     pub level: ErrorLevel,
+    /// `module_path!()` of the sender, for per module levels
+    pub module: &'static str,
     pub msg: String,
+    /// trace ID of the connection this is about, if known
+    pub trace: Option<String>,
 }
 
 impl Display for LogMessage {
@@ -91,12 +441,140 @@ impl Handler<LogMessage> for MozLogger {
     type Result = ();
 
     fn handle(&mut self, msg: LogMessage, context: &mut Context<Self>) -> Self::Result {
+        if !self.levels.enabled(msg.module, msg.level.level()) {
+            return;
+        }
+        if !self.sampling.keep(msg.level) {
+            *self.suppressed.lock().unwrap().entry(msg.level).or_insert(0) += 1;
+            return;
+        }
+        let log = match msg.trace {
+            Some(ref trace) => self.log.new(o!("trace" => trace.clone())),
+            None => self.log.clone(),
+        };
         match &msg.level {
-            ErrorLevel::Debug => slog_debug!(self.log, "{}", &msg),
-            ErrorLevel::Info => slog_info!(self.log, "{}", &msg),
-            ErrorLevel::Warn => slog_warn!(self.log, "{}", &msg),
-            ErrorLevel::Error => slog_error!(self.log, "{}", &msg),
-            ErrorLevel::Critical => slog_crit!(self.log, "{}", &msg),
+            ErrorLevel::Debug => slog_debug!(log, "{}", &msg),
+            ErrorLevel::Info => slog_info!(log, "{}", &msg),
+            ErrorLevel::Warn => slog_warn!(log, "{}", &msg),
+            ErrorLevel::Error => slog_error!(log, "{}", &msg),
+            ErrorLevel::Critical => slog_crit!(log, "{}", &msg),
+        };
+    }
+}
+
+/// Change a log level for a while, reverting to the previous level after
+/// `revert_after`.
+#[derive(Message, Debug)]
+pub struct SetLevel {
+    /// module name, or `None` for the overall level
+    pub module: Option<String>,
+    pub level: ErrorLevel,
+    pub revert_after: Duration,
+}
+
+impl Handler<SetLevel> for MozLogger {
+    type Result = ();
+
+    fn handle(&mut self, msg: SetLevel, ctx: &mut Context<Self>) -> Self::Result {
+        let module = msg.module;
+        self.generation += 1;
+        let generation = self.generation;
+        // Overlapping overrides revert to the level from before the first.
+        let original = match self.overrides.get(&module) {
+            Some(&(_, original)) => original,
+            None => self.levels.get(module.as_ref().map(|m| m.as_str())),
+        };
+        self.overrides.insert(module.clone(), (generation, original));
+        self.levels
+            .set(module.as_ref().map(|m| m.as_str()), Some(msg.level.level()));
+        slog_info!(
+            self.log,
+            "Log level for {} set to {:?} for {}s",
+            module.as_ref().map_or("*", |m| m.as_str()),
+            msg.level,
+            msg.revert_after.as_secs()
+        );
+        ctx.run_later(msg.revert_after, move |act, _| {
+            let current = act.overrides.get(&module).map(|&(current, _)| current);
+            if current != Some(generation) {
+                // replaced by a later override
+                return;
+            }
+            act.overrides.remove(&module);
+            act.levels
+                .set(module.as_ref().map(|m| m.as_str()), original);
+            slog_info!(
+                act.log,
+                "Log level for {} reverted",
+                module.as_ref().map_or("*", |m| m.as_str())
+            );
+        });
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_sampling() {
+        let sampling = Sampling::parse("debug:0, info:100,warn:x,bogus:5");
+        assert!((0..100).all(|_| !sampling.keep(ErrorLevel::Debug)));
+        assert!((0..100).all(|_| sampling.keep(ErrorLevel::Info)));
+        // unparsable or unlisted levels are always logged
+        assert!((0..100).all(|_| sampling.keep(ErrorLevel::Warn)));
+        assert!((0..100).all(|_| sampling.keep(ErrorLevel::Error)));
+    }
+
+    #[test]
+    fn test_levels() {
+        let levels = Levels::default();
+        levels.set(None, Some(Level::Warning));
+        levels.set(Some("session"), Some(Level::Debug));
+        assert!(levels.enabled("channelserver::session", Level::Debug));
+        assert!(levels.enabled("channelserver::session::inner", Level::Debug));
+        assert!(!levels.enabled("channelserver::sessions", Level::Info));
+        assert!(!levels.enabled("channelserver", Level::Info));
+        assert!(levels.enabled("channelserver", Level::Error));
+        levels.set(Some("session"), None);
+        assert!(!levels.enabled("channelserver::session", Level::Debug));
+        assert_eq!(levels.report()["*"], "warn");
+
+        levels.apply("error, server=debug,bogus, =info,main=loud");
+        assert_eq!(levels.get(None), Some(Level::Error));
+        assert_eq!(levels.get(Some("server")), Some(Level::Debug));
+        assert_eq!(levels.get(Some("main")), None);
+        assert!(directive("server=warn").is_some());
+        assert!(directive("server=").is_none());
+    }
+
+    struct Count(Arc<Mutex<u64>>);
+
+    impl Drain for Count {
+        type Ok = ();
+        type Err = slog::Never;
+
+        fn log(&self, _: &Record, _: &OwnedKVList) -> result::Result<(), slog::Never> {
+            *self.0.lock().unwrap() += 1;
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn test_sampled_drain() {
+        // As a channel server logs, straight to the logger.
+        let count = Arc::new(Mutex::new(0));
+        let suppressed = Suppressed::default();
+        let drain = LevelFilter {
+            drain: Count(count.clone()),
+            levels: Levels::default(),
+            sampling: Sampling::parse("info:0"),
+            suppressed: suppressed.clone(),
         };
+        let log = slog::Logger::root(drain, o!());
+        slog_info!(log, "sampled out");
+        slog_warn!(log, "kept");
+        assert_eq!(*count.lock().unwrap(), 1);
+        assert_eq!(suppressed.lock().unwrap()[&ErrorLevel::Info], 1);
     }
 }
@@ -0,0 +1,54 @@
+//! Statsd metrics reporting.
+
+use std::net::UdpSocket;
+use std::time::Duration;
+
+use cadence::{BufferedUdpMetricSink, NopMetricSink, QueuingMetricSink, StatsdClient};
+
+use logging::MozLogger;
+use settings::Settings;
+
+/// Build the statsd client described by the settings.
+///
+/// Metrics are discarded if no `statsd_host` is configured, or if the
+/// client can't be set up.
+pub fn metrics_from_settings(settings: &Settings, log: &MozLogger) -> StatsdClient {
+    if settings.statsd_host.is_empty() {
+        return StatsdClient::from_sink(&settings.statsd_label, NopMetricSink);
+    }
+    let sink = UdpSocket::bind("0.0.0.0:0").and_then(|socket| {
+        socket.set_nonblocking(true)?;
+        let host = (settings.statsd_host.as_str(), settings.statsd_port);
+        BufferedUdpMetricSink::from(host, socket)
+            .map_err(|e| ::std::io::Error::new(::std::io::ErrorKind::Other, e))
+    });
+    match sink {
+        Ok(sink) => {
+            let err_log = log.clone();
+            StatsdClient::builder(&settings.statsd_label, QueuingMetricSink::from(sink))
+                .with_error_handler(move |err| {
+                    error!(err_log.log, "Could not send metric: {:?}", err);
+                })
+                .build()
+        }
+        Err(err) => {
+            error!(log.log, "Could not connect to statsd, not reporting metrics: {:?}", err);
+            StatsdClient::from_sink(&settings.statsd_label, NopMetricSink)
+        }
+    }
+}
+
+/// Duration as whole microseconds.
+pub fn micros(duration: Duration) -> u64 {
+    duration.as_secs() * 1_000_000 + u64::from(duration.subsec_micros())
+}
+
+/// Coarse payload size label, so per-size metrics stay low cardinality.
+pub fn size_bucket(size: usize) -> &'static str {
+    match size {
+        0...255 => "lt256",
+        256...4095 => "lt4k",
+        4096...65535 => "lt64k",
+        _ => "ge64k",
+    }
+}
@@ -0,0 +1,73 @@
+//! Live migration of channels between nodes, for rebalancing a cluster or
+//! emptying a node before it is stopped.
+//!
+//! `POST /admin/migrate` on the node a channel is leaving copies the
+//! channel (as a snapshot would) and sends it to the `POST
+//! /admin/migrated` endpoint of the node it is going to. That node holds
+//! every participant's slot for `reconnect_grace`, as if they had just
+//! dropped. Once it has them, the leaving node closes each participant's
+//! connection with a `4019` error carrying where to reconnect and their
+//! reconnect token, and both nodes place the channel on the new node, so
+//! that clients are sent there whichever node they try.
+//!
+//! From the copy being taken until then, the channel is frozen on the
+//! leaving node: joins are refused as `4012`, so clients retry, and
+//! relayed frames are held. If the channel can't be sent, it thaws and the
+//! held frames are relayed; if it can, they are dropped with the
+//! connections they came on.
+//!
+//! Placements are not shared with the rest of the cluster. A client that
+//! tries a third node is sent to the channel's owner by hash, the node it
+//! was created on, which sends it on again: one extra redirect.
+
+use std::collections::HashMap;
+use std::time::Duration;
+
+use actix_web::{client, http};
+use futures::{future, Future};
+use uuid::Uuid;
+
+use snapshot::SavedChannel;
+
+/// Largest migration accepted, in bytes.
+pub const MAX_BODY: usize = 64 * 1024 * 1024;
+
+/// How long the node a channel is going to has to take it.
+const TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Body of a migration request.
+#[derive(Debug, Deserialize)]
+pub struct Migrate {
+    /// Public base URL of the node to migrate to.
+    pub to: String,
+    /// Channels to migrate; every channel on this node if absent.
+    pub channels: Option<Vec<Uuid>>,
+}
+
+/// Channels in transit, as sent to the node they are going to.
+pub type Migration = HashMap<Uuid, SavedChannel>;
+
+/// Send `channels` to the node at `to`, presenting its admin `token`.
+pub fn send(to: &str, token: &str, channels: Migration) -> Box<Future<Item = (), Error = String>> {
+    let request = client::post(format!("{}/admin/migrated", to))
+        .header(http::header::AUTHORIZATION, format!("Bearer {}", token))
+        .json(channels);
+    let request = match request {
+        Ok(request) => request,
+        Err(err) => return Box::new(future::err(format!("Could not encode channels: {:?}", err))),
+    };
+    let to = to.to_owned();
+    Box::new(
+        request
+            .send()
+            .timeout(TIMEOUT)
+            .map_err(move |err| format!("Could not reach {}: {}", to, err))
+            .and_then(|resp| {
+                if resp.status().is_success() {
+                    Ok(())
+                } else {
+                    Err(format!("Refused: {}", resp.status()))
+                }
+            }),
+    )
+}
@@ -0,0 +1,134 @@
+//! Strict pairing mode: a message exchange pattern declared by the client
+//! that creates a channel, and enforced by the server.
+//!
+//! A pairing flow usually has a fixed shape (so many messages, in turn,
+//! ending with a known message). Declaring it limits what a compromised
+//! client can do with the relay; a channel that deviates is closed.
+//!
+//! The pattern is declared with query arguments on the request that
+//! creates the channel:
+//!
+//! * `turns=alternate` - participants must take turns sending.
+//! * `max_messages=<n>` - at most `n` messages may be relayed.
+//! * `final=<message>` - the channel is closed once `message` is relayed.
+
+use std::collections::HashMap;
+
+use server::SessionId;
+
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Pattern {
+    pub alternate: bool,
+    pub max_messages: Option<u64>,
+    pub final_message: Option<String>,
+    last_sender: Option<SessionId>,
+    sent: u64,
+}
+
+/// Where a channel is in its pattern after a message.
+#[derive(Debug, PartialEq)]
+pub enum Step {
+    /// Relay the message and carry on.
+    Continue,
+    /// Relay the message, then close the channel.
+    Finished,
+}
+
+impl Pattern {
+    /// Read a pattern declaration from request query arguments. Returns
+    /// `Ok(None)` if none was declared.
+    pub fn from_query(query: &HashMap<String, String>) -> Result<Option<Self>, String> {
+        let mut pattern = Pattern::default();
+        let mut declared = false;
+        if let Some(turns) = query.get("turns") {
+            if turns != "alternate" {
+                return Err(format!("Unknown turns: {:?}", turns));
+            }
+            pattern.alternate = true;
+            declared = true;
+        }
+        if let Some(max) = query.get("max_messages") {
+            match max.parse::<u64>() {
+                Ok(max) if max > 0 => pattern.max_messages = Some(max),
+                _ => return Err(format!("Invalid max_messages: {:?}", max)),
+            }
+            declared = true;
+        }
+        if let Some(last) = query.get("final") {
+            pattern.final_message = Some(last.clone());
+            declared = true;
+        }
+        Ok(if declared { Some(pattern) } else { None })
+    }
+
+    /// A participant's session ID changed (on handoff).
+    pub fn rekey(&mut self, old: SessionId, new: SessionId) {
+        if self.last_sender == Some(old) {
+            self.last_sender = Some(new);
+        }
+    }
+
+    /// Account for a message from `from`. Returns an error if the message
+    /// breaks the pattern.
+    pub fn step(&mut self, from: SessionId, message: &str) -> Result<Step, String> {
+        if self.alternate && self.last_sender == Some(from) {
+            return Err("sent out of turn".to_owned());
+        }
+        self.sent += 1;
+        if let Some(max) = self.max_messages {
+            if self.sent > max {
+                return Err(format!("more than {} messages", max));
+            }
+        }
+        self.last_sender = Some(from);
+        if self.final_message.as_ref().map_or(false, |last| last == message) {
+            return Ok(Step::Finished);
+        }
+        Ok(Step::Continue)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn query(pairs: &[(&str, &str)]) -> HashMap<String, String> {
+        pairs
+            .iter()
+            .map(|&(k, v)| (k.to_owned(), v.to_owned()))
+            .collect()
+    }
+
+    #[test]
+    fn test_from_query() {
+        assert_eq!(Pattern::from_query(&query(&[("key", "abc")])), Ok(None));
+        assert!(Pattern::from_query(&query(&[("turns", "any")])).is_err());
+        assert!(Pattern::from_query(&query(&[("max_messages", "0")])).is_err());
+        let pattern = Pattern::from_query(&query(&[("turns", "alternate"), ("final", "done")]))
+            .unwrap()
+            .unwrap();
+        assert!(pattern.alternate);
+        assert_eq!(pattern.final_message, Some("done".to_owned()));
+    }
+
+    #[test]
+    fn test_step() {
+        let mut pattern = Pattern {
+            alternate: true,
+            max_messages: Some(3),
+            ..Default::default()
+        };
+        assert_eq!(pattern.step(1, "a"), Ok(Step::Continue));
+        assert!(pattern.clone().step(1, "b").is_err());
+        assert_eq!(pattern.step(2, "b"), Ok(Step::Continue));
+        assert_eq!(pattern.step(1, "c"), Ok(Step::Continue));
+        assert!(pattern.step(2, "d").is_err());
+
+        let mut pattern = Pattern {
+            final_message: Some("done".to_owned()),
+            ..Default::default()
+        };
+        assert_eq!(pattern.step(1, "a"), Ok(Step::Continue));
+        assert_eq!(pattern.step(1, "done"), Ok(Step::Finished));
+    }
+}
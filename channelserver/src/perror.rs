@@ -1,6 +1,12 @@
 use std::fmt;
 
+use actix_web::{http::StatusCode, HttpResponse};
 use failure::{Backtrace, Context, Fail};
+use serde_json::Value;
+
+use i18n;
+
+pub use pairsona_protocol::ErrorEnvelope;
 
 /*
 #[allow(dead_code)]
@@ -27,16 +33,165 @@ pub enum HandlerErrorKind {
     ExpiredErr,
     #[fail(display = "Channel Shutdown Requested")]
     ShutdownErr,
+    #[fail(display = "Only the initiator may send the first message")]
+    RoleErr,
+    #[fail(display = "Invalid application key")]
+    InvalidKeyErr,
+    #[fail(display = "Not authorized")]
+    UnauthorizedErr,
+    #[fail(display = "Too many requests")]
+    RateLimitErr,
+    #[fail(display = "Not found")]
+    NotFoundErr,
+    #[fail(display = "Channel is owned by another node")]
+    WrongNodeErr,
+    #[fail(display = "Service unavailable")]
+    UnavailableErr,
+    #[fail(display = "Channel quota exceeded")]
+    QuotaErr,
+    #[fail(display = "Connection handed off to another device")]
+    HandedOffErr,
+    #[fail(display = "Message exchange pattern violated")]
+    PatternErr,
+    #[fail(display = "Message is not valid JSON")]
+    NotJsonErr,
+    #[fail(display = "Invalid chunked transfer")]
+    ChunkErr,
+    #[fail(display = "Invalid websocket upgrade request")]
+    UpgradeErr,
+    #[fail(display = "Channel moved to another node")]
+    MigratedErr,
+    #[fail(display = "Invalid request")]
+    InvalidRequestErr,
+    #[fail(display = "Connection too slow to keep up")]
+    SlowReaderErr,
+}
+
+impl HandlerErrorKind {
+    /// Every error kind, for exhaustive checks.
+    pub fn all() -> Vec<HandlerErrorKind> {
+        vec![
+            HandlerErrorKind::XSDataErr,
+            HandlerErrorKind::XSMessageErr,
+            HandlerErrorKind::XSConnectionErr,
+            HandlerErrorKind::ExpiredErr,
+            HandlerErrorKind::ShutdownErr,
+            HandlerErrorKind::RoleErr,
+            HandlerErrorKind::InvalidKeyErr,
+            HandlerErrorKind::UnauthorizedErr,
+            HandlerErrorKind::RateLimitErr,
+            HandlerErrorKind::NotFoundErr,
+            HandlerErrorKind::WrongNodeErr,
+            HandlerErrorKind::UnavailableErr,
+            HandlerErrorKind::QuotaErr,
+            HandlerErrorKind::HandedOffErr,
+            HandlerErrorKind::PatternErr,
+            HandlerErrorKind::NotJsonErr,
+            HandlerErrorKind::ChunkErr,
+            HandlerErrorKind::UpgradeErr,
+            HandlerErrorKind::MigratedErr,
+            HandlerErrorKind::InvalidRequestErr,
+            HandlerErrorKind::SlowReaderErr,
+        ]
+    }
+
+    /// Numeric error code reported to clients. These are in the range
+    /// reserved for applications by RFC 6455, so can also be used as the
+    /// websocket close code.
+    pub fn code(&self) -> u16 {
+        match self {
+            HandlerErrorKind::XSDataErr => 4001,
+            HandlerErrorKind::XSMessageErr => 4002,
+            HandlerErrorKind::XSConnectionErr => 4003,
+            HandlerErrorKind::ExpiredErr => 4004,
+            HandlerErrorKind::ShutdownErr => 4005,
+            HandlerErrorKind::RoleErr => 4006,
+            HandlerErrorKind::InvalidKeyErr => 4007,
+            HandlerErrorKind::UnauthorizedErr => 4008,
+            HandlerErrorKind::RateLimitErr => 4009,
+            HandlerErrorKind::NotFoundErr => 4010,
+            HandlerErrorKind::WrongNodeErr => 4011,
+            HandlerErrorKind::UnavailableErr => 4012,
+            HandlerErrorKind::QuotaErr => 4013,
+            HandlerErrorKind::HandedOffErr => 4014,
+            HandlerErrorKind::PatternErr => 4015,
+            HandlerErrorKind::NotJsonErr => 4016,
+            HandlerErrorKind::ChunkErr => 4017,
+            HandlerErrorKind::UpgradeErr => 4018,
+            HandlerErrorKind::MigratedErr => 4019,
+            HandlerErrorKind::InvalidRequestErr => 4020,
+            HandlerErrorKind::SlowReaderErr => 4021,
+        }
+    }
+
+    /// Might the same request succeed if retried later?
+    pub fn retriable(&self) -> bool {
+        match self {
+            HandlerErrorKind::RoleErr
+            | HandlerErrorKind::RateLimitErr
+            | HandlerErrorKind::WrongNodeErr
+            | HandlerErrorKind::UnavailableErr
+            | HandlerErrorKind::MigratedErr
+            | HandlerErrorKind::SlowReaderErr => true,
+            _ => false,
+        }
+    }
+
+    /// HTTP status used when this error is the response to a request.
+    pub fn status(&self) -> StatusCode {
+        match self {
+            HandlerErrorKind::InvalidKeyErr | HandlerErrorKind::UnauthorizedErr => {
+                StatusCode::UNAUTHORIZED
+            }
+            HandlerErrorKind::RateLimitErr => StatusCode::TOO_MANY_REQUESTS,
+            HandlerErrorKind::NotFoundErr => StatusCode::NOT_FOUND,
+            HandlerErrorKind::WrongNodeErr | HandlerErrorKind::MigratedErr => {
+                StatusCode::MISDIRECTED_REQUEST
+            }
+            HandlerErrorKind::UnavailableErr => StatusCode::SERVICE_UNAVAILABLE,
+            HandlerErrorKind::XSConnectionErr => StatusCode::CONFLICT,
+            HandlerErrorKind::ExpiredErr | HandlerErrorKind::ShutdownErr => StatusCode::GONE,
+            _ => StatusCode::BAD_REQUEST,
+        }
+    }
+
+    pub fn envelope(&self, details: Option<Value>) -> ErrorEnvelope {
+        ErrorEnvelope {
+            code: self.code(),
+            reason: self.to_string(),
+            retriable: self.retriable(),
+            details,
+        }
+    }
+
+    /// As `envelope`, with the reason translated to `lang`.
+    pub fn localized(&self, lang: &str, details: Option<Value>) -> ErrorEnvelope {
+        ErrorEnvelope {
+            reason: i18n::reason(self, lang),
+            ..self.envelope(details)
+        }
+    }
+
+    /// An HTTP response reporting this error.
+    pub fn response(&self) -> HttpResponse {
+        self.response_with(None)
+    }
+
+    /// An HTTP response reporting this error in the client's language.
+    pub fn response_in(&self, lang: &str, details: Option<Value>) -> HttpResponse {
+        HttpResponse::build(self.status()).json(self.localized(lang, details))
+    }
+
+    pub fn response_with(&self, details: Option<Value>) -> HttpResponse {
+        HttpResponse::build(self.status()).json(self.envelope(details))
+    }
 }
 
-/*
-#[allow(dead_code)]
 impl HandlerError {
     pub fn kind(&self) -> &HandlerErrorKind {
         self.inner.get_context()
     }
 }
-*/
 
 impl Fail for HandlerError {
     fn cause(&self) -> Option<&Fail> {
@@ -65,3 +220,71 @@ impl From<Context<HandlerErrorKind>> for HandlerError {
         HandlerError { inner }
     }
 }
+
+#[cfg(test)]
+mod test {
+    use std::collections::HashSet;
+
+    use serde_json;
+
+    use super::*;
+
+    /// Fails to compile when a kind is added, until it is given a place
+    /// here; `test_all_kinds` then fails until it is in `all()`.
+    fn ordinal(kind: &HandlerErrorKind) -> usize {
+        match kind {
+            HandlerErrorKind::XSDataErr => 0,
+            HandlerErrorKind::XSMessageErr => 1,
+            HandlerErrorKind::XSConnectionErr => 2,
+            HandlerErrorKind::ExpiredErr => 3,
+            HandlerErrorKind::ShutdownErr => 4,
+            HandlerErrorKind::RoleErr => 5,
+            HandlerErrorKind::InvalidKeyErr => 6,
+            HandlerErrorKind::UnauthorizedErr => 7,
+            HandlerErrorKind::RateLimitErr => 8,
+            HandlerErrorKind::NotFoundErr => 9,
+            HandlerErrorKind::WrongNodeErr => 10,
+            HandlerErrorKind::UnavailableErr => 11,
+            HandlerErrorKind::QuotaErr => 12,
+            HandlerErrorKind::HandedOffErr => 13,
+            HandlerErrorKind::PatternErr => 14,
+            HandlerErrorKind::NotJsonErr => 15,
+            HandlerErrorKind::ChunkErr => 16,
+            HandlerErrorKind::UpgradeErr => 17,
+            HandlerErrorKind::MigratedErr => 18,
+            HandlerErrorKind::InvalidRequestErr => 19,
+            HandlerErrorKind::SlowReaderErr => 20,
+        }
+    }
+
+    #[test]
+    fn test_all_kinds() {
+        let all = HandlerErrorKind::all();
+        let listed: HashSet<usize> = all.iter().map(ordinal).collect();
+        assert_eq!(listed.len(), all.len(), "all() lists a kind twice");
+        assert_eq!(listed, (0..21).collect(), "all() is missing a kind");
+    }
+
+    /// Every error path must produce a well formed, distinct envelope.
+    #[test]
+    fn test_error_envelopes() {
+        let mut codes = HashSet::new();
+        for kind in HandlerErrorKind::all() {
+            assert!(codes.insert(kind.code()), "duplicate code for {:?}", kind);
+            assert!(kind.code() >= 4000 && kind.code() < 5000);
+            assert!(kind.status().is_client_error() || kind.status().is_server_error());
+            let envelope = serde_json::to_value(kind.envelope(None)).unwrap();
+            let fields: HashSet<&str> = envelope
+                .as_object()
+                .unwrap()
+                .keys()
+                .map(|k| k.as_str())
+                .collect();
+            assert_eq!(fields, ["code", "reason", "retriable"].iter().cloned().collect());
+            assert!(!envelope["reason"].as_str().unwrap().is_empty());
+            let envelope =
+                serde_json::to_value(kind.envelope(Some(json!({"extra": 1})))).unwrap();
+            assert_eq!(envelope["details"]["extra"], json!(1));
+        }
+    }
+}
@@ -0,0 +1,68 @@
+//! Free lists of per channel and per participant state, so that their
+//! maps and buffers are reused by later connections rather than freed and
+//! allocated again at every connect and disconnect.
+
+/// State that can be emptied for reuse while keeping its allocations.
+pub trait Recycle {
+    fn recycle(&mut self);
+}
+
+impl<T> Recycle for ::std::collections::VecDeque<T> {
+    fn recycle(&mut self) {
+        self.clear()
+    }
+}
+
+#[derive(Debug, Default)]
+pub struct Pool<T> {
+    free: Vec<T>,
+}
+
+impl<T: Default + Recycle> Pool<T> {
+    /// An empty item, reused if there is one to hand.
+    pub fn take(&mut self) -> T {
+        self.free.pop().unwrap_or_default()
+    }
+
+    /// Keep `item` for reuse, unless `max` are already kept.
+    pub fn give(&mut self, mut item: T, max: usize) {
+        if self.free.len() < max {
+            item.recycle();
+            self.free.push(item);
+        }
+    }
+
+    /// Items waiting to be reused.
+    pub fn len(&self) -> usize {
+        self.free.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.free.is_empty()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::collections::VecDeque;
+
+    use super::*;
+
+    #[test]
+    fn test_reuse() {
+        let mut pool: Pool<VecDeque<String>> = Pool::default();
+        let mut ids = pool.take();
+        ids.extend((0..100).map(|i| i.to_string()));
+        let capacity = ids.capacity();
+        pool.give(ids, 1);
+        assert_eq!(pool.len(), 1);
+        let ids = pool.take();
+        assert!(pool.is_empty());
+        assert!(ids.is_empty());
+        assert_eq!(ids.capacity(), capacity);
+        // only `max` are kept
+        pool.give(ids, 1);
+        pool.give(VecDeque::new(), 1);
+        assert_eq!(pool.len(), 1);
+    }
+}
@@ -0,0 +1,65 @@
+//! On demand CPU profiling, for `GET /admin/pprof/cpu`.
+//!
+//! Profiles are taken by gperftools' sampling profiler, which is only
+//! built in with the `profiling` feature (and needs `libprofiler` to link
+//! against). They are in its format, which `pprof` reads directly, e.g.
+//! `pprof -http=:8000 channelserver cpu.prof` for a flame graph.
+
+use std::time::Duration;
+
+use futures::Future;
+
+/// How long a profile runs, unless the request says otherwise.
+pub const DEFAULT_SECONDS: u64 = 30;
+
+/// The longest profile that may be asked for.
+pub const MAX_SECONDS: u64 = 300;
+
+/// Profile the whole process for `duration`. Only one profile can run at
+/// a time.
+#[cfg(feature = "profiling")]
+pub fn cpu(duration: Duration) -> Box<Future<Item = Vec<u8>, Error = String>> {
+    use std::{env, fs, process, thread};
+
+    use cpuprofiler::PROFILER;
+    use futures::future;
+    use futures::sync::oneshot;
+    use uuid::Uuid;
+
+    let path = env::temp_dir().join(format!(
+        "pairsona-{}-{}.prof",
+        process::id(),
+        Uuid::new_v4().simple()
+    ));
+    if let Err(e) = PROFILER
+        .lock()
+        .unwrap()
+        .start(path.to_string_lossy().as_bytes())
+    {
+        return Box::new(future::err(format!("Could not start profiler: {}", e)));
+    }
+    let (tx, rx) = oneshot::channel();
+    // The profiler samples every thread, so this one only has to wait.
+    thread::spawn(move || {
+        thread::sleep(duration);
+        let result = PROFILER
+            .lock()
+            .unwrap()
+            .stop()
+            .map_err(|e| format!("Could not stop profiler: {}", e))
+            .and_then(|_| fs::read(&path).map_err(|e| format!("Could not read profile: {}", e)));
+        fs::remove_file(&path).ok();
+        tx.send(result).ok();
+    });
+    Box::new(rx.then(|res| match res {
+        Ok(result) => result,
+        Err(_) => Err("Profiler went away".to_owned()),
+    }))
+}
+
+#[cfg(not(feature = "profiling"))]
+pub fn cpu(_duration: Duration) -> Box<Future<Item = Vec<u8>, Error = String>> {
+    Box::new(::futures::future::err(
+        "Built without the profiling feature".to_owned(),
+    ))
+}
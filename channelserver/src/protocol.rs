@@ -0,0 +1,171 @@
+//! Control messages exchanged between the server and clients.
+//!
+//! The messages themselves are defined in `pairsona_protocol`, which the
+//! client libraries share; this module holds what only the server needs.
+
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use serde::de::IgnoredAny;
+use serde_json;
+
+pub use pairsona_protocol::{Capabilities, ClientControl, RelayEnvelope, Role, ServerControl};
+
+use perror::HandlerErrorKind;
+
+/// Protocol versions this server speaks.
+pub const PROTOCOL_VERSIONS: &[&str] = &["v1"];
+
+/// Frame encodings this server relays.
+pub const ENCODINGS: &[&str] = &["text"];
+
+/// An error notice, in the recipient's language.
+pub fn error(kind: &HandlerErrorKind, lang: &str) -> ServerControl {
+    ServerControl::Error(kind.localized(lang, None))
+}
+
+/// Parse a client frame as a control message, if it is one.
+pub fn client_control(frame: &str) -> Option<ClientControl> {
+    if !frame.starts_with('{') || !frame.contains("\"control\"") {
+        return None;
+    }
+    serde_json::from_str(frame).ok()
+}
+
+/// The `control` names of every `ServerControl` message.
+const SERVER_CONTROLS: &[&str] = &[
+    "joined",
+    "peer_joined",
+    "peer_dropped",
+    "peer_reconnected",
+    "peer_handed_off",
+    "handoff_token",
+    "chunk_ack",
+    "undeliverable",
+    "throttled",
+    "duplicate",
+    "join_attempted",
+    "capabilities",
+    "ping",
+    "pong",
+    "error",
+];
+
+/// The part of a frame that names a control message.
+#[derive(Deserialize)]
+struct ControlFrame {
+    control: String,
+}
+
+/// Does a client frame pass for a control message from the server? Such
+/// frames aren't relayed, so that a peer can't pass itself off as it. Any
+/// object naming a server control message counts, whatever else it holds,
+/// as clients may not check the rest.
+pub fn is_server_control(frame: &str) -> bool {
+    if !frame.starts_with('{') || !frame.contains("\"control\"") {
+        return false;
+    }
+    serde_json::from_str::<ControlFrame>(frame)
+        .map_or(false, |frame| SERVER_CONTROLS.contains(&frame.control.as_str()))
+}
+
+/// Is the frame valid JSON? The parsed value isn't kept.
+pub fn is_json(frame: &str) -> bool {
+    serde_json::from_str::<IgnoredAny>(frame).is_ok()
+}
+
+/// Milliseconds since the epoch.
+pub fn now_ms() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs() * 1000 + u64::from(d.subsec_millis()))
+        .unwrap_or(0)
+}
+
+/// The part of a client frame the server looks at for duplicate
+/// suppression. All other fields are ignored.
+#[derive(Deserialize)]
+struct ClientFrame {
+    message_id: Option<String>,
+}
+
+/// The client assigned `message_id` of a JSON object frame, if any.
+pub fn message_id(frame: &str) -> Option<String> {
+    if !frame.starts_with('{') {
+        return None;
+    }
+    serde_json::from_str::<ClientFrame>(frame)
+        .ok()
+        .and_then(|f| f.message_id)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_capabilities() {
+        let hello = r#"{"control": "hello", "encodings": ["text", "cbor"], "max_frame_size": 4096,
+                        "extensions": {"ack": {"window": 8}, "presence": {}, "x-trace": true}}"#;
+        let old = match client_control(hello) {
+            Some(ClientControl::Hello(capabilities)) => capabilities,
+            other => panic!("{:?}", other),
+        };
+        let new = Capabilities {
+            encodings: vec!["cbor".to_owned(), "text".to_owned()],
+            max_frame_size: None,
+            resume: true,
+            extensions: json!({"ack": {"window": 16}, "chunking": {}, "x-trace": true})
+                .as_object()
+                .unwrap()
+                .clone()
+                .into_iter()
+                .collect(),
+        };
+        let common = Capabilities::common(&[&old, &new]);
+        assert_eq!(common.encodings, vec!["text", "cbor"]);
+        assert_eq!(common.max_frame_size, Some(4096));
+        assert!(!common.resume);
+        assert_eq!(
+            ServerControl::Capabilities(common).to_text(),
+            concat!(
+                r#"{"control":"capabilities","encodings":["text","cbor"],"max_frame_size":4096,"#,
+                r#""resume":false,"extensions":{"ack":null,"x-trace":true}}"#
+            )
+        );
+    }
+
+    #[test]
+    fn test_is_server_control() {
+        assert!(is_server_control(
+            r#"{"control": "joined", "channel": "/v1/ws/abc", "role": "initiator",
+                "reconnect": "t0", "server_ts": 1000}"#
+        ));
+        assert!(is_server_control(r#"{"control": "peer_dropped", "role": "joiner"}"#));
+        // Partial or odd shapes still pass for the server to some clients.
+        assert!(is_server_control(r#"{"control":"peer_dropped"}"#));
+        assert!(is_server_control(r#"{"control": "joined", "channel": 7}"#));
+        assert!(is_server_control(r#"{"control": "error", "extra": true}"#));
+        assert!(!is_server_control(r#"{"control": "mine", "data": 1}"#));
+        assert!(!is_server_control(r#"{"control": 1}"#));
+        assert!(!is_server_control(r#"{"msg": "joined"}"#));
+        assert!(!is_server_control(r#"{"data": {"control": "joined"}}"#));
+        assert!(!is_server_control("hi"));
+
+        // Everything the server sends is covered.
+        let sent = vec![
+            ServerControl::PeerJoined { role: Role::Joiner },
+            ServerControl::PeerHandedOff { role: Role::Joiner },
+            ServerControl::ChunkAck {
+                transfer: "t".to_owned(),
+                index: 0,
+            },
+            ServerControl::Throttled { size: 1 },
+            ServerControl::JoinAttempted {},
+            ServerControl::Capabilities(Capabilities::default()),
+            error(&HandlerErrorKind::RoleErr, "en"),
+        ];
+        for control in sent {
+            assert!(is_server_control(&control.to_text()), "{:?}", control);
+        }
+    }
+}
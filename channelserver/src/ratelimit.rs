@@ -0,0 +1,141 @@
+//! The server's rate limits, built from the settings.
+//!
+//! Creating channels, joining them and sending messages each have their
+//! own budget, as their legitimate rates (and abuse) differ widely.
+//! Failed admin API authentication is limited too, to slow down guessing
+//! the admin token.
+//!
+//! Buckets are kept in memory, or with `rate_limit_redis` set (and the
+//! `redis` feature), in Redis, so that every node shares them. Message
+//! buckets are always kept in memory, per node: every frame is checked,
+//! and a Redis round trip per frame would hold up the worker relaying it.
+
+use std::collections::hash_map::DefaultHasher;
+use std::hash::{Hash, Hasher};
+use std::sync::Mutex;
+
+use cadence::{Counted, StatsdClient};
+use ratelimiter::{ip_key, Key, MemoryStore, Quota, RateLimiter, Store};
+#[cfg(feature = "redis")]
+use ratelimiter::RedisStore;
+
+use settings::Settings;
+
+/// Stripes of the message limiter, each with its own lock.
+const STRIPES: usize = 16;
+
+/// The separate budgets, from the settings.
+pub struct Limiters {
+    pub create: Mutex<RateLimiter>,
+    pub join: Mutex<RateLimiter>,
+    pub message: Striped,
+    /// failed admin authentication
+    pub admin_auth: Mutex<RateLimiter>,
+}
+
+#[cfg(feature = "redis")]
+fn redis(url: &str) -> Result<Box<Store>, String> {
+    RedisStore::open(url)
+        .map(|store| Box::new(store) as Box<Store>)
+        .map_err(|e| e.to_string())
+}
+
+#[cfg(not(feature = "redis"))]
+fn redis(_url: &str) -> Result<Box<Store>, String> {
+    Err("rate_limit_redis: needs the redis feature".to_owned())
+}
+
+impl Limiters {
+    pub fn new(settings: &Settings) -> Result<Self, String> {
+        let limiter = |rate, burst, name| -> Result<Mutex<RateLimiter>, String> {
+            let store: Box<Store> = if settings.rate_limit_redis.is_empty() {
+                Box::new(MemoryStore::default())
+            } else {
+                redis(&settings.rate_limit_redis)?
+            };
+            Ok(Mutex::new(RateLimiter::with_store(
+                rate,
+                burst,
+                settings.ipv6_prefix,
+                &format!("pairsona:{}:", name),
+                store,
+            )))
+        };
+        Ok(Limiters {
+            create: limiter(settings.create_rate, settings.create_burst, "create")?,
+            join: limiter(settings.join_rate, settings.join_burst, "join")?,
+            message: Striped::new(
+                settings.message_rate,
+                settings.message_burst,
+                settings.ipv6_prefix,
+            ),
+            admin_auth: limiter(
+                settings.admin_auth_rate,
+                settings.admin_auth_burst,
+                "admin_auth",
+            )?,
+        })
+    }
+}
+
+/// Run `f` on `limiter`, counting the checks that failed open because its
+/// store couldn't be reached as the `ratelimit.error` metric.
+pub fn with<T, F>(limiter: &Mutex<RateLimiter>, metrics: &StatsdClient, f: F) -> T
+where
+    F: FnOnce(&mut RateLimiter) -> T,
+{
+    let mut limiter = limiter.lock().unwrap();
+    let errors = limiter.errors();
+    let result = f(&mut limiter);
+    if limiter.errors() > errors {
+        metrics.incr("ratelimit.error").ok();
+    }
+    result
+}
+
+/// An in memory limiter split into stripes by client, so that workers
+/// checking different clients' frames rarely wait on each other.
+pub struct Striped {
+    stripes: Vec<Mutex<RateLimiter>>,
+    v6_prefix: u8,
+}
+
+impl Striped {
+    pub fn new(per_minute: u64, burst: u64, v6_prefix: u8) -> Self {
+        Self {
+            stripes: (0..STRIPES)
+                .map(|_| Mutex::new(RateLimiter::new(per_minute, burst, v6_prefix)))
+                .collect(),
+            v6_prefix,
+        }
+    }
+
+    /// Take a token for `key`, from its stripe.
+    pub fn check(&self, key: &Key) -> Quota {
+        let mut hasher = DefaultHasher::new();
+        // Addresses sharing a bucket must share a stripe too.
+        match key {
+            Key::Ip(addr) => ip_key(*addr, self.v6_prefix).hash(&mut hasher),
+            other => other.hash(&mut hasher),
+        }
+        let stripe = hasher.finish() as usize % self.stripes.len();
+        self.stripes[stripe].lock().unwrap().check(key)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_striped() {
+        let limiter = Striped::new(1, 2, 64);
+        let a = Key::Ip("2001:db8::1".parse().unwrap());
+        let b = Key::Ip("2001:db8::2".parse().unwrap());
+        assert!(limiter.check(&a).allowed);
+        // same /64, so the same stripe and bucket
+        assert!(limiter.check(&b).allowed);
+        assert!(!limiter.check(&a).allowed);
+        assert!(limiter.check(&Key::Ip("192.0.2.1".parse().unwrap())).allowed);
+    }
+}
@@ -0,0 +1,228 @@
+//! Warm-standby replication.
+//!
+//! The `Replicator` collects channel registry mutations from the
+//! `ChannelServer` and periodically forwards them to a standby node's
+//! `/admin/replica` endpoint. The standby keeps a record of the live
+//! channels, so that if it is promoted, clients reconnecting to it land
+//! back in a channel it already knows about.
+//!
+//! Events stay queued until the standby accepts them, for up to
+//! `MAX_RETRIES` attempts. Every `RESYNC_INTERVAL`, and whenever the
+//! standby comes back after a failure, the whole of the shard's registry
+//! (with reconnect tokens) is sent along with them. The standby forgets
+//! channels it hasn't heard about for `REPLICA_TTL`.
+
+use std::collections::VecDeque;
+use std::time::{Duration, Instant};
+
+use actix::prelude::{Actor, AsyncContext, Context, Handler};
+use actix::{fut, ActorFuture, ContextFutureSpawner, Recipient, WrapFuture};
+use actix_web::{client, http};
+use futures::Future;
+use uuid::Uuid;
+
+use logging::MozLogger;
+use server::{ChannelEvent, ReplicaSync};
+use snapshot::SavedChannel;
+
+/// How often queued events are forwarded to the standby.
+const FLUSH_INTERVAL: Duration = Duration::from_secs(1);
+
+/// How often the whole registry is sent to the standby.
+pub const RESYNC_INTERVAL: Duration = Duration::from_secs(60);
+
+/// How long the standby remembers a channel it hasn't heard about.
+pub const REPLICA_TTL: Duration = Duration::from_secs(300);
+
+/// Attempts at sending a batch of events before giving up on them. The
+/// resync once the standby is back covers what they said.
+const MAX_RETRIES: u32 = 10;
+
+/// Events queued while the standby is unreachable. Beyond this, new
+/// events are dropped in favour of a resync.
+const MAX_PENDING: usize = 10_000;
+
+/// What the primary posts to the standby's `/admin/replica`.
+#[derive(Debug, Default, Deserialize, Serialize)]
+pub struct ReplicaBatch {
+    /// Registry mutations, oldest first.
+    pub events: Vec<ChannelEvent>,
+    /// Every channel live on the sending shard, with reconnect tokens, if
+    /// this batch is a resync.
+    #[serde(default)]
+    pub channels: Option<Vec<(Uuid, SavedChannel)>>,
+}
+
+/// Queue a registry mutation for the standby.
+#[derive(Message)]
+pub struct Replicate(pub ChannelEvent);
+
+/// Present a new admin token to the standby, once it is rotated.
+#[derive(Message)]
+pub struct SetToken(pub String);
+
+pub struct Replicator {
+    /// Base URL of the standby node (e.g. `http://standby:8000`)
+    standby_url: String,
+    /// Admin token presented to the standby
+    token: String,
+    /// The channel server whose registry is replicated, for resyncs
+    server: Recipient<ReplicaSync>,
+    pending: VecDeque<ChannelEvent>,
+    /// Is a batch on its way to the standby?
+    in_flight: bool,
+    /// Failed attempts at sending the oldest pending events
+    failures: u32,
+    /// When the standby last took the whole registry; `None` if it needs
+    /// it again
+    synced: Option<Instant>,
+    log: MozLogger,
+}
+
+impl Replicator {
+    pub fn new(
+        standby_url: &str,
+        token: &str,
+        server: Recipient<ReplicaSync>,
+        log: MozLogger,
+    ) -> Self {
+        Self {
+            standby_url: standby_url.trim_right_matches('/').to_owned(),
+            token: token.to_owned(),
+            server,
+            pending: VecDeque::new(),
+            in_flight: false,
+            failures: 0,
+            synced: None,
+            log,
+        }
+    }
+
+    fn flush(&mut self, ctx: &mut Context<Self>) {
+        if self.in_flight {
+            return;
+        }
+        let resync = self
+            .synced
+            .map_or(true, |synced| synced.elapsed() >= RESYNC_INTERVAL);
+        if resync {
+            self.in_flight = true;
+            self.server
+                .send(ReplicaSync)
+                .into_actor(self)
+                .then(|res, act, ctx| {
+                    act.in_flight = false;
+                    match res {
+                        Ok(channels) => act.post(Some(channels), ctx),
+                        Err(err) => error!(
+                            act.log.log,
+                            "Could not read channels for the standby: {:?}", err
+                        ),
+                    }
+                    fut::ok(())
+                })
+                .spawn(ctx);
+        } else if !self.pending.is_empty() {
+            self.post(None, ctx);
+        }
+    }
+
+    /// Send the pending events, and `channels` if resyncing. The events
+    /// are only dropped once the standby has them.
+    fn post(&mut self, channels: Option<Vec<(Uuid, SavedChannel)>>, ctx: &mut Context<Self>) {
+        let count = self.pending.len();
+        let resync = channels.is_some();
+        let batch = ReplicaBatch {
+            events: self.pending.iter().cloned().collect(),
+            channels,
+        };
+        let request = client::post(format!("{}/admin/replica", self.standby_url))
+            .header(
+                http::header::AUTHORIZATION,
+                format!("Bearer {}", self.token),
+            )
+            .json(batch);
+        let request = match request {
+            Ok(request) => request,
+            Err(err) => {
+                error!(self.log.log, "Could not encode replica events: {:?}", err);
+                self.pending.drain(..count);
+                return;
+            }
+        };
+        self.in_flight = true;
+        request
+            .send()
+            .into_actor(self)
+            .then(move |res, act, _| {
+                act.in_flight = false;
+                let failure = match res {
+                    Ok(ref resp) if resp.status().is_success() => None,
+                    Ok(resp) => Some(format!("rejected: {}", resp.status())),
+                    Err(err) => Some(format!("{:?}", err)),
+                };
+                match failure {
+                    None => {
+                        act.pending.drain(..count);
+                        act.failures = 0;
+                        if resync {
+                            act.synced = Some(Instant::now());
+                        }
+                    }
+                    Some(reason) => {
+                        act.failures += 1;
+                        // The standby may have missed anything by now;
+                        // catch it up once it is back.
+                        act.synced = None;
+                        if act.failures >= MAX_RETRIES {
+                            warn!(
+                                act.log.log,
+                                "Dropping {} replica events after {} attempts: {}",
+                                count,
+                                act.failures,
+                                reason
+                            );
+                            act.pending.drain(..count);
+                            act.failures = 0;
+                        } else {
+                            warn!(
+                                act.log.log,
+                                "Could not send {} replica events: {}", count, reason
+                            );
+                        }
+                    }
+                }
+                fut::ok(())
+            })
+            .spawn(ctx);
+    }
+}
+
+impl Actor for Replicator {
+    type Context = Context<Self>;
+
+    fn started(&mut self, ctx: &mut Self::Context) {
+        ctx.run_interval(FLUSH_INTERVAL, |act, ctx| act.flush(ctx));
+    }
+}
+
+impl Handler<Replicate> for Replicator {
+    type Result = ();
+
+    fn handle(&mut self, msg: Replicate, _: &mut Context<Self>) {
+        if self.pending.len() >= MAX_PENDING {
+            // The next resync says what this would have.
+            self.synced = None;
+            return;
+        }
+        self.pending.push_back(msg.0);
+    }
+}
+
+impl Handler<SetToken> for Replicator {
+    type Result = ();
+
+    fn handle(&mut self, msg: SetToken, _: &mut Context<Self>) {
+        self.token = msg.0;
+    }
+}
@@ -0,0 +1,114 @@
+//! Encryption of frames the server has to hold on to for a while: a
+//! throttled channel's backlog, frames held while a channel migrates, and
+//! each connection's outgoing queue.
+//!
+//! Payloads are expected to be end-to-end encrypted between the clients
+//! already; this keeps them from sitting in memory in the clear (to turn
+//! up in a core dump or swap) when they aren't. Each channel, and each
+//! connection's queue, has its own random key that only ever lives in
+//! memory, and every frame is sealed with ChaCha20 under a fresh nonce.
+//! Sealed frames never leave the process, so they aren't authenticated.
+
+use std::fmt;
+
+use rand::prng::ChaChaRng;
+use rand::{self, Rng, RngCore, SeedableRng};
+
+/// A payload sealed under a `Key`.
+#[derive(Clone, Debug)]
+pub struct Sealed {
+    nonce: u64,
+    data: Vec<u8>,
+}
+
+impl Sealed {
+    /// Octets sealed, the same as the payload.
+    pub fn len(&self) -> usize {
+        self.data.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.data.is_empty()
+    }
+}
+
+/// A random key for sealing payloads.
+#[derive(Clone)]
+pub struct Key {
+    key: [u8; 32],
+    /// nonce for the next payload sealed
+    next: u64,
+}
+
+impl Default for Key {
+    fn default() -> Self {
+        Key {
+            key: rand::thread_rng().gen(),
+            next: 0,
+        }
+    }
+}
+
+/// Never shows the key.
+impl fmt::Debug for Key {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "Key {{ next: {} }}", self.next)
+    }
+}
+
+impl Key {
+    /// XOR `data` with the key stream for `nonce`.
+    fn apply(&self, nonce: u64, data: &mut [u8]) {
+        let mut stream = ChaChaRng::from_seed(self.key);
+        stream.set_counter(0, nonce);
+        let mut block = [0u8; 64];
+        for chunk in data.chunks_mut(64) {
+            let block = &mut block[..chunk.len()];
+            stream.fill_bytes(block);
+            for (byte, key) in chunk.iter_mut().zip(block.iter()) {
+                *byte ^= *key;
+            }
+        }
+    }
+
+    pub fn seal(&mut self, text: String) -> Sealed {
+        let nonce = self.next;
+        self.next += 1;
+        let mut data = text.into_bytes();
+        self.apply(nonce, &mut data);
+        Sealed { nonce, data }
+    }
+
+    pub fn open(&self, sealed: Sealed) -> String {
+        let mut data = sealed.data;
+        self.apply(sealed.nonce, &mut data);
+        // Only ever sealed from a String, under this key.
+        String::from_utf8(data).unwrap_or_default()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_seal() {
+        let mut key = Key::default();
+        let text = "{\"msg\": \"hello\"}".repeat(10);
+        let one = key.seal(text.clone());
+        let two = key.seal(text.clone());
+        assert_eq!(one.len(), text.len());
+        assert_ne!(one.data, text.as_bytes());
+        // a fresh nonce for every payload.
+        assert_ne!(one.data, two.data);
+        assert_eq!(key.open(one), text);
+        assert_eq!(key.open(two), text);
+
+        let other = Key::default();
+        let three = key.seal(text.clone());
+        assert_ne!(other.open(three), text);
+        let empty = key.seal(String::new());
+        assert!(empty.is_empty());
+        assert_eq!(key.open(empty), "");
+    }
+}
@@ -0,0 +1,228 @@
+//! Sensitive settings, loaded through a provider rather than only given
+//! in plain text.
+//!
+//! The value of a secret setting may be a reference to where the secret
+//! really lives:
+//!
+//! * `env:NAME` - the environment variable `NAME`.
+//! * `file:/path` - the contents of a file (e.g. a mounted Kubernetes or
+//!   Docker secret), without the trailing newline.
+//! * `vault:path#field` - `field` of the HashiCorp Vault secret at `path`
+//!   (e.g. `secret/data/pairsona#admin_token`), read from `VAULT_ADDR`
+//!   with `VAULT_TOKEN`. Only plain HTTP is spoken, so point `VAULT_ADDR`
+//!   at a local Vault Agent listener.
+//!
+//! Anything else is the secret itself. With `secrets_refresh` set,
+//! references are read again periodically, and rotation hooks are told of
+//! any that changed.
+
+use std::collections::BTreeMap;
+use std::env;
+use std::fs;
+use std::io::{Read, Write};
+use std::net::TcpStream;
+use std::sync::{Arc, Mutex, RwLock};
+use std::thread;
+use std::time::Duration;
+
+use serde_json::{self, Value};
+use slog::Logger;
+
+/// The settings that may hold secret references.
+pub const SECRETS: &[&str] = &[
+    "admin_token",
+    "standby_token",
+    "migrate_token",
+    "audit_postgres",
+    "gossip_key",
+];
+
+const TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Fetch `field` of the Vault secret at `path`. Handles both KV version 1
+/// (`data.<field>`) and version 2 (`data.data.<field>`) responses.
+fn vault(path: &str, field: &str) -> Result<String, String> {
+    let addr = env::var("VAULT_ADDR").map_err(|_| "VAULT_ADDR is not set".to_owned())?;
+    let token = env::var("VAULT_TOKEN").map_err(|_| "VAULT_TOKEN is not set".to_owned())?;
+    if !addr.starts_with("http://") {
+        return Err(format!("VAULT_ADDR {:?} is not an http:// URL", addr));
+    }
+    let host = addr[7..].trim_right_matches('/');
+    let mut stream = TcpStream::connect(host).map_err(|e| format!("{}: {}", host, e))?;
+    stream.set_read_timeout(Some(TIMEOUT)).ok();
+    // HTTP/1.0, so the response is never chunked.
+    write!(
+        stream,
+        "GET /v1/{} HTTP/1.0\r\nHost: {}\r\nX-Vault-Token: {}\r\n\r\n",
+        path.trim_left_matches('/'),
+        host,
+        token
+    ).map_err(|e| e.to_string())?;
+    let mut response = String::new();
+    stream
+        .read_to_string(&mut response)
+        .map_err(|e| e.to_string())?;
+    let mut parts = response.splitn(2, "\r\n\r\n");
+    let status = parts.next().unwrap_or("").lines().next().unwrap_or("");
+    if !status.contains(" 200 ") {
+        return Err(format!("vault answered {:?}", status));
+    }
+    let body: Value = serde_json::from_str(parts.next().unwrap_or(""))
+        .map_err(|e| format!("vault answered with invalid JSON: {}", e))?;
+    let data = &body["data"];
+    let data = if data["data"].is_object() { &data["data"] } else { data };
+    data[field]
+        .as_str()
+        .map(|v| v.to_owned())
+        .ok_or_else(|| format!("vault secret {} has no {:?}", path, field))
+}
+
+/// The secret `value` refers to.
+pub fn resolve(value: &str) -> Result<String, String> {
+    if value.starts_with("env:") {
+        env::var(&value[4..]).map_err(|_| format!("{} is not set", &value[4..]))
+    } else if value.starts_with("file:") {
+        fs::read_to_string(&value[5..])
+            .map(|v| v.trim_right_matches(|c| c == '\r' || c == '\n').to_owned())
+            .map_err(|e| format!("{}: {}", &value[5..], e))
+    } else if value.starts_with("vault:") {
+        let mut parts = value[6..].splitn(2, '#');
+        match (parts.next(), parts.next()) {
+            (Some(path), Some(field)) if !path.is_empty() && !field.is_empty() => {
+                vault(path, field)
+            }
+            _ => Err(format!("{:?} is not vault:path#field", value)),
+        }
+    } else {
+        Ok(value.to_owned())
+    }
+}
+
+/// Is `value` a reference, rather than the secret itself?
+pub fn is_reference(value: &str) -> bool {
+    value.starts_with("env:") || value.starts_with("file:") || value.starts_with("vault:")
+}
+
+type Hook = Box<Fn(&str, &str) + Send>;
+
+/// The current values of the secret settings.
+#[derive(Clone)]
+pub struct Secrets {
+    /// setting -> the reference it was loaded from
+    references: BTreeMap<String, String>,
+    values: Arc<RwLock<BTreeMap<String, String>>>,
+    hooks: Arc<Mutex<Vec<Hook>>>,
+}
+
+impl Secrets {
+    /// `values` are the resolved secret settings, and `references` where
+    /// those that came from a provider were loaded from.
+    pub fn new(values: BTreeMap<String, String>, references: BTreeMap<String, String>) -> Self {
+        Self {
+            references,
+            values: Arc::new(RwLock::new(values)),
+            hooks: Arc::new(Mutex::new(Vec::new())),
+        }
+    }
+
+    /// The current value of a secret setting.
+    pub fn get(&self, name: &str) -> String {
+        self.values
+            .read()
+            .unwrap()
+            .get(name)
+            .cloned()
+            .unwrap_or_default()
+    }
+
+    /// Call `hook` with the setting's name and new value whenever a secret
+    /// is rotated.
+    pub fn on_rotate<F>(&self, hook: F)
+    where
+        F: Fn(&str, &str) + Send + 'static,
+    {
+        self.hooks.lock().unwrap().push(Box::new(hook));
+    }
+
+    /// Read every reference again, and apply any that changed.
+    pub fn refresh(&self, log: &Logger) {
+        for (name, reference) in &self.references {
+            let value = match resolve(reference) {
+                Ok(value) => value,
+                Err(err) => {
+                    error!(log, "Could not refresh {}: {}", name, err);
+                    continue;
+                }
+            };
+            if self.values.read().unwrap().get(name) == Some(&value) {
+                continue;
+            }
+            info!(log, "Rotated {}", name);
+            self.values
+                .write()
+                .unwrap()
+                .insert(name.clone(), value.clone());
+            for hook in self.hooks.lock().unwrap().iter() {
+                hook(name, &value);
+            }
+        }
+    }
+
+    /// Refresh every `every` in the background, if there is anything to
+    /// refresh.
+    pub fn watch(&self, every: Duration, log: Logger) {
+        if self.references.is_empty() {
+            return;
+        }
+        let secrets = self.clone();
+        thread::Builder::new()
+            .name("secrets".to_owned())
+            .spawn(move || loop {
+                thread::sleep(every);
+                secrets.refresh(&log);
+            })
+            .expect("Could not start the secrets refresher");
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::io::Write;
+
+    use slog::Discard;
+    use uuid::Uuid;
+
+    use super::*;
+
+    #[test]
+    fn test_rotate() {
+        let path = env::temp_dir().join(format!("pairsona-{}", Uuid::new_v4().simple()));
+        fs::File::create(&path).unwrap().write_all(b"first\n").unwrap();
+        let reference = format!("file:{}", path.to_str().unwrap());
+        assert_eq!(resolve(&reference).unwrap(), "first");
+        assert_eq!(resolve("plain").unwrap(), "plain");
+        assert!(resolve("env:PAIRSONA_TEST_UNSET").is_err());
+        assert!(resolve("vault:secret/pairsona").is_err());
+
+        let mut values = BTreeMap::new();
+        values.insert("admin_token".to_owned(), "first".to_owned());
+        let mut references = BTreeMap::new();
+        references.insert("admin_token".to_owned(), reference);
+        let secrets = Secrets::new(values, references);
+        let rotated = Arc::new(Mutex::new(Vec::new()));
+        {
+            let rotated = rotated.clone();
+            secrets.on_rotate(move |name, value| {
+                rotated.lock().unwrap().push(format!("{}={}", name, value))
+            });
+        }
+        let log = Logger::root(Discard, o!());
+        secrets.refresh(&log);
+        assert!(rotated.lock().unwrap().is_empty());
+        fs::write(&path, "second").unwrap();
+        secrets.refresh(&log);
+        assert_eq!(secrets.get("admin_token"), "second");
+        assert_eq!(*rotated.lock().unwrap(), vec!["admin_token=second"]);
+        fs::remove_file(&path).ok();
+    }
+}
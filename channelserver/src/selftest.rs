@@ -0,0 +1,231 @@
+//! `channelserver selftest`: start a throwaway server and drive a two
+//! party pairing through it (handshake, relay both ways, close), as a one
+//! command smoke test for packaging and deploy pipelines.
+//!
+//! The server is this binary, run as a child process on a free local
+//! port. The clients are a minimal blocking websocket implementation, so
+//! the test doesn't depend on the server's own websocket stack.
+
+use std::env;
+use std::io::{self, Read, Write};
+use std::net::{SocketAddr, TcpListener, TcpStream};
+use std::process::{Child, Command, Stdio};
+use std::thread;
+use std::time::Duration;
+
+use byteorder::{BigEndian, ReadBytesExt, WriteBytesExt};
+use rand;
+use serde_json::{self, Value};
+
+const TIMEOUT: Duration = Duration::from_secs(5);
+/// The sample nonce from RFC 6455, so the expected accept value is known
+/// without needing SHA-1 here.
+const KEY: &str = "dGhlIHNhbXBsZSBub25jZQ==";
+const ACCEPT: &str = "s3pPLMBiTxaQ9kYGzzhZRbK+xOo=";
+
+const OP_TEXT: u8 = 0x1;
+const OP_CLOSE: u8 = 0x8;
+
+enum Frame {
+    Text(String),
+    Close,
+    Other,
+}
+
+struct Client {
+    stream: TcpStream,
+}
+
+impl Client {
+    fn connect(addr: &SocketAddr, path: &str) -> Result<Self, String> {
+        let mut stream = TcpStream::connect_timeout(addr, TIMEOUT).map_err(|e| e.to_string())?;
+        stream
+            .set_read_timeout(Some(TIMEOUT))
+            .map_err(|e| e.to_string())?;
+        write!(
+            stream,
+            "GET {} HTTP/1.1\r\nHost: {}\r\nUpgrade: websocket\r\nConnection: Upgrade\r\n\
+             Sec-WebSocket-Key: {}\r\nSec-WebSocket-Version: 13\r\n\r\n",
+            path, addr, KEY
+        ).map_err(|e| e.to_string())?;
+        // Read the response head a byte at a time, so no frame data is
+        // consumed with it.
+        let mut head = Vec::new();
+        while !head.ends_with(b"\r\n\r\n") {
+            let mut byte = [0u8; 1];
+            stream
+                .read_exact(&mut byte)
+                .map_err(|e| format!("reading handshake: {}", e))?;
+            head.push(byte[0]);
+        }
+        let head = String::from_utf8_lossy(&head).to_lowercase();
+        if !head.starts_with("http/1.1 101") {
+            return Err(format!("handshake refused: {}", head.lines().next().unwrap_or("")));
+        }
+        if !head.contains(&format!("sec-websocket-accept: {}", ACCEPT.to_lowercase())) {
+            return Err("handshake returned the wrong Sec-WebSocket-Accept".to_owned());
+        }
+        Ok(Client { stream })
+    }
+
+    fn send(&mut self, opcode: u8, payload: &[u8]) -> io::Result<()> {
+        let mut frame = vec![0x80 | opcode];
+        // Client frames must be masked.
+        match payload.len() {
+            len if len < 126 => frame.push(0x80 | len as u8),
+            len if len <= 0xffff => {
+                frame.push(0x80 | 126);
+                frame.write_u16::<BigEndian>(len as u16)?;
+            }
+            len => {
+                frame.push(0x80 | 127);
+                frame.write_u64::<BigEndian>(len as u64)?;
+            }
+        }
+        let mask: [u8; 4] = rand::random();
+        frame.extend_from_slice(&mask);
+        frame.extend(payload.iter().enumerate().map(|(i, b)| b ^ mask[i % 4]));
+        self.stream.write_all(&frame)
+    }
+
+    fn recv(&mut self) -> io::Result<Frame> {
+        let first = self.stream.read_u8()?;
+        let second = self.stream.read_u8()?;
+        let len = match u64::from(second & 0x7f) {
+            126 => u64::from(self.stream.read_u16::<BigEndian>()?),
+            127 => self.stream.read_u64::<BigEndian>()?,
+            len => len,
+        };
+        let mut mask = [0u8; 4];
+        if second & 0x80 != 0 {
+            self.stream.read_exact(&mut mask)?;
+        }
+        let mut payload = vec![0u8; len as usize];
+        self.stream.read_exact(&mut payload)?;
+        for (i, b) in payload.iter_mut().enumerate() {
+            *b ^= mask[i % 4];
+        }
+        Ok(match first & 0x0f {
+            OP_TEXT => Frame::Text(String::from_utf8_lossy(&payload).into_owned()),
+            OP_CLOSE => Frame::Close,
+            _ => Frame::Other,
+        })
+    }
+
+    /// The next relayed text message, skipping server control messages.
+    fn recv_text(&mut self) -> Result<String, String> {
+        loop {
+            match self.recv().map_err(|e| format!("waiting for a message: {}", e))? {
+                Frame::Text(text) => {
+                    let control = serde_json::from_str::<Value>(&text)
+                        .ok()
+                        .map_or(false, |v| v.get("control").is_some());
+                    if !control {
+                        return Ok(text);
+                    }
+                }
+                Frame::Close => return Err("connection closed unexpectedly".to_owned()),
+                Frame::Other => {}
+            }
+        }
+    }
+
+    fn wait_close(&mut self) -> Result<(), String> {
+        loop {
+            match self.recv().map_err(|e| format!("waiting for close: {}", e))? {
+                Frame::Close => return Ok(()),
+                _ => {}
+            }
+        }
+    }
+}
+
+fn expect(what: &str, got: String, want: &str) -> Result<(), String> {
+    if got == want {
+        println!("ok - {}", what);
+        Ok(())
+    } else {
+        Err(format!("{}: expected {:?}, got {:?}", what, want, got))
+    }
+}
+
+/// The scripted pairing.
+fn pairing(addr: &SocketAddr) -> Result<(), String> {
+    let mut alice = Client::connect(addr, "/v1/ws/")?;
+    let link = alice.recv_text()?;
+    println!("ok - created channel {}", link);
+    let mut bob = Client::connect(addr, &link)?;
+    expect("joined channel", bob.recv_text()?, &link)?;
+
+    let err = |e: io::Error| e.to_string();
+    alice.send(OP_TEXT, b"selftest ping").map_err(err)?;
+    expect("relayed to joiner", bob.recv_text()?, "selftest ping")?;
+    bob.send(OP_TEXT, b"selftest pong").map_err(err)?;
+    expect("relayed to initiator", alice.recv_text()?, "selftest pong")?;
+
+    alice.send(OP_CLOSE, &[0x03, 0xe8]).map_err(err)?;
+    bob.wait_close()?;
+    println!("ok - close reached the joiner");
+    Ok(())
+}
+
+fn free_port() -> io::Result<u16> {
+    Ok(TcpListener::bind("127.0.0.1:0")?.local_addr()?.port())
+}
+
+fn start_server(port: u16) -> io::Result<Child> {
+    Command::new(env::current_exe()?)
+        .env("PAIR_HOSTNAME", "127.0.0.1")
+        .env("PAIR_PORT", port.to_string())
+        .env("PAIR_LISTEN", "")
+        .env("PAIR_REQUIRE_API_KEY", "false")
+        .stdout(Stdio::null())
+        .stderr(Stdio::null())
+        .spawn()
+}
+
+fn wait_for(addr: &SocketAddr) -> bool {
+    for _ in 0..50 {
+        if TcpStream::connect_timeout(addr, TIMEOUT).is_ok() {
+            return true;
+        }
+        thread::sleep(Duration::from_millis(100));
+    }
+    false
+}
+
+/// Run the self test, returning the process exit code.
+pub fn run() -> i32 {
+    let port = match free_port() {
+        Ok(port) => port,
+        Err(e) => {
+            eprintln!("selftest: no free port: {}", e);
+            return 1;
+        }
+    };
+    let addr: SocketAddr = ([127, 0, 0, 1], port).into();
+    let mut server = match start_server(port) {
+        Ok(server) => server,
+        Err(e) => {
+            eprintln!("selftest: could not start the server: {}", e);
+            return 1;
+        }
+    };
+    let result = if wait_for(&addr) {
+        pairing(&addr)
+    } else {
+        Err("server did not start listening".to_owned())
+    };
+    server.kill().ok();
+    server.wait().ok();
+    match result {
+        Ok(()) => {
+            println!("selftest passed");
+            0
+        }
+        Err(e) => {
+            eprintln!("selftest failed: {}", e);
+            1
+        }
+    }
+}
@@ -4,22 +4,96 @@
 
 // use std::sync::{Arc, Mutex};
 use std::cell::RefCell;
-use std::collections::HashMap;
-use std::time::Instant;
+use std::collections::{BTreeSet, HashMap, HashSet, VecDeque};
+use std::mem;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
 
-use actix::prelude::{Actor, Context, Handler, Recipient};
+use actix::actors::signal;
+use actix::prelude::{
+    Actor, Addr, AsyncContext, Context, Handler, Message, MessageResult, Recipient, System,
+};
+use cadence::{Counted, Histogrammed, StatsdClient};
 use rand::{self, Rng, ThreadRng};
+use serde_json::Value;
 use uuid::Uuid;
 
+use apikey::now;
+use audit::{AuditLog, AuditRecord};
+use chunking::{self, Transfers};
+use features::FeatureFlags;
 use logging::MozLogger;
-use perror;
+use metrics;
+use pattern::{Pattern, Step};
+use perror::{self, ErrorEnvelope};
+use pool::{Pool, Recycle};
+use protocol::{self, Capabilities, ClientControl, RelayEnvelope, Role, ServerControl};
+use replica;
+use sealed::{self, Sealed};
 use settings::Settings;
+use snapshot;
+use store::{self, ChannelStore};
+use throttle::Throttle;
 
 pub const EOL:&'static str = "\x04";
 
+/// How often a persistent channel store writes out channels whose
+/// counters have changed.
+const STORE_FLUSH_INTERVAL: Duration = Duration::from_secs(5);
+
 /// Chat server sends this messages to session
-#[derive(Message)]
-pub struct TextMessage(pub String);
+#[derive(Debug, Message)]
+pub struct TextMessage {
+    pub text: String,
+    /// When a relayed frame was received from its sender. `None` for
+    /// messages originated by the server.
+    pub received: Option<Instant>,
+    /// Why the connection is being closed, if this is an `EOL` sent
+    /// because of an error.
+    pub error: Option<ErrorEnvelope>,
+    /// Written in order with relayed frames, rather than ahead of them, as
+    /// presence events are.
+    pub ordered: bool,
+}
+
+impl TextMessage {
+    pub fn new<T: Into<String>>(text: T) -> Self {
+        Self {
+            text: text.into(),
+            received: None,
+            error: None,
+            ordered: false,
+        }
+    }
+
+    pub fn relayed<T: Into<String>>(text: T, received: Instant) -> Self {
+        Self {
+            text: text.into(),
+            received: Some(received),
+            error: None,
+            ordered: true,
+        }
+    }
+
+    /// A presence event, which mustn't overtake what the peer it is about
+    /// sent before it.
+    pub fn presence<T: Into<String>>(notice: T) -> Self {
+        Self {
+            ordered: true,
+            ..Self::new(notice)
+        }
+    }
+
+    /// Close the connection, reporting `error` to the client.
+    pub fn close(error: ErrorEnvelope) -> Self {
+        Self {
+            text: EOL.to_owned(),
+            received: None,
+            error: Some(error),
+            ordered: false,
+        }
+    }
+}
 
 /// Message for chat server communications
 /// Individual session identifier
@@ -27,11 +101,28 @@ pub type SessionId = usize;
 pub type ChannelId = usize;
 
 /// New chat session is created
-#[derive(Message)]
-#[rtype(SessionId)]
 pub struct Connect {
     pub addr: Recipient<TextMessage>,
     pub channel: Uuid,
+    /// language for user facing text sent to this session
+    pub lang: &'static str,
+    /// reconnect token presented by the client, to reclaim a held slot
+    pub resume: Option<String>,
+    /// reconnect token issued to this connection
+    pub token: String,
+    /// handoff token presented by the client, to take over a slot
+    pub handoff: Option<String>,
+    /// exchange pattern to enforce, if this connection creates the channel
+    pub pattern: Option<Pattern>,
+    /// trace ID from the edge proxy, for log correlation
+    pub trace: Option<String>,
+    /// refuse the connection if it would create a channel, as the node is
+    /// over its latency budget
+    pub shed: bool,
+}
+
+impl Message for Connect {
+    type Result = Result<SessionId, perror::HandlerErrorKind>;
 }
 
 /// Session is disconnected
@@ -41,8 +132,15 @@ pub struct Disconnect {
     pub id: SessionId,
 }
 
-/// Send message to specific channel
+/// Session's connection dropped without being closed
 #[derive(Message)]
+pub struct Dropped {
+    pub channel: Uuid,
+    pub id: SessionId,
+}
+
+/// Send message to specific channel
+#[derive(Clone, Debug, Message)]
 pub struct ClientMessage {
     /// Id of the client session
     pub id: SessionId,
@@ -50,58 +148,589 @@ pub struct ClientMessage {
     pub msg: String,
     /// channel name
     pub channel: Uuid,
+    /// when the message was received from the client
+    pub received: Instant,
+    /// transfer, index and data size, if this is a chunk of a chunked
+    /// transfer
+    pub chunk: Option<(String, u32, usize)>,
+}
+
+/// A frame held back from relaying, with its payload sealed under its
+/// channel's key (see `sealed`).
+#[derive(Clone, Debug)]
+pub struct HeldMessage {
+    /// the frame, without its payload
+    msg: ClientMessage,
+    payload: Sealed,
+}
+
+impl HeldMessage {
+    fn seal(key: &mut sealed::Key, mut msg: ClientMessage) -> Self {
+        let payload = key.seal(mem::replace(&mut msg.msg, String::new()));
+        HeldMessage { msg, payload }
+    }
+
+    fn open(self, key: &sealed::Key) -> ClientMessage {
+        let mut msg = self.msg;
+        msg.msg = key.open(self.payload);
+        msg
+    }
+
+    fn len(&self) -> usize {
+        self.payload.len()
+    }
+}
+
+/// Channel lifecycle events. Registry mutations are replicated to a
+/// standby node, and all events are published to admin subscribers.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(tag = "event", rename_all = "snake_case")]
+pub enum ChannelEvent {
+    Created { channel: Uuid, session: SessionId, ts: u64 },
+    Joined { channel: Uuid, session: SessionId, ts: u64 },
+    Closed { channel: Uuid, ts: u64 },
+    Rejected { channel: Uuid, reason: String, ts: u64 },
+}
+
+impl ChannelEvent {
+    pub fn channel(&self) -> &Uuid {
+        match self {
+            ChannelEvent::Created { channel, .. }
+            | ChannelEvent::Joined { channel, .. }
+            | ChannelEvent::Closed { channel, .. }
+            | ChannelEvent::Rejected { channel, .. } => channel,
+        }
+    }
+
+    /// Does this event change the channel registry?
+    pub fn is_mutation(&self) -> bool {
+        match self {
+            ChannelEvent::Rejected { .. } => false,
+            _ => true,
+        }
+    }
+}
+
+/// Publish an event that happened outside the channel server (e.g. an
+/// upgrade request refused by the HTTP handler)
+#[derive(Message)]
+pub struct Publish(pub ChannelEvent);
+
+/// Subscribe to all channel lifecycle events
+#[derive(Message)]
+pub struct Subscribe {
+    pub addr: Recipient<TextMessage>,
+}
+
+/// Replicated registry mutations received from the primary node, and
+/// its channels if it is resyncing
+#[derive(Message)]
+pub struct ApplyReplica {
+    pub events: Vec<ChannelEvent>,
+    pub channels: Vec<(Uuid, snapshot::SavedChannel)>,
+}
+
+/// Copy every channel, for a resync of the standby.
+pub struct ReplicaSync;
+
+impl Message for ReplicaSync {
+    type Result = Vec<(Uuid, snapshot::SavedChannel)>;
+}
+
+/// An administrator did something, to be logged and audited
+#[derive(Debug, Message, Serialize)]
+pub struct AdminAction {
+    pub action: String,
+    /// who, as far as the admin API can tell
+    pub principal: String,
+    /// client address, unless in relay only mode
+    pub from: Option<String>,
+    /// what was acted on
+    pub resource: Value,
+    pub ts: u64,
+}
+
+/// A secret setting was rotated
+#[derive(Message)]
+pub struct SecretRotated {
+    pub name: String,
+    pub value: String,
+}
+
+/// What a standby knows about a channel that is live on the primary.
+#[derive(Debug)]
+pub struct ReplicaChannel {
+    pub started: Instant,
+    pub participants: usize,
+    /// The channel as of the last resync, with reconnect tokens.
+    pub saved: Option<snapshot::SavedChannel>,
+    /// When the primary last said anything about the channel.
+    pub seen: Instant,
+}
+
+/// Channels restored from a snapshot, to be rejoined
+#[derive(Message)]
+pub struct Restore(pub Vec<(Uuid, ChannelState)>);
+
+/// Attach a read-only debugging tap to a channel
+#[derive(Message)]
+pub struct AddTap {
+    /// chosen by the tap, to detach it by
+    pub id: SessionId,
+    pub channel: Uuid,
+    pub addr: Recipient<TextMessage>,
+    /// Include frame contents, not just metadata
+    pub payload: bool,
+}
+
+/// Detach a tap whose stream has closed
+#[derive(Message)]
+pub struct RemoveTap {
+    pub id: SessionId,
+    pub channel: Uuid,
+}
+
+/// A debugging tap attached to a channel.
+pub struct Tap {
+    id: SessionId,
+    addr: Recipient<TextMessage>,
+    payload: bool,
+}
+
+/// Report a relayed frame to a channel's taps, dropping any that have gone
+/// away.
+fn tap_frame(taps: &mut Vec<Tap>, from: SessionId, to: SessionId, message: &str) {
+    let ts = protocol::now_ms();
+    taps.retain(|tap| {
+        let mut frame = json!({
+            "from": from,
+            "to": to,
+            "size": message.len(),
+            "ts": ts,
+        });
+        if tap.payload {
+            frame["payload"] = json!(message);
+        }
+        tap.addr.do_send(TextMessage::new(frame.to_string())).is_ok()
+    });
+}
+
+/// How a channel wraps the frames it relays.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct Framing<'a> {
+    /// number frames (`sequence_frames`)
+    pub sequence: bool,
+    /// stamp frames with when they were received (`stamp_frames`)
+    pub stamp: bool,
+    /// name the relaying node (`hop_frames`)
+    pub node: Option<&'a str>,
+}
+
+impl<'a> Framing<'a> {
+    /// `message`, as relayed to the peers as the channel's `seq`th frame,
+    /// having been received at `received`.
+    pub fn encode(&self, message: &str, seq: u64, received: Instant) -> String {
+        if !self.sequence && !self.stamp {
+            return message.to_owned();
+        }
+        RelayEnvelope {
+            seq,
+            ts: if self.stamp {
+                Some(protocol::now_ms() - metrics::micros(received.elapsed()) / 1000)
+            } else {
+                None
+            },
+            node: self.node.map(|node| node.into()),
+            data: message.into(),
+        }.to_text()
+    }
+}
+
+/// How a channel with `features` frames what it relays, on the node called
+/// `node`.
+fn framing<'a>(settings: &Settings, node: &'a str, features: &HashSet<String>) -> Framing<'a> {
+    let hop = settings.hop_frames || features.contains("hop_frames");
+    Framing {
+        sequence: settings.sequence_frames || features.contains("sequence_frames"),
+        stamp: settings.stamp_frames || features.contains("stamp_frames") || hop,
+        node: if hop { Some(node) } else { None },
+    }
+}
+
+/// Move `channel` from `was` to `active` in the LRU index.
+fn mark_active(
+    lru: &mut BTreeSet<(Instant, Uuid)>,
+    channel: &Uuid,
+    was: Option<Instant>,
+    active: Instant,
+) {
+    if let Some(was) = was {
+        lru.remove(&(was, *channel));
+    }
+    lru.insert((active, *channel));
+}
+
+/// Copy channels, all of them if `None`, for migration to another node.
+/// They stay here, frozen, until they are `Moved` or `Thaw`ed: joins are
+/// refused and frames held, so the copy stays true.
+pub struct Export(pub Option<Vec<Uuid>>);
+
+impl Message for Export {
+    type Result = Vec<(Uuid, snapshot::SavedChannel)>;
+}
+
+/// Channels now live on the node at `to`: send their participants there.
+#[derive(Message)]
+pub struct Moved {
+    pub channels: Vec<Uuid>,
+    pub to: String,
+}
+
+/// Channels, all of them if `None`, could not be migrated: let them carry
+/// on, relaying the frames held meanwhile.
+#[derive(Message)]
+pub struct Thaw(pub Option<Vec<Uuid>>);
+
+/// The node is stopping other than on a signal, e.g. an embedder shutting
+/// it down: save or close the channels, as on `SIGTERM`.
+pub struct Stop;
+
+impl Message for Stop {
+    type Result = ();
+}
+
+/// Reads the node's settings again, from wherever they first came from,
+/// for `SIGHUP`.
+pub type Reload = Arc<Fn() -> Result<Settings, String> + Send + Sync>;
+
+/// Stop (or resume) taking new channels, for a deploy. Existing channels
+/// carry on until they finish.
+#[derive(Message)]
+pub struct Drain(pub bool);
+
+/// Request a summary of the server's state
+pub struct Status;
+
+impl Message for Status {
+    type Result = ServerStatus;
 }
 
-#[derive(Hash, Eq, PartialEq, Clone, Debug)]
+#[derive(Clone, Debug, Serialize)]
+pub struct ServerStatus {
+    /// Is this node refusing new channels?
+    pub draining: bool,
+    pub channels: usize,
+    pub sessions: usize,
+}
+
+/// A channel and its participants.
+#[derive(Clone, Debug, Default)]
+pub struct ChannelState {
+    pub participants: HashMap<SessionId, Channel>,
+    /// Sequence number of the last frame relayed on the channel.
+    pub seq: u64,
+    /// Feature flags that were on when the channel was created.
+    pub features: HashSet<String>,
+    /// Messages relayed on the channel, by all senders.
+    pub messages: u64,
+    /// Octets relayed on the channel, by all senders.
+    pub bytes: u64,
+    /// Bandwidth limit, if `channel_rate` is set.
+    pub throttle: Option<Throttle>,
+    /// Declared message exchange pattern, in strict mode.
+    pub pattern: Option<Pattern>,
+    /// Chunked transfers in progress.
+    pub transfers: Transfers,
+    /// Frames waiting for room under the bandwidth limit, oldest first.
+    pub backlog: VecDeque<HeldMessage>,
+    pub backlog_bytes: usize,
+    /// Is a timer set to relay the backlog?
+    pub backlog_waiting: bool,
+    /// When a frame was last relayed, or the channel was created.
+    pub last_active: Option<Instant>,
+    /// Seals the frames held for the channel.
+    pub key: sealed::Key,
+}
+
+impl Recycle for ChannelState {
+    /// Participants are emptied separately, to reuse their buffers too.
+    fn recycle(&mut self) {
+        self.participants.clear();
+        self.seq = 0;
+        self.features.clear();
+        self.messages = 0;
+        self.bytes = 0;
+        self.throttle = None;
+        self.pattern = None;
+        self.transfers.clear();
+        self.backlog.clear();
+        self.backlog_bytes = 0;
+        self.backlog_waiting = false;
+        self.last_active = None;
+        self.key = sealed::Key::default();
+    }
+}
+
+#[derive(Eq, PartialEq, Clone, Debug)]
 pub struct Channel {
     pub id: ChannelId,
+    pub role: Role,
+    /// language for user facing text sent to this participant
+    pub lang: &'static str,
     pub started: Instant,
     pub msg_count: u8,
     pub data_exchanged: usize,
+    /// The most recent client message IDs sent by this participant.
+    pub recent_ids: VecDeque<String>,
+    /// Token that lets this participant reclaim their slot after a drop.
+    pub token: String,
+    /// When the connection dropped, if the slot is being held for a
+    /// reconnect.
+    pub held: Option<Instant>,
+    /// One-time token that lets another device take over this slot.
+    pub handoff: Option<String>,
+    /// Trace ID of this participant's connection.
+    pub trace: Option<String>,
+    /// What this participant declared it supports, in its `hello`.
+    pub capabilities: Option<Capabilities>,
 }
 
 /// `ChannelServer` manages chat channels and responsible for coordinating chat
 /// session. implementation is super primitive
 pub struct ChannelServer {
     // collections of sessions grouped by channel
-    channels: HashMap<Uuid, HashMap<ChannelId, Channel>>,
+    channels: Box<ChannelStore>,
+    // channels by when they last relayed a frame, oldest first
+    lru: BTreeSet<(Instant, Uuid)>,
     // individual connections
     sessions: HashMap<SessionId, Recipient<TextMessage>>,
     rng: RefCell<ThreadRng>,
     log: MozLogger,
     pub settings: RefCell<Settings>,
+    // what this node calls itself in relay envelopes
+    node: String,
+    // forwards registry mutations to the standby node, if one is configured
+    replicator: Option<Addr<replica::Replicator>>,
+    // records events and channel summaries in Postgres, if configured
+    audit: Option<AuditLog>,
+    // channels live on the primary, as known to this (standby) node
+    replicated: HashMap<Uuid, ReplicaChannel>,
+    // refusing new channels while existing ones finish
+    draining: bool,
+    // channels on their way to another node, with the frames held until
+    // they get there
+    migrating: HashMap<Uuid, VecDeque<HeldMessage>>,
+    // debugging taps attached to channels
+    taps: HashMap<Uuid, Vec<Tap>>,
+    // admin lifecycle event subscribers
+    subscribers: Vec<Recipient<TextMessage>>,
+    metrics: StatsdClient,
+    // feature flag rollout, reloaded on SIGHUP
+    flags: FeatureFlags,
+    // where SIGHUP reloads the settings from; process signals are only
+    // handled if set
+    reload: Option<Reload>,
+    // channels still to be checked by eviction sweeps, this round
+    unswept: Vec<Uuid>,
+    // closed channels' states, and their participants' message ID
+    // buffers, kept for reuse
+    states: Pool<ChannelState>,
+    recent_ids: Pool<VecDeque<String>>,
+    // this server's share of the registry, when it is sharded
+    shard: usize,
+    shards: usize,
 }
 
 impl Default for ChannelServer {
     fn default() -> ChannelServer {
+        ChannelServer::new(Settings::new().unwrap(), MozLogger::default())
+    }
+}
+
+impl ChannelServer {
+    /// A channel server with the node's `settings`, logging to `log`, so
+    /// that it shares the node's output, format and levels.
+    pub fn new(settings: Settings, log: MozLogger) -> Self {
+        let channels = store::from_settings(&settings, &log).expect("Could not open channel_store");
+        let lru = channels
+            .ids()
+            .into_iter()
+            .filter_map(|id| {
+                channels
+                    .get(&id)
+                    .and_then(|state| state.last_active)
+                    .map(|active| (active, id))
+            })
+            .collect();
         ChannelServer {
-            channels: HashMap::new(),
+            channels,
+            lru,
             sessions: HashMap::new(),
             rng: RefCell::new(rand::thread_rng()),
-            log: MozLogger::default(),
-            settings: RefCell::new(Settings::new().unwrap()),
+            metrics: metrics::metrics_from_settings(&settings, &log),
+            flags: FeatureFlags::parse(&settings.feature_flags),
+            reload: None,
+            log,
+            node: settings.node_name(),
+            settings: RefCell::new(settings),
+            replicator: None,
+            audit: None,
+            replicated: HashMap::new(),
+            draining: false,
+            migrating: HashMap::new(),
+            taps: HashMap::new(),
+            subscribers: Vec::new(),
+            unswept: Vec::new(),
+            states: Pool::default(),
+            recent_ids: Pool::default(),
+            shard: 0,
+            shards: 1,
+        }
+    }
+
+    /// Shard `index` of `count` of a sharded registry (see `shard`).
+    pub fn shard(index: usize, count: usize, settings: Settings, log: MozLogger) -> Self {
+        ChannelServer {
+            shard: index,
+            shards: count.max(1),
+            ..Self::new(settings, log)
+        }
+    }
+
+    /// Handle the process' signals: reload the feature flags with `reload`
+    /// on `SIGHUP`, and save or close the channels on `SIGTERM`, `SIGINT`
+    /// and `SIGQUIT`. Only a node that owns its process should.
+    pub fn signals(mut self, reload: Reload) -> Self {
+        self.reload = Some(reload);
+        self
+    }
+
+    /// Record a lifecycle event.
+    fn emit(&mut self, event: ChannelEvent) {
+        if !self.subscribers.is_empty() {
+            if let Ok(text) = serde_json::to_string(&event) {
+                self.subscribers
+                    .retain(|addr| addr.do_send(TextMessage::new(text.as_str())).is_ok());
+            }
+        }
+        if let Some(ref audit) = self.audit {
+            if !audit.record(AuditRecord::event(&event)) {
+                self.metrics.incr("audit.dropped").ok();
+            }
+        }
+        if let Some(ref replicator) = self.replicator {
+            if event.is_mutation() {
+                replicator.do_send(replica::Replicate(event));
+            }
         }
     }
-}
 
-impl ChannelServer {
     /// Send message to all users in the channel except skip_id
+    ///
+    /// All frames for a channel pass through here, on the single actor that
+    /// owns the channel (in a cluster, the owning node), which makes this
+    /// the channel's sequencing point. Returns whether the frame reached
+    /// any peer.
     fn send_message(
         &mut self,
         channel: &Uuid,
         message: &str,
         skip_id: SessionId,
-    ) -> Result<(), perror::HandlerError> {
-        if let Some(participants) = self.channels.get_mut(channel) {
+        received: Instant,
+    ) -> Result<bool, perror::HandlerError> {
+        let mut reached = false;
+        if let Some(state) = self.channels.get_mut(channel) {
+            let participants = &mut state.participants;
             // show's over, everyone go home.
             if message == EOL {
                 for (id, info) in participants {
                     if let Some(addr) = self.sessions.get(id) {
-                        addr.do_send(TextMessage(EOL.to_owned())).unwrap_or(());
+                        addr.do_send(TextMessage::new(EOL)).unwrap_or(());
                     }
                 }
                 return Err(perror::HandlerErrorKind::ShutdownErr.into());
             }
+            if self.settings.borrow().initiator_first
+                && participants.values().all(|p| p.msg_count == 0)
+                && participants
+                    .get(&skip_id)
+                    .map_or(false, |p| p.role != Role::Initiator)
+            {
+                // Refuse, but don't close the channel; the joiner may
+                // simply have been early.
+                if let (Some(addr), Some(sender)) =
+                    (self.sessions.get(&skip_id), participants.get(&skip_id))
+                {
+                    let err = protocol::error(&perror::HandlerErrorKind::RoleErr, sender.lang);
+                    addr.do_send(TextMessage::new(err.to_text())).unwrap_or(());
+                }
+                return Ok(false);
+            }
+            if self.settings.borrow().require_json && !protocol::is_json(message) {
+                // Most likely corruption; let the sender know now rather
+                // than have the peer choke on it.
+                if let (Some(addr), Some(sender)) =
+                    (self.sessions.get(&skip_id), participants.get(&skip_id))
+                {
+                    let err = protocol::error(&perror::HandlerErrorKind::NotJsonErr, sender.lang);
+                    addr.do_send(TextMessage::new(err.to_text())).unwrap_or(());
+                }
+                return Ok(false);
+            }
+            let window = self.settings.borrow().dedup_window;
+            if window > 0 {
+                if let Some(message_id) = protocol::message_id(message) {
+                    if let Some(sender) = participants.get_mut(&skip_id) {
+                        if sender.recent_ids.contains(&message_id) {
+                            // Most likely a client retry after an
+                            // ambiguous send; don't relay it twice.
+                            if let Some(addr) = self.sessions.get(&skip_id) {
+                                let notice = ServerControl::Duplicate { message_id }.to_text();
+                                addr.do_send(TextMessage::new(notice)).unwrap_or(());
+                            }
+                            return Ok(false);
+                        }
+                        sender.recent_ids.push_back(message_id);
+                        while sender.recent_ids.len() > window {
+                            sender.recent_ids.pop_front();
+                        }
+                    }
+                }
+            }
+            let mut finished = false;
+            if let Some(ref mut pattern) = state.pattern {
+                match pattern.step(skip_id, message) {
+                    Ok(Step::Continue) => {}
+                    Ok(Step::Finished) => finished = true,
+                    Err(deviation) => {
+                        info!(
+                            self.log.log,
+                            "Channel {} broke its declared pattern ({}), closing",
+                            channel,
+                            deviation
+                        );
+                        return Err(perror::HandlerErrorKind::PatternErr.into());
+                    }
+                }
+            }
+            let (max_messages, max_bytes) = {
+                let settings = self.settings.borrow();
+                (settings.channel_max_messages, settings.channel_max_bytes)
+            };
+            state.messages += 1;
+            state.bytes += message.len() as u64;
+            mark_active(&mut self.lru, channel, state.last_active, received);
+            state.last_active = Some(received);
+            if (max_messages > 0 && state.messages > max_messages)
+                || (max_bytes > 0 && state.bytes > max_bytes)
+            {
+                // Pairing channels aren't a general purpose data pipe.
+                info!(self.log.log, "Channel {} exceeded its quota, closing", channel);
+                return Err(perror::HandlerErrorKind::QuotaErr.into());
+            }
+            state.seq += 1;
+            let frame = framing(&self.settings.borrow(), &self.node, &state.features)
+                .encode(message, state.seq, received);
             for party in participants.values_mut() {
                 if party.started.elapsed().as_secs() > self.settings.borrow().timeout {
                     info!(self.log.log, "Connection {} expired, closing", channel);
@@ -129,106 +758,947 @@ impl ChannelServer {
                     return Err(perror::HandlerErrorKind::XSMessageErr.into());
                 }
                 if party.id != skip_id {
-                    if let Some(addr) = self.sessions.get(&party.id) {
-                        addr.do_send(TextMessage(message.to_owned())).unwrap_or(());
+                    let delivered = match self.sessions.get(&party.id) {
+                        Some(addr) => addr
+                            .do_send(TextMessage::relayed(frame.as_str(), received))
+                            .is_ok(),
+                        // dropped, and their slot is held for a reconnect.
+                        None => false,
+                    };
+                    reached |= delivered;
+                    if !delivered {
+                        // The peer went away mid-relay. Don't let the
+                        // frame vanish silently.
+                        debug!(
+                            self.log.log,
+                            "Undeliverable frame on {} for [{}]",
+                            channel,
+                            party.id
+                        );
+                        self.metrics.incr("relay.dead_letter").ok();
+                        if let Some(sender) = self.sessions.get(&skip_id) {
+                            let notice = ServerControl::Undeliverable {
+                                size: message.len(),
+                            }.to_text();
+                            sender.do_send(TextMessage::new(notice)).unwrap_or(());
+                        }
+                    }
+                    if let Some(taps) = self.taps.get_mut(channel) {
+                        tap_frame(taps, skip_id, party.id, message);
                     }
                 } else {
                 }
             }
+            if finished {
+                // The declared exchange is complete.
+                return Err(perror::HandlerErrorKind::ShutdownErr.into());
+            }
         }
-        Ok(())
+        Ok(reached)
     }
 
-    /// Kill a channel and terminate all participants.
-    ///
-    /// This sends a ^D message to each participant, which forces the connection closed.
-    fn shutdown(&mut self, channel: &Uuid) {
-        if let Some(participants) = self.channels.get_mut(channel) {
-            for (id, info) in participants {
-                if let Some(addr) = self.sessions.get(&id) {
-                    // send a control message to force close
-                    addr.do_send(TextMessage(EOL.to_owned())).unwrap_or(());
+    /// Relay a frame, or hold it in the channel's backlog if the channel is
+    /// over its bandwidth.
+    fn relay(&mut self, msg: ClientMessage, ctx: &mut Context<Self>) {
+        let channel = msg.channel;
+        let throttled = self.channels
+            .get(&channel)
+            .map_or(false, |state| state.throttle.is_some());
+        if !throttled {
+            self.deliver(msg);
+            return;
+        }
+        let mut waiting = true;
+        if let Some(state) = self.channels.get_mut(&channel) {
+            let limit = state.throttle.as_ref().map_or(0, |t| t.backlog_limit());
+            if msg.msg != EOL && state.backlog_bytes + msg.msg.len() > limit {
+                debug!(
+                    self.log.log,
+                    "Backlog full on {}, dropping frame from [{}]",
+                    channel,
+                    msg.id
+                );
+                self.metrics.incr("relay.throttled").ok();
+                if let Some(addr) = self.sessions.get(&msg.id) {
+                    let notice = ServerControl::Throttled {
+                        size: msg.msg.len(),
+                    }.to_text();
+                    addr.do_send(TextMessage::new(notice)).unwrap_or(());
                 }
-                self.sessions.remove(&id);
+                if let Some((ref transfer, index, size)) = msg.chunk {
+                    state.transfers.unaccept(msg.id, transfer, index, size);
+                }
+                return;
             }
+            state.backlog_bytes += msg.msg.len();
+            state.backlog.push_back(HeldMessage::seal(&mut state.key, msg));
+            waiting = state.backlog_waiting;
+        }
+        if !waiting {
+            self.drain_backlog(channel, ctx);
         }
     }
-}
 
-/// Make actor from `ChannelServer`
-impl Actor for ChannelServer {
-    /// We are going to use simple Context, we just need ability to communicate
-    /// with other actors.
-    type Context = Context<Self>;
-}
+    /// Relay as much of a channel's backlog as its bandwidth allows, and
+    /// set a timer for the rest.
+    fn drain_backlog(&mut self, channel: Uuid, ctx: &mut Context<Self>) {
+        loop {
+            let msg = match self.channels.get_mut(&channel) {
+                Some(state) => {
+                    let size = match state.backlog.front() {
+                        Some(held) => held.len(),
+                        None => return,
+                    };
+                    if let Some(ref mut throttle) = state.throttle {
+                        if let Err(wait) = throttle.admit(size, Instant::now()) {
+                            state.backlog_waiting = true;
+                            ctx.run_later(wait, move |act, ctx| {
+                                if let Some(state) = act.channels.get_mut(&channel) {
+                                    state.backlog_waiting = false;
+                                }
+                                act.drain_backlog(channel, ctx);
+                            });
+                            return;
+                        }
+                    }
+                    state.backlog_bytes -= size;
+                    match state.backlog.pop_front() {
+                        Some(held) => held.open(&state.key),
+                        None => return,
+                    }
+                }
+                None => return,
+            };
+            self.deliver(msg);
+        }
+    }
 
-/// Handler for Connect message.
-///
-/// Register new session and assign unique id to this session
-impl Handler<Connect> for ChannelServer {
-    type Result = SessionId;
+    /// Relay a frame now, closing the channel if it breaks the channel's
+    /// limits.
+    fn deliver(&mut self, msg: ClientMessage) {
+        match self.send_message(&msg.channel, msg.msg.as_str(), msg.id, msg.received) {
+            Ok(reached) => {
+                self.channels.touch(&msg.channel);
+                if let Some((transfer, index, size)) = msg.chunk {
+                    if reached {
+                        self.chunk_relayed(&msg.channel, msg.id, transfer, index);
+                    } else if let Some(state) = self.channels.get_mut(&msg.channel) {
+                        // Not relayed, so not acknowledged: the sender may
+                        // send it again.
+                        state.transfers.unaccept(msg.id, &transfer, index, size);
+                    }
+                }
+            }
+            Err(err) => {
+                // A requested shutdown isn't an error worth reporting.
+                let reason = match err.kind() {
+                    perror::HandlerErrorKind::ShutdownErr => None,
+                    kind => Some(kind.clone()),
+                };
+                self.shutdown(&msg.channel, reason.as_ref())
+            }
+        }
+    }
 
-    fn handle(&mut self, msg: Connect, ctx: &mut Context<Self>) -> Self::Result {
-        let session_id = self.rng.borrow_mut().gen::<SessionId>();
-        let new_chan = Channel {
-            // register session with random id
-            id: session_id.clone(),
-            started: Instant::now(),
-            msg_count: 0,
-            data_exchanged: 0,
+    /// Check a chunk of a chunked transfer against the transfer limits,
+    /// telling the sender if it is refused.
+    fn accept_chunk(
+        &mut self,
+        channel: &Uuid,
+        from: SessionId,
+        transfer: &str,
+        index: u32,
+        count: u32,
+        size: usize,
+    ) -> bool {
+        let limits = {
+            let settings = self.settings.borrow();
+            chunking::Limits {
+                max_size: settings.max_transfer_size,
+                window: settings.chunk_window,
+                concurrent: settings.max_transfers,
+            }
+        };
+        let state = match self.channels.get_mut(channel) {
+            Some(state) => state,
+            None => return false,
+        };
+        let reason = match state.transfers.accept(from, transfer, index, count, size, limits) {
+            Ok(()) => return true,
+            Err(reason) => reason,
         };
-        self.sessions.insert(new_chan.id, msg.addr.clone());
         debug!(
             self.log.log,
-            "New connection to {}: [{}]",
-            &msg.channel.simple(),
-            &new_chan.id
+            "Refused chunk {} of {} from [{}]: {}",
+            index,
+            transfer,
+            from,
+            reason
         );
-
-        let chan_id = &msg.channel.simple();
+        if let (Some(addr), Some(sender)) = (self.sessions.get(&from), state.participants.get(&from))
         {
-            if !self.channels.contains_key(&msg.channel) {
-                debug!(
-                    self.log.log,
-                    "Creating new channel set {}: [{}]",
-                    chan_id,
-                    &new_chan.id,
-                );
-                self.channels.insert(msg.channel, HashMap::new());
-            } else {
-                debug!(
-                    self.log.log,
-                    "Adding session [{}] to existing channel set {}",
-                    &new_chan.id,
-                    chan_id
-                )
+            let err = perror::HandlerErrorKind::ChunkErr.localized(
+                sender.lang,
+                Some(json!({ "transfer": transfer, "reason": reason })),
+            );
+            addr.do_send(TextMessage::new(ServerControl::Error(err).to_text()))
+                .unwrap_or(());
+        }
+        false
+    }
+
+    /// A chunk has been relayed; let its sender send another.
+    fn chunk_relayed(&mut self, channel: &Uuid, from: SessionId, transfer: String, index: u32) {
+        if let Some(state) = self.channels.get_mut(channel) {
+            state.transfers.relayed(from, &transfer);
+        }
+        if let Some(addr) = self.sessions.get(&from) {
+            let ack = ServerControl::ChunkAck { transfer, index }.to_text();
+            addr.do_send(TextMessage::new(ack)).unwrap_or(());
+        }
+    }
+
+    /// Handle a control message from a participant.
+    ///
+    /// Application level pings and pongs are passed on to the other
+    /// participants, stamped with the server time, so that clients can
+    /// measure round trips through the whole relay path. They don't count
+    /// towards any of the channel's limits.
+    fn control(&mut self, channel: &Uuid, from: SessionId, control: ClientControl) {
+        let server_ts = protocol::now_ms();
+        let forward = match control {
+            ClientControl::Ping { nonce } => ServerControl::Ping { nonce, server_ts },
+            ClientControl::Pong { nonce } => ServerControl::Pong {
+                nonce,
+                server_ts,
+                peer: true,
+            },
+            ClientControl::Handoff {} => {
+                self.issue_handoff(channel, from);
+                return;
             }
-            // we've already checked and created this, so calling unwrap 
-            // should be safe. Creating here hits lifetime exceptions as
-            // well.
-            let group = self.channels.get_mut(&msg.channel).unwrap();
-            if group.len() >= self.settings.borrow().max_clients.into() {
-                info!(
-                    self.log.log,
-                    "Too many connections requested for channel {}", 
-                    chan_id);
-                self.sessions.remove(&new_chan.id);
-                return 0;
+            ClientControl::Hello(capabilities) => {
+                self.hello(channel, from, capabilities);
+                return;
+            }
+            // relayed like any other frame, by the ClientMessage handler.
+            ClientControl::Chunk { .. } => return,
+        };
+        let peers: Vec<SessionId> = self.channels
+            .get(channel)
+            .map(|state| {
+                state
+                    .participants
+                    .keys()
+                    .filter(|id| **id != from)
+                    .cloned()
+                    .collect()
+            })
+            .unwrap_or_default();
+        if peers.is_empty() {
+            // Nobody to bounce a ping off; answer it ourselves.
+            if let ServerControl::Ping { nonce, server_ts } = forward {
+                if let Some(addr) = self.sessions.get(&from) {
+                    let pong = ServerControl::Pong {
+                        nonce,
+                        server_ts,
+                        peer: false,
+                    };
+                    addr.do_send(TextMessage::new(pong.to_text())).unwrap_or(());
+                }
+            }
+            return;
+        }
+        let text = forward.to_text();
+        for id in peers {
+            if let Some(addr) = self.sessions.get(&id) {
+                addr.do_send(TextMessage::new(text.as_str())).unwrap_or(());
             }
-            group.insert(session_id.clone(), new_chan);
-            debug!(self.log.log, "channel {}: [{:?}]", chan_id, group,);
         }
-        // tell the client what their channel is.
-        &msg.addr.do_send(TextMessage(format!("/v1/ws/{}", chan_id)));
-
-        // send id back
-        session_id
     }
-}
 
-/// Handler for Disconnect message.
-impl Handler<Disconnect> for ChannelServer {
-    type Result = ();
+    /// Record a participant's declared capabilities. Once every
+    /// participant has declared theirs, tell them all what they have in
+    /// common with each other and the server.
+    fn hello(&mut self, channel: &Uuid, from: SessionId, capabilities: Capabilities) {
+        let server = {
+            let settings = self.settings.borrow();
+            Capabilities {
+                encodings: protocol::ENCODINGS.iter().map(|e| e.to_string()).collect(),
+                max_frame_size: Some(settings.max_message_size),
+                resume: settings.reconnect_grace > 0,
+            }
+        };
+        let (common, ids) = {
+            let state = match self.channels.get_mut(channel) {
+                Some(state) => state,
+                None => return,
+            };
+            if let Some(party) = state.participants.get_mut(&from) {
+                party.capabilities = Some(capabilities);
+            }
+            let declared: Option<Vec<&Capabilities>> = state
+                .participants
+                .values()
+                .map(|party| party.capabilities.as_ref())
+                .collect();
+            let mut declared = match declared {
+                // wait for the peer, and for everyone to say hello
+                Some(ref declared) if declared.len() > 1 => declared.clone(),
+                _ => return,
+            };
+            // extensions are between the clients
+            let extensions = Capabilities::common_extensions(&declared);
+            declared.push(&server);
+            let mut common = Capabilities::common(&declared);
+            common.extensions = extensions;
+            let ids: Vec<SessionId> = state.participants.keys().cloned().collect();
+            (common, ids)
+        };
+        self.channels.save(channel);
+        let text = ServerControl::Capabilities(common).to_text();
+        for id in ids {
+            if let Some(addr) = self.sessions.get(&id) {
+                addr.do_send(TextMessage::new(text.as_str())).unwrap_or(());
+            }
+        }
+    }
+
+    /// Tell a new or returning participant what their channel is.
+    fn welcome(&self, addr: &Recipient<TextMessage>, channel: &Uuid, role: Role, token: &str) {
+        let link = format!("/v1/ws/{}", channel.simple());
+        addr.do_send(TextMessage::new(link.as_str())).unwrap_or(());
+        let reconnect = if self.settings.borrow().reconnect_grace > 0 {
+            Some(token.to_owned())
+        } else {
+            None
+        };
+        addr.do_send(TextMessage::new(
+            ServerControl::Joined {
+                channel: link,
+                role,
+                reconnect,
+                server_ts: protocol::now_ms(),
+            }.to_text(),
+        )).unwrap_or(());
+    }
+
+    /// Put a participant whose connection dropped back in their held slot,
+    /// if the connection presented their reconnect token.
+    fn reattach(&mut self, msg: &Connect) -> Option<SessionId> {
+        let resume = match msg.resume {
+            Some(ref resume) => resume,
+            None => return None,
+        };
+        let (id, role) = {
+            let state = self.channels.get_mut(&msg.channel)?;
+            let (id, party) = state
+                .participants
+                .iter_mut()
+                .find(|&(_, ref party)| party.held.is_some() && &party.token == resume)?;
+            party.held = None;
+            // a token is only good once.
+            party.token = msg.token.clone();
+            (*id, party.role)
+        };
+        self.channels.save(&msg.channel);
+        self.sessions.insert(id, msg.addr.clone());
+        info!(
+            self.log.log,
+            "Session [{}] reconnected to {}",
+            id,
+            msg.channel.simple()
+        );
+        let notice = ServerControl::PeerReconnected { role }.to_text();
+        if let Some(state) = self.channels.get(&msg.channel) {
+            for other in state.participants.keys().filter(|other| **other != id) {
+                if let Some(addr) = self.sessions.get(other) {
+                    addr.do_send(TextMessage::presence(notice.as_str())).unwrap_or(());
+                }
+            }
+        }
+        self.welcome(&msg.addr, &msg.channel, role, &msg.token);
+        Some(id)
+    }
+
+    /// Mint a handoff token for a participant, replacing any earlier one.
+    fn issue_handoff(&mut self, channel: &Uuid, from: SessionId) {
+        let token = Uuid::new_v4().simple().to_string();
+        match self.channels
+            .get_mut(channel)
+            .and_then(|state| state.participants.get_mut(&from))
+        {
+            Some(party) => party.handoff = Some(token.clone()),
+            None => return,
+        }
+        self.channels.save(channel);
+        if let Some(addr) = self.sessions.get(&from) {
+            let reply = ServerControl::HandoffToken { token }.to_text();
+            addr.do_send(TextMessage::new(reply)).unwrap_or(());
+        }
+    }
+
+    /// Move a participant's slot to a new connection, if it presented
+    /// their handoff token. The old connection is closed.
+    fn take_over(&mut self, msg: &Connect) -> Option<SessionId> {
+        let handoff = match msg.handoff {
+            Some(ref handoff) => handoff,
+            None => return None,
+        };
+        let new_id = self.rng.borrow_mut().gen::<SessionId>();
+        let (old_id, old_lang, role) = {
+            let state = self.channels.get_mut(&msg.channel)?;
+            let old_id = *state
+                .participants
+                .iter()
+                .find(|&(_, ref party)| party.handoff.as_ref() == Some(handoff))?
+                .0;
+            // Re-key the slot, so anything still in flight from the old
+            // connection is ignored.
+            let mut party = state.participants.remove(&old_id)?;
+            let old_lang = party.lang;
+            party.id = new_id;
+            party.lang = msg.lang;
+            party.token = msg.token.clone();
+            party.held = None;
+            party.handoff = None;
+            party.trace = msg.trace.clone();
+            // the new device says its own hello.
+            party.capabilities = None;
+            let role = party.role;
+            state.participants.insert(new_id, party);
+            if let Some(ref mut pattern) = state.pattern {
+                pattern.rekey(old_id, new_id);
+            }
+            (old_id, old_lang, role)
+        };
+        self.channels.save(&msg.channel);
+        if let Some(old) = self.sessions.remove(&old_id) {
+            let err = perror::HandlerErrorKind::HandedOffErr.localized(old_lang, None);
+            old.do_send(TextMessage::close(err)).unwrap_or(());
+        }
+        self.sessions.insert(new_id, msg.addr.clone());
+        info!(
+            self.log.log,
+            "Session [{}] on {} handed off to [{}]",
+            old_id,
+            msg.channel.simple(),
+            new_id
+        );
+        let notice = ServerControl::PeerHandedOff { role }.to_text();
+        if let Some(state) = self.channels.get(&msg.channel) {
+            for other in state.participants.keys().filter(|other| **other != new_id) {
+                if let Some(addr) = self.sessions.get(other) {
+                    addr.do_send(TextMessage::presence(notice.as_str())).unwrap_or(());
+                }
+            }
+        }
+        self.welcome(&msg.addr, &msg.channel, role, &msg.token);
+        Some(new_id)
+    }
+
+    /// Is `id` (still) a participant in `channel`?
+    fn is_participant(&self, channel: &Uuid, id: SessionId) -> bool {
+        self.channels
+            .get(channel)
+            .map_or(false, |state| state.participants.contains_key(&id))
+    }
+
+    /// The channel that has gone longest without relaying a frame.
+    fn least_recently_used(&self) -> Option<Uuid> {
+        self.lru.iter().next().map(|&(_, channel)| channel)
+    }
+
+    /// Add a channel to the registry, and to the LRU index.
+    fn insert_channel(&mut self, channel: Uuid, state: ChannelState) {
+        if let Some(active) = state.last_active {
+            self.lru.insert((active, channel));
+        }
+        self.channels.insert(channel, state);
+    }
+
+    /// Close a channel to reclaim its memory, counting why in the
+    /// `channel.evicted.<reason>` metric.
+    fn evict(&mut self, channel: &Uuid, reason: &str) {
+        info!(self.log.log, "Evicting channel {} ({})", channel.simple(), reason);
+        self.metrics
+            .incr(&format!("channel.evicted.{}", reason))
+            .ok();
+        self.shutdown(channel, Some(&perror::HandlerErrorKind::ExpiredErr));
+    }
+
+    /// Evict channels that have outlived `timeout`, or relayed nothing for
+    /// `channel_max_idle` seconds.
+    ///
+    /// Each sweep checks up to `gc_batch` channels, carrying on from where
+    /// the last sweep stopped, so a sweep over many channels doesn't hold
+    /// up relaying.
+    fn sweep(&mut self) {
+        let start = Instant::now();
+        let (timeout, max_idle, batch) = {
+            let settings = self.settings.borrow();
+            (settings.timeout, settings.channel_max_idle, settings.gc_batch)
+        };
+        if self.unswept.is_empty() {
+            self.unswept = self.channels.ids();
+        }
+        let take = if batch == 0 {
+            self.unswept.len()
+        } else {
+            batch.min(self.unswept.len())
+        };
+        let scan: Vec<Uuid> = self.unswept.drain(..take).collect();
+        let scanned = scan.len();
+        let mut evicted = Vec::new();
+        for channel in scan {
+            let state = match self.channels.get(&channel) {
+                Some(state) => state,
+                None => continue,
+            };
+            if state
+                .participants
+                .values()
+                .any(|party| party.started.elapsed().as_secs() > timeout)
+            {
+                evicted.push((channel, "expired"));
+            } else if max_idle > 0 && state
+                .last_active
+                .map_or(false, |active| active.elapsed().as_secs() > max_idle)
+            {
+                evicted.push((channel, "idle"));
+            }
+        }
+        let reaped = evicted.len();
+        for (channel, reason) in evicted {
+            self.evict(&channel, reason);
+        }
+        // Taps are dropped as their channel closes or their stream does;
+        // catch any left on a channel that has gone, and any left behind
+        // empty.
+        let channels = &self.channels;
+        self.taps.retain(|channel, taps| {
+            if !channels.contains(channel) {
+                for tap in taps.drain(..) {
+                    tap.addr.do_send(TextMessage::new(EOL)).unwrap_or(());
+                }
+            }
+            !taps.is_empty()
+        });
+        self.metrics
+            .histogram("gc.duration_us", metrics::micros(start.elapsed()))
+            .ok();
+        self.metrics.count("gc.scanned", scanned as i64).ok();
+        self.metrics.count("gc.reaped", reaped as i64).ok();
+        debug!(
+            self.log.log,
+            "Swept {} channels, evicted {}", scanned, reaped;
+            "scanned" => scanned,
+            "reaped" => reaped
+        );
+    }
+
+    /// Close any of the `restored` channels whose participants haven't
+    /// all reconnected within `grace`.
+    fn await_rejoin(&mut self, restored: Vec<Uuid>, grace: Duration, ctx: &mut Context<Self>) {
+        if restored.is_empty() {
+            return;
+        }
+        info!(self.log.log, "Restored {} channels", restored.len());
+        ctx.run_later(grace, move |act, _| {
+            for channel in restored {
+                let abandoned = act.channels.get(&channel).map_or(false, |state| {
+                    state
+                        .participants
+                        .values()
+                        .any(|party| party.held.map_or(false, |held| held.elapsed() >= grace))
+                });
+                if abandoned {
+                    act.shutdown(&channel, None);
+                }
+            }
+        });
+    }
+
+    /// `channels`, as they would be saved, leaving out any that are gone.
+    fn saved(&self, channels: Vec<Uuid>) -> Vec<(Uuid, snapshot::SavedChannel)> {
+        channels
+            .into_iter()
+            .filter_map(|channel| {
+                self.channels
+                    .get(&channel)
+                    .map(|state| (channel, snapshot::SavedChannel::new(state)))
+            })
+            .collect()
+    }
+
+    /// On a promoted standby, bring back a channel the primary had, with
+    /// its participants' slots held, so that they can reclaim them with
+    /// their reconnect or handoff tokens.
+    fn restore_replica(&mut self, channel: &Uuid, ctx: &mut Context<Self>) {
+        if self.channels.contains(channel) {
+            return;
+        }
+        let saved = match self.replicated.get_mut(channel).and_then(|r| r.saved.take()) {
+            Some(saved) => saved,
+            None => return,
+        };
+        let (rate, grace) = {
+            let settings = self.settings.borrow();
+            (settings.channel_rate, settings.reconnect_grace)
+        };
+        self.replicated.remove(channel);
+        self.insert_channel(*channel, saved.restore(Instant::now(), rate));
+        self.await_rejoin(vec![*channel], Duration::from_secs(grace), ctx);
+    }
+
+    /// Write this shard's channels to its snapshot, if snapshots are on.
+    fn snapshot(&self) {
+        let base = self.settings.borrow().snapshot_path.clone();
+        if base.is_empty() {
+            return;
+        }
+        match snapshot::save(&base, self.shard, &*self.channels) {
+            Ok(count) => info!(self.log.log, "Saved {} channels to snapshot", count),
+            Err(err) => error!(self.log.log, "Could not write snapshot: {}", err),
+        }
+    }
+
+    /// The node is being stopped; channels can outlive the process. Fail
+    /// readiness meanwhile, so no new traffic is routed here.
+    fn stop(&mut self) {
+        // A drain ends with the node stopping; its clients were meant to
+        // move on.
+        let drained = self.draining;
+        self.draining = true;
+        self.channels.flush();
+        self.snapshot();
+        self.close_all(drained)
+    }
+
+    /// Close every channel as the node stops, if it was draining or the
+    /// channels won't outlive it, so that participants can go to another
+    /// node straight away.
+    fn close_all(&mut self, drained: bool) {
+        let survives = {
+            let settings = self.settings.borrow();
+            !settings.snapshot_path.is_empty()
+                || settings.channel_store.trim().starts_with("sqlite:")
+        };
+        if survives && !drained {
+            return;
+        }
+        for channel in self.channels.ids() {
+            self.shutdown(&channel, Some(&perror::HandlerErrorKind::ShutdownErr));
+        }
+    }
+
+    /// Keep a closed channel's state, and its participants' buffers, for
+    /// reuse by new channels.
+    fn recycle(&mut self, mut state: ChannelState) {
+        let max = self.settings.borrow().state_pool_size;
+        for (_, participant) in state.participants.drain() {
+            self.recent_ids.give(participant.recent_ids, max);
+        }
+        self.states.give(state, max);
+    }
+
+    /// Kill a channel and terminate all participants.
+    ///
+    /// This sends a ^D message to each participant, which forces the connection closed.
+    /// If the channel is being killed because of an error, participants are
+    /// told about it and the connection is closed with the error's code.
+    fn shutdown(&mut self, channel: &Uuid, reason: Option<&perror::HandlerErrorKind>) {
+        if let Some(state) = self.channels.get_mut(channel) {
+            // Summarize the channel, with the participants' trace IDs so
+            // it can be found from the edge proxy's traces.
+            let traces: Vec<&str> = state
+                .participants
+                .values()
+                .filter_map(|party| party.trace.as_ref().map(|t| t.as_str()))
+                .collect();
+            info!(
+                self.log.log,
+                "Channel {} closed", channel.simple();
+                "participants" => state.participants.len(),
+                "messages" => state.messages,
+                "bytes" => state.bytes,
+                "traces" => traces.join(",")
+            );
+            if let Some(ref audit) = self.audit {
+                let summary = AuditRecord {
+                    ts: now(),
+                    channel: Some(*channel),
+                    kind: "summary".to_owned(),
+                    detail: json!({
+                        "participants": state.participants.len(),
+                        "messages": state.messages,
+                        "bytes": state.bytes,
+                        "traces": traces,
+                        "reason": reason.map(|kind| kind.to_string()),
+                    }),
+                };
+                if !audit.record(summary) {
+                    self.metrics.incr("audit.dropped").ok();
+                }
+            }
+            for (id, info) in &state.participants {
+                if let Some(addr) = self.sessions.get(&id) {
+                    // send a control message to force close
+                    let eol = match reason {
+                        Some(kind) => TextMessage::close(kind.localized(info.lang, None)),
+                        None => TextMessage::new(EOL),
+                    };
+                    addr.do_send(eol).unwrap_or(());
+                }
+                self.sessions.remove(&id);
+            }
+        }
+        self.migrating.remove(channel);
+        if let Some(taps) = self.taps.remove(channel) {
+            for tap in taps {
+                tap.addr.do_send(TextMessage::new(EOL)).unwrap_or(());
+            }
+        }
+        if let Some(state) = self.channels.remove(channel) {
+            if let Some(active) = state.last_active {
+                self.lru.remove(&(active, *channel));
+            }
+            self.recycle(state);
+            self.emit(ChannelEvent::Closed {
+                channel: channel.clone(),
+                ts: now(),
+            });
+        }
+    }
+}
+
+/// Make actor from `ChannelServer`
+impl Actor for ChannelServer {
+    /// We are going to use simple Context, we just need ability to communicate
+    /// with other actors.
+    type Context = Context<Self>;
+
+    fn started(&mut self, ctx: &mut Self::Context) {
+        if self.reload.is_some() {
+            // reload feature flags on SIGHUP
+            let signals = System::current().registry().get::<signal::ProcessSignals>();
+            signals.do_send(signal::Subscribe(ctx.address().recipient()));
+        }
+
+        let grace = {
+            let settings = self.settings.borrow();
+            if self.shards > 1 {
+                debug!(
+                    self.log.log,
+                    "Started channel server shard {} of {}",
+                    self.shard + 1,
+                    self.shards
+                );
+            }
+            if settings.gc_interval > 0 {
+                ctx.run_interval(Duration::from_secs(settings.gc_interval), |act, _| {
+                    act.sweep()
+                });
+            }
+            if !settings.standby_url.is_empty() {
+                self.replicator = Some(
+                    replica::Replicator::new(
+                        &settings.standby_url,
+                        &settings.standby_token,
+                        ctx.address().recipient(),
+                        self.log.clone(),
+                    ).start(),
+                );
+            }
+            ctx.run_interval(STORE_FLUSH_INTERVAL, |act, _| act.channels.flush());
+            // Forget channels a primary has stopped telling us about.
+            ctx.run_interval(replica::RESYNC_INTERVAL, |act, _| {
+                act.replicated
+                    .retain(|_, replica| replica.seen.elapsed() < replica::REPLICA_TTL)
+            });
+            match AuditLog::start(&settings, &self.log) {
+                Ok(audit) => self.audit = audit,
+                Err(err) => error!(self.log.log, "Could not start the audit log: {}", err),
+            }
+            Duration::from_secs(settings.reconnect_grace)
+        };
+
+        // Channels restored by a persistent store are waiting for their
+        // participants to reconnect, as if they had all just dropped.
+        let restored = self.channels.ids();
+        self.await_rejoin(restored, grace, ctx);
+    }
+}
+
+/// Handler for Connect message.
+///
+/// Register new session and assign unique id to this session
+impl Handler<Connect> for ChannelServer {
+    type Result = Result<SessionId, perror::HandlerErrorKind>;
+
+    fn handle(&mut self, msg: Connect, ctx: &mut Context<Self>) -> Self::Result {
+        if self.migrating.contains_key(&msg.channel) {
+            // Whoever it is can follow the channel once it has moved.
+            self.metrics.incr("channel.refused.migrating").ok();
+            return Err(perror::HandlerErrorKind::UnavailableErr);
+        }
+        if msg.resume.is_some() || msg.handoff.is_some() {
+            self.restore_replica(&msg.channel, ctx);
+        }
+        if let Some(session_id) = self.take_over(&msg) {
+            return Ok(session_id);
+        }
+        if let Some(session_id) = self.reattach(&msg) {
+            return Ok(session_id);
+        }
+        if self.draining && !self.channels.contains(&msg.channel) {
+            self.metrics.incr("channel.refused.draining").ok();
+            return Err(perror::HandlerErrorKind::UnavailableErr);
+        }
+        if msg.shed && !self.channels.contains(&msg.channel) {
+            self.metrics.incr("channel.refused.shed").ok();
+            return Err(perror::HandlerErrorKind::UnavailableErr);
+        }
+        let session_id = self.rng.borrow_mut().gen::<SessionId>();
+        let mut new_chan = Channel {
+            // register session with random id
+            id: session_id.clone(),
+            role: Role::Joiner,
+            lang: msg.lang,
+            started: Instant::now(),
+            msg_count: 0,
+            data_exchanged: 0,
+            recent_ids: self.recent_ids.take(),
+            token: msg.token.clone(),
+            held: None,
+            handoff: None,
+            trace: msg.trace.clone(),
+            capabilities: None,
+        };
+        self.sessions.insert(new_chan.id, msg.addr.clone());
+        debug!(
+            self.log.log,
+            "New connection to {}: [{}]",
+            &msg.channel.simple(),
+            &new_chan.id
+        );
+
+        let chan_id = &msg.channel.simple();
+        let event;
+        let role = {
+            if !self.channels.contains(&msg.channel) {
+                // Each shard holds its share of the channels.
+                let max_channels = match self.settings.borrow().max_channels {
+                    0 => 0,
+                    max => (max + self.shards - 1) / self.shards,
+                };
+                if max_channels > 0 && self.channels.len() >= max_channels {
+                    if let Some(channel) = self.least_recently_used() {
+                        self.evict(&channel, "capacity");
+                    }
+                }
+                if let Some(replica) = self.replicated.remove(&msg.channel) {
+                    // This channel was live on the primary before we were
+                    // promoted. Keep its original clock running.
+                    info!(
+                        self.log.log,
+                        "Resuming replicated channel {} ({} participants)",
+                        chan_id,
+                        replica.participants
+                    );
+                    new_chan.started = replica.started;
+                }
+                debug!(
+                    self.log.log,
+                    "Creating new channel set {}: [{}]",
+                    chan_id,
+                    &new_chan.id,
+                );
+                self.metrics
+                    .incr(if self.states.is_empty() {
+                        "pool.allocated"
+                    } else {
+                        "pool.reused"
+                    })
+                    .ok();
+                let mut state = self.states.take();
+                state.features = self.flags.for_channel(&msg.channel);
+                state.pattern = msg.pattern.clone();
+                state.last_active = Some(Instant::now());
+                state.throttle = match self.settings.borrow().channel_rate {
+                    0 => None,
+                    rate => Some(Throttle::new(rate)),
+                };
+                self.insert_channel(msg.channel, state);
+                new_chan.role = Role::Initiator;
+                event = ChannelEvent::Created {
+                    channel: msg.channel.clone(),
+                    session: session_id,
+                    ts: now(),
+                };
+            } else {
+                debug!(
+                    self.log.log,
+                    "Adding session [{}] to existing channel set {}",
+                    &new_chan.id,
+                    chan_id
+                );
+                event = ChannelEvent::Joined {
+                    channel: msg.channel.clone(),
+                    session: session_id,
+                    ts: now(),
+                };
+            }
+            // we've already checked and created this, so calling unwrap 
+            // should be safe. Creating here hits lifetime exceptions as
+            // well.
+            let group = &mut self.channels.get_mut(&msg.channel).unwrap().participants;
+            if group.len() >= self.settings.borrow().max_clients.into() {
+                info!(
+                    self.log.log,
+                    "Too many connections requested for channel {}", 
+                    chan_id);
+                self.sessions.remove(&new_chan.id);
+                // Someone else trying to get into a pairing is worth
+                // knowing about.
+                let notice = ServerControl::JoinAttempted {}.to_text();
+                for id in group.keys() {
+                    if let Some(addr) = self.sessions.get(id) {
+                        addr.do_send(TextMessage::new(notice.as_str())).unwrap_or(());
+                    }
+                }
+                self.emit(ChannelEvent::Rejected {
+                    channel: msg.channel.clone(),
+                    reason: perror::HandlerErrorKind::XSConnectionErr.to_string(),
+                    ts: now(),
+                });
+                return Err(perror::HandlerErrorKind::XSConnectionErr);
+            }
+            let role = new_chan.role;
+            group.insert(session_id.clone(), new_chan);
+            debug!(self.log.log, "channel {}: [{:?}]", chan_id, group,);
+            // let everyone else know who has arrived.
+            let notice = ServerControl::PeerJoined { role }.to_text();
+            for id in group.keys().filter(|id| **id != session_id) {
+                if let Some(addr) = self.sessions.get(id) {
+                    addr.do_send(TextMessage::presence(notice.as_str())).unwrap_or(());
+                }
+            }
+            role
+        };
+        self.channels.save(&msg.channel);
+        self.emit(event);
+        // tell the client what their channel is.
+        self.welcome(&msg.addr, &msg.channel, role, &msg.token);
+
+        // send id back
+        Ok(session_id)
+    }
+}
+
+/// Handler for Disconnect message.
+impl Handler<Disconnect> for ChannelServer {
+    type Result = ();
 
     fn handle(&mut self, msg: Disconnect, ctx: &mut Context<Self>) {
         debug!(
@@ -237,7 +1707,73 @@ impl Handler<Disconnect> for ChannelServer {
             &msg.channel.simple(),
             &msg.id
         );
-        self.shutdown(&msg.channel);
+        if !self.is_participant(&msg.channel, msg.id) {
+            // handed off; the channel carries on without this connection.
+            return;
+        }
+        self.shutdown(&msg.channel, None);
+    }
+}
+
+/// Handler for Dropped message.
+///
+/// If `reconnect_grace` is set, the participant's slot is held for that
+/// long so a transient network blip doesn't end the pairing.
+impl Handler<Dropped> for ChannelServer {
+    type Result = ();
+
+    fn handle(&mut self, msg: Dropped, ctx: &mut Context<Self>) {
+        if !self.is_participant(&msg.channel, msg.id) {
+            return;
+        }
+        let grace = Duration::from_secs(self.settings.borrow().reconnect_grace);
+        if grace == Duration::from_secs(0) {
+            self.shutdown(&msg.channel, None);
+            return;
+        }
+        let role = match self.channels
+            .get_mut(&msg.channel)
+            .and_then(|state| state.participants.get_mut(&msg.id))
+        {
+            Some(party) => {
+                party.held = Some(Instant::now());
+                party.role
+            }
+            None => return,
+        };
+        self.channels.save(&msg.channel);
+        self.sessions.remove(&msg.id);
+        debug!(
+            self.log.log,
+            "Holding slot on {} for [{}]",
+            &msg.channel.simple(),
+            &msg.id
+        );
+        let notice = ServerControl::PeerDropped { role }.to_text();
+        if let Some(state) = self.channels.get(&msg.channel) {
+            for id in state.participants.keys() {
+                if let Some(addr) = self.sessions.get(id) {
+                    addr.do_send(TextMessage::presence(notice.as_str())).unwrap_or(());
+                }
+            }
+        }
+        let (channel, id) = (msg.channel, msg.id);
+        ctx.run_later(grace, move |act, _| {
+            let expired = act.channels
+                .get(&channel)
+                .and_then(|state| state.participants.get(&id))
+                .and_then(|party| party.held)
+                .map_or(false, |held| held.elapsed() >= grace);
+            if expired {
+                info!(
+                    act.log.log,
+                    "Session [{}] did not reconnect to {}, closing",
+                    id,
+                    channel.simple()
+                );
+                act.shutdown(&channel, None);
+            }
+        });
     }
 }
 
@@ -245,11 +1781,584 @@ impl Handler<Disconnect> for ChannelServer {
 impl Handler<ClientMessage> for ChannelServer {
     type Result = ();
 
-    fn handle(&mut self, msg: ClientMessage, _: &mut Context<Self>) {
-        if self.send_message(&msg.channel, msg.msg.as_str(), msg.id)
-            .is_err()
-        {
-            self.shutdown(&msg.channel)
+    fn handle(&mut self, mut msg: ClientMessage, ctx: &mut Context<Self>) {
+        // How long the frame waited in this shard's mailbox, behind every
+        // other channel's joins, relays and sweeps.
+        self.metrics
+            .histogram_with_tags("shard.queue_us", metrics::micros(msg.received.elapsed()))
+            .with_tag("shard", &self.shard.to_string())
+            .send();
+        if !self.is_participant(&msg.channel, msg.id) {
+            // left over from a connection that was handed off.
+            return;
+        }
+        if let Some(held) = self.migrating.get_mut(&msg.channel) {
+            if let Some(state) = self.channels.get_mut(&msg.channel) {
+                held.push_back(HeldMessage::seal(&mut state.key, msg));
+            }
+            return;
+        }
+        match protocol::client_control(&msg.msg) {
+            Some(ClientControl::Chunk {
+                transfer,
+                index,
+                count,
+                data,
+            }) => {
+                let size = data.len();
+                if self.accept_chunk(&msg.channel, msg.id, &transfer, index, count, size) {
+                    msg.chunk = Some((transfer, index, size));
+                    self.relay(msg, ctx);
+                }
+            }
+            Some(control) => self.control(&msg.channel, msg.id, control),
+            None if protocol::is_server_control(&msg.msg) => {
+                // Peers would take it as coming from the server.
+                self.metrics.incr("relay.spoofed").ok();
+                if let (Some(addr), Some(sender)) = (
+                    self.sessions.get(&msg.id),
+                    self.channels
+                        .get(&msg.channel)
+                        .and_then(|state| state.participants.get(&msg.id)),
+                ) {
+                    let details = json!({ "reason": "Frame passes for a server control message" });
+                    let err = perror::HandlerErrorKind::InvalidRequestErr
+                        .localized(sender.lang, Some(details));
+                    addr.do_send(TextMessage::new(ServerControl::Error(err).to_text()))
+                        .unwrap_or(());
+                }
+            }
+            None => self.relay(msg, ctx),
+        }
+    }
+}
+
+/// Handler for replicated registry mutations (standby side).
+impl Handler<ApplyReplica> for ChannelServer {
+    type Result = ();
+
+    fn handle(&mut self, msg: ApplyReplica, _: &mut Context<Self>) {
+        let current = now();
+        let seen = Instant::now();
+        // The resync is as of when it was read, which may be before some
+        // of the events.
+        for (channel, saved) in msg.channels {
+            self.replicated.insert(
+                channel,
+                ReplicaChannel {
+                    started: seen
+                        .checked_sub(Duration::from_secs(saved.age()))
+                        .unwrap_or(seen),
+                    participants: saved.participants(),
+                    saved: Some(saved),
+                    seen,
+                },
+            );
+        }
+        for event in msg.events {
+            match event {
+                ChannelEvent::Created { channel, ts, .. } => {
+                    let age = Duration::from_secs(current.saturating_sub(ts));
+                    self.replicated
+                        .entry(channel)
+                        .or_insert_with(|| ReplicaChannel {
+                            started: seen.checked_sub(age).unwrap_or(seen),
+                            participants: 1,
+                            saved: None,
+                            seen,
+                        })
+                        .seen = seen;
+                }
+                ChannelEvent::Joined { channel, .. } => {
+                    if let Some(replica) = self.replicated.get_mut(&channel) {
+                        replica.participants += 1;
+                        replica.seen = seen;
+                    }
+                }
+                ChannelEvent::Closed { channel, .. } => {
+                    self.replicated.remove(&channel);
+                }
+                ChannelEvent::Rejected { .. } => {}
+            }
+        }
+    }
+}
+
+/// Handler for Restore message.
+impl Handler<Restore> for ChannelServer {
+    type Result = ();
+
+    fn handle(&mut self, msg: Restore, ctx: &mut Context<Self>) {
+        let mut restored = Vec::new();
+        for (channel, state) in msg.0 {
+            // Don't clobber a channel a persistent store already restored.
+            if !self.channels.contains(&channel) {
+                self.insert_channel(channel, state);
+                restored.push(channel);
+            }
+        }
+        let grace = Duration::from_secs(self.settings.borrow().reconnect_grace);
+        self.await_rejoin(restored, grace, ctx);
+    }
+}
+
+/// Handler for Export message.
+impl Handler<Export> for ChannelServer {
+    type Result = MessageResult<Export>;
+
+    fn handle(&mut self, msg: Export, _: &mut Context<Self>) -> Self::Result {
+        let channels = msg.0.unwrap_or_else(|| self.channels.ids());
+        for channel in &channels {
+            if self.channels.contains(channel) {
+                self.migrating.entry(*channel).or_insert_with(VecDeque::new);
+            }
+        }
+        MessageResult(self.saved(channels))
+    }
+}
+
+/// Handler for Thaw message.
+impl Handler<Thaw> for ChannelServer {
+    type Result = ();
+
+    fn handle(&mut self, msg: Thaw, ctx: &mut Context<Self>) {
+        let channels = msg.0.unwrap_or_else(|| self.migrating.keys().cloned().collect());
+        for channel in channels {
+            if let Some(held) = self.migrating.remove(&channel) {
+                for frame in held {
+                    let msg = match self.channels.get(&channel) {
+                        Some(state) => frame.open(&state.key),
+                        None => break,
+                    };
+                    self.handle(msg, ctx);
+                }
+            }
+        }
+    }
+}
+
+/// Handler for ReplicaSync message.
+impl Handler<ReplicaSync> for ChannelServer {
+    type Result = MessageResult<ReplicaSync>;
+
+    fn handle(&mut self, _: ReplicaSync, _: &mut Context<Self>) -> Self::Result {
+        let channels = self.channels.ids();
+        MessageResult(self.saved(channels))
+    }
+}
+
+/// Handler for Moved message.
+///
+/// Each participant is closed with a `MigratedErr` telling them where to
+/// reconnect, with their reconnect token. Participants away from the
+/// channel are redirected there when they come back.
+impl Handler<Moved> for ChannelServer {
+    type Result = ();
+
+    fn handle(&mut self, msg: Moved, _: &mut Context<Self>) {
+        let kind = perror::HandlerErrorKind::MigratedErr;
+        for channel in &msg.channels {
+            // Frames held since the copy was taken never reach the new
+            // node; as after any drop, senders resend what went unanswered.
+            if let Some(held) = self.migrating.remove(channel) {
+                if !held.is_empty() {
+                    debug!(
+                        self.log.log,
+                        "Dropping {} frames held for {}",
+                        held.len(),
+                        channel.simple()
+                    );
+                }
+            }
+            if let Some(state) = self.channels.get(channel) {
+                for (id, party) in &state.participants {
+                    if let Some(addr) = self.sessions.remove(id) {
+                        let location = format!(
+                            "{}/v1/ws/{}?reconnect={}",
+                            msg.to,
+                            channel.simple(),
+                            party.token
+                        );
+                        let details = json!({ "location": location, "reconnect": party.token });
+                        addr.do_send(TextMessage::close(kind.localized(party.lang, Some(details))))
+                            .unwrap_or(());
+                    }
+                }
+            } else {
+                continue;
+            }
+            info!(
+                self.log.log,
+                "Channel {} migrated to {}",
+                channel.simple(),
+                msg.to
+            );
+            self.metrics.incr("channel.migrated").ok();
+            // Participants have been told; this closes what's left.
+            self.shutdown(channel, Some(&kind));
+        }
+    }
+}
+
+/// Handler for Drain message.
+impl Handler<Drain> for ChannelServer {
+    type Result = ();
+
+    fn handle(&mut self, msg: Drain, _: &mut Context<Self>) {
+        if msg.0 != self.draining {
+            info!(
+                self.log.log,
+                "Drain {} with {} channels live",
+                if msg.0 { "started" } else { "stopped" },
+                self.channels.len()
+            );
+        }
+        self.draining = msg.0;
+    }
+}
+
+/// Handler for Status message.
+impl Handler<Status> for ChannelServer {
+    type Result = MessageResult<Status>;
+
+    fn handle(&mut self, _: Status, _: &mut Context<Self>) -> Self::Result {
+        MessageResult(ServerStatus {
+            draining: self.draining,
+            channels: self.channels.len(),
+            sessions: self.sessions.len(),
+        })
+    }
+}
+
+/// Handler for AddTap message.
+impl Handler<AddTap> for ChannelServer {
+    type Result = ();
+
+    fn handle(&mut self, msg: AddTap, _: &mut Context<Self>) {
+        if !self.channels.contains(&msg.channel) {
+            // Nothing to tap, and nothing would ever close it.
+            debug!(self.log.log, "No channel {} to tap", msg.channel.simple());
+            msg.addr.do_send(TextMessage::new(EOL)).unwrap_or(());
+            return;
+        }
+        info!(
+            self.log.log,
+            "Tap attached to channel {} (payload: {})",
+            msg.channel.simple(),
+            msg.payload
+        );
+        self.taps.entry(msg.channel).or_insert_with(Vec::new).push(Tap {
+            id: msg.id,
+            addr: msg.addr,
+            payload: msg.payload,
+        });
+    }
+}
+
+/// Handler for RemoveTap message.
+impl Handler<RemoveTap> for ChannelServer {
+    type Result = ();
+
+    fn handle(&mut self, msg: RemoveTap, _: &mut Context<Self>) {
+        let empty = match self.taps.get_mut(&msg.channel) {
+            Some(taps) => {
+                taps.retain(|tap| tap.id != msg.id);
+                taps.is_empty()
+            }
+            None => false,
+        };
+        if empty {
+            self.taps.remove(&msg.channel);
         }
     }
 }
+
+/// Handler for Publish message.
+impl Handler<Publish> for ChannelServer {
+    type Result = ();
+
+    fn handle(&mut self, msg: Publish, _: &mut Context<Self>) {
+        self.emit(msg.0);
+    }
+}
+
+/// Handler for AdminAction message.
+impl Handler<AdminAction> for ChannelServer {
+    type Result = ();
+
+    fn handle(&mut self, msg: AdminAction, _: &mut Context<Self>) {
+        info!(
+            self.log.log,
+            "Admin {} by {}", msg.action, msg.principal;
+            "resource" => msg.resource.to_string(),
+            "from" => msg.from.clone().unwrap_or_default()
+        );
+        if let Some(ref audit) = self.audit {
+            if !audit.record(AuditRecord::admin(&msg)) {
+                self.metrics.incr("audit.dropped").ok();
+            }
+        }
+    }
+}
+
+/// Handler for SecretRotated message.
+impl Handler<SecretRotated> for ChannelServer {
+    type Result = ();
+
+    fn handle(&mut self, msg: SecretRotated, _: &mut Context<Self>) {
+        self.settings.borrow_mut().set_secret(&msg.name, &msg.value);
+        if msg.name == "standby_token" {
+            if let Some(ref replicator) = self.replicator {
+                replicator.do_send(replica::SetToken(msg.value));
+            }
+        }
+    }
+}
+
+/// Handler for Subscribe message.
+impl Handler<Subscribe> for ChannelServer {
+    type Result = ();
+
+    fn handle(&mut self, msg: Subscribe, _: &mut Context<Self>) {
+        info!(self.log.log, "Admin event subscriber attached");
+        self.subscribers.push(msg.addr);
+    }
+}
+
+/// Handler for process signals.
+impl Handler<signal::Signal> for ChannelServer {
+    type Result = ();
+
+    fn handle(&mut self, msg: signal::Signal, _: &mut Context<Self>) {
+        match msg.0 {
+            signal::SignalType::Hup => {
+                let reloaded = match self.reload {
+                    Some(ref reload) => reload(),
+                    None => return,
+                };
+                match reloaded {
+                    Ok(settings) => {
+                        info!(
+                            self.log.log,
+                            "Reloading feature flags: {:?}", settings.feature_flags
+                        );
+                        self.flags = FeatureFlags::parse(&settings.feature_flags);
+                    }
+                    Err(err) => error!(self.log.log, "Could not reload settings: {}", err),
+                }
+            }
+            signal::SignalType::Term | signal::SignalType::Int | signal::SignalType::Quit => {
+                self.stop()
+            }
+            _ => {}
+        }
+    }
+}
+
+/// Handler for Stop message.
+impl Handler<Stop> for ChannelServer {
+    type Result = ();
+
+    fn handle(&mut self, _: Stop, _: &mut Context<Self>) {
+        self.stop()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use slog::Level;
+
+    use super::*;
+
+    #[test]
+    fn test_shard_log_levels() {
+        let log = MozLogger::default();
+        log.levels.apply("warn,server=debug");
+        let server = ChannelServer::shard(0, 2, Settings::new().unwrap(), log.clone());
+        assert!(server.log.levels.enabled("channelserver::server", Level::Debug));
+        assert!(!server.log.levels.enabled("channelserver::session", Level::Info));
+        // as `/admin/log_level` does, on the node's logger
+        log.levels.set(Some("server"), Some(Level::Error));
+        assert!(!server.log.levels.enabled("channelserver::server", Level::Warning));
+    }
+
+    #[test]
+    fn test_apply_replica() {
+        let mut server =
+            ChannelServer::shard(0, 1, Settings::new().unwrap(), MozLogger::default());
+        let mut ctx = Context::new();
+        let (live, closed) = (Uuid::new_v4(), Uuid::new_v4());
+        let saved: snapshot::SavedChannel = serde_json::from_value(json!({
+            "participants": [{"id": 1, "role": "initiator", "lang": "en", "age": 30,
+                              "msg_count": 0, "data_exchanged": 0, "token": "t0",
+                              "handoff": null, "trace": null}],
+            "seq": 4, "features": [], "messages": 4, "bytes": 64,
+        })).unwrap();
+        let events = vec![
+            ChannelEvent::Created { channel: closed, session: 2, ts: now() },
+            ChannelEvent::Closed { channel: closed, ts: now() },
+        ];
+        server.handle(
+            ApplyReplica {
+                events,
+                channels: vec![(live, saved)],
+            },
+            &mut ctx,
+        );
+        assert!(!server.replicated.contains_key(&closed));
+        assert_eq!(server.replicated[&live].participants, 1);
+        assert!(server.replicated[&live].started.elapsed() >= Duration::from_secs(30));
+
+        // A creation stamped at the epoch (or before the monotonic clock
+        // began) is as old as can be, not a panic.
+        let ancient = Uuid::new_v4();
+        server.handle(
+            ApplyReplica {
+                events: vec![ChannelEvent::Created { channel: ancient, session: 3, ts: 0 }],
+                channels: vec![],
+            },
+            &mut ctx,
+        );
+        assert!(server.replicated.contains_key(&ancient));
+        server.replicated.remove(&ancient);
+
+        // Reclaiming a slot on the promoted standby brings the channel back,
+        // with the primary's reconnect tokens.
+        server.restore_replica(&live, &mut ctx);
+        assert!(!server.replicated.contains_key(&live));
+        let state = server.channels.get(&live).unwrap();
+        assert_eq!(state.seq, 4);
+        assert!(
+            state
+                .participants
+                .values()
+                .any(|party| party.token == "t0" && party.held.is_some())
+        );
+    }
+
+    #[test]
+    fn test_framing() {
+        let received = Instant::now();
+        assert_eq!(Framing::default().encode("hi", 3, received), "hi");
+        let framing = Framing {
+            sequence: true,
+            stamp: true,
+            node: Some("relay-0"),
+        };
+        let envelope: serde_json::Value =
+            serde_json::from_str(&framing.encode("hi", 3, received)).unwrap();
+        assert_eq!(envelope["seq"], json!(3));
+        assert_eq!(envelope["data"], json!("hi"));
+        assert_eq!(envelope["node"], json!("relay-0"));
+        assert!(envelope["ts"].is_u64());
+    }
+
+    #[test]
+    fn test_hop_frames() {
+        let server =
+            ChannelServer::shard(0, 1, Settings::new().unwrap(), MozLogger::default());
+        assert_eq!(server.node, server.settings.borrow().node_name());
+
+        let mut settings = server.settings.borrow().clone();
+        settings.hop_frames = false;
+        settings.sequence_frames = false;
+        settings.stamp_frames = false;
+        let mut features = HashSet::new();
+        let received = Instant::now();
+        assert_eq!(framing(&settings, "relay-0", &features).encode("hi", 1, received), "hi");
+        features.insert("hop_frames".to_owned());
+        let envelope: serde_json::Value = serde_json::from_str(
+            &framing(&settings, "relay-0", &features).encode("hi", 1, received),
+        ).unwrap();
+        assert_eq!(envelope["node"], json!("relay-0"));
+
+        features.clear();
+        settings.hop_frames = true;
+        let envelope: serde_json::Value = serde_json::from_str(
+            &framing(&settings, "relay-0", &features).encode("hi", 2, received),
+        ).unwrap();
+        assert_eq!(envelope["node"], json!("relay-0"));
+        assert_eq!(envelope["seq"], json!(2));
+    }
+
+    #[test]
+    fn test_least_recently_used() {
+        let mut server =
+            ChannelServer::shard(0, 1, Settings::new().unwrap(), MozLogger::default());
+        assert_eq!(server.least_recently_used(), None);
+        let start = Instant::now();
+        let channels: Vec<Uuid> = (0..3).map(|_| Uuid::new_v4()).collect();
+        for (i, channel) in channels.iter().enumerate() {
+            let mut state = ChannelState::default();
+            state.last_active = Some(start + Duration::from_secs(i as u64));
+            server.insert_channel(*channel, state);
+        }
+        assert_eq!(server.least_recently_used(), Some(channels[0]));
+
+        // The oldest relays a frame, and the next oldest goes.
+        let later = start + Duration::from_secs(10);
+        mark_active(&mut server.lru, &channels[0], Some(start), later);
+        assert_eq!(server.least_recently_used(), Some(channels[1]));
+        server.shutdown(&channels[1], None);
+        assert_eq!(server.least_recently_used(), Some(channels[2]));
+        assert_eq!(server.lru.len(), 2);
+    }
+
+    #[test]
+    fn test_migrating() {
+        let mut server =
+            ChannelServer::shard(0, 1, Settings::new().unwrap(), MozLogger::default());
+        let mut ctx = Context::new();
+        let channel = Uuid::new_v4();
+        let mut state = ChannelState::default();
+        state.participants.insert(
+            1,
+            Channel {
+                id: 1,
+                role: Role::Initiator,
+                lang: "en",
+                started: Instant::now(),
+                msg_count: 0,
+                data_exchanged: 0,
+                recent_ids: VecDeque::new(),
+                token: "t0".to_owned(),
+                held: None,
+                handoff: None,
+                trace: None,
+                capabilities: None,
+            },
+        );
+        server.insert_channel(channel, state);
+        let frame = || ClientMessage {
+            id: 1,
+            msg: "{}".to_owned(),
+            channel,
+            received: Instant::now(),
+            chunk: None,
+        };
+
+        let exported = server.handle(Export(None), &mut ctx).0;
+        assert_eq!(exported.len(), 1);
+        // Frozen: what the copy says stays true.
+        server.handle(frame(), &mut ctx);
+        assert_eq!(server.channels.get(&channel).unwrap().messages, 0);
+        assert_eq!(server.migrating[&channel].len(), 1);
+
+        // The migration failed, so the held frame is relayed after all.
+        server.handle(Thaw(None), &mut ctx);
+        assert!(server.migrating.is_empty());
+        assert_eq!(server.channels.get(&channel).unwrap().messages, 1);
+
+        server.handle(Export(Some(vec![channel])), &mut ctx);
+        server.handle(frame(), &mut ctx);
+        server.handle(
+            Moved {
+                channels: vec![channel],
+                to: "http://b:8000".to_owned(),
+            },
+            &mut ctx,
+        );
+        assert!(server.migrating.is_empty());
+        assert!(!server.channels.contains(&channel));
+    }
+}
@@ -1,20 +1,132 @@
-use std::time::Instant;
+use std::collections::VecDeque;
+use std::mem;
+use std::net::{IpAddr, SocketAddr};
+use std::sync::{Arc, RwLock};
+use std::time::{Duration, Instant};
 
 use actix::{
     fut, Actor, ActorContext, ActorFuture, Addr, AsyncContext, ContextFutureSpawner, Handler,
     Running, StreamHandler, WrapFuture,
 };
-use actix_web::ws;
+use actix_web::{ws, HttpRequest};
+use cadence::{Counted, Histogrammed, StatsdClient};
+use serde_json::Value;
 use uuid::Uuid;
 
+use apikey;
+use cluster::Cluster;
+use geo::GeoResolver;
+use i18n;
+use listener;
 use logging;
+use metrics;
+use pattern::Pattern;
+use protocol::{self, ServerControl};
+use perror::HandlerErrorKind;
+use ratelimit::Limiters;
+use ratelimiter::Key;
+use sealed::{self, Sealed};
+use secrets::Secrets;
 use server;
+use settings::Settings;
+use shard::Shards;
+use slo::SloTracker;
+
+/// Bytes of relayed data written to a connection before waiting for it to
+/// take them, so that control messages never wait behind more than this.
+const DATA_BATCH: usize = 64 * 1024;
+
+/// Frames waiting to be written to a connection: relayed data, and the
+/// presence events that must not overtake it, in order. Their text is
+/// sealed (see `sealed`) until they are taken to be written.
+#[derive(Debug, Default)]
+pub struct Outbox {
+    frames: VecDeque<(server::TextMessage, Sealed)>,
+    bytes: usize,
+    key: sealed::Key,
+}
+
+impl Outbox {
+    /// Queue `msg`, unless that would take the outbox over `limit` bytes.
+    pub fn push(&mut self, msg: server::TextMessage, limit: usize) -> bool {
+        if self.bytes + msg.text.len() > limit {
+            return false;
+        }
+        self.bytes += msg.text.len();
+        let mut msg = msg;
+        let text = self.key.seal(mem::replace(&mut msg.text, String::new()));
+        self.frames.push_back((msg, text));
+        true
+    }
+
+    /// The next frames to write, oldest first, until there are `max` bytes
+    /// of them.
+    pub fn batch(&mut self, max: usize) -> Vec<server::TextMessage> {
+        let mut batch = Vec::new();
+        let mut taken = 0;
+        while taken < max {
+            match self.frames.pop_front() {
+                Some((mut msg, text)) => {
+                    taken += text.len();
+                    msg.text = self.key.open(text);
+                    batch.push(msg);
+                }
+                None => break,
+            }
+        }
+        self.bytes -= taken;
+        batch
+    }
+}
 
 /// This is our websocket route state, this state is shared with all route
 /// instances via `HttpContext::state()`
+#[derive(Clone)]
 pub struct WsChannelSessionState {
-    pub addr: Addr<server::ChannelServer>,
+    pub shards: Shards,
     pub log: Addr<logging::MozLogger>,
+    pub settings: Arc<Settings>,
+    pub keys: Arc<RwLock<apikey::KeyStore>>,
+    pub cluster: Arc<RwLock<Cluster>>,
+    pub metrics: Arc<StatsdClient>,
+    pub limiters: Arc<Limiters>,
+    pub slo: Arc<SloTracker>,
+    pub languages: Arc<i18n::Negotiator>,
+    pub secrets: Secrets,
+    /// Set by an embedder to count connections by region.
+    pub geo: Option<Arc<GeoResolver>>,
+}
+
+/// The address of the client making the request.
+pub fn client_ip(req: &HttpRequest<WsChannelSessionState>) -> Option<IpAddr> {
+    if req.state().settings.trust_forwarded {
+        let remote = req.connection_info().remote().map(|r| r.to_owned());
+        if let Some(remote) = remote {
+            // may or may not include a port.
+            return remote
+                .parse::<SocketAddr>()
+                .map(|addr| addr.ip())
+                .or_else(|_| remote.parse::<IpAddr>())
+                .ok()
+                .map(listener::canonical_ip);
+        }
+    }
+    req.peer_addr().map(|addr| listener::canonical_ip(addr.ip()))
+}
+
+/// Where to reconnect instead, for a client turned away because this node
+/// is draining or overloaded, or whose channel closed as it stopped.
+pub fn hints(state: &WsChannelSessionState, kind: &HandlerErrorKind) -> Option<Value> {
+    let cluster = state.cluster.read().unwrap();
+    let alternates = cluster.alternates();
+    match kind {
+        HandlerErrorKind::UnavailableErr | HandlerErrorKind::ShutdownErr
+            if !alternates.is_empty() =>
+        {
+            Some(json!({ "alternates": alternates }))
+        }
+        _ => None,
+    }
 }
 
 pub struct WsChannelSession {
@@ -27,6 +139,29 @@ pub struct WsChannelSession {
     pub channel: Uuid,
     /// peer name
     pub name: Option<String>,
+    /// language for user facing text, from `Accept-Language`
+    pub lang: &'static str,
+    /// client address, for rate limiting
+    pub ip: Option<IpAddr>,
+    /// trace ID from the edge proxy, for log correlation
+    pub trace: Option<String>,
+    /// reconnect token presented by the client
+    pub resume: Option<String>,
+    /// reconnect token issued to this connection
+    pub token: String,
+    /// handoff token presented by the client
+    pub handoff: Option<String>,
+    /// declared exchange pattern, for strict mode
+    pub pattern: Option<Pattern>,
+    /// was the connection closed, rather than dropped?
+    pub closed: bool,
+    /// relayed frames waiting to be written, behind any control messages
+    pub data: Outbox,
+    /// is a batch of data being written out?
+    pub flushing: bool,
+    /// did this connection ask for a new channel? If so the channel is
+    /// pinned to this node once it is created.
+    pub created: bool,
 }
 
 impl Actor for WsChannelSession {
@@ -41,31 +176,57 @@ impl Actor for WsChannelSession {
         // HttpContext::state() is instance of WsChatSessionState, state is shared
         // across all routes within application
         let addr: Addr<Self> = ctx.address();
+        let shed = ctx.state().slo.over_budget(Instant::now());
         ctx.state()
-            .addr
+            .shards
+            .get(&self.channel)
             .send(server::Connect {
                 addr: addr.recipient(),
                 channel: self.channel.clone(),
+                lang: self.lang,
+                resume: self.resume.take(),
+                token: self.token.clone(),
+                handoff: self.handoff.take(),
+                pattern: self.pattern.take(),
+                trace: self.trace.clone(),
+                shed,
             })
             .into_actor(self)
             .then(|res, act, ctx| {
                 match res {
-                    Ok(session_id) => {
-                        if session_id == 0 {
-                            ctx.stop();
-                            return fut::err(());
-                        }
+                    Ok(Err(kind)) => {
+                        ctx.state().slo.join(false, Instant::now());
+                        // The channel refused us; say why before closing.
+                        let err = kind.localized(act.lang, hints(ctx.state(), &kind));
+                        ctx.text(ServerControl::Error(err.clone()).to_text());
+                        ctx.close(Some(ws::CloseReason {
+                            code: ws::CloseCode::Other(err.code),
+                            description: Some(err.reason),
+                        }));
+                        ctx.stop();
+                        return fut::err(());
+                    }
+                    Ok(Ok(session_id)) => {
+                        ctx.state().slo.join(true, Instant::now());
                         ctx.state().log.do_send(logging::LogMessage {
                             level: logging::ErrorLevel::Debug,
+                            module: module_path!(),
                             msg: format!("Starting new session [{:?}]", session_id),
+                            trace: act.trace.clone(),
                         });
                         act.id = session_id;
+                        if act.created {
+                            act.pin(ctx);
+                        }
                     }
                     // something is wrong with chat server
                     Err(err) => {
+                        ctx.state().slo.join(false, Instant::now());
                         ctx.state().log.do_send(logging::LogMessage {
                             level: logging::ErrorLevel::Error,
+                            module: module_path!(),
                             msg: format!("{:?}", err),
+                            trace: act.trace.clone(),
                         });
                         ctx.stop()
                     }
@@ -80,33 +241,135 @@ impl Actor for WsChannelSession {
 
         ctx.state().log.do_send(logging::LogMessage {
             level: logging::ErrorLevel::Debug,
+            module: module_path!(),
             msg: format!("Killing session [{:?}]", self.id),
+            trace: self.trace.clone(),
         });
         if self.id != 0 {
-            // Broadcast the close to all attached clients.
-            ctx.state().addr.do_send(server::ClientMessage {
-                id: 0,
-                msg: server::EOL.to_owned(),
-                channel: self.channel.clone(),
-            });
+            if self.closed {
+                // Broadcast the close to all attached clients.
+                ctx.state().shards.get(&self.channel).do_send(server::ClientMessage {
+                    id: self.id,
+                    msg: server::EOL.to_owned(),
+                    channel: self.channel.clone(),
+                    received: Instant::now(),
+                    chunk: None,
+                });
+            } else {
+                // The connection went away; the client may be back.
+                ctx.state().shards.get(&self.channel).do_send(server::Dropped {
+                    id: self.id,
+                    channel: self.channel.clone(),
+                });
+            }
         }
         Running::Stop
     }
 }
 
-/// Handle messages from chat server, we simply send it to peer websocket
+impl WsChannelSession {
+    /// Pin a channel this connection created to this node, so that it stays
+    /// here if the node list changes while it is live. Only done once the
+    /// channel exists, so that refused connections leave no placements.
+    fn pin(&self, ctx: &mut <Self as Actor>::Context) {
+        let until = Instant::now() + Duration::from_secs(ctx.state().settings.timeout);
+        let mut cluster = ctx.state().cluster.write().unwrap();
+        if cluster.enabled() {
+            let me = cluster.me.clone();
+            cluster.place(self.channel, &me, until);
+        }
+    }
+
+    /// Write a message to the client.
+    fn write(&mut self, msg: server::TextMessage, ctx: &mut <Self as Actor>::Context) {
+        let size = msg.text.len();
+        ctx.text(msg.text);
+        if let Some(received) = msg.received {
+            // Time from the sender's frame arriving to it being
+            // written out to this peer.
+            let latency = metrics::micros(received.elapsed());
+            ctx.state().slo.relay(latency, Instant::now());
+            ctx.state()
+                .metrics
+                .histogram_with_tags("relay.latency_us", latency)
+                .with_tag("encoding", "text")
+                .with_tag("size", metrics::size_bucket(size))
+                .send();
+        }
+    }
+
+    /// Write a batch of queued data frames, then wait for the connection
+    /// to take them before writing the next.
+    fn flush_data(&mut self, ctx: &mut <Self as Actor>::Context) {
+        let batch = self.data.batch(DATA_BATCH);
+        self.flushing = !batch.is_empty();
+        for msg in batch {
+            self.write(msg, ctx);
+        }
+        if self.flushing {
+            ctx.drain()
+                .map(|_, act, ctx| act.flush_data(ctx))
+                .spawn(ctx);
+        }
+    }
+}
+
+/// Handle messages from chat server, we simply send it to peer websocket.
+///
+/// Messages from the server (warnings, acks, errors) are written as they
+/// arrive. Relayed frames are queued and written in batches, so a peer
+/// streaming large payloads doesn't hold up control messages. Presence
+/// events are queued behind them, so that a peer's last frames arrive
+/// before word that it has gone.
 impl Handler<server::TextMessage> for WsChannelSession {
     type Result = ();
 
     fn handle(&mut self, msg: server::TextMessage, ctx: &mut Self::Context) {
-        if msg.0 == server::EOL {
+        if msg.text == server::EOL {
+            // Deliver what was relayed before closing.
+            for queued in self.data.batch(usize::max_value()) {
+                self.write(queued, ctx);
+            }
             ctx.state().log.do_send(logging::LogMessage {
                 level: logging::ErrorLevel::Debug,
+                module: module_path!(),
                 msg: format!("Close recv'd for session [{:?}]", self.id),
+                trace: self.trace.clone(),
             });
-            ctx.close(None);
+            self.closed = true;
+            match msg.error {
+                Some(mut err) => {
+                    let shutdown = HandlerErrorKind::ShutdownErr;
+                    if err.code == shutdown.code() && err.details.is_none() {
+                        err.details = hints(ctx.state(), &shutdown);
+                    }
+                    ctx.text(ServerControl::Error(err.clone()).to_text());
+                    ctx.close(Some(ws::CloseReason {
+                        code: ws::CloseCode::Other(err.code),
+                        description: Some(err.reason),
+                    }));
+                }
+                None => ctx.close(None),
+            }
+        } else if msg.ordered {
+            let limit = ctx.state().settings.max_queued_bytes;
+            if !self.data.push(msg, limit) {
+                // The client isn't reading. Its slot is held, as if it dropped.
+                ctx.state().metrics.incr("session.overflow").ok();
+                let err = HandlerErrorKind::SlowReaderErr.localized(self.lang, None);
+                ctx.text(ServerControl::Error(err.clone()).to_text());
+                ctx.close(Some(ws::CloseReason {
+                    code: ws::CloseCode::Other(err.code),
+                    description: Some(err.reason),
+                }));
+                ctx.stop();
+                return;
+            }
+            if !self.flushing {
+                self.flush_data(ctx);
+            }
         } else {
-            ctx.text(msg.0);
+            self.write(msg, ctx);
         }
     }
 }
@@ -116,37 +379,102 @@ impl StreamHandler<ws::Message, ws::ProtocolError> for WsChannelSession {
     fn handle(&mut self, msg: ws::Message, ctx: &mut Self::Context) {
         ctx.state().log.do_send(logging::LogMessage {
             level: logging::ErrorLevel::Debug,
+            module: module_path!(),
             msg: format!("Websocket Message: {:?}", msg),
+            trace: self.trace.clone(),
         });
         match msg {
             ws::Message::Ping(msg) => ctx.pong(&msg),
             ws::Message::Pong(msg) => self.hb = Instant::now(),
             ws::Message::Text(text) => {
+                if let Some(ip) = self.ip {
+                    let key = Key::Ip(ip);
+                    if !ctx.state().limiters.message.check(&key).allowed {
+                        // Drop the message, but leave the channel open.
+                        ctx.text(
+                            protocol::error(&HandlerErrorKind::RateLimitErr, self.lang).to_text(),
+                        );
+                        return;
+                    }
+                }
                 let m = text.trim();
                 // send message to chat server
-                ctx.state().addr.do_send(server::ClientMessage {
+                ctx.state().shards.get(&self.channel).do_send(server::ClientMessage {
                     id: self.id,
                     msg: m.to_owned(),
                     channel: self.channel.clone(),
+                    received: Instant::now(),
+                    chunk: None,
                 })
             }
             ws::Message::Binary(bin) => {
                 ctx.state().log.do_send(logging::LogMessage {
                     level: logging::ErrorLevel::Info,
+                    module: module_path!(),
                     msg: format!("TODO: Binary format not yet supported"),
+                    trace: self.trace.clone(),
                 });
             }
             ws::Message::Close(_) => {
-                ctx.state().addr.do_send(server::Disconnect {
+                self.closed = true;
+                ctx.state().shards.get(&self.channel).do_send(server::Disconnect {
                     id: self.id,
                     channel: self.channel.clone(),
                 });
                 ctx.state().log.do_send(logging::LogMessage {
                     level: logging::ErrorLevel::Debug,
+                    module: module_path!(),
                     msg: format!("Shutting down session [{}].", self.id),
+                    trace: self.trace.clone(),
                 });
                 ctx.stop();
             }
         }
     }
+
+    /// A malformed or oversized message ends the session, and with it the
+    /// channel.
+    fn error(&mut self, err: ws::ProtocolError, ctx: &mut Self::Context) -> Running {
+        ctx.state().log.do_send(logging::LogMessage {
+            level: logging::ErrorLevel::Info,
+            module: module_path!(),
+            msg: format!("Protocol error on session [{}]: {:?}", self.id, err),
+            trace: self.trace.clone(),
+        });
+        self.closed = true;
+        let code = match err {
+            ws::ProtocolError::Overflow => ws::CloseCode::Size,
+            _ => ws::CloseCode::Protocol,
+        };
+        ctx.close(Some(code.into()));
+        Running::Stop
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_outbox() {
+        let mut outbox = Outbox::default();
+        let now = Instant::now();
+        assert!(outbox.push(server::TextMessage::relayed("one", now), 8));
+        assert!(outbox.push(server::TextMessage::relayed("two", now), 8));
+        // A peer's departure doesn't overtake what it sent.
+        let left = ServerControl::PeerDropped {
+            role: protocol::Role::Initiator,
+        }.to_text();
+        assert!(outbox.push(server::TextMessage::presence(left.as_str()), usize::max_value()));
+        // Over the limit.
+        assert!(!outbox.push(server::TextMessage::relayed("three", now), 8));
+
+        let texts = |batch: Vec<server::TextMessage>| -> Vec<String> {
+            batch.into_iter().map(|msg| msg.text).collect()
+        };
+        assert_eq!(texts(outbox.batch(1)), vec!["one"]);
+        assert_eq!(texts(outbox.batch(usize::max_value())), vec!["two".to_owned(), left]);
+        assert!(outbox.batch(1).is_empty());
+        assert!(outbox.push(server::TextMessage::relayed("three", now), 8));
+    }
 }
@@ -1,10 +1,23 @@
+use std::collections::{BTreeMap, HashSet};
 use std::env;
 
 use config::{Config, ConfigError, Environment, File};
+use serde_json;
+
+use secrets::{self, Secrets};
 
 static PREFIX: &str = "PAIR";
 
-#[derive(Debug, Deserialize)]
+/// Where a setting's value came from.
+#[derive(Clone, Copy, Debug, PartialEq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Source {
+    Default,
+    File,
+    Env,
+}
+
+#[derive(Clone, Debug, Deserialize, Serialize)]
 pub struct Settings {
     pub hostname: String,  // server hostname (localhost)
     pub port: u16,         // server port (8000)
@@ -14,10 +27,119 @@ pub struct Settings {
     pub max_data: u64,     // Max amount of data octets to exchange (0 ; unlimited)
     pub debug: bool,       // In debug mode?
     pub verbose: bool,     // Verbose Errors?
+    pub admin_token: String,    // Bearer token for the admin API ("" ; admin API disabled)
+    pub secrets_refresh: u64,   // seconds between re-reading secret references (0 ; never)
+    pub admin_auth_rate: u64,   // Failed admin authentications a client may make per minute (10 ; 0 unlimited)
+    pub admin_auth_burst: u64,  // Failed admin authentications a client may make in a burst (5)
+    pub require_api_key: bool,  // Require an application key to open a channel (false)
+    pub api_key_overlap: u64,   // seconds a rotated key remains valid (86400)
+    pub api_key_file: String,   // File keeping issued keys' hashes, shared by every node ("" ; in memory)
+    pub standby_url: String,    // Base URL of a warm-standby node to replicate to ("")
+    pub standby_token: String,  // Admin token of the standby node ("")
+    pub cluster_nodes: String,  // Comma separated public URLs of all cluster nodes ("")
+    pub public_url: String,     // Public URL of this node, as listed in cluster_nodes ("")
+    pub cluster_redirect: String, // "redirect" (307) or "hint" (421 + JSON) for foreign channels
+    pub reconnect_hints: String, // Comma separated URLs for turned away clients to try ("" ; other cluster nodes)
+    pub cluster_srv: String,    // DNS SRV record listing the cluster nodes, in place of cluster_nodes ("")
+    pub cluster_srv_scheme: String, // scheme of the node URLs built from SRV records ("http")
+    pub cluster_srv_refresh: u64, // seconds between SRV lookups (30)
+    pub gossip_bind: String,    // UDP address to gossip cluster membership on ("" ; no gossip)
+    pub gossip_advertise: String, // Address other nodes reach this node's gossip at ("" ; gossip_bind)
+    pub gossip_seeds: String,   // Comma separated gossip addresses of nodes to join through ("")
+    pub gossip_interval: u64,   // milliseconds between probes of another node (1000)
+    pub gossip_suspect_timeout: u64, // seconds a suspected node has to refute it before it's dead (5)
+    pub gossip_key: String,     // Shared secret every gossip datagram is authenticated with ("")
+    pub gossip_allow: String,   // Comma separated public URLs of the nodes that may join ("" ; seeds only)
+    pub migrate_token: String,  // Admin token of the nodes channels are migrated to ("" ; admin_token)
+    pub statsd_host: String,    // statsd host to report metrics to ("" ; metrics disabled)
+    pub statsd_port: u16,       // statsd port (8125)
+    pub statsd_label: String,   // prefix for all metric names ("pairsona")
+    pub state_pool_size: usize, // Emptied channel and participant states kept for reuse (1024 ; 0 none)
+    pub alloc_stats_interval: u64, // seconds between allocator statistics reports (60 ; 0 never)
+    pub tap_allow_payload: bool, // Allow admin channel taps to see frame contents (false)
+    pub relay_only: bool,       // Keep nothing about clients beyond what relaying needs (false)
+    pub default_language: String, // Language of user facing text when no better match ("en")
+    pub language_fallback: String, // Languages to try in place of others, "pt-BR>pt>es,..." ("")
+    pub create_rate: u64,       // Channels a client may create per minute (0 ; unlimited)
+    pub create_burst: u64,      // Channels a client may create in a burst (10)
+    pub join_rate: u64,         // Channels a client may join per minute (0 ; unlimited)
+    pub join_burst: u64,        // Channels a client may join in a burst (10)
+    pub message_rate: u64,      // Messages a client may send per minute (0 ; unlimited)
+    pub message_burst: u64,     // Messages a client may send in a burst (100)
+    pub ipv6_prefix: u8,        // IPv6 prefix length rate limited as one client (64)
+    pub rate_limit_redis: String, // Redis URL to share rate limits between nodes in ("" ; in memory)
+    pub trust_forwarded: bool,  // Take client addresses from X-Forwarded-For (false)
+    pub initiator_first: bool,  // Only the channel initiator may send the first message (false)
+    pub sequence_frames: bool,  // Wrap relayed frames in a sequence numbered envelope (false)
+    pub stamp_frames: bool,     // Add the server receive time to the relay envelope (false)
+    pub hop_frames: bool,       // Add this node's ID and the receive time to the relay envelope (false)
+    pub node_id: String,        // This node's ID in relay envelopes ("" ; public_url, or hostname:port)
+    pub dedup_window: usize,    // Client message IDs remembered per sender (0 ; no suppression)
+    pub feature_flags: String,  // Percentage rollout of features, "name:percent,..." ("")
+    pub channel_max_messages: u64, // Max messages relayed per channel, all senders (0 ; unlimited)
+    pub channel_max_bytes: u64, // Max octets relayed per channel, all senders (0 ; unlimited)
+    pub channel_max_idle: u64,  // seconds a channel may relay nothing before it is evicted (0 ; no limit)
+    pub channel_shards: usize,  // Channel server actors the registry is split across (1)
+    pub max_channels: usize,    // Live channels before the least recently active is evicted (0 ; unlimited)
+    pub gc_interval: u64,       // seconds between channel eviction sweeps (10 ; 0 never)
+    pub gc_batch: usize,        // Channels checked per eviction sweep (0 ; all)
+    pub channel_rate: u64,      // Octets per second relayed per channel (0 ; unlimited)
+    pub reconnect_grace: u64,   // seconds a dropped participant's slot is held (0 ; not held)
+    pub channel_store: String,  // "sqlite:<path>" to keep channels across restarts ("" ; in memory)
+    pub snapshot_path: String,  // Where channels are saved on shutdown, to restore on start ("" ; not saved)
+    pub require_json: bool,     // Refuse to relay text frames that aren't valid JSON (false)
+    pub max_message_size: usize, // Largest websocket message accepted, in octets (65536)
+    pub max_transfer_size: usize, // Largest chunked transfer, in octets (1048576)
+    pub chunk_window: u32,      // Unacknowledged chunks a sender may have in flight (8)
+    pub max_transfers: usize,   // Chunked transfers a sender may have in progress at once (4)
+    pub max_queued_bytes: usize, // Octets relayed to a connection but not yet written before it is closed as too slow (16777216)
+    pub tcp_nodelay: bool,      // Disable Nagle's algorithm on connections (true)
+    pub tcp_keepalive: u64,     // seconds idle before TCP keepalive probes (0 ; platform default)
+    pub recv_buffer: usize,     // Socket receive buffer size, in octets (0 ; platform default)
+    pub send_buffer: usize,     // Socket send buffer size, in octets (0 ; platform default)
+    pub reuse_port: bool,       // Bind with SO_REUSEPORT (false)
+    pub acceptors: usize,       // Listening sockets to bind when reuse_port is set (1)
+    pub listen: String,         // "host:port=routes,..." endpoints ("" ; hostname:port, all routes)
+    pub ip_stack: String,       // "v4", "v6" (v6 only) or "dual" ("" ; as hostname resolves)
+    pub log_level: String,      // "level,module=level,..." directives ("" ; info, debug in debug builds)
+    pub log_sample: String,     // Percentage of records logged per level, "level:percent,..." ("" ; all)
+    pub log_queue_size: usize,  // Log records queued for writing before records are dropped (1024)
+    pub log_format: String,     // "text", "json", "gcp" or "cloudwatch" ("text")
+    pub log_file: String,       // Write logs to this file rather than stdout ("")
+    pub log_max_size: u64,      // Rotate log_file once it is this many octets (0 ; never)
+    pub log_max_age: u64,       // Rotate log_file once it is this many seconds old (0 ; never)
+    pub log_keep: usize,        // Rotated log files to keep (5)
+    pub syslog: String,         // Log to syslog at "udp://host:port", "tcp://host:port" or "unix:///path" ("")
+    pub syslog_facility: String, // Syslog facility ("daemon")
+    pub audit_postgres: String, // Postgres URL to record channel events and summaries in ("" ; not recorded)
+    pub audit_batch: usize,     // Audit records written per transaction (100)
+    pub audit_queue: usize,     // Audit records queued for writing before records are dropped (10000)
+    pub slo_windows: String,    // Windows SLIs are reported over, as seconds "300,3600" ("300,3600")
+    pub latency_budget: u64,    // p99 relay latency, in microseconds, over which new channels are shed (0 ; never)
+    pub latency_budget_window: u64, // seconds the p99 relay latency is measured over (10)
+    pub max_headers: usize,     // Most headers allowed on an upgrade request (64 ; 0 unlimited)
+    pub max_header_size: usize, // Total octets of headers allowed on an upgrade request (8192 ; 0 unlimited)
+    pub security_headers: String, // Route groups given security headers ("health,admin,public" ; "" none)
+    pub content_security_policy: String, // Content-Security-Policy for those groups ("default-src 'none'; ...")
+    pub hsts_max_age: u64,      // seconds browsers should insist on HTTPS (31536000 ; 0 no HSTS)
+    pub cors_origins: String,   // Origins that may call the public HTTP API, comma separated ("" ; none, "*" any)
+    pub cors_methods: String,   // Methods allowed cross-origin ("GET")
+    pub cors_max_age: u64,      // seconds browsers may cache a preflight answer (86400)
+    pub cors_credentials: bool, // Allow cross-origin requests with credentials (false)
+    #[serde(skip)]
+    pub sources: BTreeMap<String, Source>, // Where each setting above came from
+    #[serde(skip)]
+    pub secret_refs: BTreeMap<String, String>, // Secret settings loaded from a reference, and the reference
 }
 
 impl Settings {
     pub fn new() -> Result<Self, ConfigError> {
+        Self::load(None)
+    }
+
+    /// Load the settings, from `config` rather than `config/<RUN_MODE>` if
+    /// given. An explicitly named config file must exist.
+    pub fn load(config: Option<&str>) -> Result<Self, ConfigError> {
         let mut settings = Config::default();
 
         settings.set_default("debug", false)?;
@@ -28,12 +150,233 @@ impl Settings {
         settings.set_default("max_data", 0)?;
         settings.set_default("port", 8000)?;
         settings.set_default("hostname", "0.0.0.0".to_owned())?;
-        // Get the run environment
-        let env = env::var("RUN_MODE").unwrap_or("development".to_owned());
-        // start with any local config file.
-        settings.merge(File::with_name(&format!("config/{}", env)).required(false))?;
+        settings.set_default("admin_token", "".to_owned())?;
+        settings.set_default("secrets_refresh", 0)?;
+        settings.set_default("admin_auth_rate", 10)?;
+        settings.set_default("admin_auth_burst", 5)?;
+        settings.set_default("require_api_key", false)?;
+        settings.set_default("api_key_overlap", 86400)?;
+        settings.set_default("api_key_file", "".to_owned())?;
+        settings.set_default("standby_url", "".to_owned())?;
+        settings.set_default("standby_token", "".to_owned())?;
+        settings.set_default("cluster_nodes", "".to_owned())?;
+        settings.set_default("public_url", "".to_owned())?;
+        settings.set_default("cluster_redirect", "redirect".to_owned())?;
+        settings.set_default("reconnect_hints", "".to_owned())?;
+        settings.set_default("cluster_srv", "".to_owned())?;
+        settings.set_default("cluster_srv_scheme", "http".to_owned())?;
+        settings.set_default("cluster_srv_refresh", 30)?;
+        settings.set_default("gossip_bind", "".to_owned())?;
+        settings.set_default("gossip_advertise", "".to_owned())?;
+        settings.set_default("gossip_seeds", "".to_owned())?;
+        settings.set_default("gossip_interval", 1000)?;
+        settings.set_default("gossip_suspect_timeout", 5)?;
+        settings.set_default("gossip_key", "".to_owned())?;
+        settings.set_default("gossip_allow", "".to_owned())?;
+        settings.set_default("migrate_token", "".to_owned())?;
+        settings.set_default("statsd_host", "".to_owned())?;
+        settings.set_default("statsd_port", 8125)?;
+        settings.set_default("statsd_label", "pairsona".to_owned())?;
+        settings.set_default("alloc_stats_interval", 60)?;
+        settings.set_default("state_pool_size", 1024)?;
+        settings.set_default("tap_allow_payload", false)?;
+        settings.set_default("relay_only", false)?;
+        settings.set_default("default_language", "en".to_owned())?;
+        settings.set_default("language_fallback", "".to_owned())?;
+        settings.set_default("create_rate", 0)?;
+        settings.set_default("create_burst", 10)?;
+        settings.set_default("join_rate", 0)?;
+        settings.set_default("join_burst", 10)?;
+        settings.set_default("message_rate", 0)?;
+        settings.set_default("message_burst", 100)?;
+        settings.set_default("ipv6_prefix", 64)?;
+        settings.set_default("rate_limit_redis", "".to_owned())?;
+        settings.set_default("trust_forwarded", false)?;
+        settings.set_default("initiator_first", false)?;
+        settings.set_default("sequence_frames", false)?;
+        settings.set_default("stamp_frames", false)?;
+        settings.set_default("hop_frames", false)?;
+        settings.set_default("node_id", "".to_owned())?;
+        settings.set_default("dedup_window", 0)?;
+        settings.set_default("feature_flags", "".to_owned())?;
+        settings.set_default("channel_max_messages", 0)?;
+        settings.set_default("channel_max_bytes", 0)?;
+        settings.set_default("channel_max_idle", 0)?;
+        settings.set_default("channel_shards", 1)?;
+        settings.set_default("max_channels", 0)?;
+        settings.set_default("gc_interval", 10)?;
+        settings.set_default("gc_batch", 0)?;
+        settings.set_default("channel_rate", 0)?;
+        settings.set_default("reconnect_grace", 0)?;
+        settings.set_default("channel_store", "".to_owned())?;
+        settings.set_default("snapshot_path", "".to_owned())?;
+        settings.set_default("require_json", false)?;
+        settings.set_default("max_message_size", 65536)?;
+        settings.set_default("max_transfer_size", 1_048_576)?;
+        settings.set_default("chunk_window", 8)?;
+        settings.set_default("max_transfers", 4)?;
+        settings.set_default("max_queued_bytes", 16_777_216)?;
+        settings.set_default("tcp_nodelay", true)?;
+        settings.set_default("tcp_keepalive", 0)?;
+        settings.set_default("recv_buffer", 0)?;
+        settings.set_default("send_buffer", 0)?;
+        settings.set_default("reuse_port", false)?;
+        settings.set_default("acceptors", 1)?;
+        settings.set_default("listen", "".to_owned())?;
+        settings.set_default("ip_stack", "".to_owned())?;
+        settings.set_default("log_level", "".to_owned())?;
+        settings.set_default("log_sample", "".to_owned())?;
+        settings.set_default("log_queue_size", 1024)?;
+        settings.set_default("log_format", "text".to_owned())?;
+        settings.set_default("log_file", "".to_owned())?;
+        settings.set_default("log_max_size", 0)?;
+        settings.set_default("log_max_age", 0)?;
+        settings.set_default("log_keep", 5)?;
+        settings.set_default("syslog", "".to_owned())?;
+        settings.set_default("syslog_facility", "daemon".to_owned())?;
+        settings.set_default("audit_postgres", "".to_owned())?;
+        settings.set_default("audit_batch", 100)?;
+        settings.set_default("audit_queue", 10_000)?;
+        settings.set_default("slo_windows", "300,3600".to_owned())?;
+        settings.set_default("latency_budget", 0)?;
+        settings.set_default("latency_budget_window", 10)?;
+        settings.set_default("max_headers", 64)?;
+        settings.set_default("max_header_size", 8192)?;
+        settings.set_default("security_headers", "health,admin,public".to_owned())?;
+        settings.set_default(
+            "content_security_policy",
+            "default-src 'none'; frame-ancestors 'none'".to_owned(),
+        )?;
+        settings.set_default("hsts_max_age", 31_536_000)?;
+        settings.set_default("cors_origins", "".to_owned())?;
+        settings.set_default("cors_methods", "GET".to_owned())?;
+        settings.set_default("cors_max_age", 86400)?;
+        settings.set_default("cors_credentials", false)?;
+        let file = match config {
+            Some(path) => File::with_name(path),
+            None => {
+                // Get the run environment
+                let env = env::var("RUN_MODE").unwrap_or("development".to_owned());
+                // start with any local config file.
+                File::with_name(&format!("config/{}", env)).required(false)
+            }
+        };
+        settings.merge(file.clone())?;
         // Add/overwrite with the environments
         settings.merge(Environment::with_prefix(PREFIX))?;
-        settings.try_into()
+        let mut settings: Self = settings.try_into()?;
+
+        // Secrets may be references to where they really live.
+        for name in secrets::SECRETS {
+            let reference = match settings.secret(name) {
+                Some(value) if secrets::is_reference(value) => value.clone(),
+                _ => continue,
+            };
+            let value = secrets::resolve(&reference)
+                .map_err(|e| ConfigError::Message(format!("{}: {}", name, e)))?;
+            settings.set_secret(name, &value);
+            settings.secret_refs.insert(name.to_string(), reference);
+        }
+
+        // Note which values were set by the file or the environment, for
+        // the admin API.
+        let mut from_file = Config::default();
+        from_file.merge(file)?;
+        let prefix = format!("{}_", PREFIX.to_lowercase());
+        let from_env: HashSet<String> = env::vars()
+            .map(|(key, _)| key.to_lowercase())
+            .filter(|key| key.starts_with(&prefix))
+            .map(|key| key[prefix.len()..].to_owned())
+            .collect();
+        if let Ok(serde_json::Value::Object(values)) = serde_json::to_value(&settings) {
+            settings.sources = values
+                .keys()
+                .map(|key| {
+                    let source = if from_env.contains(key) {
+                        Source::Env
+                    } else if from_file.get_str(key).is_ok() {
+                        Source::File
+                    } else {
+                        Source::Default
+                    };
+                    (key.clone(), source)
+                })
+                .collect();
+        }
+        Ok(settings)
+    }
+
+    fn secret(&self, name: &str) -> Option<&String> {
+        match name {
+            "admin_token" => Some(&self.admin_token),
+            "standby_token" => Some(&self.standby_token),
+            "migrate_token" => Some(&self.migrate_token),
+            "audit_postgres" => Some(&self.audit_postgres),
+            "gossip_key" => Some(&self.gossip_key),
+            _ => None,
+        }
+    }
+
+    /// Replace the value of a secret setting, e.g. once it is rotated.
+    pub fn set_secret(&mut self, name: &str, value: &str) {
+        let secret = match name {
+            "admin_token" => &mut self.admin_token,
+            "standby_token" => &mut self.standby_token,
+            "migrate_token" => &mut self.migrate_token,
+            "audit_postgres" => &mut self.audit_postgres,
+            "gossip_key" => &mut self.gossip_key,
+            _ => return,
+        };
+        *secret = value.to_owned();
+    }
+
+    /// What this node calls itself in relay envelopes.
+    pub fn node_name(&self) -> String {
+        if !self.node_id.is_empty() {
+            self.node_id.clone()
+        } else if !self.public_url.is_empty() {
+            self.public_url.trim_right_matches('/').to_owned()
+        } else {
+            format!("{}:{}", self.hostname, self.port)
+        }
+    }
+
+    /// The secret settings, to be kept current as they are rotated.
+    pub fn secrets(&self) -> Secrets {
+        let values = secrets::SECRETS
+            .iter()
+            .filter_map(|name| self.secret(name).map(|value| (name.to_string(), value.clone())))
+            .collect();
+        Secrets::new(values, self.secret_refs.clone())
+    }
+
+    /// A copy of these settings that is safe to write to the logs.
+    pub fn redacted(&self) -> Self {
+        let mut settings = self.clone();
+        if !settings.admin_token.is_empty() {
+            settings.admin_token = "[redacted]".to_owned();
+        }
+        if !settings.standby_token.is_empty() {
+            settings.standby_token = "[redacted]".to_owned();
+        }
+        if !settings.migrate_token.is_empty() {
+            settings.migrate_token = "[redacted]".to_owned();
+        }
+        if !settings.audit_postgres.is_empty() {
+            // may carry a password
+            settings.audit_postgres = "[redacted]".to_owned();
+        }
+        if !settings.gossip_key.is_empty() {
+            settings.gossip_key = "[redacted]".to_owned();
+        }
+        if !settings.rate_limit_redis.is_empty() {
+            // may carry a password
+            settings.rate_limit_redis = "[redacted]".to_owned();
+        }
+        // where they are kept is not a secret
+        for (name, reference) in &self.secret_refs {
+            settings.set_secret(name, reference);
+        }
+        settings
     }
 }
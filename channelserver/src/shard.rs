@@ -0,0 +1,228 @@
+//! The channel registry, split by channel ID across several
+//! `ChannelServer` actors, each on its own thread.
+//!
+//! Everything about a channel (its participants, their sessions, taps
+//! and backlog) lives on the one shard that owns it, so joins and relays
+//! on different shards never wait on each other. Messages about a channel
+//! go to its shard; the few that concern the whole node go to every
+//! shard, or to any one of them.
+
+use std::collections::hash_map::DefaultHasher;
+use std::hash::{Hash, Hasher};
+
+use actix::{Addr, Arbiter, MailboxError, Recipient};
+use futures::{future, Future};
+use uuid::Uuid;
+
+use logging::MozLogger;
+use replica::ReplicaBatch;
+use server::{
+    ApplyReplica, ChannelEvent, ChannelServer, ChannelState, Drain, Export, Moved, Reload,
+    Restore, ServerStatus, Status, Stop, Subscribe, TextMessage, Thaw,
+};
+use settings::Settings;
+use snapshot::SavedChannel;
+
+/// The shard, of `count`, that owns `channel`.
+pub fn index(channel: &Uuid, count: usize) -> usize {
+    let mut hasher = DefaultHasher::new();
+    channel.as_bytes().hash(&mut hasher);
+    (hasher.finish() % count.max(1) as u64) as usize
+}
+
+#[derive(Clone)]
+pub struct Shards {
+    servers: Vec<Addr<ChannelServer>>,
+}
+
+impl Shards {
+    /// Start `count` channel servers with the node's `settings`, logging
+    /// to `log`. They handle the process' signals if given `reload`.
+    pub fn start(
+        count: usize,
+        settings: &Settings,
+        reload: Option<Reload>,
+        log: &MozLogger,
+    ) -> Self {
+        let count = count.max(1);
+        Shards {
+            servers: (0..count)
+                .map(|index| {
+                    let (settings, reload, log) = (settings.clone(), reload.clone(), log.clone());
+                    Arbiter::start(move |_| {
+                        let server = ChannelServer::shard(index, count, settings, log);
+                        match reload {
+                            Some(reload) => server.signals(reload),
+                            None => server,
+                        }
+                    })
+                })
+                .collect(),
+        }
+    }
+
+    /// The server that owns `channel`.
+    pub fn get(&self, channel: &Uuid) -> &Addr<ChannelServer> {
+        &self.servers[index(channel, self.servers.len())]
+    }
+
+    /// Every server, for node wide messages.
+    pub fn all(&self) -> &[Addr<ChannelServer>] {
+        &self.servers
+    }
+
+    /// Some server, for messages any of them can handle.
+    pub fn any(&self) -> &Addr<ChannelServer> {
+        &self.servers[0]
+    }
+
+    /// Subscribe `addr` to every shard's channel lifecycle events.
+    pub fn subscribe(&self, addr: Recipient<TextMessage>) {
+        for server in &self.servers {
+            server.do_send(Subscribe { addr: addr.clone() });
+        }
+    }
+
+    /// Pass replicated events, and channels, on to the shards that own
+    /// them.
+    pub fn replicate(&self, batch: ReplicaBatch) {
+        let mut events: Vec<Vec<ChannelEvent>> = self.servers.iter().map(|_| Vec::new()).collect();
+        let mut channels: Vec<Vec<_>> = self.servers.iter().map(|_| Vec::new()).collect();
+        for event in batch.events {
+            events[index(event.channel(), self.servers.len())].push(event);
+        }
+        for (channel, saved) in batch.channels.unwrap_or_default() {
+            channels[index(&channel, self.servers.len())].push((channel, saved));
+        }
+        for ((server, events), channels) in self.servers.iter().zip(events).zip(channels) {
+            if !events.is_empty() || !channels.is_empty() {
+                server.do_send(ApplyReplica { events, channels });
+            }
+        }
+    }
+
+    /// Pass channels restored from a snapshot on to the shards that now
+    /// own them.
+    pub fn restore(&self, channels: Vec<(Uuid, ChannelState)>) {
+        let mut split: Vec<Vec<_>> = self.servers.iter().map(|_| Vec::new()).collect();
+        for (channel, state) in channels {
+            split[index(&channel, self.servers.len())].push((channel, state));
+        }
+        for (server, channels) in self.servers.iter().zip(split) {
+            if !channels.is_empty() {
+                server.do_send(Restore(channels));
+            }
+        }
+    }
+
+    /// Copy `channels` (or every channel) from the shards that own them,
+    /// for migration. They are frozen until `moved` or `thaw`.
+    pub fn export(
+        &self,
+        channels: Option<Vec<Uuid>>,
+    ) -> Box<Future<Item = Vec<(Uuid, SavedChannel)>, Error = MailboxError>> {
+        let replies: Vec<_> = match channels {
+            None => self.servers.iter().map(|server| server.send(Export(None))).collect(),
+            Some(channels) => self
+                .split(channels)
+                .into_iter()
+                .filter(|&(_, ref channels)| !channels.is_empty())
+                .map(|(server, channels)| server.send(Export(Some(channels))))
+                .collect(),
+        };
+        Box::new(future::join_all(replies).map(|all| all.into_iter().flat_map(|c| c).collect()))
+    }
+
+    /// Tell the shards that own `channels` they now live at `to`.
+    pub fn moved(&self, channels: Vec<Uuid>, to: &str) {
+        for (server, channels) in self.split(channels) {
+            if !channels.is_empty() {
+                server.do_send(Moved {
+                    channels,
+                    to: to.to_owned(),
+                });
+            }
+        }
+    }
+
+    /// Let `channels` (or every frozen channel) carry on here, as their
+    /// migration failed.
+    pub fn thaw(&self, channels: Option<Vec<Uuid>>) {
+        match channels {
+            None => {
+                for server in &self.servers {
+                    server.do_send(Thaw(None));
+                }
+            }
+            Some(channels) => {
+                for (server, channels) in self.split(channels) {
+                    if !channels.is_empty() {
+                        server.do_send(Thaw(Some(channels)));
+                    }
+                }
+            }
+        }
+    }
+
+    /// `channels`, with the server that owns each share of them.
+    fn split(&self, channels: Vec<Uuid>) -> Vec<(&Addr<ChannelServer>, Vec<Uuid>)> {
+        let mut split: Vec<Vec<Uuid>> = self.servers.iter().map(|_| Vec::new()).collect();
+        for channel in channels {
+            split[index(&channel, self.servers.len())].push(channel);
+        }
+        self.servers.iter().zip(split).collect()
+    }
+
+    /// Save or close every shard's channels, as the node stops.
+    pub fn stop(&self) -> Box<Future<Item = (), Error = MailboxError>> {
+        let replies: Vec<_> = self.servers.iter().map(|server| server.send(Stop)).collect();
+        Box::new(future::join_all(replies).map(|_| ()))
+    }
+
+    /// Start or stop draining every shard.
+    pub fn drain(&self, draining: bool) {
+        for server in &self.servers {
+            server.do_send(Drain(draining));
+        }
+    }
+
+    /// The node's status, over all shards. The node is draining if any
+    /// shard is.
+    pub fn status(&self) -> Box<Future<Item = ServerStatus, Error = MailboxError>> {
+        let replies: Vec<_> = self.servers.iter().map(|server| server.send(Status)).collect();
+        Box::new(future::join_all(replies).map(|all| {
+            all.into_iter().fold(
+                ServerStatus {
+                    draining: false,
+                    channels: 0,
+                    sessions: 0,
+                },
+                |total, status| ServerStatus {
+                    draining: total.draining || status.draining,
+                    channels: total.channels + status.channels,
+                    sessions: total.sessions + status.sessions,
+                },
+            )
+        }))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_index() {
+        let mut counts = [0; 4];
+        for _ in 0..1000 {
+            let channel = Uuid::new_v4();
+            let shard = index(&channel, 4);
+            assert_eq!(index(&channel, 4), shard);
+            counts[shard] += 1;
+        }
+        // roughly even
+        assert!(counts.iter().all(|count| *count > 150));
+        assert_eq!(index(&Uuid::new_v4(), 1), 0);
+        assert_eq!(index(&Uuid::new_v4(), 0), 0);
+    }
+}
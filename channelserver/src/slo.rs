@@ -0,0 +1,404 @@
+//! Rolling service level indicators, computed in process so that small
+//! deployments can alert on burn rate without a metrics pipeline.
+//!
+//! Two SLIs are tracked over each configured window: the percentage of
+//! channel joins that succeed, and the 99th percentile relay latency.
+//!
+//! With a latency budget, the relay latency is also checked against it, so
+//! that a node whose relays are slowing down can stop taking on new
+//! channels and keep serving the ones it has.
+//!
+//! Samples are counted rather than kept: each window is divided into
+//! `SLOTS` slots of joins and a histogram of relay latencies, so memory
+//! doesn't grow with load and no sample is ever left out. A window's
+//! figures cover the whole window, give or take a slot, and latencies are
+//! reported to within an eighth. The counts are striped over several
+//! locks, so that workers recording at once rarely wait for each other.
+
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use serde_json::Value;
+
+/// Slots each window is divided into.
+const SLOTS: u64 = 60;
+
+/// Independently locked sets of counts.
+const STRIPES: usize = 8;
+
+/// Histogram buckets per power of two of latency.
+const SUB_BUCKETS: u64 = 8;
+
+/// Histogram buckets, enough for latencies up to 2^36µs (about 19 hours).
+const BUCKETS: usize = 35 * SUB_BUCKETS as usize;
+
+/// Fewest relays in the budget window to judge the latency by.
+const MIN_BUDGET_SAMPLES: u64 = 20;
+
+/// How often the latency is checked against the budget, as checking
+/// merges every stripe's counts.
+const BUDGET_CHECK: Duration = Duration::from_secs(1);
+
+/// Which stripe the next thread to record a sample uses.
+static NEXT_STRIPE: AtomicUsize = AtomicUsize::new(0);
+
+thread_local!(static STRIPE: usize = NEXT_STRIPE.fetch_add(1, Ordering::Relaxed) % STRIPES);
+
+fn micros(duration: Duration) -> u64 {
+    duration.as_secs() * 1_000_000 + u64::from(duration.subsec_micros())
+}
+
+/// The histogram bucket counting `latency`: exact below `SUB_BUCKETS`,
+/// then `SUB_BUCKETS` buckets for each power of two.
+fn bucket(latency: u64) -> usize {
+    if latency < SUB_BUCKETS {
+        return latency as usize;
+    }
+    let exp = 63 - u64::from(latency.leading_zeros());
+    let sub = (latency >> (exp - 3)) - SUB_BUCKETS;
+    (((exp - 2) * SUB_BUCKETS + sub) as usize).min(BUCKETS - 1)
+}
+
+/// The largest latency counted in bucket `index`.
+fn bucket_max(index: usize) -> u64 {
+    let index = index as u64;
+    if index < SUB_BUCKETS {
+        return index;
+    }
+    let exp = index / SUB_BUCKETS + 2;
+    let sub = index % SUB_BUCKETS;
+    ((SUB_BUCKETS + sub + 1) << (exp - 3)) - 1
+}
+
+/// Counts for one slot of a window.
+#[derive(Clone, Default)]
+struct Slot {
+    /// which slot since the tracker started this is counting
+    number: u64,
+    joins: u64,
+    joins_ok: u64,
+    /// relay latencies, by `bucket`; empty until there is a relay
+    latencies: Vec<u64>,
+}
+
+/// A window's slots, reused round robin.
+#[derive(Clone)]
+struct Ring {
+    /// slot width, in microseconds
+    width: u64,
+    slots: Vec<Slot>,
+}
+
+impl Ring {
+    fn new(window: Duration) -> Self {
+        Self {
+            width: (micros(window) / SLOTS).max(1),
+            slots: vec![Slot::default(); SLOTS as usize],
+        }
+    }
+
+    /// The slot counting `number`, emptied if it was counting an older one.
+    fn slot(&mut self, number: u64) -> &mut Slot {
+        let slot = &mut self.slots[(number % SLOTS) as usize];
+        if slot.number != number {
+            *slot = Slot {
+                number,
+                ..Default::default()
+            };
+        }
+        slot
+    }
+}
+
+/// Counts merged over a window.
+struct Totals {
+    joins: u64,
+    joins_ok: u64,
+    latencies: Vec<u64>,
+}
+
+impl Totals {
+    fn relays(&self) -> u64 {
+        self.latencies.iter().sum()
+    }
+
+    /// The nearest rank 99th percentile latency, to within a bucket.
+    fn p99(&self) -> Option<u64> {
+        let relays = self.relays();
+        if relays == 0 {
+            return None;
+        }
+        let rank = ((relays as f64 * 0.99).ceil() as u64).max(1);
+        let mut seen = 0;
+        for (index, count) in self.latencies.iter().enumerate() {
+            seen += count;
+            if seen >= rank {
+                return Some(bucket_max(index));
+            }
+        }
+        None
+    }
+}
+
+/// Whether the latency was over budget when last checked, and when that
+/// was.
+#[derive(Default)]
+struct BudgetCheck {
+    over: bool,
+    checked: Option<Instant>,
+}
+
+pub struct SloTracker {
+    windows: Vec<Duration>,
+    /// p99 relay latency, in microseconds, over which new channels are
+    /// shed, and the window it is measured over
+    budget: Option<(u64, Duration)>,
+    /// one ring per window, then one for the budget window, per stripe
+    stripes: Vec<Mutex<Vec<Ring>>>,
+    check: Mutex<BudgetCheck>,
+    started: Instant,
+}
+
+/// Parse `slo_windows`, a comma separated list of seconds.
+pub fn parse_windows(spec: &str) -> Vec<Duration> {
+    spec.split(',')
+        .filter_map(|item| item.trim().parse::<u64>().ok())
+        .filter(|secs| *secs > 0)
+        .map(Duration::from_secs)
+        .collect()
+}
+
+impl SloTracker {
+    pub fn new(windows: Vec<Duration>) -> Self {
+        Self::build(windows, None)
+    }
+
+    fn build(windows: Vec<Duration>, budget: Option<(u64, Duration)>) -> Self {
+        let rings: Vec<Ring> = windows
+            .iter()
+            .cloned()
+            .chain(budget.map(|(_, window)| window))
+            .map(Ring::new)
+            .collect();
+        Self {
+            windows,
+            budget,
+            stripes: (0..STRIPES).map(|_| Mutex::new(rings.clone())).collect(),
+            check: Mutex::new(BudgetCheck::default()),
+            started: Instant::now(),
+        }
+    }
+
+    /// Shed new channels while the p99 relay latency over `window` is over
+    /// `budget_us` microseconds. A budget of 0 never sheds.
+    pub fn with_budget(self, budget_us: u64, window: Duration) -> Self {
+        if budget_us == 0 {
+            return self;
+        }
+        Self::build(self.windows, Some((budget_us, window)))
+    }
+
+    /// Microseconds since the tracker started, as of `now`.
+    fn since(&self, now: Instant) -> u64 {
+        if now > self.started {
+            micros(now - self.started)
+        } else {
+            0
+        }
+    }
+
+    /// Count a sample in this thread's stripe, in every window.
+    fn record<F>(&self, now: Instant, count: F)
+    where
+        F: Fn(&mut Slot),
+    {
+        let since = self.since(now);
+        let stripe = STRIPE.with(|stripe| *stripe);
+        let mut rings = self.stripes[stripe].lock().unwrap();
+        for ring in rings.iter_mut() {
+            let number = since / ring.width;
+            count(ring.slot(number));
+        }
+    }
+
+    /// Ring `index`'s counts over its window, merged over the stripes.
+    fn totals(&self, index: usize, now: Instant) -> Totals {
+        let since = self.since(now);
+        let mut totals = Totals {
+            joins: 0,
+            joins_ok: 0,
+            latencies: vec![0; BUCKETS],
+        };
+        for stripe in &self.stripes {
+            let rings = stripe.lock().unwrap();
+            let ring = &rings[index];
+            let current = since / ring.width;
+            let live = ring
+                .slots
+                .iter()
+                .filter(|slot| slot.number <= current && current - slot.number < SLOTS);
+            for slot in live {
+                totals.joins += slot.joins;
+                totals.joins_ok += slot.joins_ok;
+                for (total, count) in totals.latencies.iter_mut().zip(&slot.latencies) {
+                    *total += count;
+                }
+            }
+        }
+        totals
+    }
+
+    /// Is relay latency over budget, so that new channels should be shed?
+    pub fn over_budget(&self, now: Instant) -> bool {
+        let budget = match self.budget {
+            Some((budget, _)) => budget,
+            None => return false,
+        };
+        let mut check = self.check.lock().unwrap();
+        if let Some(checked) = check.checked {
+            if now < checked + BUDGET_CHECK {
+                return check.over;
+            }
+        }
+        check.checked = Some(now);
+        let totals = self.totals(self.windows.len(), now);
+        check.over =
+            totals.relays() >= MIN_BUDGET_SAMPLES && totals.p99().map_or(false, |p| p > budget);
+        check.over
+    }
+
+    pub fn join(&self, succeeded: bool, now: Instant) {
+        self.record(now, |slot| {
+            slot.joins += 1;
+            if succeeded {
+                slot.joins_ok += 1;
+            }
+        });
+    }
+
+    pub fn relay(&self, latency_us: u64, now: Instant) {
+        self.record(now, |slot| {
+            if slot.latencies.is_empty() {
+                slot.latencies = vec![0; BUCKETS];
+            }
+            slot.latencies[bucket(latency_us)] += 1;
+        });
+    }
+
+    /// The SLIs over each window, as JSON.
+    pub fn report(&self, now: Instant) -> Value {
+        let windows: Vec<Value> = self
+            .windows
+            .iter()
+            .enumerate()
+            .map(|(index, window)| {
+                let totals = self.totals(index, now);
+                let join_success = if totals.joins == 0 {
+                    None
+                } else {
+                    Some(totals.joins_ok as f64 * 100.0 / totals.joins as f64)
+                };
+                json!({
+                    "window": window.as_secs(),
+                    "joins": totals.joins,
+                    "join_success_pct": join_success,
+                    "relays": totals.relays(),
+                    "relay_p99_us": totals.p99(),
+                })
+            })
+            .collect();
+        let mut report = json!({ "windows": windows });
+        if let Some((budget, _)) = self.budget {
+            report["latency_budget_us"] = json!(budget);
+            report["shedding"] = json!(self.check.lock().unwrap().over);
+        }
+        report
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_buckets() {
+        for latency in (0..5000).chain(vec![99_000, 1 << 30]) {
+            let index = bucket(latency);
+            assert!(latency <= bucket_max(index), "{}", latency);
+            assert!(index == 0 || latency > bucket_max(index - 1), "{}", latency);
+            // within an eighth
+            assert!(bucket_max(index) - latency <= latency / SUB_BUCKETS, "{}", latency);
+        }
+        assert_eq!(bucket(u64::max_value()), BUCKETS - 1);
+    }
+
+    #[test]
+    fn test_report() {
+        assert_eq!(
+            parse_windows("300, 3600,x,0"),
+            vec![Duration::from_secs(300), Duration::from_secs(3600)]
+        );
+        let slo = SloTracker::new(parse_windows("60,3600"));
+        let start = Instant::now();
+        let empty = slo.report(start);
+        assert_eq!(empty["windows"][0]["join_success_pct"], Value::Null);
+
+        slo.join(false, start);
+        for latency in 1..101 {
+            slo.relay(latency * 1000, start);
+        }
+        let later = start + Duration::from_secs(120);
+        for _ in 0..3 {
+            slo.join(true, later);
+        }
+        slo.relay(5, later);
+        let report = slo.report(later);
+        // only the later samples are in the last minute
+        assert_eq!(report["windows"][0]["joins"], json!(3));
+        assert_eq!(report["windows"][0]["join_success_pct"], json!(100.0));
+        assert_eq!(report["windows"][0]["relay_p99_us"], json!(5));
+        assert_eq!(report["windows"][1]["joins"], json!(4));
+        assert_eq!(report["windows"][1]["join_success_pct"], json!(75.0));
+        assert_eq!(report["windows"][1]["relays"], json!(101));
+        let p99 = report["windows"][1]["relay_p99_us"].as_u64().unwrap();
+        assert!(p99 >= 99_000 && p99 <= 99_000 + 99_000 / 8, "{}", p99);
+        assert_eq!(report["shedding"], Value::Null);
+    }
+
+    #[test]
+    fn test_no_truncation() {
+        // Far more samples than any fixed sized buffer would hold.
+        let slo = SloTracker::new(vec![Duration::from_secs(3600)]);
+        let start = Instant::now();
+        for i in 0..500_000u64 {
+            slo.join(true, start + Duration::from_millis(i));
+        }
+        let report = slo.report(start + Duration::from_secs(500));
+        assert_eq!(report["windows"][0]["joins"], json!(500_000));
+    }
+
+    #[test]
+    fn test_budget() {
+        let slo = SloTracker::new(vec![]).with_budget(10_000, Duration::from_secs(10));
+        let start = Instant::now();
+        // too few relays to judge
+        slo.relay(50_000, start);
+        assert!(!slo.over_budget(start));
+        for _ in 0..MIN_BUDGET_SAMPLES {
+            slo.relay(50_000, start);
+        }
+        // not checked again so soon
+        assert!(!slo.over_budget(start));
+        let later = start + BUDGET_CHECK;
+        assert!(slo.over_budget(later));
+        assert_eq!(slo.report(later)["shedding"], json!(true));
+        // the slow relays age out of the window
+        let much_later = start + Duration::from_secs(30);
+        for _ in 0..MIN_BUDGET_SAMPLES {
+            slo.relay(1_000, much_later);
+        }
+        assert!(!slo.over_budget(much_later));
+        assert!(!SloTracker::new(vec![]).over_budget(start));
+    }
+}
@@ -0,0 +1,322 @@
+//! Snapshots of the channel registry, so that a node can be restarted
+//! without its channels noticing.
+//!
+//! With `snapshot_path` set, each channel server writes its channels (IDs,
+//! participants, counters and reconnect tokens, but not connections) to
+//! `<snapshot_path>.<shard>` when the node is told to stop. On start the
+//! snapshot is read, removed, and restored with every participant's slot
+//! held, as if their connection had dropped when the snapshot was taken:
+//! clients that reconnect with their reconnect token within
+//! `reconnect_grace` land back in their channel. A snapshot older than
+//! that is discarded, as its clients will have given up.
+
+use std::collections::{HashMap, HashSet};
+use std::fs::{self, OpenOptions};
+use std::io::{self, Write};
+#[cfg(unix)]
+use std::os::unix::fs::OpenOptionsExt;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant};
+
+use serde_json;
+use slog::Logger;
+use uuid::Uuid;
+
+use apikey::now;
+use i18n;
+use protocol::{Capabilities, Role};
+use server::{Channel, ChannelState, SessionId};
+use store::ChannelStore;
+use throttle::Throttle;
+
+#[derive(Debug, Deserialize, Serialize)]
+struct SavedParticipant {
+    id: SessionId,
+    role: Role,
+    lang: String,
+    /// seconds since the participant connected
+    age: u64,
+    msg_count: u8,
+    data_exchanged: usize,
+    token: String,
+    handoff: Option<String>,
+    trace: Option<String>,
+    #[serde(default)]
+    capabilities: Option<Capabilities>,
+}
+
+/// A channel, as persisted by a store or a snapshot.
+#[derive(Debug, Deserialize, Serialize)]
+pub struct SavedChannel {
+    participants: Vec<SavedParticipant>,
+    seq: u64,
+    features: HashSet<String>,
+    messages: u64,
+    bytes: u64,
+}
+
+impl SavedChannel {
+    pub fn new(state: &ChannelState) -> Self {
+        Self {
+            participants: state
+                .participants
+                .values()
+                .map(|party| SavedParticipant {
+                    id: party.id,
+                    role: party.role,
+                    lang: party.lang.to_owned(),
+                    age: party.started.elapsed().as_secs(),
+                    msg_count: party.msg_count,
+                    data_exchanged: party.data_exchanged,
+                    token: party.token.clone(),
+                    handoff: party.handoff.clone(),
+                    trace: party.trace.clone(),
+                    capabilities: party.capabilities.clone(),
+                })
+                .collect(),
+            seq: state.seq,
+            features: state.features.clone(),
+            messages: state.messages,
+            bytes: state.bytes,
+        }
+    }
+
+    pub fn participants(&self) -> usize {
+        self.participants.len()
+    }
+
+    /// Seconds since the first remaining participant connected.
+    pub fn age(&self) -> u64 {
+        self.participants.iter().map(|party| party.age).max().unwrap_or(0)
+    }
+
+    /// The channel as it was saved `now`, with every participant's slot
+    /// held for them to reconnect.
+    pub fn restore(self, now: Instant, channel_rate: u64) -> ChannelState {
+        ChannelState {
+            participants: self
+                .participants
+                .into_iter()
+                .map(|party| {
+                    let started = now
+                        .checked_sub(Duration::from_secs(party.age))
+                        .unwrap_or(now);
+                    let restored = Channel {
+                        id: party.id,
+                        role: party.role,
+                        lang: i18n::negotiate(&party.lang),
+                        started,
+                        msg_count: party.msg_count,
+                        data_exchanged: party.data_exchanged,
+                        recent_ids: Default::default(),
+                        token: party.token,
+                        held: Some(now),
+                        handoff: party.handoff,
+                        trace: party.trace,
+                        capabilities: party.capabilities,
+                    };
+                    (party.id, restored)
+                })
+                .collect(),
+            seq: self.seq,
+            features: self.features,
+            messages: self.messages,
+            bytes: self.bytes,
+            throttle: match channel_rate {
+                0 => None,
+                rate => Some(Throttle::new(rate)),
+            },
+            last_active: Some(now),
+            ..Default::default()
+        }
+    }
+}
+
+#[derive(Debug, Deserialize, Serialize)]
+struct Snapshot {
+    /// when it was taken, in seconds since the epoch
+    ts: u64,
+    channels: HashMap<Uuid, SavedChannel>,
+}
+
+/// Where shard `shard` keeps its snapshot.
+pub fn path(base: &str, shard: usize) -> PathBuf {
+    PathBuf::from(format!("{}.{}", base, shard))
+}
+
+/// Write a shard's channels out. Returns how many were written.
+pub fn save(base: &str, shard: usize, channels: &ChannelStore) -> io::Result<usize> {
+    let snapshot = Snapshot {
+        ts: now(),
+        channels: channels
+            .ids()
+            .into_iter()
+            .filter_map(|id| channels.get(&id).map(|state| (id, SavedChannel::new(state))))
+            .collect(),
+    };
+    let path = path(base, shard);
+    // Written aside and renamed into place, so a half written snapshot
+    // is never read.
+    let partial = PathBuf::from(format!("{}.partial", path.display()));
+    let body = serde_json::to_vec(&snapshot)
+        .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+    // Created afresh, so that it is only ever readable by us: it holds
+    // reconnect tokens.
+    if let Err(e) = fs::remove_file(&partial) {
+        if e.kind() != io::ErrorKind::NotFound {
+            return Err(e);
+        }
+    }
+    let mut options = OpenOptions::new();
+    options.write(true).create_new(true);
+    #[cfg(unix)]
+    options.mode(0o600);
+    options.open(&partial)?.write_all(&body)?;
+    fs::rename(&partial, &path)?;
+    Ok(snapshot.channels.len())
+}
+
+/// Every shard's snapshot at `base`, however many shards the node had.
+fn shard_paths(base: &str) -> io::Result<Vec<PathBuf>> {
+    let base = Path::new(base);
+    let dir = match base.parent() {
+        Some(dir) if dir != Path::new("") => dir,
+        _ => Path::new("."),
+    };
+    let prefix = match base.file_name().and_then(|name| name.to_str()) {
+        Some(name) => format!("{}.", name),
+        None => return Ok(Vec::new()),
+    };
+    let mut paths = Vec::new();
+    for entry in fs::read_dir(dir)? {
+        let entry = entry?;
+        let name = entry.file_name();
+        let shard = name.to_str().and_then(|name| {
+            if name.starts_with(&prefix) {
+                name[prefix.len()..].parse::<usize>().ok()
+            } else {
+                None
+            }
+        });
+        if shard.is_some() {
+            paths.push(entry.path());
+        }
+    }
+    paths.sort();
+    Ok(paths)
+}
+
+/// Read and remove every shard's snapshot, returning the channels that
+/// may still be rejoined within `grace` seconds.
+pub fn load(
+    base: &str,
+    grace: u64,
+    channel_rate: u64,
+    log: &Logger,
+) -> Vec<(Uuid, ChannelState)> {
+    let mut restored = Vec::new();
+    // The node may have had more or fewer shards than it has now, and a
+    // shard may not have written its snapshot.
+    let paths = match shard_paths(base) {
+        Ok(paths) => paths,
+        Err(e) => {
+            error!(log, "Could not list snapshots {}.*: {}", base, e);
+            return restored;
+        }
+    };
+    for path in paths {
+        let body = match fs::read(&path) {
+            Ok(body) => body,
+            Err(e) => {
+                error!(log, "Could not read snapshot {}: {}", path.display(), e);
+                continue;
+            }
+        };
+        if let Err(e) = fs::remove_file(&path) {
+            error!(log, "Could not remove snapshot {}: {}", path.display(), e);
+        }
+        let snapshot: Snapshot = match serde_json::from_slice(&body) {
+            Ok(snapshot) => snapshot,
+            Err(e) => {
+                warn!(log, "Skipping unreadable snapshot {}: {}", path.display(), e);
+                continue;
+            }
+        };
+        let age = now().saturating_sub(snapshot.ts);
+        if age > grace {
+            warn!(
+                log,
+                "Skipping snapshot {} taken {}s ago, past reconnect_grace",
+                path.display(),
+                age
+            );
+            continue;
+        }
+        let current = Instant::now();
+        let taken = current
+            .checked_sub(Duration::from_secs(age))
+            .unwrap_or(current);
+        for (id, saved) in snapshot.channels {
+            restored.push((id, saved.restore(taken, channel_rate)));
+        }
+    }
+    restored
+}
+
+#[cfg(test)]
+mod test {
+    use std::env;
+
+    use slog::Discard;
+
+    use store::MemoryStore;
+
+    use super::*;
+
+    #[test]
+    fn test_snapshot() {
+        let base = env::temp_dir().join(format!("pairsona-{}", Uuid::new_v4().simple()));
+        let base = base.to_str().unwrap();
+        let log = Logger::root(Discard, o!());
+        let channel = Uuid::new_v4();
+        let mut store = MemoryStore::default();
+        let mut state = ChannelState::default();
+        state.seq = 3;
+        state.participants.insert(
+            7,
+            Channel {
+                id: 7,
+                role: Role::Joiner,
+                lang: "en",
+                started: Instant::now(),
+                msg_count: 1,
+                data_exchanged: 5,
+                recent_ids: Default::default(),
+                token: "resume".to_owned(),
+                held: None,
+                handoff: None,
+                trace: None,
+                capabilities: None,
+            },
+        );
+        store.insert(channel, state);
+        // Shard 1 didn't write one.
+        assert_eq!(save(base, 2, &store).unwrap(), 1);
+        save(base, 0, &MemoryStore::default()).unwrap();
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+            let mode = fs::metadata(path(base, 2)).unwrap().permissions().mode();
+            assert_eq!(mode & 0o777, 0o600);
+        }
+        let restored = load(base, 60, 0, &log);
+        assert_eq!(restored.len(), 1);
+        let (id, state) = &restored[0];
+        assert_eq!((*id, state.seq), (channel, 3));
+        let party = &state.participants[&7];
+        assert_eq!(party.token, "resume");
+        assert!(party.held.is_some());
+        // read once
+        assert!(load(base, 60, 0, &log).is_empty());
+    }
+}
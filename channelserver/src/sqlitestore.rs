@@ -0,0 +1,208 @@
+//! A `ChannelStore` that writes channels through to SQLite, so a single
+//! node can restart without losing its channels.
+//!
+//! Channels are restored with every participant's slot held, as if their
+//! connection had just dropped: clients reconnect with their reconnect
+//! token and land back in their channel. Declared patterns (strict mode)
+//! and recent message IDs are not persisted.
+
+use std::collections::HashSet;
+use std::time::Instant;
+
+use rusqlite::{self, Connection};
+use serde_json;
+use slog::Logger;
+use uuid::Uuid;
+
+use server::ChannelState;
+use snapshot::SavedChannel;
+use store::{ChannelStore, MemoryStore};
+
+pub struct SqliteStore {
+    db: Connection,
+    /// the live channels; the database is only read at startup
+    channels: MemoryStore,
+    /// channels touched since they were last written
+    dirty: HashSet<Uuid>,
+    log: Logger,
+}
+
+impl SqliteStore {
+    pub fn open(path: &str, channel_rate: u64, log: Logger) -> rusqlite::Result<Self> {
+        let db = Connection::open(path)?;
+        db.execute(
+            "CREATE TABLE IF NOT EXISTS channels (id TEXT PRIMARY KEY, state TEXT NOT NULL)",
+            &[],
+        )?;
+        let mut channels = MemoryStore::default();
+        {
+            let now = Instant::now();
+            let mut query = db.prepare("SELECT id, state FROM channels")?;
+            let rows = query.query_map(&[], |row| {
+                (row.get::<_, String>(0), row.get::<_, String>(1))
+            })?;
+            for row in rows {
+                let (id, state) = row?;
+                match (Uuid::parse_str(&id), serde_json::from_str::<SavedChannel>(&state)) {
+                    (Ok(id), Ok(saved)) => channels.insert(id, saved.restore(now, channel_rate)),
+                    _ => warn!(log, "Skipping unreadable stored channel {}", id),
+                }
+            }
+        }
+        Ok(Self {
+            db,
+            channels,
+            dirty: HashSet::new(),
+            log,
+        })
+    }
+
+    fn write(&self, channel: &Uuid) {
+        let id = channel.simple().to_string();
+        let result = match self.channels.get(channel) {
+            Some(state) => match serde_json::to_string(&SavedChannel::new(state)) {
+                Ok(state) => self.db.execute(
+                    "INSERT OR REPLACE INTO channels (id, state) VALUES (?1, ?2)",
+                    &[&id, &state],
+                ),
+                Err(err) => {
+                    error!(self.log, "Could not encode channel {}: {:?}", id, err);
+                    return;
+                }
+            },
+            None => self.db.execute("DELETE FROM channels WHERE id = ?1", &[&id]),
+        };
+        if let Err(err) = result {
+            error!(self.log, "Could not store channel {}: {:?}", id, err);
+        }
+    }
+}
+
+impl ChannelStore for SqliteStore {
+    fn get(&self, channel: &Uuid) -> Option<&ChannelState> {
+        self.channels.get(channel)
+    }
+
+    fn get_mut(&mut self, channel: &Uuid) -> Option<&mut ChannelState> {
+        self.channels.get_mut(channel)
+    }
+
+    fn insert(&mut self, channel: Uuid, state: ChannelState) {
+        self.channels.insert(channel, state);
+        self.write(&channel);
+    }
+
+    fn remove(&mut self, channel: &Uuid) -> Option<ChannelState> {
+        let state = self.channels.remove(channel);
+        self.dirty.remove(channel);
+        self.write(channel);
+        state
+    }
+
+    fn len(&self) -> usize {
+        self.channels.len()
+    }
+
+    fn ids(&self) -> Vec<Uuid> {
+        self.channels.ids()
+    }
+
+    fn save(&mut self, channel: &Uuid) {
+        self.dirty.remove(channel);
+        self.write(channel);
+    }
+
+    fn touch(&mut self, channel: &Uuid) {
+        self.dirty.insert(*channel);
+    }
+
+    fn flush(&mut self) {
+        if self.dirty.is_empty() {
+            return;
+        }
+        // One transaction, rather than a sync per channel.
+        if let Err(err) = self.db.execute_batch("BEGIN") {
+            error!(self.log, "Could not store channels: {:?}", err);
+            return;
+        }
+        let dirty: Vec<Uuid> = self.dirty.drain().collect();
+        for channel in dirty {
+            self.write(&channel);
+        }
+        if let Err(err) = self.db.execute_batch("COMMIT") {
+            error!(self.log, "Could not store channels: {:?}", err);
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::collections::{HashMap, VecDeque};
+    use std::env;
+    use std::fs;
+
+    use slog::Discard;
+
+    use protocol::Role;
+    use server::Channel;
+
+    use super::*;
+
+    #[test]
+    fn test_restore() {
+        let path = env::temp_dir().join(format!("pairsona-{}.db", Uuid::new_v4().simple()));
+        let path = path.to_str().unwrap();
+        let log = Logger::root(Discard, o!());
+        let channel = Uuid::new_v4();
+        {
+            let mut store = SqliteStore::open(path, 0, log.clone()).unwrap();
+            let mut participants = HashMap::new();
+            participants.insert(
+                7,
+                Channel {
+                    id: 7,
+                    role: Role::Initiator,
+                    lang: "de",
+                    started: Instant::now(),
+                    msg_count: 2,
+                    data_exchanged: 10,
+                    recent_ids: VecDeque::new(),
+                    token: "resume".to_owned(),
+                    held: None,
+                    handoff: None,
+                    trace: None,
+                    capabilities: None,
+                },
+            );
+            store.insert(
+                channel,
+                ChannelState {
+                    participants,
+                    ..Default::default()
+                },
+            );
+            store.get_mut(&channel).unwrap().messages = 2;
+            store.save(&channel);
+            // counters alone are only written on a flush
+            store.get_mut(&channel).unwrap().messages = 3;
+            store.touch(&channel);
+            assert_eq!(store.dirty.len(), 1);
+            store.flush();
+            assert!(store.dirty.is_empty());
+            store.get_mut(&channel).unwrap().messages = 4;
+            store.touch(&channel);
+            store.insert(Uuid::new_v4(), ChannelState::default());
+            let gone = store.ids().into_iter().find(|id| *id != channel).unwrap();
+            store.remove(&gone);
+        }
+        let store = SqliteStore::open(path, 0, log).unwrap();
+        assert_eq!(store.ids(), vec![channel]);
+        let state = store.get(&channel).unwrap();
+        assert_eq!(state.messages, 3);
+        let party = &state.participants[&7];
+        assert_eq!((party.lang, party.token.as_str()), ("de", "resume"));
+        // waiting for the participant to reconnect
+        assert!(party.held.is_some());
+        fs::remove_file(path).ok();
+    }
+}
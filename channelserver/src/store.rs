@@ -0,0 +1,112 @@
+//! Where `ChannelServer` keeps its channels' bookkeeping: IDs,
+//! participants, counters and reconnect tokens.
+//!
+//! Channels are always worked on in memory, since their state includes
+//! throttles, backlogs and pattern progress that only make sense inside
+//! this process. A store that persists channels elsewhere (Redis, SQLite)
+//! keeps its own in-memory copy and writes through when told a channel
+//! changed, so the actor logic doesn't depend on the backend. Changes to
+//! a channel's counters alone, which every relayed frame makes, are only
+//! written out in batches, by `flush`.
+
+use std::collections::HashMap;
+
+use uuid::Uuid;
+
+use logging::MozLogger;
+use server::ChannelState;
+use settings::Settings;
+#[cfg(feature = "sqlite")]
+use sqlitestore::SqliteStore;
+
+pub trait ChannelStore {
+    fn get(&self, channel: &Uuid) -> Option<&ChannelState>;
+
+    fn get_mut(&mut self, channel: &Uuid) -> Option<&mut ChannelState>;
+
+    fn insert(&mut self, channel: Uuid, state: ChannelState);
+
+    fn remove(&mut self, channel: &Uuid) -> Option<ChannelState>;
+
+    /// The number of live channels.
+    fn len(&self) -> usize;
+
+    fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    fn contains(&self, channel: &Uuid) -> bool {
+        self.get(channel).is_some()
+    }
+
+    /// The IDs of all live channels.
+    fn ids(&self) -> Vec<Uuid>;
+
+    /// `channel`'s participants, tokens or features were changed in
+    /// place. Persistent stores write it out.
+    fn save(&mut self, _channel: &Uuid) {}
+
+    /// Only `channel`'s counters changed. Persistent stores write it out
+    /// at the next `flush`.
+    fn touch(&mut self, _channel: &Uuid) {}
+
+    /// Write out every channel touched since the last flush.
+    fn flush(&mut self) {}
+}
+
+/// The default store: channels only live as long as the process.
+#[derive(Default)]
+pub struct MemoryStore {
+    channels: HashMap<Uuid, ChannelState>,
+}
+
+impl ChannelStore for MemoryStore {
+    fn get(&self, channel: &Uuid) -> Option<&ChannelState> {
+        self.channels.get(channel)
+    }
+
+    fn get_mut(&mut self, channel: &Uuid) -> Option<&mut ChannelState> {
+        self.channels.get_mut(channel)
+    }
+
+    fn insert(&mut self, channel: Uuid, state: ChannelState) {
+        self.channels.insert(channel, state);
+    }
+
+    fn remove(&mut self, channel: &Uuid) -> Option<ChannelState> {
+        self.channels.remove(channel)
+    }
+
+    fn len(&self) -> usize {
+        self.channels.len()
+    }
+
+    fn ids(&self) -> Vec<Uuid> {
+        self.channels.keys().cloned().collect()
+    }
+}
+
+/// The store named by `channel_store`: empty for `MemoryStore`, or
+/// `sqlite:<path>`.
+pub fn from_settings(settings: &Settings, log: &MozLogger) -> Result<Box<ChannelStore>, String> {
+    let spec = settings.channel_store.trim();
+    if spec.is_empty() || spec == "memory" {
+        Ok(Box::new(MemoryStore::default()))
+    } else if spec.starts_with("sqlite:") {
+        sqlite(&spec[7..], settings, log)
+    } else {
+        Err(format!("Unknown channel_store {:?}", spec))
+    }
+}
+
+#[cfg(feature = "sqlite")]
+fn sqlite(path: &str, settings: &Settings, log: &MozLogger) -> Result<Box<ChannelStore>, String> {
+    SqliteStore::open(path, settings.channel_rate, log.log.clone())
+        .map(|store| Box::new(store) as Box<ChannelStore>)
+        .map_err(|e| format!("Could not open {}: {}", path, e))
+}
+
+#[cfg(not(feature = "sqlite"))]
+fn sqlite(path: &str, settings: &Settings, log: &MozLogger) -> Result<Box<ChannelStore>, String> {
+    Err("channel_store sqlite: needs the sqlite feature".to_owned())
+}
@@ -0,0 +1,279 @@
+//! An RFC 5424 syslog log sink, over UDP, TCP or a Unix socket.
+//!
+//! The target is given as a URL: `udp://host:514`, `tcp://host:601` or
+//! `unix:///dev/log`. TCP messages are framed by octet counting (RFC 6587),
+//! and a TCP connection is re-established if a write to it fails.
+//!
+//! A record's key/values follow its message as ` key=value` pairs.
+
+use std::env;
+use std::fmt;
+use std::io::{self, Write};
+use std::net::{TcpStream, UdpSocket};
+#[cfg(unix)]
+use std::os::unix::net::UnixDatagram;
+use std::process;
+use std::sync::Mutex;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use slog::{self, Drain, Level, OwnedKVList, Record, KV};
+
+const APP_NAME: &str = "pairsona";
+
+enum Transport {
+    Udp(UdpSocket),
+    Tcp(TcpStream),
+    #[cfg(unix)]
+    Unix(UnixDatagram),
+}
+
+impl Transport {
+    fn send(&mut self, msg: &[u8]) -> io::Result<()> {
+        match self {
+            Transport::Udp(socket) => socket.send(msg).map(|_| ()),
+            Transport::Tcp(stream) => {
+                write!(stream, "{} ", msg.len())?;
+                stream.write_all(msg)
+            }
+            #[cfg(unix)]
+            Transport::Unix(socket) => socket.send(msg).map(|_| ()),
+        }
+    }
+}
+
+pub struct SyslogDrain {
+    transport: Mutex<Transport>,
+    /// What the transport was connected to, for reconnecting.
+    target: String,
+    facility: u8,
+    hostname: String,
+    pid: u32,
+}
+
+/// The code for a syslog facility name.
+pub fn facility(name: &str) -> Option<u8> {
+    let code = match name {
+        "kern" => 0,
+        "user" => 1,
+        "mail" => 2,
+        "daemon" => 3,
+        "auth" => 4,
+        "syslog" => 5,
+        "lpr" => 6,
+        "news" => 7,
+        "uucp" => 8,
+        "cron" => 9,
+        "authpriv" => 10,
+        "ftp" => 11,
+        _ if name.starts_with("local") => match name[5..].parse::<u8>() {
+            Ok(n) if n < 8 => 16 + n,
+            _ => return None,
+        },
+        _ => return None,
+    };
+    Some(code)
+}
+
+impl SyslogDrain {
+    pub fn connect(target: &str, facility_name: &str) -> io::Result<Self> {
+        let invalid = |msg: String| io::Error::new(io::ErrorKind::InvalidInput, msg);
+        let facility = facility(facility_name)
+            .ok_or_else(|| invalid(format!("Unknown syslog facility {:?}", facility_name)))?;
+        Ok(Self {
+            transport: Mutex::new(open(target)?),
+            target: target.to_owned(),
+            facility,
+            hostname: env::var("HOSTNAME").unwrap_or_else(|_| "-".to_owned()),
+            pid: process::id(),
+        })
+    }
+
+    /// Send `msg`, reconnecting and trying once more if a TCP connection
+    /// has gone away.
+    fn send(&self, msg: &[u8]) -> io::Result<()> {
+        let mut transport = self.transport.lock().unwrap();
+        match transport.send(msg) {
+            Err(_) if is_tcp(&transport) => {
+                *transport = open(&self.target)?;
+                transport.send(msg)
+            }
+            result => result,
+        }
+    }
+
+    fn format(&self, level: Level, now: SystemTime, msg: &str) -> String {
+        let severity = match level {
+            Level::Critical => 2,
+            Level::Error => 3,
+            Level::Warning => 4,
+            Level::Info => 6,
+            Level::Debug | Level::Trace => 7,
+        };
+        format!(
+            "<{}>1 {} {} {} {} - - {}",
+            u32::from(self.facility) * 8 + severity,
+            timestamp(now),
+            self.hostname,
+            APP_NAME,
+            self.pid,
+            msg
+        )
+    }
+}
+
+fn open(target: &str) -> io::Result<Transport> {
+    if target.starts_with("udp://") {
+        let socket = UdpSocket::bind("0.0.0.0:0")?;
+        socket.connect(&target[6..])?;
+        Ok(Transport::Udp(socket))
+    } else if target.starts_with("tcp://") {
+        Ok(Transport::Tcp(TcpStream::connect(&target[6..])?))
+    } else if cfg!(unix) && target.starts_with("unix://") {
+        unix(&target[7..])
+    } else {
+        Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!("Unsupported syslog target {:?}", target),
+        ))
+    }
+}
+
+fn is_tcp(transport: &Transport) -> bool {
+    match *transport {
+        Transport::Tcp(_) => true,
+        _ => false,
+    }
+}
+
+/// Appends a record's key/values to its message as ` key=value` pairs.
+struct Pairs<'a>(&'a mut String);
+
+impl<'a> slog::Serializer for Pairs<'a> {
+    fn emit_arguments(&mut self, key: slog::Key, val: &fmt::Arguments) -> slog::Result {
+        use std::fmt::Write;
+        write!(self.0, " {}={}", key, val)?;
+        Ok(())
+    }
+}
+
+#[cfg(unix)]
+fn unix(path: &str) -> io::Result<Transport> {
+    let socket = UnixDatagram::unbound()?;
+    socket.connect(path)?;
+    Ok(Transport::Unix(socket))
+}
+
+#[cfg(not(unix))]
+fn unix(_path: &str) -> io::Result<Transport> {
+    unreachable!()
+}
+
+impl Drain for SyslogDrain {
+    type Ok = ();
+    type Err = io::Error;
+
+    fn log(&self, record: &Record, values: &OwnedKVList) -> io::Result<()> {
+        let mut text = record.msg().to_string();
+        {
+            let mut pairs = Pairs(&mut text);
+            record.kv().serialize(record, &mut pairs)?;
+            values.serialize(record, &mut pairs)?;
+        }
+        let msg = self.format(record.level(), SystemTime::now(), &text);
+        self.send(msg.as_bytes())
+    }
+}
+
+/// An RFC 3339 UTC timestamp, as RFC 5424 requires.
+pub fn timestamp(now: SystemTime) -> String {
+    let since = now.duration_since(UNIX_EPOCH).unwrap_or_default();
+    let secs = since.as_secs();
+    let (days, rem) = ((secs / 86_400) as i64, secs % 86_400);
+    // Days to civil date, after Howard Hinnant's `civil_from_days`.
+    let z = days + 719_468;
+    let era = z / 146_097;
+    let doe = z - era * 146_097;
+    let yoe = (doe - doe / 1460 + doe / 36_524 - doe / 146_096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + if month <= 2 { 1 } else { 0 };
+    format!(
+        "{:04}-{:02}-{:02}T{:02}:{:02}:{:02}.{:06}Z",
+        year,
+        month,
+        day,
+        rem / 3600,
+        rem / 60 % 60,
+        rem % 60,
+        since.subsec_micros()
+    )
+}
+
+#[cfg(test)]
+mod test {
+    use std::time::Duration;
+
+    use super::*;
+
+    #[test]
+    fn test_facility() {
+        assert_eq!(facility("daemon"), Some(3));
+        assert_eq!(facility("local7"), Some(23));
+        assert_eq!(facility("local8"), None);
+        assert_eq!(facility("bogus"), None);
+    }
+
+    #[test]
+    fn test_format() {
+        let then = UNIX_EPOCH + Duration::from_secs(1_000_000_000);
+        assert_eq!(timestamp(then), "2001-09-09T01:46:40.000000Z");
+        assert_eq!(timestamp(UNIX_EPOCH), "1970-01-01T00:00:00.000000Z");
+
+        let socket = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let target = format!("udp://{}", socket.local_addr().unwrap());
+        let drain = SyslogDrain::connect(&target, "local0").unwrap();
+        let msg = drain.format(Level::Error, then, "hello");
+        assert!(msg.starts_with("<131>1 2001-09-09T01:46:40.000000Z "));
+        assert!(msg.ends_with(&format!(" pairsona {} - - hello", process::id())));
+    }
+
+    #[test]
+    fn test_pairs() {
+        let socket = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let target = format!("udp://{}", socket.local_addr().unwrap());
+        let drain = SyslogDrain::connect(&target, "user").unwrap();
+        let log = slog::Logger::root(drain.fuse(), o!("shard" => 1));
+        slog_info!(log, "joined"; "channel" => "abc", "count" => 2);
+        let mut buf = [0; 512];
+        let len = socket.recv(&mut buf).unwrap();
+        let msg = String::from_utf8_lossy(&buf[..len]);
+        assert!(msg.contains(" - - joined "), "{}", msg);
+        for pair in &[" channel=abc", " count=2", " shard=1"] {
+            assert!(msg.contains(pair), "{}", msg);
+        }
+    }
+
+    #[test]
+    fn test_tcp_reconnect() {
+        use std::io::Read;
+        use std::net::{Shutdown, TcpListener};
+
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let target = format!("tcp://{}", listener.local_addr().unwrap());
+        let drain = SyslogDrain::connect(&target, "user").unwrap();
+        let (first, _) = listener.accept().unwrap();
+        first.shutdown(Shutdown::Both).unwrap();
+        drop(first);
+        // The first write after the peer goes may still succeed; keep
+        // writing until the drain notices and reconnects.
+        for _ in 0..10 {
+            drain.send(b"hello").unwrap();
+        }
+        let (mut second, _) = listener.accept().unwrap();
+        let mut buf = [0; 7];
+        second.read_exact(&mut buf).unwrap();
+        assert_eq!(&buf, b"5 hello");
+    }
+}
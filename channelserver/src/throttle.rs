@@ -0,0 +1,73 @@
+//! Leaky bucket bandwidth throttling of the frames relayed on a channel.
+//!
+//! A channel may relay `channel_rate` octets per second on average, with
+//! bursts of up to a second's worth. Frames that don't fit wait for the
+//! bucket to drain, so a client streaming bulk data through the relay only
+//! slows down its own channel.
+
+use std::time::{Duration, Instant};
+
+/// Seconds of traffic a channel may have waiting before frames are dropped.
+const BACKLOG_SECS: f64 = 10.0;
+
+#[derive(Clone, Debug)]
+pub struct Throttle {
+    /// octets drained per second
+    rate: f64,
+    /// octets the bucket holds
+    capacity: f64,
+    level: f64,
+    updated: Instant,
+}
+
+impl Throttle {
+    pub fn new(bytes_per_sec: u64) -> Self {
+        let rate = bytes_per_sec.max(1) as f64;
+        Self {
+            rate,
+            capacity: rate,
+            level: 0.0,
+            updated: Instant::now(),
+        }
+    }
+
+    /// Octets of frames that may wait for room in the bucket.
+    pub fn backlog_limit(&self) -> usize {
+        (self.rate * BACKLOG_SECS) as usize
+    }
+
+    /// Pass `size` octets through the bucket. If there isn't room yet,
+    /// returns how long until there will be.
+    pub fn admit(&mut self, size: usize, now: Instant) -> Result<(), Duration> {
+        let elapsed = now.duration_since(self.updated);
+        let elapsed = elapsed.as_secs() as f64 + f64::from(elapsed.subsec_nanos()) / 1e9;
+        self.level = (self.level - elapsed * self.rate).max(0.0);
+        self.updated = now;
+        let size = size as f64;
+        // A frame bigger than the whole bucket gets through once it's empty.
+        if self.level == 0.0 || self.level + size <= self.capacity {
+            self.level += size;
+            return Ok(());
+        }
+        let wait = (self.level + size.min(self.capacity) - self.capacity) / self.rate;
+        Err(Duration::from_millis((wait * 1000.0).ceil() as u64))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_admit() {
+        let mut throttle = Throttle::new(1000);
+        let start = Instant::now();
+        assert!(throttle.admit(600, start).is_ok());
+        assert_eq!(throttle.admit(600, start), Err(Duration::from_millis(200)));
+        assert!(throttle.admit(600, start + Duration::from_millis(200)).is_ok());
+        // oversized frames wait for an empty bucket, then pass.
+        let later = start + Duration::from_secs(2);
+        assert!(throttle.admit(5000, later).is_ok());
+        assert_eq!(throttle.admit(1000, later), Err(Duration::from_secs(5)));
+    }
+}
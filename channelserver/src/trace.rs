@@ -0,0 +1,89 @@
+//! Trace context from the edge proxy, so a connection's logs can be
+//! correlated with the proxy's traces.
+//!
+//! The W3C `traceparent` header is preferred, falling back to Google's
+//! `X-Cloud-Trace-Context`.
+
+use actix_web::http::header::HeaderMap;
+
+/// The trace ID the upgrade request was made under, as lower case hex.
+pub fn trace_id(headers: &HeaderMap) -> Option<String> {
+    let header = |name: &str| headers.get(name).and_then(|v| v.to_str().ok());
+    header("traceparent")
+        .and_then(from_traceparent)
+        .or_else(|| header("x-cloud-trace-context").and_then(from_cloud_trace))
+}
+
+fn valid_id(id: &str, len: usize) -> bool {
+    id.len() == len
+        && id.chars().all(|c| c.is_ascii_hexdigit())
+        && !id.chars().all(|c| c == '0')
+}
+
+/// `version-traceid-parentid-flags`, e.g.
+/// `00-4bf92f3577b34da6a3ce929d0e0e4736-00f067aa0ba902b7-01`.
+fn from_traceparent(value: &str) -> Option<String> {
+    let parts: Vec<&str> = value.trim().split('-').collect();
+    if parts.len() < 4 || parts[0].len() != 2 || parts[0] == "ff" {
+        return None;
+    }
+    // Later versions may append fields, but version 00 has exactly four.
+    if parts[0] == "00" && parts.len() != 4 {
+        return None;
+    }
+    if valid_id(parts[1], 32) && valid_id(parts[2], 16) {
+        Some(parts[1].to_lowercase())
+    } else {
+        None
+    }
+}
+
+/// `TRACE_ID/SPAN_ID;o=OPTIONS`, where only the trace ID is required.
+fn from_cloud_trace(value: &str) -> Option<String> {
+    let id = value.trim().split(|c: char| c == '/' || c == ';').next()?;
+    if valid_id(id, 32) {
+        Some(id.to_lowercase())
+    } else {
+        None
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use actix_web::http::header::HeaderValue;
+
+    use super::*;
+
+    const ID: &str = "4bf92f3577b34da6a3ce929d0e0e4736";
+
+    #[test]
+    fn test_trace_id() {
+        let mut headers = HeaderMap::new();
+        assert_eq!(trace_id(&headers), None);
+        headers.insert(
+            "x-cloud-trace-context",
+            HeaderValue::from_static("4BF92F3577B34DA6A3CE929D0E0E4736/1;o=1"),
+        );
+        assert_eq!(trace_id(&headers), Some(ID.to_owned()));
+        // traceparent wins
+        headers.insert(
+            "traceparent",
+            HeaderValue::from_static("00-0af7651916cd43dd8448eb211c80319c-00f067aa0ba902b7-01"),
+        );
+        assert_eq!(
+            trace_id(&headers),
+            Some("0af7651916cd43dd8448eb211c80319c".to_owned())
+        );
+        // ...unless it's invalid
+        for bad in [
+            "00-00000000000000000000000000000000-00f067aa0ba902b7-01",
+            "00-4bf92f3577b34da6a3ce929d0e0e4736-00f067aa0ba902b7-01-extra",
+            "ff-4bf92f3577b34da6a3ce929d0e0e4736-00f067aa0ba902b7-01",
+            "00-4bf92f35-00f067aa0ba902b7-01",
+        ].iter()
+        {
+            headers.insert("traceparent", HeaderValue::from_static(*bad));
+            assert_eq!(trace_id(&headers), Some(ID.to_owned()));
+        }
+    }
+}
@@ -0,0 +1,133 @@
+//! Strict validation of websocket upgrade requests.
+//!
+//! actix-web's handshake only checks that the upgrade headers are present.
+//! These checks run first, and refuse requests a well behaved client would
+//! never send: oversized or excessive headers, conflicting duplicates of
+//! headers that request smuggling relies on, and malformed websocket keys
+//! or versions.
+
+use actix_web::http::header::{self, HeaderMap, HeaderName};
+
+/// Why an upgrade request was refused.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Rejection {
+    TooManyHeaders,
+    HeadersTooLarge,
+    ConflictingHeader,
+    BadKey,
+    BadVersion,
+}
+
+impl Rejection {
+    /// Short name, used in metrics and error details.
+    pub fn name(&self) -> &'static str {
+        match self {
+            Rejection::TooManyHeaders => "too_many_headers",
+            Rejection::HeadersTooLarge => "headers_too_large",
+            Rejection::ConflictingHeader => "conflicting_header",
+            Rejection::BadKey => "bad_key",
+            Rejection::BadVersion => "bad_version",
+        }
+    }
+}
+
+/// Headers that may appear more than once only if every copy agrees.
+const SINGLE_VALUED: [HeaderName; 2] = [header::HOST, header::CONTENT_LENGTH];
+
+/// Check an upgrade request's headers. `max_headers` and `max_size` (the
+/// total octets of header names and values) of zero are unlimited.
+pub fn validate(headers: &HeaderMap, max_headers: usize, max_size: usize) -> Result<(), Rejection> {
+    if max_headers > 0 && headers.len() > max_headers {
+        return Err(Rejection::TooManyHeaders);
+    }
+    let size: usize = headers
+        .iter()
+        .map(|(name, value)| name.as_str().len() + value.len())
+        .sum();
+    if max_size > 0 && size > max_size {
+        return Err(Rejection::HeadersTooLarge);
+    }
+    for name in SINGLE_VALUED.iter() {
+        let mut values = headers.get_all(name).iter();
+        if let Some(first) = values.next() {
+            if values.any(|value| value != first) {
+                return Err(Rejection::ConflictingHeader);
+            }
+        }
+    }
+    match single(headers, &header::SEC_WEBSOCKET_VERSION) {
+        Some(version) if version == b"13" => {}
+        _ => return Err(Rejection::BadVersion),
+    }
+    match single(headers, &header::SEC_WEBSOCKET_KEY) {
+        Some(key) if valid_key(key) => Ok(()),
+        _ => Err(Rejection::BadKey),
+    }
+}
+
+/// The value of a header that must appear exactly once.
+fn single<'a>(headers: &'a HeaderMap, name: &HeaderName) -> Option<&'a [u8]> {
+    let mut values = headers.get_all(name).iter();
+    match (values.next(), values.next()) {
+        (Some(value), None) => Some(value.as_bytes()),
+        _ => None,
+    }
+}
+
+/// A key must be exactly 16 octets, base64 encoded (RFC 6455, 4.1).
+fn valid_key(key: &[u8]) -> bool {
+    let is_base64 = |c: &u8| c.is_ascii_alphanumeric() || *c == b'+' || *c == b'/';
+    // 16 octets encode to 22 characters, the last of which carries only
+    // two bits, then two padding characters.
+    key.len() == 24
+        && key[..21].iter().all(is_base64)
+        && b"AQgw".contains(&key[21])
+        && &key[22..] == b"=="
+}
+
+#[cfg(test)]
+mod test {
+    use actix_web::http::header::HeaderValue;
+
+    use super::*;
+
+    fn upgrade() -> HeaderMap {
+        let mut headers = HeaderMap::new();
+        headers.insert(header::HOST, HeaderValue::from_static("example.com"));
+        headers.insert(
+            header::SEC_WEBSOCKET_KEY,
+            HeaderValue::from_static("dGhlIHNhbXBsZSBub25jZQ=="),
+        );
+        headers.insert(header::SEC_WEBSOCKET_VERSION, HeaderValue::from_static("13"));
+        headers
+    }
+
+    #[test]
+    fn test_validate() {
+        assert_eq!(validate(&upgrade(), 0, 0), Ok(()));
+        assert_eq!(validate(&upgrade(), 2, 0), Err(Rejection::TooManyHeaders));
+        assert_eq!(validate(&upgrade(), 0, 40), Err(Rejection::HeadersTooLarge));
+
+        let mut headers = upgrade();
+        headers.append(header::HOST, HeaderValue::from_static("example.com"));
+        assert_eq!(validate(&headers, 0, 0), Ok(()));
+        headers.append(header::HOST, HeaderValue::from_static("evil.example.com"));
+        assert_eq!(validate(&headers, 0, 0), Err(Rejection::ConflictingHeader));
+
+        let mut headers = upgrade();
+        headers.insert(header::SEC_WEBSOCKET_VERSION, HeaderValue::from_static("8"));
+        assert_eq!(validate(&headers, 0, 0), Err(Rejection::BadVersion));
+
+        for key in ["short==", "dGhlIHNhbXBsZSBub25jZR==", "dGhlIHNhbXBsZSBub25j!Q=="].iter() {
+            let mut headers = upgrade();
+            headers.insert(header::SEC_WEBSOCKET_KEY, HeaderValue::from_static(*key));
+            assert_eq!(validate(&headers, 0, 0), Err(Rejection::BadKey));
+        }
+        let mut headers = upgrade();
+        headers.append(
+            header::SEC_WEBSOCKET_KEY,
+            HeaderValue::from_static("dGhlIHNhbXBsZSBub25jZQ=="),
+        );
+        assert_eq!(validate(&headers, 0, 0), Err(Rejection::BadKey));
+    }
+}
@@ -0,0 +1,275 @@
+//! A browser client for the pairsona channel server, so that web clients
+//! needn't implement the wire protocol themselves.
+//!
+//! ```js
+//! const client = new PairsonaClient("wss://relay.example.com", null);
+//! client.on_state(state => console.log(state));
+//! client.on_control(control => console.log(control.control));
+//! client.on_message((data, seq) => console.log(data));
+//! client.hello({encodings: ["text"], resume: true, extensions: {}});
+//! client.connect();
+//! ```
+//!
+//! The client reconnects by itself: with its reconnect token after a drop,
+//! to the new node when its channel migrates (`4019`), and to one of the
+//! alternates when a node turns it away (`4012`) or stops (`4005`).
+
+extern crate js_sys;
+extern crate pairsona_protocol;
+extern crate serde_json;
+extern crate wasm_bindgen;
+extern crate web_sys;
+
+pub mod session;
+
+use std::cell::RefCell;
+use std::rc::{Rc, Weak};
+
+use js_sys::{Date, Function};
+use wasm_bindgen::prelude::*;
+use wasm_bindgen::JsCast;
+use web_sys::{CloseEvent, Event, MessageEvent, WebSocket};
+
+use session::{Incoming, Next, Session};
+
+struct Inner {
+    session: Session,
+    socket: Option<WebSocket>,
+    /// Kept alive for as long as the socket they are attached to.
+    handlers: Vec<Box<AsRef<JsValue>>>,
+    on_message: Option<Function>,
+    on_control: Option<Function>,
+    on_state: Option<Function>,
+    /// `close()` was called, so the socket closing isn't a drop.
+    closing: bool,
+}
+
+/// A connection to a channel, reconnecting as needed.
+#[wasm_bindgen]
+pub struct PairsonaClient {
+    inner: Rc<RefCell<Inner>>,
+}
+
+#[wasm_bindgen]
+impl PairsonaClient {
+    /// A client for the server at `url`, joining `channel` (a
+    /// `/v1/ws/...` path) or, if null, creating a new channel.
+    #[wasm_bindgen(constructor)]
+    pub fn new(url: &str, channel: Option<String>) -> PairsonaClient {
+        PairsonaClient {
+            inner: Rc::new(RefCell::new(Inner {
+                session: Session::new(url, channel.as_ref().map(|c| c.as_str())),
+                socket: None,
+                handlers: Vec::new(),
+                on_message: None,
+                on_control: None,
+                on_state: None,
+                closing: false,
+            })),
+        }
+    }
+
+    /// Called with `(data, seq)` for every frame relayed from a peer.
+    pub fn on_message(&self, callback: Function) {
+        self.inner.borrow_mut().on_message = Some(callback);
+    }
+
+    /// Called with every control message, as an object.
+    pub fn on_control(&self, callback: Function) {
+        self.inner.borrow_mut().on_control = Some(callback);
+    }
+
+    /// Called with `"connecting"`, `"open"`, `"reconnecting"` or
+    /// `"closed"`.
+    pub fn on_state(&self, callback: Function) {
+        self.inner.borrow_mut().on_state = Some(callback);
+    }
+
+    /// Declare these capabilities on every connect. The answer arrives as
+    /// a `capabilities` control message once every participant has.
+    pub fn hello(&self, capabilities: JsValue) -> Result<(), JsValue> {
+        let capabilities = capabilities
+            .into_serde()
+            .map_err(|e| JsValue::from_str(&e.to_string()))?;
+        self.inner.borrow_mut().session.hello = Some(capabilities);
+        Ok(())
+    }
+
+    /// Whether the server wraps relayed frames in envelopes
+    /// (`sequence_frames`, `stamp_frames` or `hop_frames`).
+    pub fn set_envelopes(&self, envelopes: bool) {
+        self.inner.borrow_mut().session.envelopes = envelopes;
+    }
+
+    pub fn connect(&self) -> Result<(), JsValue> {
+        self.inner.borrow_mut().closing = false;
+        open(&self.inner)
+    }
+
+    /// Relay `data` to the other participants.
+    pub fn send(&self, data: &str) -> Result<(), JsValue> {
+        match self.inner.borrow().socket {
+            Some(ref socket) => socket.send_with_str(data),
+            None => Err(JsValue::from_str("Not connected")),
+        }
+    }
+
+    /// Leave the channel, giving up our slot.
+    pub fn close(&self) -> Result<(), JsValue> {
+        let mut inner = self.inner.borrow_mut();
+        inner.closing = true;
+        match inner.socket {
+            Some(ref socket) => socket.close(),
+            None => Ok(()),
+        }
+    }
+
+    /// The channel's path, once joined.
+    pub fn channel(&self) -> Option<String> {
+        self.inner.borrow().session.channel.clone()
+    }
+
+    /// What every participant has in common, once they have all sent a
+    /// `hello`; otherwise null.
+    pub fn capabilities(&self) -> JsValue {
+        match self.inner.borrow().session.capabilities {
+            Some(ref common) => JsValue::from_serde(common).unwrap_or(JsValue::NULL),
+            None => JsValue::NULL,
+        }
+    }
+
+    /// How far this device's clock is ahead of the server's, in
+    /// milliseconds, as of joining.
+    pub fn skew_ms(&self) -> Option<f64> {
+        self.inner.borrow().session.skew_ms.map(|skew| skew as f64)
+    }
+}
+
+/// Tell the `on_state` callback, if any. Callbacks are called without the
+/// client borrowed, so that they may call back into it.
+fn emit_state(inner: &Rc<RefCell<Inner>>, state: &str) {
+    let callback = inner.borrow().on_state.clone();
+    if let Some(callback) = callback {
+        callback.call1(&JsValue::NULL, &JsValue::from_str(state)).ok();
+    }
+}
+
+/// Open a socket to where the session says, and attach its handlers.
+fn open(inner: &Rc<RefCell<Inner>>) -> Result<(), JsValue> {
+    let url = inner.borrow().session.url();
+    let socket = WebSocket::new(&url)?;
+    let weak = Rc::downgrade(inner);
+
+    let onopen = {
+        let weak = weak.clone();
+        Closure::wrap(Box::new(move |_: Event| {
+            if let Some(inner) = weak.upgrade() {
+                opened(&inner);
+            }
+        }) as Box<FnMut(Event)>)
+    };
+    let onmessage = {
+        let weak = weak.clone();
+        Closure::wrap(Box::new(move |event: MessageEvent| {
+            if let (Some(inner), Some(text)) = (weak.upgrade(), event.data().as_string()) {
+                received(&inner, &text);
+            }
+        }) as Box<FnMut(MessageEvent)>)
+    };
+    let onclose = Closure::wrap(Box::new(move |event: CloseEvent| {
+        if let Some(inner) = weak.upgrade() {
+            closed(&inner, event.code());
+        }
+    }) as Box<FnMut(CloseEvent)>);
+    socket.set_onopen(Some(onopen.as_ref().unchecked_ref()));
+    socket.set_onmessage(Some(onmessage.as_ref().unchecked_ref()));
+    socket.set_onclose(Some(onclose.as_ref().unchecked_ref()));
+
+    {
+        let mut inner = inner.borrow_mut();
+        inner.socket = Some(socket);
+        inner.handlers = vec![Box::new(onopen), Box::new(onmessage), Box::new(onclose)];
+    }
+    emit_state(inner, "connecting");
+    Ok(())
+}
+
+fn opened(inner: &Rc<RefCell<Inner>>) {
+    {
+        let mut inner = inner.borrow_mut();
+        let hello = inner.session.opened();
+        if let (Some(hello), Some(socket)) = (hello, inner.socket.as_ref()) {
+            socket.send_with_str(&hello).ok();
+        }
+    }
+    emit_state(inner, "open");
+}
+
+fn received(inner: &Rc<RefCell<Inner>>, text: &str) {
+    let (incoming, on_message, on_control) = {
+        let mut inner = inner.borrow_mut();
+        let incoming = inner.session.receive(text, Date::now() as u64);
+        if let Incoming::Control(ref control) = incoming {
+            let answer = Session::answer(control);
+            if let (Some(answer), Some(socket)) = (answer, inner.socket.as_ref()) {
+                socket.send_with_str(&answer).ok();
+            }
+        }
+        (incoming, inner.on_message.clone(), inner.on_control.clone())
+    };
+    match incoming {
+        Incoming::Path(_) | Incoming::Partial { .. } => {}
+        Incoming::Control(control) => {
+            if let Some(callback) = on_control {
+                let control = JsValue::from_serde(&control).unwrap_or(JsValue::NULL);
+                callback.call1(&JsValue::NULL, &control).ok();
+            }
+        }
+        Incoming::Data { data, seq } => {
+            if let Some(callback) = on_message {
+                let seq = seq.map_or(JsValue::NULL, |seq| JsValue::from_f64(seq as f64));
+                callback.call2(&JsValue::NULL, &JsValue::from_str(&data), &seq).ok();
+            }
+        }
+    }
+}
+
+fn closed(inner: &Rc<RefCell<Inner>>, code: u16) {
+    // The handlers are left attached, as one of them is running.
+    let next = {
+        let mut inner = inner.borrow_mut();
+        inner.socket = None;
+        if inner.closing {
+            Next::Stop
+        } else {
+            inner.session.closed(code)
+        }
+    };
+    let delay_ms = match next {
+        Next::Reconnect { delay_ms, .. } => delay_ms,
+        Next::Stop => {
+            emit_state(inner, "closed");
+            return;
+        }
+    };
+    let weak: Weak<RefCell<Inner>> = Rc::downgrade(inner);
+    let retry = Closure::once_into_js(move || {
+        if let Some(inner) = weak.upgrade() {
+            let closing = inner.borrow().closing;
+            if !closing && open(&inner).is_err() {
+                emit_state(&inner, "closed");
+            }
+        }
+    });
+    let scheduled = web_sys::window().map(|window| {
+        window.set_timeout_with_callback_and_timeout_and_arguments_0(
+            retry.unchecked_ref(),
+            delay_ms as i32,
+        )
+    });
+    if let Some(Ok(_)) = scheduled {
+        emit_state(inner, "reconnecting");
+    } else {
+        emit_state(inner, "closed");
+    }
+}
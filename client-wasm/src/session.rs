@@ -0,0 +1,443 @@
+//! What a client knows about its channel, apart from the socket, so that
+//! the protocol can be followed (and tested) outside a browser.
+
+use std::collections::HashMap;
+
+use serde_json::{self, Value};
+
+use pairsona_protocol::{Capabilities, ClientControl, ErrorEnvelope, RelayEnvelope, ServerControl};
+
+/// Delay before the first reconnect attempt, in milliseconds. Each
+/// further attempt waits twice as long, up to `BACKOFF_MAX`.
+pub const BACKOFF_MIN: u32 = 250;
+
+/// Longest delay between reconnect attempts, in milliseconds.
+pub const BACKOFF_MAX: u32 = 8000;
+
+/// Reconnect attempts before giving up on a channel.
+pub const MAX_ATTEMPTS: u32 = 8;
+
+/// The channel is being refused because the node is draining or
+/// overloaded; try one of the alternates.
+const UNAVAILABLE: u16 = 4012;
+
+/// The node stopped and closed the channel; try one of the alternates,
+/// if it named any.
+const SHUTDOWN: u16 = 4005;
+
+/// The channel moved to another node; reconnect to its `location`.
+const MIGRATED: u16 = 4019;
+
+/// A text frame from the server.
+#[derive(Debug, PartialEq)]
+pub enum Incoming {
+    /// The channel's path, sent before `joined` for older clients.
+    Path(String),
+    Control(ServerControl),
+    /// A frame relayed from a peer. `seq` is only known if the server
+    /// wraps frames in envelopes.
+    Data { data: String, seq: Option<u64> },
+    /// Chunk `index` of `count` of a chunked transfer from a peer; the
+    /// whole payload arrives as `Data` with the last chunk.
+    Partial { transfer: String, index: u32, count: u32 },
+}
+
+/// What to do once the socket has closed.
+#[derive(Debug, PartialEq)]
+pub enum Next {
+    /// Connect to `url` after `delay_ms`.
+    Reconnect { url: String, delay_ms: u32 },
+    Stop,
+}
+
+/// The `ws` or `wss` URL for a node's `http` or `https` one.
+pub fn ws_url(url: &str) -> String {
+    if url.starts_with("http") {
+        format!("ws{}", &url[4..])
+    } else {
+        url.to_owned()
+    }
+}
+
+#[derive(Debug, Default)]
+pub struct Session {
+    /// Base URL of the node, e.g. `wss://relay.example.com`.
+    pub base: String,
+    /// Path of the channel, once joined or if joining an existing one.
+    pub channel: Option<String>,
+    /// Token for reclaiming our slot after a drop.
+    pub reconnect: Option<String>,
+    /// Capabilities to declare in a `hello` on connecting.
+    pub hello: Option<Capabilities>,
+    /// What every participant has in common, once they have all said.
+    pub capabilities: Option<Capabilities>,
+    /// How far our clock is ahead of the server's, in milliseconds.
+    pub skew_ms: Option<i64>,
+    pub last_error: Option<ErrorEnvelope>,
+    /// Whether relayed frames arrive wrapped in a `RelayEnvelope`.
+    pub envelopes: bool,
+    alternates: Vec<String>,
+    attempts: u32,
+    /// Chunks received so far of each transfer in progress.
+    chunks: HashMap<String, Vec<String>>,
+}
+
+impl Session {
+    pub fn new(base: &str, channel: Option<&str>) -> Self {
+        Self {
+            base: ws_url(base.trim_right_matches('/')),
+            channel: channel.map(|c| c.to_owned()),
+            ..Default::default()
+        }
+    }
+
+    /// Where to connect: the channel if we have one, otherwise a new one.
+    pub fn url(&self) -> String {
+        let path = self.channel.as_ref().map_or("/v1/ws/", |c| c.as_str());
+        match self.reconnect {
+            Some(ref token) => format!("{}{}?reconnect={}", self.base, path, token),
+            None => format!("{}{}", self.base, path),
+        }
+    }
+
+    /// The socket is open; returns the `hello` to send, if any.
+    pub fn opened(&mut self) -> Option<String> {
+        self.hello
+            .clone()
+            .map(|hello| ClientControl::Hello(hello).to_text())
+    }
+
+    /// Make sense of a text frame received at `now_ms`.
+    pub fn receive(&mut self, text: &str, now_ms: u64) -> Incoming {
+        if text.starts_with("/v1/ws/") {
+            return Incoming::Path(text.to_owned());
+        }
+        // The server refuses to relay frames that would pass for its own
+        // control messages, so these are from it.
+        if text.starts_with('{') && text.contains("\"control\"") {
+            if let Ok(control) = serde_json::from_str::<ServerControl>(text) {
+                self.control(&control, now_ms);
+                return Incoming::Control(control);
+            }
+        }
+        let envelope = if self.envelopes {
+            serde_json::from_str::<RelayEnvelope>(text).ok()
+        } else {
+            None
+        };
+        let (data, seq) = match envelope {
+            Some(envelope) => (envelope.data.into_owned(), Some(envelope.seq)),
+            None => (text.to_owned(), None),
+        };
+        if data.starts_with('{') && data.contains("\"chunk\"") {
+            if let Ok(ClientControl::Chunk {
+                transfer,
+                index,
+                count,
+                data,
+            }) = serde_json::from_str::<ClientControl>(&data)
+            {
+                return self.chunk(transfer, index, count, data, seq);
+            }
+        }
+        Incoming::Data { data, seq }
+    }
+
+    /// Reassemble a chunked transfer. The server relays chunks in order,
+    /// so anything else means the transfer was abandoned and restarted.
+    fn chunk(
+        &mut self,
+        transfer: String,
+        index: u32,
+        count: u32,
+        data: String,
+        seq: Option<u64>,
+    ) -> Incoming {
+        let complete = {
+            let parts = self.chunks.entry(transfer.clone()).or_insert_with(Vec::new);
+            if index == 0 || parts.len() as u32 != index {
+                parts.clear();
+            }
+            if parts.len() as u32 == index {
+                parts.push(data);
+            }
+            parts.len() as u32 >= count
+        };
+        if complete {
+            if let Some(parts) = self.chunks.remove(&transfer) {
+                return Incoming::Data {
+                    data: parts.concat(),
+                    seq,
+                };
+            }
+        }
+        Incoming::Partial {
+            transfer,
+            index,
+            count,
+        }
+    }
+
+    fn control(&mut self, control: &ServerControl, now_ms: u64) {
+        match *control {
+            ServerControl::Joined {
+                ref channel,
+                ref reconnect,
+                server_ts,
+                ..
+            } => {
+                self.channel = Some(channel.clone());
+                self.reconnect = reconnect.clone();
+                self.skew_ms = Some(now_ms as i64 - server_ts as i64);
+                self.last_error = None;
+                self.attempts = 0;
+            }
+            ServerControl::Capabilities(ref common) => self.capabilities = Some(common.clone()),
+            ServerControl::Error(ref err) => self.last_error = Some(err.clone()),
+            _ => {}
+        }
+    }
+
+    /// The answer a control message needs, if any.
+    pub fn answer(control: &ServerControl) -> Option<String> {
+        match *control {
+            ServerControl::Ping { ref nonce, .. } => Some(
+                ClientControl::Pong {
+                    nonce: nonce.clone(),
+                }.to_text(),
+            ),
+            _ => None,
+        }
+    }
+
+    /// The socket closed with `code`; work out whether and where to
+    /// reconnect.
+    pub fn closed(&mut self, code: u16) -> Next {
+        let details = self
+            .last_error
+            .take()
+            .filter(|err| err.code == code)
+            .and_then(|err| err.details)
+            .unwrap_or(Value::Null);
+        match code {
+            MIGRATED => {
+                let location = match details["location"].as_str() {
+                    Some(location) => location,
+                    None => return Next::Stop,
+                };
+                let base = location.find("/v1/ws/").map_or(location, |i| &location[..i]);
+                self.base = ws_url(base);
+                self.reconnect = details["reconnect"].as_str().map(|t| t.to_owned());
+                // Our slot is already waiting on the new node.
+                self.attempts = 0;
+                Next::Reconnect {
+                    url: self.url(),
+                    delay_ms: 0,
+                }
+            }
+            UNAVAILABLE | SHUTDOWN => {
+                if let Some(alternates) = details["alternates"].as_array() {
+                    self.alternates = alternates
+                        .iter()
+                        .filter_map(|a| a.as_str())
+                        .map(|a| a.to_owned())
+                        .collect();
+                }
+                if self.alternates.is_empty() {
+                    // A stopped node won't have the channel back.
+                    return if code == SHUTDOWN {
+                        Next::Stop
+                    } else {
+                        self.backoff()
+                    };
+                }
+                let next = self.alternates.remove(0);
+                self.base = ws_url(next.trim_right_matches('/'));
+                self.backoff()
+            }
+            // Dropped, or the server went away, with a slot to go back to.
+            1001 | 1006 if self.reconnect.is_some() => self.backoff(),
+            _ => Next::Stop,
+        }
+    }
+
+    fn backoff(&mut self) -> Next {
+        if self.attempts >= MAX_ATTEMPTS {
+            return Next::Stop;
+        }
+        let delay_ms = BACKOFF_MIN
+            .checked_shl(self.attempts)
+            .map_or(BACKOFF_MAX, |d| d.min(BACKOFF_MAX));
+        self.attempts += 1;
+        Next::Reconnect {
+            url: self.url(),
+            delay_ms,
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    use pairsona_protocol::Role;
+
+    #[test]
+    fn test_receive() {
+        let mut session = Session::new("https://relay.example.com/", None);
+        assert_eq!(session.url(), "wss://relay.example.com/v1/ws/");
+        session.hello = Some(Capabilities {
+            resume: true,
+            ..Default::default()
+        });
+        assert!(session.opened().unwrap().starts_with(r#"{"control":"hello","#));
+
+        assert_eq!(
+            session.receive("/v1/ws/abc", 0),
+            Incoming::Path("/v1/ws/abc".to_owned())
+        );
+        let joined = session.receive(
+            r#"{"control": "joined", "channel": "/v1/ws/abc", "role": "initiator",
+                "reconnect": "t0", "server_ts": 1000}"#,
+            1250,
+        );
+        match joined {
+            Incoming::Control(ServerControl::Joined { role, .. }) => {
+                assert_eq!(role, Role::Initiator)
+            }
+            other => panic!("{:?}", other),
+        }
+        assert_eq!(session.skew_ms, Some(250));
+        assert_eq!(session.url(), "wss://relay.example.com/v1/ws/abc?reconnect=t0");
+
+        let ping = match session.receive(r#"{"control": "ping", "nonce": 7, "server_ts": 1}"#, 0) {
+            Incoming::Control(ping) => ping,
+            other => panic!("{:?}", other),
+        };
+        assert_eq!(
+            Session::answer(&ping),
+            Some(r#"{"control":"pong","nonce":7}"#.to_owned())
+        );
+
+        let frame = r#"{"seq": 3, "data": "{\"msg\": \"hi\"}"}"#;
+        assert_eq!(
+            session.receive(frame, 0),
+            Incoming::Data {
+                data: frame.to_owned(),
+                seq: None
+            }
+        );
+        session.envelopes = true;
+        assert_eq!(
+            session.receive(frame, 0),
+            Incoming::Data {
+                data: r#"{"msg": "hi"}"#.to_owned(),
+                seq: Some(3)
+            }
+        );
+    }
+
+    #[test]
+    fn test_chunks() {
+        let mut session = Session::new("https://relay.example.com/", None);
+        let chunk = |transfer: &str, index: u32, data: &str| {
+            ClientControl::Chunk {
+                transfer: transfer.to_owned(),
+                index,
+                count: 3,
+                data: data.to_owned(),
+            }.to_text()
+        };
+        assert_eq!(
+            session.receive(&chunk("t", 0, "ab"), 0),
+            Incoming::Partial {
+                transfer: "t".to_owned(),
+                index: 0,
+                count: 3
+            }
+        );
+        // transfers may be interleaved.
+        session.receive(&chunk("u", 0, "xy"), 0);
+        session.receive(&chunk("t", 1, "cd"), 0);
+        assert_eq!(
+            session.receive(&chunk("t", 2, "e"), 0),
+            Incoming::Data {
+                data: "abcde".to_owned(),
+                seq: None
+            }
+        );
+
+        // a restarted transfer starts over.
+        session.receive(&chunk("u", 0, "12"), 0);
+        session.receive(&chunk("u", 1, "34"), 0);
+        session.envelopes = true;
+        let last = format!(
+            r#"{{"seq": 9, "data": {}}}"#,
+            serde_json::to_string(&chunk("u", 2, "5")).unwrap()
+        );
+        assert_eq!(
+            session.receive(&last, 0),
+            Incoming::Data {
+                data: "12345".to_owned(),
+                seq: Some(9)
+            }
+        );
+        assert!(session.chunks.is_empty());
+    }
+
+    #[test]
+    fn test_closed() {
+        let mut session = Session::new("http://a:8000", Some("/v1/ws/abc"));
+        // Nothing to go back to.
+        assert_eq!(session.closed(1006), Next::Stop);
+
+        session.reconnect = Some("t0".to_owned());
+        let delays: Vec<u32> = (0..MAX_ATTEMPTS + 1)
+            .map(|_| match session.closed(1006) {
+                Next::Reconnect { delay_ms, .. } => delay_ms,
+                Next::Stop => 0,
+            })
+            .collect();
+        assert_eq!(delays, vec![250, 500, 1000, 2000, 4000, 8000, 8000, 8000, 0]);
+
+        session.receive(
+            r#"{"control": "error", "code": 4019, "reason": "Moved", "retriable": true,
+                "details": {"location": "http://b:8000/v1/ws/abc?reconnect=t1",
+                            "reconnect": "t1"}}"#,
+            0,
+        );
+        assert_eq!(
+            session.closed(4019),
+            Next::Reconnect {
+                url: "ws://b:8000/v1/ws/abc?reconnect=t1".to_owned(),
+                delay_ms: 0
+            }
+        );
+
+        session.receive(
+            r#"{"control": "error", "code": 4012, "reason": "Unavailable", "retriable": true,
+                "details": {"alternates": ["http://c:8000", "http://d:8000"]}}"#,
+            0,
+        );
+        match session.closed(4012) {
+            Next::Reconnect { url, .. } => assert_eq!(url, "ws://c:8000/v1/ws/abc?reconnect=t1"),
+            other => panic!("{:?}", other),
+        }
+        match session.closed(4012) {
+            Next::Reconnect { url, .. } => assert_eq!(url, "ws://d:8000/v1/ws/abc?reconnect=t1"),
+            other => panic!("{:?}", other),
+        }
+
+        session.receive(
+            r#"{"control": "error", "code": 4005, "reason": "Shutdown", "retriable": false,
+                "details": {"alternates": ["http://e:8000"]}}"#,
+            0,
+        );
+        match session.closed(4005) {
+            Next::Reconnect { url, .. } => assert_eq!(url, "ws://e:8000/v1/ws/abc?reconnect=t1"),
+            other => panic!("{:?}", other),
+        }
+        assert_eq!(session.closed(4005), Next::Stop);
+        assert_eq!(session.closed(4003), Next::Stop);
+    }
+}
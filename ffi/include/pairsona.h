@@ -0,0 +1,51 @@
+/* Run the pairsona relay inside another process. Functions returning int
+ * return 0 on success and -1 on failure; pairsona_server_error then says
+ * why. */
+
+#ifndef PAIRSONA_H
+#define PAIRSONA_H
+
+#ifdef __cplusplus
+extern "C" {
+#endif
+
+typedef struct PairsonaServer PairsonaServer;
+
+/* Called on the server's thread with each channel lifecycle event, as a
+ * JSON object, e.g. {"event":"created","channel":"...","session":1,"ts":...}.
+ * The string is only valid for the duration of the call. */
+typedef void (*pairsona_event_callback)(const char *event, void *user_data);
+
+/* Load the settings from the config file at `config`, or if NULL, the
+ * usual config/<RUN_MODE> file and PAIR_* environment variables. Returns
+ * NULL if the settings are invalid, and if `error` isn't NULL, sets
+ * *error to why; free it with pairsona_string_free. */
+PairsonaServer *pairsona_server_new(const char *config, char **error);
+
+/* Free a string returned through pairsona_server_new's `error`. */
+void pairsona_string_free(char *string);
+
+/* Must be called before pairsona_server_start. */
+int pairsona_server_on_event(PairsonaServer *server,
+                             pairsona_event_callback callback,
+                             void *user_data);
+
+/* Start the server on a thread of its own; returns once it is listening. */
+int pairsona_server_start(PairsonaServer *server);
+
+/* Stop the server, saving or closing its channels as SIGTERM would;
+ * returns once it has. */
+int pairsona_server_stop(PairsonaServer *server);
+
+/* Why the last failed call failed, or NULL. Valid until the next call on
+ * this server. */
+const char *pairsona_server_error(const PairsonaServer *server);
+
+/* Stop the server if it is running, and free it. */
+void pairsona_server_free(PairsonaServer *server);
+
+#ifdef __cplusplus
+}
+#endif
+
+#endif
@@ -0,0 +1,220 @@
+//! A C interface for running the relay inside another process, for
+//! services that aren't written in Rust, air-gapped deployments, and
+//! tests that want a real server without managing a separate one.
+//!
+//! See `include/pairsona.h` for the declarations. Every function returns
+//! `0` (or a non-null pointer) on success; otherwise
+//! `pairsona_server_error`, or for `pairsona_server_new` its `error`
+//! out-parameter, describes what went wrong.
+//!
+//! The server leaves the process' signals to its host.
+
+extern crate channelserver;
+
+use std::ffi::{CStr, CString};
+use std::os::raw::{c_char, c_int, c_void};
+use std::panic::{self, AssertUnwindSafe};
+use std::ptr;
+
+use channelserver::embed::{PairsonaServer as Server, ServerHandle};
+use channelserver::settings::Settings;
+
+/// Called with each channel lifecycle event, as a JSON object, and the
+/// `user_data` registered with it.
+pub type EventCallback = extern "C" fn(event: *const c_char, user_data: *mut c_void);
+
+/// Passed back to the embedder, on the server's thread.
+struct UserData(*mut c_void);
+
+unsafe impl Send for UserData {}
+
+pub struct PairsonaServer {
+    /// Taken when the server starts.
+    settings: Option<Settings>,
+    on_event: Option<(EventCallback, *mut c_void)>,
+    handle: Option<ServerHandle>,
+    error: Option<CString>,
+}
+
+impl PairsonaServer {
+    fn fail(&mut self, err: &str) -> c_int {
+        self.error = CString::new(err.replace('\0', "")).ok();
+        -1
+    }
+}
+
+/// Report `err` through the `error` out-parameter, if there is one, and
+/// return null.
+unsafe fn fail_new(error: *mut *mut c_char, err: &str) -> *mut PairsonaServer {
+    if !error.is_null() {
+        *error = CString::new(err.replace('\0', ""))
+            .map(|err| err.into_raw())
+            .unwrap_or(ptr::null_mut());
+    }
+    ptr::null_mut()
+}
+
+/// Load the settings from the config file at `config`, or if null, the
+/// usual `config/<RUN_MODE>` file and `PAIR_*` environment variables.
+/// Returns null if the settings are invalid, setting `*error` (if `error`
+/// isn't null) to why, to be freed with `pairsona_string_free`.
+#[no_mangle]
+pub unsafe extern "C" fn pairsona_server_new(
+    config: *const c_char,
+    error: *mut *mut c_char,
+) -> *mut PairsonaServer {
+    if !error.is_null() {
+        *error = ptr::null_mut();
+    }
+    let config = if config.is_null() {
+        None
+    } else {
+        match CStr::from_ptr(config).to_str() {
+            Ok(config) => Some(config),
+            Err(_) => return fail_new(error, "The config path isn't valid UTF-8"),
+        }
+    };
+    match Settings::load(config) {
+        Ok(settings) => Box::into_raw(Box::new(PairsonaServer {
+            settings: Some(settings),
+            on_event: None,
+            handle: None,
+            error: None,
+        })),
+        Err(err) => fail_new(error, &format!("Invalid settings: {}", err)),
+    }
+}
+
+/// Free a string returned by `pairsona_server_new`.
+#[no_mangle]
+pub unsafe extern "C" fn pairsona_string_free(string: *mut c_char) {
+    if !string.is_null() {
+        drop(CString::from_raw(string));
+    }
+}
+
+/// Call `callback` with every channel lifecycle event. Must be set before
+/// the server starts. The callback is called on the server's thread.
+#[no_mangle]
+pub unsafe extern "C" fn pairsona_server_on_event(
+    server: *mut PairsonaServer,
+    callback: EventCallback,
+    user_data: *mut c_void,
+) -> c_int {
+    let server = match server.as_mut() {
+        Some(server) => server,
+        None => return -1,
+    };
+    if server.settings.is_none() {
+        return server.fail("The server has already started");
+    }
+    server.on_event = Some((callback, user_data));
+    0
+}
+
+/// Start the server on a thread of its own, returning once it is
+/// listening. A server can only be started once.
+#[no_mangle]
+pub unsafe extern "C" fn pairsona_server_start(server: *mut PairsonaServer) -> c_int {
+    let server = match server.as_mut() {
+        Some(server) => server,
+        None => return -1,
+    };
+    let settings = match server.settings.take() {
+        Some(settings) => settings,
+        None => return server.fail("The server has already started"),
+    };
+    let mut builder = Server::builder().settings(settings);
+    if let Some((callback, user_data)) = server.on_event {
+        let user_data = UserData(user_data);
+        builder = builder.on_event(move |event| {
+            if let Ok(event) = CString::new(event) {
+                callback(event.as_ptr(), user_data.0);
+            }
+        });
+    }
+    // Unwinding into C is undefined behaviour.
+    match panic::catch_unwind(AssertUnwindSafe(|| builder.build().run())) {
+        Ok(Ok(handle)) => {
+            server.handle = Some(handle);
+            server.error = None;
+            0
+        }
+        Ok(Err(err)) => server.fail(&err),
+        Err(_) => server.fail("The server panicked while starting"),
+    }
+}
+
+/// Stop the server, returning once it has.
+#[no_mangle]
+pub unsafe extern "C" fn pairsona_server_stop(server: *mut PairsonaServer) -> c_int {
+    let server = match server.as_mut() {
+        Some(server) => server,
+        None => return -1,
+    };
+    match server.handle.take() {
+        Some(handle) => {
+            handle.shutdown();
+            0
+        }
+        None => server.fail("The server isn't running"),
+    }
+}
+
+/// What went wrong with the last call that failed, or null. Valid until
+/// the next call on this server.
+#[no_mangle]
+pub unsafe extern "C" fn pairsona_server_error(server: *const PairsonaServer) -> *const c_char {
+    match server.as_ref().and_then(|server| server.error.as_ref()) {
+        Some(error) => error.as_ptr(),
+        None => ptr::null(),
+    }
+}
+
+/// Stop the server if it is running, and free it.
+#[no_mangle]
+pub unsafe extern "C" fn pairsona_server_free(server: *mut PairsonaServer) {
+    if server.is_null() {
+        return;
+    }
+    let server = Box::from_raw(server);
+    if let Some(handle) = server.handle {
+        handle.shutdown();
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::env;
+    use std::fs;
+    use std::process;
+
+    use super::*;
+
+    #[test]
+    fn test_lifecycle() {
+        unsafe {
+            let missing = CString::new("/nonexistent/relay.toml").unwrap();
+            let mut error = ptr::null_mut();
+            assert!(pairsona_server_new(missing.as_ptr(), &mut error).is_null());
+            assert!(!error.is_null());
+            assert!(CStr::from_ptr(error).to_str().unwrap().starts_with("Invalid settings"));
+            pairsona_string_free(error);
+
+            let path = env::temp_dir().join(format!("pairsona-ffi-{}.toml", process::id()));
+            fs::write(&path, "hostname = \"127.0.0.1\"\nport = 0\n").unwrap();
+            let config = CString::new(path.to_str().unwrap()).unwrap();
+            let server = pairsona_server_new(config.as_ptr(), &mut error);
+            fs::remove_file(&path).ok();
+            assert!(!server.is_null());
+            assert!(error.is_null());
+
+            assert_eq!(pairsona_server_start(server), 0, "{:?}", (*server).error);
+            assert_eq!(pairsona_server_start(server), -1);
+            assert!(!pairsona_server_error(server).is_null());
+            assert_eq!(pairsona_server_stop(server), 0);
+            assert_eq!(pairsona_server_stop(server), -1);
+            pairsona_server_free(server);
+        }
+    }
+}
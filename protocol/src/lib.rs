@@ -0,0 +1,265 @@
+//! The messages exchanged between the relay and its clients, shared by the
+//! server and client libraries.
+//!
+//! Control messages are JSON objects carrying a `"control"` key naming the
+//! message type. They are sent as text frames alongside the relayed peer
+//! frames, which may be wrapped in a `RelayEnvelope`.
+
+extern crate serde;
+#[macro_use]
+extern crate serde_derive;
+#[cfg_attr(test, macro_use)]
+extern crate serde_json;
+
+use std::borrow::Cow;
+use std::collections::BTreeMap;
+
+use serde_json::Value;
+
+/// How a participant came to be in a channel.
+#[derive(Clone, Copy, Debug, Eq, Hash, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Role {
+    /// Created the channel.
+    Initiator,
+    /// Joined a channel someone else created.
+    Joiner,
+}
+
+/// What a client can handle, as declared in its `hello`. The server
+/// answers with what every participant (and the server) has in common.
+#[derive(Clone, Debug, Default, Deserialize, Eq, PartialEq, Serialize)]
+pub struct Capabilities {
+    /// Frame encodings, e.g. `text`.
+    #[serde(default)]
+    pub encodings: Vec<String>,
+    /// Largest frame accepted, in octets.
+    #[serde(default)]
+    pub max_frame_size: Option<usize>,
+    /// Reconnects with its reconnect token after a drop.
+    #[serde(default)]
+    pub resume: bool,
+    /// Optional protocol extensions, by name, with their parameters.
+    /// These are between the clients; the server only brokers them.
+    #[serde(default)]
+    pub extensions: BTreeMap<String, Value>,
+}
+
+impl Capabilities {
+    /// The extensions every one of `all` offers. The parameters are those
+    /// they all sent, or `null` if they differ, meaning the extension's
+    /// defaults.
+    pub fn common_extensions(all: &[&Capabilities]) -> BTreeMap<String, Value> {
+        let first = match all.first() {
+            Some(first) => first,
+            None => return BTreeMap::new(),
+        };
+        first
+            .extensions
+            .iter()
+            .filter_map(|(name, params)| {
+                let mut agreed = params.clone();
+                for other in all {
+                    match other.extensions.get(name) {
+                        Some(theirs) if *theirs != agreed => agreed = Value::Null,
+                        Some(_) => {}
+                        None => return None,
+                    }
+                }
+                Some((name.clone(), agreed))
+            })
+            .collect()
+    }
+
+    /// What all of `all` support.
+    pub fn common(all: &[&Capabilities]) -> Self {
+        let first = match all.first() {
+            Some(first) => first,
+            None => return Self::default(),
+        };
+        Self {
+            encodings: first
+                .encodings
+                .iter()
+                .filter(|encoding| all.iter().all(|c| c.encodings.contains(encoding)))
+                .cloned()
+                .collect(),
+            max_frame_size: all.iter().filter_map(|c| c.max_frame_size).min(),
+            resume: all.iter().all(|c| c.resume),
+            extensions: Self::common_extensions(all),
+        }
+    }
+}
+
+/// The shape of every error the server reports, over websockets (as an
+/// `error` control message) and HTTP (as the response body).
+#[derive(Clone, Debug, Deserialize, PartialEq, Serialize)]
+pub struct ErrorEnvelope {
+    pub code: u16,
+    pub reason: String,
+    /// Might the same request succeed if retried later?
+    pub retriable: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub details: Option<Value>,
+}
+
+/// Control messages sent by the server.
+#[derive(Clone, Debug, Deserialize, PartialEq, Serialize)]
+#[serde(tag = "control", rename_all = "snake_case")]
+pub enum ServerControl {
+    /// You have joined `channel` as `role`. `reconnect` is the token to
+    /// present to reclaim your slot if your connection drops. `server_ts`
+    /// is the server's clock, in milliseconds since the epoch, for working
+    /// out how far off your own is.
+    Joined {
+        channel: String,
+        role: Role,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        reconnect: Option<String>,
+        server_ts: u64,
+    },
+    /// Another participant has joined your channel.
+    PeerJoined { role: Role },
+    /// A participant's connection dropped. Their slot is held for a while
+    /// in case they reconnect.
+    PeerDropped { role: Role },
+    /// A participant whose connection dropped has reconnected.
+    PeerReconnected { role: Role },
+    /// A participant has moved to another device.
+    PeerHandedOff { role: Role },
+    /// Answer to your `handoff`. Another device connecting to the channel
+    /// with `?handoff=<token>` takes over your slot, and you are
+    /// disconnected. Only the latest token is valid, and only once.
+    HandoffToken { token: String },
+    /// Chunk `index` of your chunked `transfer` has been relayed.
+    ChunkAck { transfer: String, index: u32 },
+    /// A frame you sent of `size` bytes could not be delivered to a peer.
+    Undeliverable { size: usize },
+    /// A frame you sent of `size` bytes was dropped because the channel is
+    /// too far over its bandwidth.
+    Throttled { size: usize },
+    /// A frame carrying an already seen `message_id` was not relayed.
+    Duplicate { message_id: String },
+    /// Someone tried to join your channel after it was full.
+    JoinAttempted {},
+    /// Every participant has sent a `hello`; this is what you all (and
+    /// the server) support.
+    Capabilities(Capabilities),
+    /// Application level ping from a peer. Answer with a `pong` carrying
+    /// the same `nonce`.
+    Ping { nonce: Option<Value>, server_ts: u64 },
+    /// Answer to your `ping`. `peer` is false if the server answered
+    /// because there was no one else in the channel.
+    Pong {
+        nonce: Option<Value>,
+        server_ts: u64,
+        peer: bool,
+    },
+    /// Your last message or request was refused.
+    Error(ErrorEnvelope),
+}
+
+impl ServerControl {
+    pub fn to_text(&self) -> String {
+        serde_json::to_string(self).unwrap_or_default()
+    }
+}
+
+/// Control messages sent by clients. These are handled by the server
+/// rather than relayed verbatim.
+#[derive(Clone, Debug, Deserialize, PartialEq, Serialize)]
+#[serde(tag = "control", rename_all = "snake_case")]
+pub enum ClientControl {
+    Ping { nonce: Option<Value> },
+    Pong { nonce: Option<Value> },
+    /// Ask for a token another device can use to take over your slot.
+    Handoff {},
+    /// Declare what this client supports.
+    Hello(Capabilities),
+    /// Part `index` of `count` of a chunked transfer (see `chunking`).
+    Chunk {
+        transfer: String,
+        index: u32,
+        count: u32,
+        data: String,
+    },
+}
+
+impl ClientControl {
+    pub fn to_text(&self) -> String {
+        serde_json::to_string(self).unwrap_or_default()
+    }
+}
+
+/// Wrapper around relayed frames when `sequence_frames`, `stamp_frames`
+/// or `hop_frames` is enabled.
+///
+/// `seq` increases by one for every frame relayed on a channel, so clients
+/// can detect frames lost or reordered between the server and themselves.
+/// `ts` is when the server received the frame, in milliseconds since the
+/// epoch, for computing one way latency and spotting stale frames. `node`
+/// is the node that relayed the frame, so that in a cluster a latency or
+/// gap can be pinned on the hop that caused it.
+#[derive(Debug, Deserialize, PartialEq, Serialize)]
+pub struct RelayEnvelope<'a> {
+    pub seq: u64,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub ts: Option<u64>,
+    #[serde(borrow, default, skip_serializing_if = "Option::is_none")]
+    pub node: Option<Cow<'a, str>>,
+    #[serde(borrow)]
+    pub data: Cow<'a, str>,
+}
+
+impl<'a> RelayEnvelope<'a> {
+    pub fn to_text(&self) -> String {
+        serde_json::to_string(self).unwrap_or_default()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_round_trip() {
+        let joined = ServerControl::Joined {
+            channel: "/v1/ws/abc".to_owned(),
+            role: Role::Joiner,
+            reconnect: Some("token".to_owned()),
+            server_ts: 1_534_567_890_123,
+        };
+        let text = joined.to_text();
+        assert!(text.starts_with(r#"{"control":"joined","#));
+        assert_eq!(serde_json::from_str::<ServerControl>(&text).unwrap(), joined);
+        let error: ServerControl = serde_json::from_str(
+            r#"{"control": "error", "code": 4019, "reason": "Moved", "retriable": true,
+                "details": {"location": "http://b:8000/v1/ws/abc"}}"#,
+        ).unwrap();
+        match error {
+            ServerControl::Error(err) => {
+                assert_eq!(err.code, 4019);
+                assert_eq!(err.details.unwrap()["location"], json!("http://b:8000/v1/ws/abc"));
+            }
+            other => panic!("{:?}", other),
+        }
+
+        let hello = ClientControl::Hello(Capabilities {
+            encodings: vec!["text".to_owned()],
+            resume: true,
+            ..Default::default()
+        });
+        let text = hello.to_text();
+        assert_eq!(serde_json::from_str::<ClientControl>(&text).unwrap(), hello);
+
+        // frames are usually JSON themselves, so escaped
+        let frame = RelayEnvelope {
+            seq: 7,
+            ts: Some(1),
+            node: Some("http://a:8000".into()),
+            data: r#"{"msg": "hi"}"#.into(),
+        };
+        let text = frame.to_text();
+        assert_eq!(serde_json::from_str::<RelayEnvelope>(&text).unwrap(), frame);
+    }
+}
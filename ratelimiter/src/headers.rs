@@ -0,0 +1,19 @@
+//! Reporting limits to HTTP clients.
+
+use actix_web::http::header::{HeaderName, HeaderValue};
+use actix_web::HttpResponse;
+
+use Quota;
+
+/// Let the client know how to pace itself, per the IETF `RateLimit`
+/// header fields draft.
+pub fn set_headers(resp: &mut HttpResponse, quota: &Quota) {
+    let headers = resp.headers_mut();
+    for &(name, value) in &[
+        ("ratelimit-limit", quota.limit),
+        ("ratelimit-remaining", quota.remaining),
+        ("ratelimit-reset", quota.reset),
+    ] {
+        headers.insert(HeaderName::from_static(name), HeaderValue::from(value));
+    }
+}
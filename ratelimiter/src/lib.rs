@@ -0,0 +1,250 @@
+//! Token bucket rate limiting, shared by everything that needs to slow
+//! clients down: websocket upgrades, messages and the admin API.
+//!
+//! A `RateLimiter` gives each `Key` (a client address, tenant or channel)
+//! a bucket of `burst` tokens, refilled at a steady rate. Buckets are held
+//! in a `Store`: in memory by default, or in Redis (with the `redis`
+//! feature) so that a cluster's nodes share them.
+//!
+//! IPv4 clients are limited per address. IPv6 clients are limited per
+//! prefix (a /64 by default), since anyone holding a prefix can trivially
+//! rotate through the addresses within it.
+
+extern crate actix_web;
+#[cfg(feature = "redis")]
+extern crate redis;
+
+mod headers;
+mod memory;
+#[cfg(feature = "redis")]
+mod redisstore;
+
+use std::fmt;
+use std::net::{IpAddr, Ipv6Addr};
+
+pub use headers::set_headers;
+pub use memory::MemoryStore;
+#[cfg(feature = "redis")]
+pub use redisstore::RedisStore;
+
+/// The outcome of a check, as reported in `RateLimit-*` headers.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Quota {
+    pub allowed: bool,
+    /// requests a client may make in a burst
+    pub limit: u64,
+    /// requests left in the current burst
+    pub remaining: u64,
+    /// seconds until another request will be allowed
+    pub reset: u64,
+}
+
+impl Quota {
+    /// Not limited at all.
+    pub fn unlimited() -> Self {
+        Quota {
+            allowed: true,
+            limit: 0,
+            remaining: 0,
+            reset: 0,
+        }
+    }
+
+    /// The quota of a bucket left holding `tokens`.
+    pub fn new(allowed: bool, tokens: f64, rate: f64, burst: f64) -> Self {
+        let reset = if tokens >= 1.0 {
+            0
+        } else {
+            ((1.0 - tokens) / rate).ceil() as u64
+        };
+        Quota {
+            allowed,
+            limit: burst as u64,
+            remaining: tokens.max(0.0).floor() as u64,
+            reset,
+        }
+    }
+}
+
+/// What is being limited.
+#[derive(Clone, Debug, Eq, Hash, PartialEq)]
+pub enum Key {
+    Ip(IpAddr),
+    Tenant(String),
+    Channel(String),
+}
+
+/// Where buckets are kept.
+pub trait Store: Send {
+    /// Take a token from the bucket named `key`, which refills at `rate`
+    /// tokens a second up to `burst`.
+    fn take(&mut self, key: &str, rate: f64, burst: f64) -> Result<Quota, StoreError>;
+
+    /// Is the bucket named `key` empty? Takes nothing.
+    fn exhausted(&mut self, key: &str, rate: f64, burst: f64) -> Result<bool, StoreError>;
+}
+
+#[derive(Debug)]
+pub struct StoreError(pub String);
+
+impl fmt::Display for StoreError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "rate limit store: {}", self.0)
+    }
+}
+
+/// IPv4 addresses as themselves (including IPv4-mapped IPv6 addresses),
+/// IPv6 addresses by their `v6_prefix` bit prefix.
+pub fn ip_key(addr: IpAddr, v6_prefix: u8) -> IpAddr {
+    match addr {
+        IpAddr::V4(v4) => IpAddr::V4(v4),
+        IpAddr::V6(v6) => {
+            let seg = v6.segments();
+            if seg[..5].iter().all(|s| *s == 0) && seg[5] == 0xffff {
+                if let Some(v4) = v6.to_ipv4() {
+                    return IpAddr::V4(v4);
+                }
+            }
+            let prefix = v6_prefix.min(128);
+            let mask = if prefix == 0 {
+                0
+            } else {
+                !0u128 << (128 - u32::from(prefix))
+            };
+            IpAddr::V6(Ipv6Addr::from(u128::from(v6) & mask))
+        }
+    }
+}
+
+pub struct RateLimiter {
+    /// tokens added per second
+    rate: f64,
+    /// bucket capacity
+    burst: f64,
+    /// IPv6 prefix length that identifies a single client
+    v6_prefix: u8,
+    /// keeps this limiter's buckets apart from others' in a shared store
+    prefix: String,
+    store: Box<Store>,
+    /// store failures; the limiter fails open
+    errors: u64,
+}
+
+impl RateLimiter {
+    /// An in memory limiter. `per_minute` of 0 disables limiting.
+    pub fn new(per_minute: u64, burst: u64, v6_prefix: u8) -> Self {
+        Self::with_store(per_minute, burst, v6_prefix, "", Box::new(MemoryStore::default()))
+    }
+
+    /// A limiter keeping its buckets in `store`, named with `prefix`.
+    pub fn with_store(
+        per_minute: u64,
+        burst: u64,
+        v6_prefix: u8,
+        prefix: &str,
+        store: Box<Store>,
+    ) -> Self {
+        Self {
+            rate: per_minute as f64 / 60.0,
+            burst: burst.max(1) as f64,
+            v6_prefix: v6_prefix.min(128),
+            prefix: prefix.to_owned(),
+            store,
+            errors: 0,
+        }
+    }
+
+    /// The bucket name for `key`.
+    pub fn name(&self, key: &Key) -> String {
+        match key {
+            Key::Ip(addr) => format!("{}ip:{}", self.prefix, ip_key(*addr, self.v6_prefix)),
+            Key::Tenant(tenant) => format!("{}tenant:{}", self.prefix, tenant),
+            Key::Channel(channel) => format!("{}channel:{}", self.prefix, channel),
+        }
+    }
+
+    /// Take a token for `key`. The quota is not `allowed` if the client
+    /// is over its limit.
+    pub fn check(&mut self, key: &Key) -> Quota {
+        if self.rate <= 0.0 {
+            return Quota::unlimited();
+        }
+        let name = self.name(key);
+        match self.store.take(&name, self.rate, self.burst) {
+            Ok(quota) => quota,
+            Err(_) => {
+                self.errors += 1;
+                Quota::unlimited()
+            }
+        }
+    }
+
+    /// Is `key` out of tokens? Unlike `check`, takes none.
+    pub fn exhausted(&mut self, key: &Key) -> bool {
+        if self.rate <= 0.0 {
+            return false;
+        }
+        let name = self.name(key);
+        match self.store.exhausted(&name, self.rate, self.burst) {
+            Ok(exhausted) => exhausted,
+            Err(_) => {
+                self.errors += 1;
+                false
+            }
+        }
+    }
+
+    /// How many checks failed open because the store was unavailable.
+    pub fn errors(&self) -> u64 {
+        self.errors
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_keys() {
+        let v4: IpAddr = "192.0.2.1".parse().unwrap();
+        assert_eq!(ip_key(v4, 64), v4);
+        let mapped: IpAddr = "::ffff:192.0.2.1".parse().unwrap();
+        assert_eq!(ip_key(mapped, 64), v4);
+        let a: IpAddr = "2001:db8:1:2:aaaa::1".parse().unwrap();
+        let b: IpAddr = "2001:db8:1:2:bbbb::2".parse().unwrap();
+        let c: IpAddr = "2001:db8:1:3::1".parse().unwrap();
+        assert_eq!(ip_key(a, 64), ip_key(b, 64));
+        assert!(ip_key(a, 64) != ip_key(c, 64));
+        let limiter = RateLimiter::new(60, 1, 64);
+        assert_eq!(limiter.name(&Key::Ip(b)), "ip:2001:db8:1:2::");
+        assert_eq!(limiter.name(&Key::Tenant("acme".to_owned())), "tenant:acme");
+    }
+
+    #[test]
+    fn test_limit() {
+        let mut limiter = RateLimiter::new(1, 2, 64);
+        let a = Key::Ip("2001:db8::1".parse().unwrap());
+        let b = Key::Ip("2001:db8::2".parse().unwrap());
+        let quota = limiter.check(&a);
+        assert!(quota.allowed);
+        assert_eq!((quota.limit, quota.remaining, quota.reset), (2, 1, 0));
+        // same /64, so shares a's bucket
+        assert!(limiter.check(&b).allowed);
+        let quota = limiter.check(&a);
+        assert!(!quota.allowed);
+        assert_eq!(quota.remaining, 0);
+        // one token a minute
+        assert!(quota.reset > 0 && quota.reset <= 60);
+        assert!(limiter.exhausted(&a));
+        let other = Key::Ip("192.0.2.1".parse().unwrap());
+        assert!(!limiter.exhausted(&other));
+        assert!(limiter.check(&other).allowed);
+        // tenants have their own buckets
+        assert!(limiter.check(&Key::Tenant("2001:db8::".to_owned())).allowed);
+        // disabled
+        let mut limiter = RateLimiter::new(0, 0, 64);
+        for _ in 0..10 {
+            assert!(limiter.check(&a).allowed);
+        }
+    }
+}
@@ -0,0 +1,63 @@
+//! Buckets held in process.
+
+use std::collections::HashMap;
+use std::time::Instant;
+
+use {Quota, Store, StoreError};
+
+/// Stop tracking idle buckets once this many are held.
+const MAX_TRACKED: usize = 10_000;
+
+struct Bucket {
+    tokens: f64,
+    updated: Instant,
+}
+
+impl Bucket {
+    /// The tokens held at `now`.
+    fn tokens(&self, now: Instant, rate: f64, burst: f64) -> f64 {
+        let elapsed = now.duration_since(self.updated);
+        let elapsed = elapsed.as_secs() as f64 + f64::from(elapsed.subsec_nanos()) / 1e9;
+        (self.tokens + elapsed * rate).min(burst)
+    }
+}
+
+#[derive(Default)]
+pub struct MemoryStore {
+    buckets: HashMap<String, Bucket>,
+}
+
+impl MemoryStore {
+    /// Forget buckets that have refilled, and so carry no state.
+    fn prune(&mut self, now: Instant, rate: f64, burst: f64) {
+        self.buckets
+            .retain(|_, bucket| bucket.tokens(now, rate, burst) < burst);
+    }
+}
+
+impl Store for MemoryStore {
+    fn take(&mut self, key: &str, rate: f64, burst: f64) -> Result<Quota, StoreError> {
+        let now = Instant::now();
+        if self.buckets.len() >= MAX_TRACKED && !self.buckets.contains_key(key) {
+            self.prune(now, rate, burst);
+        }
+        let bucket = self.buckets.entry(key.to_owned()).or_insert(Bucket {
+            tokens: burst,
+            updated: now,
+        });
+        bucket.tokens = bucket.tokens(now, rate, burst);
+        bucket.updated = now;
+        let allowed = bucket.tokens >= 1.0;
+        if allowed {
+            bucket.tokens -= 1.0;
+        }
+        Ok(Quota::new(allowed, bucket.tokens, rate, burst))
+    }
+
+    fn exhausted(&mut self, key: &str, rate: f64, burst: f64) -> Result<bool, StoreError> {
+        Ok(self
+            .buckets
+            .get(key)
+            .map_or(false, |bucket| bucket.tokens(Instant::now(), rate, burst) < 1.0))
+    }
+}
@@ -0,0 +1,192 @@
+//! Buckets held in Redis, shared by every node using the same server.
+//!
+//! Each bucket is a hash of its `tokens` and when it was `updated`, by the
+//! Redis server's clock, updated atomically by a script. Buckets expire
+//! once they would have refilled.
+//!
+//! Every check is made on the request path, so connecting, and each round
+//! trip, is bounded by a short timeout, and after a failure the store
+//! doesn't try Redis again until a backoff has passed, failing straight
+//! away in the meantime.
+
+use std::cmp;
+use std::net::{TcpStream, ToSocketAddrs};
+use std::time::{Duration, Instant};
+
+use redis::{self, Client, Connection, ConnectionAddr, ConnectionInfo, IntoConnectionInfo, Script};
+
+use {Quota, Store, StoreError};
+
+/// Longest wait to connect to Redis, in milliseconds.
+const CONNECT_TIMEOUT: u64 = 500;
+
+/// Longest wait for Redis to take a command, or answer it, in
+/// milliseconds.
+const IO_TIMEOUT: u64 = 250;
+
+/// Wait before reconnecting after the first failure, in milliseconds. Each
+/// further failure waits twice as long, up to `BACKOFF_MAX`.
+const BACKOFF_MIN: u64 = 100;
+
+const BACKOFF_MAX: u64 = 10_000;
+
+/// KEYS[1] is the bucket; ARGV is the rate, the burst, and 1 to take a
+/// token or 0 to only look. Returns whether a token was (or could be)
+/// taken, and the tokens left as a string, as Redis truncates numbers.
+const BUCKET: &str = r"
+redis.replicate_commands()
+local rate, burst, take = tonumber(ARGV[1]), tonumber(ARGV[2]), ARGV[3] == '1'
+local time = redis.call('TIME')
+local now = tonumber(time[1]) + tonumber(time[2]) / 1000000
+local state = redis.call('HMGET', KEYS[1], 'tokens', 'updated')
+local tokens = tonumber(state[1]) or burst
+local updated = tonumber(state[2]) or now
+tokens = math.min(burst, tokens + math.max(0, now - updated) * rate)
+local allowed = tokens >= 1
+if take then
+    if allowed then
+        tokens = tokens - 1
+    end
+    redis.call('HMSET', KEYS[1], 'tokens', tostring(tokens), 'updated', tostring(now))
+    redis.call('EXPIRE', KEYS[1], math.ceil((burst - tokens) / rate) + 1)
+end
+return {allowed and 1 or 0, tostring(tokens)}
+";
+
+pub struct RedisStore {
+    info: ConnectionInfo,
+    client: Client,
+    /// reconnected on the next use after an error
+    conn: Option<Connection>,
+    script: Script,
+    /// the wait after the last failure in milliseconds, if the last
+    /// attempt failed
+    backoff: Option<u64>,
+    /// no attempt is made before this
+    retry_after: Option<Instant>,
+}
+
+impl RedisStore {
+    /// A store at a `redis://` URL.
+    pub fn open(url: &str) -> Result<Self, StoreError> {
+        let info = url.into_connection_info().map_err(|e| StoreError(e.to_string()))?;
+        let client = Client::open(info.clone()).map_err(|e| StoreError(e.to_string()))?;
+        Ok(Self {
+            info,
+            client,
+            conn: None,
+            script: Script::new(BUCKET),
+            backoff: None,
+            retry_after: None,
+        })
+    }
+
+    /// Connect, giving up after `CONNECT_TIMEOUT`.
+    fn connect(&self) -> Result<Connection, String> {
+        // The client connects without a timeout, so first check that the
+        // server can be reached at all: an unreachable host would otherwise
+        // hold up the request for as long as the OS takes to give up.
+        if let ConnectionAddr::Tcp(ref host, port) = *self.info.addr {
+            let addrs = (host.as_str(), port)
+                .to_socket_addrs()
+                .map_err(|e| e.to_string())?;
+            let mut reached = Err(format!("{}: no addresses", host));
+            for addr in addrs {
+                let timeout = Duration::from_millis(CONNECT_TIMEOUT);
+                reached = TcpStream::connect_timeout(&addr, timeout)
+                    .map(|_| ())
+                    .map_err(|e| e.to_string());
+                if reached.is_ok() {
+                    break;
+                }
+            }
+            reached?;
+        }
+        let conn = self.client.get_connection().map_err(|e| e.to_string())?;
+        let timeout = Some(Duration::from_millis(IO_TIMEOUT));
+        conn.set_read_timeout(timeout).map_err(|e| e.to_string())?;
+        conn.set_write_timeout(timeout).map_err(|e| e.to_string())?;
+        Ok(conn)
+    }
+
+    /// Note a failure, putting off the next attempt.
+    fn failed(&mut self, reason: String) -> StoreError {
+        self.conn = None;
+        let backoff = self
+            .backoff
+            .map_or(BACKOFF_MIN, |b| cmp::min(b * 2, BACKOFF_MAX));
+        self.backoff = Some(backoff);
+        self.retry_after = Some(Instant::now() + Duration::from_millis(backoff));
+        StoreError(reason)
+    }
+
+    fn run(
+        &mut self,
+        key: &str,
+        rate: f64,
+        burst: f64,
+        take: bool,
+    ) -> Result<(bool, f64), StoreError> {
+        if let Some(retry_after) = self.retry_after {
+            if Instant::now() < retry_after {
+                return Err(StoreError("backing off after a failure".to_owned()));
+            }
+        }
+        if self.conn.is_none() {
+            match self.connect() {
+                Ok(conn) => self.conn = Some(conn),
+                Err(reason) => return Err(self.failed(reason)),
+            }
+        }
+        let result: Result<(i64, String), _> = match self.conn {
+            Some(ref conn) => self
+                .script
+                .key(key)
+                .arg(rate)
+                .arg(burst)
+                .arg(if take { 1 } else { 0 })
+                .invoke(conn),
+            None => return Err(StoreError("not connected".to_owned())),
+        };
+        match result {
+            Ok((allowed, tokens)) => {
+                self.backoff = None;
+                self.retry_after = None;
+                Ok((allowed == 1, tokens.parse().unwrap_or(0.0)))
+            }
+            Err(e) => Err(self.failed(e.to_string())),
+        }
+    }
+}
+
+impl Store for RedisStore {
+    fn take(&mut self, key: &str, rate: f64, burst: f64) -> Result<Quota, StoreError> {
+        let (allowed, tokens) = self.run(key, rate, burst, true)?;
+        Ok(Quota::new(allowed, tokens, rate, burst))
+    }
+
+    fn exhausted(&mut self, key: &str, rate: f64, burst: f64) -> Result<bool, StoreError> {
+        self.run(key, rate, burst, false).map(|(allowed, _)| !allowed)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_backoff() {
+        // Nothing listens on the discard port.
+        let mut store = RedisStore::open("redis://127.0.0.1:9/").unwrap();
+        assert!(store.take("k", 1.0, 1.0).is_err());
+        let first = store.backoff.unwrap();
+        assert_eq!(first, BACKOFF_MIN);
+        // Refused without another attempt while backing off...
+        assert!(store.take("k", 1.0, 1.0).is_err());
+        assert_eq!(store.backoff, Some(first));
+        // ...and waiting longer after each further failure.
+        store.retry_after = Some(Instant::now());
+        assert!(store.exhausted("k", 1.0, 1.0).is_err());
+        assert_eq!(store.backoff, Some(first * 2));
+    }
+}
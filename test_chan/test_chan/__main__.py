@@ -1,6 +1,7 @@
 import time
 import os
 import base64
+import json
 import subprocess
 
 import psutil
@@ -31,7 +32,47 @@ class Connection(object):
         self.ws.send(message)
 
     def recv(self):
-        return self.ws.recv()
+        # skip over server control messages
+        while True:
+            message = self.ws.recv()
+            try:
+                if "control" in json.loads(message):
+                    continue
+            except (ValueError, TypeError):
+                pass
+            return message
+
+    def wait_for(self, control):
+        # wait for a particular server control message
+        while True:
+            try:
+                message = json.loads(self.ws.recv())
+            except (ValueError, TypeError):
+                continue
+            if message.get("control") == control:
+                return message
+
+    def send_chunked(self, payload, chunk_size=16384, transfer="t0"):
+        chunks = [payload[i:i + chunk_size]
+                  for i in range(0, len(payload), chunk_size)] or [""]
+        for index, data in enumerate(chunks):
+            self.send(json.dumps({
+                "control": "chunk",
+                "transfer": transfer,
+                "index": index,
+                "count": len(chunks),
+                "data": data,
+            }))
+            # keep a single chunk in flight
+            self.wait_for("chunk_ack")
+
+    def recv_chunked(self):
+        parts = []
+        while True:
+            chunk = self.wait_for("chunk")
+            parts.append(chunk["data"])
+            if chunk["index"] + 1 == chunk["count"]:
+                return "".join(parts)
 
     def is_closed(self):
         import pdb; pdb.set_trace()
@@ -102,6 +143,15 @@ def full_exchange(opts):
     print("ok")
 
 
+def chunked_transfer(opts):
+    (alice, bob) = get_connection(opts)
+    # larger than a single websocket message may be
+    message = base64.b85encode(os.urandom(100000)).decode("utf8")
+    alice.send_chunked(message)
+    assert message == bob.recv_chunked(), "Chunked message didn't match"
+    print("ok")
+
+
 def max_data(opts, max_bytes=2048):
     (alice, bob) = get_connection(opts)
     message = base64.b85encode(os.urandom(max_bytes)).decode("utf8")
@@ -124,6 +174,7 @@ def main():
         setup(opts)
         simple_connection(opts)
         full_exchange(opts)
+        chunked_transfer(opts)
     except Exception as ex:
         print("ERR:: {}".format(ex))
         raise